@@ -0,0 +1,157 @@
+use dcpu;
+use dcpu::{Fault, FaultKind, Watchpoint};
+use system::System;
+
+
+/// Why a batch run stopped
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Halt {
+	/// The program jumped to itself with interrupts turned off and none waiting,
+	/// so it could never do anything else
+	Loop,
+	/// The DCPU caught fire, with hcf or otherwise
+	Fault(Fault),
+	/// The program wrote the value to the test result address
+	Result(u16),
+	/// The cycle limit came first
+	OutOfCycles,
+}
+
+
+/// Run the machine until the program halts, or for at most the given number of
+/// cycles. Writing to the result address, if there is one, halts it too.
+pub fn run(system: &mut System, max_cycles: u64, result_address: Option<u16>) -> Halt {
+	if let Some(address) = result_address {
+		system.dcpu.watchpoints.push(Watchpoint {start: address, end: address, read: false, write: true, execute: false});
+	}
+
+	let end = system.cycle().saturating_add(max_cycles);
+	while system.cycle() < end {
+		let program_counter = system.dcpu.program_counter;
+		system.run_cycles(1);
+
+		let dcpu = &mut system.dcpu;
+		if let Some(fault) = dcpu.fault {
+			return Halt::Fault(fault);
+		}
+
+		if let Some(hit) = dcpu.watchpoint_hit.take() {
+			return Halt::Result(hit.value);
+		}
+
+		// A sub pc, 1 leaves the program counter where it was, as does sleeping, which
+		// a device can wake from. While interrupts are turned on, a device could
+		// still interrupt the loop, so it keeps going.
+		let stuck = dcpu.program_counter == program_counter && !dcpu.sleeping;
+		if stuck && dcpu.interrupt_address == 0 && dcpu.interrupt_queue.is_empty() && dcpu.hardware_interrupt.is_none() {
+			return Halt::Loop;
+		}
+	}
+
+	Halt::OutOfCycles
+}
+
+
+/// The process exit code for a run. A program that stopped itself exits with the
+/// value it wrote to the result address, or else the value of the given
+/// register, capped at 253. A DCPU that caught fire other than with hcf exits
+/// with 254, and one that ran out of cycles with 255.
+pub fn exit_code(halt: Halt, register: u16) -> i32 {
+	let value = match halt {
+		Halt::Loop | Halt::Fault(Fault {kind: FaultKind::CaughtFire(_), ..}) => register,
+		Halt::Result(value) => value,
+		Halt::Fault(_) => return 254,
+		Halt::OutOfCycles => return 255,
+	};
+
+	value.min(253) as i32
+}
+
+
+/// The index in `Dcpu::registers` of the register with the given name
+pub fn register_index(name: &str) -> Option<usize> {
+	match name.to_lowercase().as_str() {
+		"a" => Some(dcpu::A),
+		"b" => Some(dcpu::B),
+		"c" => Some(dcpu::C),
+		"x" => Some(dcpu::X),
+		"y" => Some(dcpu::Y),
+		"z" => Some(dcpu::Z),
+		"i" => Some(dcpu::I),
+		"j" => Some(dcpu::J),
+		_ => None,
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use test_support::{machine, SharedOutput};
+	use trace::{Filter, Tracer};
+
+	#[test]
+	fn halts_on_a_tight_loop() {
+		// set a, 5; sub pc, 1
+		let mut system = machine(&[0x9801, 0x8b83], vec![]);
+		let halt = run(&mut system, 1000, None);
+		assert_eq!(halt, Halt::Loop);
+		assert_eq!(exit_code(halt, system.dcpu.registers[dcpu::A]), 5);
+
+		// ias 0x10; sub pc, 1 could still be interrupted
+		let mut system = machine(&[0x7d40, 0x0010, 0x8b83], vec![]);
+		assert_eq!(run(&mut system, 1000, None), Halt::OutOfCycles);
+	}
+
+	#[test]
+	fn halts_on_fire() {
+		// hcf 3
+		let mut system = machine(&[0x90e0], vec![]);
+		match run(&mut system, 1000, None) {
+			Halt::Fault(fault) => assert_eq!(fault.kind, FaultKind::CaughtFire(3)),
+			halt => panic!("Expected the DCPU to catch fire, not {:?}", halt),
+		}
+		assert_eq!(exit_code(run(&mut system, 1000, None), 300), 253);
+
+		// An illegal instruction
+		let mut system = machine(&[0x0000], vec![]);
+		assert_eq!(exit_code(run(&mut system, 1000, None), 0), 254);
+	}
+
+	#[test]
+	fn halts_on_a_test_result() {
+		// set a, 7; set [0x1000], a; add a, 1; sub pc, 2
+		let mut system = machine(&[0xa001, 0x03c1, 0x1000, 0x8802, 0x8f83], vec![]);
+		let halt = run(&mut system, 1000, Some(0x1000));
+		assert_eq!(halt, Halt::Result(7));
+		assert_eq!(exit_code(halt, 0), 7);
+
+		let mut system = machine(&[0x8802, 0x8f83], vec![]);
+		assert_eq!(exit_code(run(&mut system, 1000, Some(0x1000)), 0), 255);
+	}
+
+	#[test]
+	fn ring_buffer_trace_is_written_on_a_halt() {
+		// set a, 5; add a, 1; sub pc, 1
+		let output = SharedOutput::new();
+		let mut system = machine(&[0x9801, 0x8802, 0x8b83], vec![]);
+		system.dcpu.trace_sinks.push(Box::new(Tracer::ring(Box::new(output.clone()), Filter::default(), 2)));
+		assert_eq!(run(&mut system, 1000, None), Halt::Loop);
+
+		let text = output.text();
+		let lines: Vec<&str> = text.lines().collect();
+		assert_eq!(lines.len(), 3, "{}", text);
+		assert!(lines[0].contains("before the CPU halted"), "{}", lines[0]);
+		assert!(lines[1].contains("add a, 1"), "{}", lines[1]);
+		assert!(lines[2].contains("sub pc, 1"), "{}", lines[2]);
+	}
+
+	#[test]
+	fn register_names() {
+		assert_eq!(register_index("A"), Some(dcpu::A));
+		assert_eq!(register_index("j"), Some(dcpu::J));
+		assert_eq!(register_index("pc"), None);
+	}
+}
@@ -0,0 +1,528 @@
+use std;
+use std::io;
+use std::io::prelude::*;
+use std::fmt;
+use std::fs::File;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use transport::{Transport, Connection};
+
+
+/// How a call placed by the modem turned out
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DialResult {
+	Connected,
+	Refused,
+	Failed,
+}
+
+
+/// Something that happened to a modem during a session
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+	/// The modem changed to the named state
+	State(String),
+	/// The modem interrupted the DCPU with the given message
+	Interrupt(u16),
+	/// The DCPU sent these words over the connection
+	Send(Vec<u16>),
+	/// These words were put into the receive buffer
+	Receive(Vec<u16>),
+
+	// The remaining events are what the line did, and are all a replay needs.
+	// Each call gets a connection number, counting up from 1.
+
+	/// A call arrived from the given number on the given connection
+	Call(u32, u32),
+	/// The line went dead while waiting for calls
+	NoService,
+	/// The given number was dialed. A connected call gets the given connection.
+	Dial(u32, DialResult, u32),
+	/// These bytes were read from a connection. No bytes means the other end hung up.
+	Read(u32, Vec<u8>),
+	/// Reading from a connection failed
+	ReadFailed(u32),
+	/// Writing to a connection failed
+	WriteFailed(u32),
+}
+
+impl Event {
+	/// Whether the event is something the line did, rather than the modem
+	pub fn is_line(&self) -> bool {
+		match *self {
+			Event::State(_) | Event::Interrupt(_) | Event::Send(_) | Event::Receive(_) => false,
+			_ => true,
+		}
+	}
+
+
+	/// Parse an event written as `<event> [arguments]`, already split at whitespace
+	pub fn parse(kind: &str, arguments: &[&str]) -> Option<Event> {
+		let connection = || arguments.get(0).and_then(|c| c.parse().ok());
+
+		let event = match kind {
+			"state" => Event::State(arguments.get(0)?.to_string()),
+			"interrupt" => Event::Interrupt(u16::from_str_radix(arguments.get(0)?, 16).ok()?),
+			"send" => Event::Send(arguments.iter().map(|w| u16::from_str_radix(w, 16)).collect::<Result<_, _>>().ok()?),
+			"receive" => Event::Receive(arguments.iter().map(|w| u16::from_str_radix(w, 16)).collect::<Result<_, _>>().ok()?),
+			"call" => Event::Call(connection()?, u32::from_str_radix(arguments.get(1)?, 16).ok()?),
+			"no-service" => Event::NoService,
+			"dial" => Event::Dial(
+				u32::from_str_radix(arguments.get(0)?, 16).ok()?,
+				match *arguments.get(1)? {
+					"connected" => DialResult::Connected,
+					"refused" => DialResult::Refused,
+					"failed" => DialResult::Failed,
+					_ => return None,
+				},
+				arguments.get(2)?.parse().ok()?),
+			"read" => Event::Read(connection()?, arguments[1..].iter().map(|b| u8::from_str_radix(b, 16)).collect::<Result<_, _>>().ok()?),
+			"read-failed" => Event::ReadFailed(connection()?),
+			"write-failed" => Event::WriteFailed(connection()?),
+			_ => return None,
+		};
+
+		Some(event)
+	}
+}
+
+impl fmt::Display for Event {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Event::State(ref name) => write!(f, "state {}", name),
+			Event::Interrupt(message) => write!(f, "interrupt {:04x}", message),
+			Event::Send(ref words) => write!(f, "send{}", words.iter().map(|w| format!(" {:04x}", w)).collect::<String>()),
+			Event::Receive(ref words) => write!(f, "receive{}", words.iter().map(|w| format!(" {:04x}", w)).collect::<String>()),
+			Event::Call(connection, number) => write!(f, "call {} {:08x}", connection, number),
+			Event::NoService => write!(f, "no-service"),
+			Event::Dial(number, result, connection) => write!(f, "dial {:08x} {} {}", number, match result {
+				DialResult::Connected => "connected",
+				DialResult::Refused => "refused",
+				DialResult::Failed => "failed",
+			}, connection),
+			Event::Read(connection, ref bytes) => write!(f, "read {}{}", connection, bytes.iter().map(|b| format!(" {:02x}", b)).collect::<String>()),
+			Event::ReadFailed(connection) => write!(f, "read-failed {}", connection),
+			Event::WriteFailed(connection) => write!(f, "write-failed {}", connection),
+		}
+	}
+}
+
+
+// Parse a single `<cycle> <event> [arguments]` line of a capture file
+fn parse_line(line: &str) -> Option<(u64, Event)> {
+	let mut parts = line.split_whitespace();
+	let cycle = parts.next()?.parse().ok()?;
+	let kind = parts.next()?;
+	let arguments: Vec<&str> = parts.collect();
+
+	Some((cycle, Event::parse(kind, &arguments)?))
+}
+
+
+
+
+/// Somewhere the events of a `RecordedLine` are written down as they happen
+pub trait LineLog {
+	fn record_line(&self, cycle: u64, event: &Event);
+}
+
+
+/// Where a `ReplayTransport` gets the line's events from
+pub trait LineEvents {
+	/// Take the line's next event if it happened by the given cycle and `wanted`
+	/// says it is the one being looked for
+	fn take(&self, cycle: u64, wanted: &dyn Fn(&Event) -> bool) -> Option<Event>;
+
+	/// Put an event back to be taken again, like the rest of a read that didn't fit
+	fn put_back(&self, cycle: u64, event: Event);
+}
+
+
+
+
+/// A log of everything a modem does, one event per line with the cycle it
+/// happened on. The modem and its line share the log, so clones write to the
+/// same place.
+#[derive(Clone)]
+pub struct Capture {
+	// None once writing has failed
+	output: Arc<Mutex<Option<Box<dyn Write>>>>,
+}
+
+impl Capture {
+	/// Capture to the given file, replacing anything already in it
+	pub fn create(path: &str) -> io::Result<Capture> {
+		Capture::new(Box::new(io::BufWriter::new(File::create(path)?)))
+	}
+
+	pub fn new(mut output: Box<dyn Write>) -> io::Result<Capture> {
+		writeln!(output, "# dcpu modem capture")?;
+		Ok(Capture {output: Arc::new(Mutex::new(Some(output)))})
+	}
+
+	pub fn record(&self, cycle: u64, event: &Event) {
+		let mut output = self.output.lock().unwrap();
+
+		// The emulator usually exits by being killed, so don't leave anything sitting in a buffer
+		let failed = match *output {
+			Some(ref mut output) => writeln!(output, "{} {}", cycle, event).and_then(|_| output.flush()).is_err(),
+			None => false,
+		};
+
+		if failed {
+			eprintln!("Unable to write to the modem capture, no longer capturing");
+			*output = None;
+		}
+	}
+}
+
+impl LineLog for Capture {
+	fn record_line(&self, cycle: u64, event: &Event) {
+		self.record(cycle, event);
+	}
+}
+
+
+
+
+/// A modem's line that writes down what the line does as it happens, so that it
+/// can be played back by a `ReplayTransport`
+pub struct RecordedLine<L> {
+	line: Box<dyn Transport>,
+	log: L,
+	cycle: Arc<Mutex<u64>>,
+	connections: u32,
+}
+
+impl<L: LineLog + Clone + 'static> RecordedLine<L> {
+	pub fn new(line: Box<dyn Transport>, log: L) -> RecordedLine<L> {
+		RecordedLine {
+			line: line,
+			log: log,
+			cycle: Arc::new(Mutex::new(0)),
+			connections: 0,
+		}
+	}
+
+	fn connect(&mut self, connection: Box<dyn Connection>) -> (Box<dyn Connection>, u32) {
+		self.connections += 1;
+		let recorded = RecordedConnection {
+			connection: connection,
+			log: self.log.clone(),
+			cycle: self.cycle.clone(),
+			id: self.connections,
+		};
+		(Box::new(recorded), self.connections)
+	}
+}
+
+impl<L: LineLog + Clone + 'static> Transport for RecordedLine<L> {
+	fn accept(&mut self) -> io::Result<(Box<dyn Connection>, u32)> {
+		let cycle = *self.cycle.lock().unwrap();
+
+		match self.line.accept() {
+			Ok((connection, number)) => {
+				let (connection, id) = self.connect(connection);
+				self.log.record_line(cycle, &Event::Call(id, number));
+				Ok((connection, number))
+			},
+
+			// Nobody calling, or a caller giving up, doesn't change anything
+			Err(e) => {
+				match e.kind() {
+					io::ErrorKind::WouldBlock | io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset => (),
+					_ => self.log.record_line(cycle, &Event::NoService),
+				}
+				Err(e)
+			},
+		}
+	}
+
+	fn dial(&mut self, number: u32) -> io::Result<Box<dyn Connection>> {
+		let cycle = *self.cycle.lock().unwrap();
+
+		match self.line.dial(number) {
+			Ok(connection) => {
+				let (connection, id) = self.connect(connection);
+				self.log.record_line(cycle, &Event::Dial(number, DialResult::Connected, id));
+				Ok(connection)
+			},
+
+			Err(e) => {
+				let result = if e.kind() == io::ErrorKind::ConnectionRefused {DialResult::Refused} else {DialResult::Failed};
+				self.log.record_line(cycle, &Event::Dial(number, result, 0));
+				Err(e)
+			},
+		}
+	}
+
+	fn advance(&mut self, cycle: u64) {
+		*self.cycle.lock().unwrap() = cycle;
+		self.line.advance(cycle);
+	}
+}
+
+
+struct RecordedConnection<L> {
+	connection: Box<dyn Connection>,
+	log: L,
+	cycle: Arc<Mutex<u64>>,
+	id: u32,
+}
+
+impl<L: LineLog> Connection for RecordedConnection<L> {
+	fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+		let cycle = *self.cycle.lock().unwrap();
+		let result = self.connection.read(buffer);
+
+		match result {
+			Ok(bytes) => self.log.record_line(cycle, &Event::Read(self.id, buffer[..bytes].to_vec())),
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+			Err(_) => self.log.record_line(cycle, &Event::ReadFailed(self.id)),
+		}
+
+		result
+	}
+
+	fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+		let result = self.connection.write(buffer);
+
+		// Only a failure changes what the modem does. A line that is full is tried again.
+		let failed = match result {
+			Ok(0) => !buffer.is_empty(),
+			Ok(_) => false,
+			Err(ref e) => e.kind() != io::ErrorKind::WouldBlock && e.kind() != io::ErrorKind::Interrupted,
+		};
+
+		if failed {
+			self.log.record_line(*self.cycle.lock().unwrap(), &Event::WriteFailed(self.id));
+		}
+
+		result
+	}
+}
+
+
+
+
+/// The line events of a capture file that are still to be played back
+#[derive(Clone)]
+pub struct Captured {
+	events: Arc<Mutex<VecDeque<(u64, Event)>>>,
+}
+
+impl LineEvents for Captured {
+	fn take(&self, cycle: u64, wanted: &dyn Fn(&Event) -> bool) -> Option<Event> {
+		let mut events = self.events.lock().unwrap();
+
+		let due = match events.front() {
+			Some(&(recorded, ref event)) => recorded <= cycle && wanted(event),
+			None => false,
+		};
+
+		if due {
+			events.pop_front().map(|(_, event)| event)
+		} else {
+			None
+		}
+	}
+
+	fn put_back(&self, cycle: u64, event: Event) {
+		self.events.lock().unwrap().push_front((cycle, event));
+	}
+}
+
+
+/// A line that plays back what a `RecordedLine` wrote down, from a capture or
+/// from a recorded session
+pub struct ReplayTransport<E> {
+	events: E,
+	cycle: Arc<Mutex<u64>>,
+}
+
+impl ReplayTransport<Captured> {
+	/// Replay the capture file at the given path
+	pub fn open(path: &str) -> io::Result<ReplayTransport<Captured>> {
+		let mut text = String::new();
+		File::open(path)?.read_to_string(&mut text)?;
+		ReplayTransport::parse(&text)
+	}
+
+	/// Replay a capture that has already been read into memory
+	pub fn parse(text: &str) -> io::Result<ReplayTransport<Captured>> {
+		let mut events = VecDeque::new();
+
+		for (number, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			match parse_line(line) {
+				Some((cycle, event)) =>
+					if event.is_line() {
+						events.push_back((cycle, event));
+					},
+
+				None =>
+					return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unreadable capture on line {}: {}", number + 1, line))),
+			}
+		}
+
+		Ok(ReplayTransport::new(Captured {events: Arc::new(Mutex::new(events))}))
+	}
+}
+
+impl<E: LineEvents + Clone + 'static> ReplayTransport<E> {
+	pub fn new(events: E) -> ReplayTransport<E> {
+		ReplayTransport {
+			events: events,
+			cycle: Arc::new(Mutex::new(0)),
+		}
+	}
+
+	fn connection(&self, id: u32) -> Box<dyn Connection> {
+		Box::new(ReplayConnection {events: self.events.clone(), cycle: self.cycle.clone(), id: id})
+	}
+}
+
+impl<E: LineEvents + Clone + 'static> Transport for ReplayTransport<E> {
+	fn accept(&mut self) -> io::Result<(Box<dyn Connection>, u32)> {
+		let cycle = *self.cycle.lock().unwrap();
+
+		match self.events.take(cycle, &|event| match *event {Event::Call(_, _) | Event::NoService => true, _ => false}) {
+			Some(Event::Call(id, number)) => Ok((self.connection(id), number)),
+			Some(_) => Err(io::Error::new(io::ErrorKind::Other, "The line went dead in the recording")),
+			None => Err(io::Error::new(io::ErrorKind::WouldBlock, "Nobody is calling")),
+		}
+	}
+
+	fn dial(&mut self, _number: u32) -> io::Result<Box<dyn Connection>> {
+		// Calls are placed whenever the program places them, so take the next one whatever its cycle
+		match self.events.take(u64::max_value(), &|event| match *event {Event::Dial(_, _, _) => true, _ => false}) {
+			Some(Event::Dial(_, DialResult::Connected, id)) => Ok(self.connection(id)),
+			Some(Event::Dial(_, DialResult::Refused, _)) => Err(io::Error::new(io::ErrorKind::ConnectionRefused, "No modem with that number")),
+			Some(_) => Err(io::Error::new(io::ErrorKind::Other, "No telephone service")),
+			None => Err(io::Error::new(io::ErrorKind::Other, "The recording did not dial here")),
+		}
+	}
+
+	fn advance(&mut self, cycle: u64) {
+		*self.cycle.lock().unwrap() = cycle;
+	}
+}
+
+
+/// A call being played back from a recording
+struct ReplayConnection<E> {
+	events: E,
+	cycle: Arc<Mutex<u64>>,
+	id: u32,
+}
+
+impl<E: LineEvents> Connection for ReplayConnection<E> {
+	fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+		let cycle = *self.cycle.lock().unwrap();
+		let id = self.id;
+
+		match self.events.take(cycle, &|event| match *event {Event::Read(read, _) | Event::ReadFailed(read) => read == id, _ => false}) {
+			Some(Event::Read(_, mut bytes)) => {
+				let length = std::cmp::min(buffer.len(), bytes.len());
+				buffer[..length].copy_from_slice(&bytes[..length]);
+
+				let remainder = bytes.split_off(length);
+				if !remainder.is_empty() {
+					self.events.put_back(cycle, Event::Read(id, remainder));
+				}
+
+				Ok(length)
+			},
+
+			Some(_) => Err(io::Error::new(io::ErrorKind::Other, "The read failed in the recording")),
+			None => Err(io::Error::new(io::ErrorKind::WouldBlock, "No data waiting")),
+		}
+	}
+
+	fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+		let cycle = *self.cycle.lock().unwrap();
+		let id = self.id;
+
+		// Nobody is listening on the other end of a replay, but a write can fail as it did
+		match self.events.take(cycle, &|event| *event == Event::WriteFailed(id)) {
+			Some(_) => Err(io::Error::new(io::ErrorKind::Other, "The write failed in the recording")),
+			None => Ok(buffer.len()),
+		}
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use transport::{Script, ScriptedConnection, ScriptedTransport};
+	use test_support::SharedOutput;
+
+	#[test]
+	fn events_round_trip() {
+		let events = vec![
+			Event::State("Connected".to_string()),
+			Event::Interrupt(0x0004),
+			Event::Send(vec![0xbeef, 0x0001]),
+			Event::Receive(vec![]),
+			Event::Call(1, 0x7f00_0001),
+			Event::NoService,
+			Event::Dial(0x0000_0002, DialResult::Connected, 2),
+			Event::Dial(0x0a00_0001, DialResult::Refused, 0),
+			Event::Read(2, vec![0xaa, 0x01]),
+			Event::Read(2, vec![]),
+			Event::ReadFailed(1),
+			Event::WriteFailed(2),
+		];
+
+		for event in events {
+			let line = format!("{} {}", 1234, event);
+			assert_eq!(parse_line(&line), Some((1234, event)), "{}", line);
+		}
+
+		assert_eq!(parse_line("40 unplugged"), None);
+	}
+
+	#[test]
+	fn unreadable_capture() {
+		assert!(ReplayTransport::parse("# header\n10 read 1 zz\n").is_err());
+	}
+
+	#[test]
+	fn replayed_line_does_what_the_recorded_one_did() {
+		let connection = ScriptedConnection::new(vec![
+			Script::Wait,
+			Script::Receive(vec![0xaa]),
+			Script::Receive(vec![1, 2, 3, 4]),
+			Script::Fail(io::ErrorKind::ConnectionReset),
+		]).failing_writes(io::ErrorKind::BrokenPipe);
+		let output = SharedOutput::new();
+
+		// Do the same things to a line and to the replay of it, noting what came back each time
+		fn exercise(line: &mut dyn Transport) -> Vec<String> {
+			let mut results = vec![];
+			let mut connection = line.dial(0x0a00_0001).unwrap();
+			for cycle in 0..5 {
+				line.advance(cycle * 100);
+				let mut buffer = [0; 3];
+				// The modem only cares whether a read failed or found nothing waiting
+				results.push(format!("{:?} {:?}", connection.read(&mut buffer).map_err(|e| e.kind() == io::ErrorKind::WouldBlock), buffer));
+			}
+			results.push(format!("{:?}", connection.write(&[1]).map_err(|e| e.kind() == io::ErrorKind::WouldBlock)));
+			results
+		}
+
+		let capture = Capture::new(Box::new(output.clone())).unwrap();
+		let mut recorded = RecordedLine::new(Box::new(ScriptedTransport::new().dial_result(Ok(connection))), capture);
+		let live = exercise(&mut recorded);
+
+		let mut replay = ReplayTransport::parse(&output.text()).unwrap();
+		assert_eq!(exercise(&mut replay), live);
+	}
+}
@@ -0,0 +1,143 @@
+use dcpu;
+use dcpu::Dcpu;
+use savestate;
+
+/// Generic clock's hardware id, version and manufacturer
+pub const HARDWARE_ID: u32 = 0x12d0_b402;
+pub const VERSION: u16 = 0x0001;
+pub const MANUFACTURER: u32 = 0x1c6c_8b36;
+
+// The clock ticks 60 / divider times a second
+const TICKS_PER_SECOND: u64 = 60;
+
+pub struct Clock {
+	// Zero while the clock is turned off
+	divider: u16,
+	interrupt_message: u16,
+	// The cycle the clock was last set going, and how many times it has ticked since
+	started: u64,
+	ticks: u64,
+}
+
+impl Clock {
+	pub fn new() -> Clock {
+		Clock {
+			divider: 0,
+			interrupt_message: 0,
+			started: 0,
+			ticks: 0,
+		}
+	}
+
+
+	// The cycle the clock ticks for the given time since it was set going.
+	// Working from the start keeps rounding from adding up over the ticks.
+	fn tick_cycle(&self, tick: u64) -> u64 {
+		self.started + tick * self.divider as u64 * dcpu::CLOCK_RATE / TICKS_PER_SECOND
+	}
+
+
+	/// Tick as many times as the clock should have by now, and return the
+	/// cycles until it ticks again
+	pub fn step(&mut self, dcpu: &mut Dcpu) -> u64 {
+		if self.divider == 0 {
+			return u64::max_value();
+		}
+
+		while self.tick_cycle(self.ticks + 1) <= dcpu.cycle_count {
+			self.ticks += 1;
+			if self.interrupt_message != 0 {
+				dcpu.interrupt_queue.push(self.interrupt_message);
+			}
+		}
+
+		self.tick_cycle(self.ticks + 1) - dcpu.cycle_count
+	}
+
+
+	pub fn interrupt(&mut self, dcpu: &mut Dcpu) {
+		match dcpu.registers[dcpu::A] {
+			0 => {
+				self.divider = dcpu.registers[dcpu::B];
+				self.started = dcpu.cycle_count;
+				self.ticks = 0;
+			},
+			1 => dcpu.registers[dcpu::C] = self.ticks as u16,
+			2 => self.interrupt_message = dcpu.registers[dcpu::B],
+			_ => (),
+		}
+	}
+
+
+	/// The clock's part of a save state: its divider, interrupt message, the cycle it started on and its ticks
+	pub fn save(&self) -> Vec<u16> {
+		let mut words = vec![self.divider, self.interrupt_message];
+		words.extend_from_slice(&savestate::split_long(self.started));
+		words.extend_from_slice(&savestate::split_long(self.ticks));
+		words
+	}
+
+	pub fn restore(&mut self, words: &[u16]) -> Result<(), String> {
+		if words.len() != 10 {
+			return Err("The clock in the save state is incomplete".to_string());
+		}
+
+		self.divider = words[0];
+		self.interrupt_message = words[1];
+		self.started = savestate::join_long(&words[2..6]);
+		self.ticks = savestate::join_long(&words[6..]);
+		Ok(())
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn command(clock: &mut Clock, dcpu: &mut Dcpu, a: u16, b: u16) {
+		dcpu.registers[dcpu::A] = a;
+		dcpu.registers[dcpu::B] = b;
+		clock.interrupt(dcpu);
+	}
+
+	#[test]
+	fn ticks_on_emulated_time() {
+		let mut clock = Clock::new();
+		let mut dcpu = Dcpu::new();
+		assert_eq!(clock.step(&mut dcpu), u64::max_value());
+
+		// Tick 30 times a second, interrupting with 0x77
+		dcpu.cycle_count = 1000;
+		command(&mut clock, &mut dcpu, 0, 2);
+		command(&mut clock, &mut dcpu, 2, 0x77);
+		assert_eq!(clock.step(&mut dcpu), 3333);
+
+		// A whole emulated second later
+		dcpu.cycle_count = 1000 + dcpu::CLOCK_RATE;
+		assert_eq!(clock.step(&mut dcpu), 3333);
+		assert_eq!(dcpu.interrupt_queue.len(), 30);
+		assert!(dcpu.interrupt_queue.iter().all(|&message| message == 0x77));
+
+		command(&mut clock, &mut dcpu, 1, 0);
+		assert_eq!(dcpu.registers[dcpu::C], 30);
+	}
+
+	#[test]
+	fn save_and_restore() {
+		let mut clock = Clock::new();
+		let mut dcpu = Dcpu::new();
+		dcpu.cycle_count = 0x1_0000_0000;
+		command(&mut clock, &mut dcpu, 0, 1);
+		command(&mut clock, &mut dcpu, 2, 5);
+
+		let mut restored = Clock::new();
+		restored.restore(&clock.save()).unwrap();
+		dcpu.cycle_count += dcpu::CLOCK_RATE;
+		restored.step(&mut dcpu);
+		command(&mut restored, &mut dcpu, 1, 0);
+		assert_eq!(dcpu.registers[dcpu::C], 60);
+	}
+}
@@ -0,0 +1,301 @@
+use std::fs;
+use std::path::Path;
+use serde_json;
+use toml;
+use clock::Clock;
+use dcpu;
+use keyboard::Keyboard;
+use lem1820::Lem1820;
+use loader;
+use modem::Modem;
+use screen::Screen;
+use spec::Spec;
+use system::HardwareType;
+use transport::Switchboard;
+
+
+/// A whole machine, described in a TOML or JSON file:
+///
+/// ```toml
+/// spec = "1.7"
+/// images = ["ship.bin", "charts.hex@0x4000"]
+///
+/// [[devices]]
+/// type = "lem1820"
+/// scale = 4
+///
+/// [[devices]]
+/// type = "keyboard"
+///
+/// [[devices]]
+/// type = "modem"
+/// port = 6500
+/// ```
+///
+/// Devices are attached in the order they are listed, which is the order the
+/// DCPU finds them in with hwn and hwq.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Machine {
+	/// Version of the DCPU-16 specification to follow
+	#[serde(default = "default_spec")]
+	pub spec: String,
+	/// Cycles run each second, or zero to run as fast as possible
+	#[serde(default = "default_speed")]
+	pub speed: u64,
+	/// How the images are stored, as for --format
+	#[serde(default = "default_format")]
+	pub format: String,
+	/// Images loaded in order, each given like ship.bin or ship.bin@0x1000.
+	/// Paths are from the directory the description is in.
+	pub images: Vec<String>,
+	#[serde(default)]
+	pub devices: Vec<Device>,
+}
+
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Device {
+	/// A monitor with a window the given multiple of the LEM1802's 128 by 96
+	/// pixels, or without one if it is headless
+	Lem1820 {
+		#[serde(default = "default_scale")]
+		scale: u32,
+		#[serde(default)]
+		headless: bool,
+	},
+	/// A keyboard that types what is pressed in the first monitor's window
+	Keyboard,
+	/// A modem that listens for calls on a port and places them to another. It
+	/// can be called from this computer by dialing its port as the number. A
+	/// modem with a number is instead plugged into an exchange shared with the
+	/// machine's other numbered modems, so that they can call each other.
+	Modem {
+		port: Option<u16>,
+		#[serde(default = "default_dial_port")]
+		dial_port: u16,
+		number: Option<u32>,
+	},
+	Clock,
+}
+
+
+fn default_spec() -> String {
+	"1.7".to_string()
+}
+
+fn default_speed() -> u64 {
+	dcpu::CLOCK_RATE
+}
+
+fn default_format() -> String {
+	"auto".to_string()
+}
+
+fn default_scale() -> u32 {
+	5
+}
+
+fn default_dial_port() -> u16 {
+	6482
+}
+
+
+impl Machine {
+	/// Read a machine description, as JSON if the file's name ends in .json and as TOML otherwise
+	pub fn load(path: &str) -> Result<Machine, String> {
+		let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+		let mut machine = if path.ends_with(".json") {
+			Machine::parse_json(&text)?
+		} else {
+			Machine::parse_toml(&text)?
+		};
+
+		let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+		for image in &mut machine.images {
+			*image = directory.join(&image).to_string_lossy().into_owned();
+		}
+
+		Ok(machine)
+	}
+
+
+	pub fn parse_toml(text: &str) -> Result<Machine, String> {
+		let machine: Machine = toml::from_str(text).map_err(|e| e.to_string())?;
+		machine.check()?;
+		Ok(machine)
+	}
+
+
+	pub fn parse_json(text: &str) -> Result<Machine, String> {
+		let machine: Machine = serde_json::from_str(text).map_err(|e| e.to_string())?;
+		machine.check()?;
+		Ok(machine)
+	}
+
+
+	// Catch settings that can't work before anything is attached
+	fn check(&self) -> Result<(), String> {
+		self.spec()?;
+		loader::Format::parse(&self.format)?;
+
+		if self.images.is_empty() {
+			return Err("The machine has no images to load".to_string());
+		}
+
+		for device in &self.devices {
+			match *device {
+				Device::Lem1820 {scale: 0, ..} =>
+					return Err("A monitor's scale has to be at least 1".to_string()),
+				Device::Modem {port: Some(_), number: Some(_), ..} =>
+					return Err("A modem with a number is plugged into the exchange, so it can't listen on a port".to_string()),
+				_ => (),
+			}
+		}
+
+		Ok(())
+	}
+
+
+	pub fn spec(&self) -> Result<Spec, String> {
+		Spec::parse(&self.spec).ok_or_else(|| format!("Unknown spec version {}. Choose 1.1, 1.7 or 2.0.", self.spec))
+	}
+
+
+	/// Attach the machine's devices, in order. Each modem without a port listens
+	/// on the one after the last modem's, starting from 6483.
+	pub fn hardware(&self) -> Result<Vec<HardwareType>, String> {
+		// The keyboard reads keys from the first monitor's window, wherever it is listed
+		let keyboard = self.devices.contains(&Device::Keyboard);
+		let mut window_events = None;
+		let mut monitors = vec![];
+		for device in &self.devices {
+			if let Device::Lem1820 {scale, headless: false} = *device {
+				let mut lem = Lem1820::with_scale(scale);
+				if keyboard && window_events.is_none() {
+					window_events = Some(lem.window_events());
+				}
+				monitors.push(lem);
+			}
+		}
+		monitors.reverse();
+
+		let switchboard = Switchboard::new();
+		let mut next_port = 6483;
+		let mut hardware = vec![];
+		for device in &self.devices {
+			hardware.push(match *device {
+				Device::Lem1820 {headless: true, ..} => HardwareType::HeadlessLem1820(Screen::new()),
+				Device::Lem1820 {..} => HardwareType::Lem1820(monitors.pop().unwrap()),
+				Device::Keyboard => HardwareType::Keyboard(match window_events.take() {
+					Some(window_events) => Keyboard::new(window_events),
+					None => Keyboard::headless(),
+				}),
+				Device::Modem {number: Some(number), ..} =>
+					HardwareType::Eklectic(Modem::with_transport(Box::new(switchboard.connect(number)))),
+				Device::Modem {port, dial_port, number: None} => {
+					let port = port.unwrap_or(next_port);
+					next_port = port.wrapping_add(1);
+					let modem = Modem::with_ports(port, dial_port)
+						.map_err(|e| format!("Unable to attach the modem on port {}: {}", port, e))?;
+					HardwareType::Eklectic(modem)
+				},
+				Device::Clock => HardwareType::Clock(Clock::new()),
+			});
+		}
+
+		Ok(hardware)
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn toml_and_json_describe_the_same_machine() {
+		let toml = r#"
+			spec = "2.0"
+			speed = 0
+			images = ["ship.bin", "charts.hex@0x4000"]
+
+			[[devices]]
+			type = "clock"
+
+			[[devices]]
+			type = "lem1820"
+			scale = 3
+
+			[[devices]]
+			type = "modem"
+			number = 7
+		"#;
+
+		let json = r#"{
+			"spec": "2.0",
+			"speed": 0,
+			"images": ["ship.bin", "charts.hex@0x4000"],
+			"devices": [
+				{"type": "clock"},
+				{"type": "lem1820", "scale": 3},
+				{"type": "modem", "number": 7}
+			]
+		}"#;
+
+		let machine = Machine::parse_toml(toml).unwrap();
+		assert_eq!(machine, Machine::parse_json(json).unwrap());
+		assert_eq!(machine.spec(), Ok(Spec::V2_0));
+		assert_eq!(machine.format, "auto");
+		assert_eq!(machine.devices, vec![
+			Device::Clock,
+			Device::Lem1820 {scale: 3, headless: false},
+			Device::Modem {port: None, dial_port: 6482, number: Some(7)},
+		]);
+	}
+
+	#[test]
+	fn defaults_and_mistakes() {
+		let machine = Machine::parse_toml("images = [\"ship.bin\"]").unwrap();
+		assert_eq!((machine.spec.as_str(), machine.speed), ("1.7", dcpu::CLOCK_RATE));
+		assert!(machine.devices.is_empty());
+
+		assert!(Machine::parse_toml("images = []").is_err());
+		assert!(Machine::parse_toml("images = [\"ship.bin\"]\nspec = \"3.0\"").is_err());
+		assert!(Machine::parse_toml("images = [\"ship.bin\"]\nformat = \"elf\"").is_err());
+		assert!(Machine::parse_toml("images = [\"ship.bin\"]\nspeeed = 5").is_err());
+		assert!(Machine::parse_toml("images = [\"ship.bin\"]\n[[devices]]\ntype = \"printer\"").is_err());
+		assert!(Machine::parse_toml("images = [\"ship.bin\"]\n[[devices]]\ntype = \"lem1820\"\nscale = 0").is_err());
+		assert!(Machine::parse_toml("images = [\"ship.bin\"]\n[[devices]]\ntype = \"lem1820\"\nscal = 3").is_err());
+		assert!(Machine::parse_toml("images = [\"ship.bin\"]\n[[devices]]\ntype = \"modem\"\nnumber = 1\nport = 6500").is_err());
+	}
+
+	#[test]
+	fn devices_are_attached_in_order() {
+		let machine = Machine::parse_toml(r#"
+			images = ["ship.bin"]
+			devices = [
+				{type = "modem", number = 1},
+				{type = "keyboard"},
+				{type = "lem1820", headless = true},
+				{type = "modem", number = 2},
+				{type = "clock"},
+			]
+		"#).unwrap();
+
+		let hardware = machine.hardware().unwrap();
+		let kinds: Vec<&str> = hardware.iter()
+			.map(|hardware| match *hardware {
+				HardwareType::Eklectic(_) => "modem",
+				HardwareType::Keyboard(_) => "keyboard",
+				HardwareType::HeadlessLem1820(_) => "monitor",
+				HardwareType::Clock(_) => "clock",
+				HardwareType::Lem1820(_) => "window",
+			})
+			.collect();
+		assert_eq!(kinds, ["modem", "keyboard", "monitor", "modem", "clock"]);
+	}
+}
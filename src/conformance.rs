@@ -0,0 +1,474 @@
+//! Conformance tests for the CPU against the 1.7 specification. Each case runs a
+//! single instruction from a known state and checks what it changed and how many
+//! cycles it took. The programs at the end check that instructions work together.
+
+use dcpu;
+use dcpu::Dcpu;
+use system::System;
+use test_support::machine;
+
+
+// Operands
+const REG_A: u16 = 0x00;
+const REG_B: u16 = 0x01;
+const REG_C: u16 = 0x02;
+const AT_B: u16 = 0x09;
+const AT_I: u16 = 0x0e;
+const AT_J: u16 = 0x0f;
+const AT_B_PLUS: u16 = 0x11;
+const PUSH_POP: u16 = 0x18;
+const PEEK: u16 = 0x19;
+const PICK: u16 = 0x1a;
+const SP: u16 = 0x1b;
+const PC: u16 = 0x1c;
+const EX: u16 = 0x1d;
+const AT_NEXT: u16 = 0x1e;
+const NEXT: u16 = 0x1f;
+
+// Basic instructions
+const SET: u16 = 0x01;
+const ADD: u16 = 0x02;
+const SUB: u16 = 0x03;
+const MUL: u16 = 0x04;
+const MLI: u16 = 0x05;
+const DIV: u16 = 0x06;
+const DVI: u16 = 0x07;
+const MOD: u16 = 0x08;
+const MDI: u16 = 0x09;
+const AND: u16 = 0x0a;
+const BOR: u16 = 0x0b;
+const XOR: u16 = 0x0c;
+const SHR: u16 = 0x0d;
+const ASR: u16 = 0x0e;
+const SHL: u16 = 0x0f;
+const IFB: u16 = 0x10;
+const IFC: u16 = 0x11;
+const IFE: u16 = 0x12;
+const IFN: u16 = 0x13;
+const IFG: u16 = 0x14;
+const IFA: u16 = 0x15;
+const IFL: u16 = 0x16;
+const IFU: u16 = 0x17;
+const ADX: u16 = 0x1a;
+const SBX: u16 = 0x1b;
+const STI: u16 = 0x1e;
+const STD: u16 = 0x1f;
+
+// Special instructions
+const JSR: u16 = 0x01;
+const INT: u16 = 0x08;
+const IAG: u16 = 0x09;
+const IAS: u16 = 0x0a;
+const RFI: u16 = 0x0b;
+const IAQ: u16 = 0x0c;
+const HWN: u16 = 0x10;
+const HWQ: u16 = 0x11;
+const HWI: u16 = 0x12;
+
+
+fn basic(instruction: u16, b: u16, a: u16) -> u16 {
+	instruction | b << 5 | a << 10
+}
+
+fn special(instruction: u16, a: u16) -> u16 {
+	instruction << 5 | a << 10
+}
+
+// An a operand holding a small literal, from -1 to 30
+fn literal(value: i16) -> u16 {
+	(value + 0x21) as u16
+}
+
+// sub pc, 1, which the programs halt on
+fn halt() -> u16 {
+	basic(SUB, PC, literal(1))
+}
+
+
+
+
+// Somewhere in the CPU a case sets up or checks
+#[derive(Copy, Clone, Debug)]
+enum At {
+	Register(usize),
+	StackPointer,
+	ProgramCounter,
+	Excess,
+	InterruptAddress,
+	Queueing,
+	Memory(u16),
+}
+
+fn set(dcpu: &mut Dcpu, at: At, value: u16) {
+	match at {
+		At::Register(index) => dcpu.registers[index] = value,
+		At::StackPointer => dcpu.stack_pointer = value,
+		At::ProgramCounter => dcpu.program_counter = value,
+		At::Excess => dcpu.excess = value,
+		At::InterruptAddress => dcpu.interrupt_address = value,
+		At::Queueing => dcpu.interrupt_queueing = value != 0,
+		At::Memory(address) => dcpu.memory[address as usize] = value,
+	}
+}
+
+fn get(dcpu: &Dcpu, at: At) -> u16 {
+	match at {
+		At::Register(index) => dcpu.registers[index],
+		At::StackPointer => dcpu.stack_pointer,
+		At::ProgramCounter => dcpu.program_counter,
+		At::Excess => dcpu.excess,
+		At::InterruptAddress => dcpu.interrupt_address,
+		At::Queueing => dcpu.interrupt_queueing as u16,
+		At::Memory(address) => dcpu.memory[address as usize],
+	}
+}
+
+const A: At = At::Register(dcpu::A);
+const B: At = At::Register(dcpu::B);
+const C: At = At::Register(dcpu::C);
+const X: At = At::Register(dcpu::X);
+const Y: At = At::Register(dcpu::Y);
+const I: At = At::Register(dcpu::I);
+const J: At = At::Register(dcpu::J);
+
+
+// One instruction, the state it starts from, and what it should leave behind.
+// Unless the case says otherwise, the program counter should end up just past
+// the program.
+struct Case {
+	name: &'static str,
+	program: Vec<u16>,
+	setup: Vec<(At, u16)>,
+	expect: Vec<(At, u16)>,
+	cycles: u64,
+}
+
+fn case(name: &'static str, program: Vec<u16>, setup: Vec<(At, u16)>, expect: Vec<(At, u16)>, cycles: u64) -> Case {
+	Case {name: name, program: program, setup: setup, expect: expect, cycles: cycles}
+}
+
+
+// Run every case, returning a description of each way they went wrong
+fn run(cases: Vec<Case>) -> Vec<String> {
+	let mut failures = vec![];
+
+	for case in cases {
+		let mut system = machine(&case.program, vec![]);
+		for &(at, value) in &case.setup {
+			set(&mut system.dcpu, at, value);
+		}
+
+		system.step_instruction();
+
+		// The cycles the instruction still owes are part of its cost
+		let cpu = &system.dcpu;
+		let cycles = cpu.cycle_count + cpu.cycle_accumulator as u64;
+		if cycles != case.cycles {
+			failures.push(format!("{}: took {} cycles instead of {}", case.name, cycles, case.cycles));
+		}
+
+		let mut expect = case.expect;
+		if !expect.iter().any(|&(at, _)| match at {At::ProgramCounter => true, _ => false}) {
+			expect.push((At::ProgramCounter, case.program.len() as u16));
+		}
+
+		for (at, value) in expect {
+			if get(cpu, at) != value {
+				failures.push(format!("{}: {:?} is 0x{:04x} instead of 0x{:04x}", case.name, at, get(cpu, at), value));
+			}
+		}
+
+		if let Some(fault) = cpu.fault {
+			failures.push(format!("{}: {}", case.name, fault));
+		}
+	}
+
+	failures
+}
+
+
+fn check(cases: Vec<Case>) {
+	let failures = run(cases);
+	assert!(failures.is_empty(), "\n{}\n", failures.join("\n"));
+}
+
+
+
+
+#[test]
+fn arithmetic() {
+	let op = |instruction| vec![basic(instruction, REG_A, REG_B)];
+
+	check(vec![
+		case("set", op(SET), vec![(B, 5)], vec![(A, 5)], 1),
+		case("add", op(ADD), vec![(A, 3), (B, 4), (At::Excess, 7)], vec![(A, 7), (At::Excess, 0)], 2),
+		case("add overflow", op(ADD), vec![(A, 0xffff), (B, 2)], vec![(A, 1), (At::Excess, 1)], 2),
+		case("sub", op(SUB), vec![(A, 5), (B, 2), (At::Excess, 7)], vec![(A, 3), (At::Excess, 0)], 2),
+		case("sub underflow", op(SUB), vec![(A, 1), (B, 2)], vec![(A, 0xffff), (At::Excess, 0xffff)], 2),
+		case("mul", op(MUL), vec![(A, 0x8000), (B, 5)], vec![(A, 0x8000), (At::Excess, 2)], 2),
+		case("mli", op(MLI), vec![(A, 0xffff), (B, 2)], vec![(A, 0xfffe), (At::Excess, 0xffff)], 2),
+		case("mli positive", op(MLI), vec![(A, 0x4000), (B, 4)], vec![(A, 0), (At::Excess, 1)], 2),
+		case("div", op(DIV), vec![(A, 7), (B, 2)], vec![(A, 3), (At::Excess, 0x8000)], 3),
+		case("div large", op(DIV), vec![(A, 0xffff), (B, 2)], vec![(A, 0x7fff), (At::Excess, 0x8000)], 3),
+		case("div by zero", op(DIV), vec![(A, 7), (B, 0), (At::Excess, 5)], vec![(A, 0), (At::Excess, 0)], 3),
+		case("dvi", op(DVI), vec![(A, -7i16 as u16), (B, 2)], vec![(A, -3i16 as u16), (At::Excess, 0x8000)], 3),
+		case("dvi overflow", op(DVI), vec![(A, 0x8000), (B, 0xffff)], vec![(A, 0x8000), (At::Excess, 0)], 3),
+		case("dvi by zero", op(DVI), vec![(A, 7), (B, 0)], vec![(A, 0), (At::Excess, 0)], 3),
+		case("mod", op(MOD), vec![(A, 7), (B, 3)], vec![(A, 1)], 3),
+		case("mod by zero", op(MOD), vec![(A, 7), (B, 0)], vec![(A, 0)], 3),
+		case("mdi", op(MDI), vec![(A, -7i16 as u16), (B, 16)], vec![(A, -7i16 as u16)], 3),
+		case("mdi overflow", op(MDI), vec![(A, 0x8000), (B, 0xffff)], vec![(A, 0)], 3),
+		case("and", op(AND), vec![(A, 0x0ff0), (B, 0x00ff)], vec![(A, 0x00f0)], 1),
+		case("bor", op(BOR), vec![(A, 0x0ff0), (B, 0x00ff)], vec![(A, 0x0fff)], 1),
+		case("xor", op(XOR), vec![(A, 0x0ff0), (B, 0x00ff)], vec![(A, 0x0f0f)], 1),
+		case("adx", op(ADX), vec![(A, 1), (B, 1), (At::Excess, 1)], vec![(A, 3), (At::Excess, 0)], 3),
+		case("adx overflow", op(ADX), vec![(A, 0xffff), (B, 0), (At::Excess, 1)], vec![(A, 0), (At::Excess, 1)], 3),
+		case("sbx", op(SBX), vec![(A, 0), (B, 1)], vec![(A, 0xffff), (At::Excess, 0xffff)], 3),
+		case("sbx borrow", op(SBX), vec![(A, 5), (B, 1), (At::Excess, 0xffff)], vec![(A, 3), (At::Excess, 0)], 3),
+		case("sbx overflow", op(SBX), vec![(A, 0xffff), (B, 0), (At::Excess, 1)], vec![(A, 0), (At::Excess, 1)], 3),
+		case("write to ex", vec![basic(ADD, EX, REG_B)], vec![(B, 1), (At::Excess, 0xffff)], vec![(At::Excess, 1)], 2),
+	]);
+}
+
+
+#[test]
+fn shifts() {
+	let op = |instruction| vec![basic(instruction, REG_A, REG_B)];
+
+	check(vec![
+		case("shr", op(SHR), vec![(A, 0x8001), (B, 1)], vec![(A, 0x4000), (At::Excess, 0x8000)], 1),
+		case("shr 16", op(SHR), vec![(A, 0x8001), (B, 16)], vec![(A, 0), (At::Excess, 0x8001)], 1),
+		case("shr 20", op(SHR), vec![(A, 0x8001), (B, 20)], vec![(A, 0), (At::Excess, 0x0800)], 1),
+		case("shr 0xffff", op(SHR), vec![(A, 0x8001), (B, 0xffff)], vec![(A, 0), (At::Excess, 0)], 1),
+		case("asr", op(ASR), vec![(A, 0x8000), (B, 4)], vec![(A, 0xf800), (At::Excess, 0)], 1),
+		case("asr 20", op(ASR), vec![(A, 0x8000), (B, 20)], vec![(A, 0xffff), (At::Excess, 0xf800)], 1),
+		case("asr positive 20", op(ASR), vec![(A, 0x7000), (B, 20)], vec![(A, 0), (At::Excess, 0x0700)], 1),
+		case("shl", op(SHL), vec![(A, 0x8001), (B, 1)], vec![(A, 0x0002), (At::Excess, 1)], 1),
+		case("shl 16", op(SHL), vec![(A, 0x1234), (B, 16)], vec![(A, 0), (At::Excess, 0x1234)], 1),
+		case("shl 20", op(SHL), vec![(A, 0x1234), (B, 20)], vec![(A, 0), (At::Excess, 0x2340)], 1),
+		case("shl 0xffff", op(SHL), vec![(A, 0x1234), (B, 0xffff)], vec![(A, 0), (At::Excess, 0)], 1),
+	]);
+}
+
+
+#[test]
+fn conditionals() {
+	// The if, then set c, 1 for it to skip
+	let op = |instruction| vec![basic(instruction, REG_A, REG_B), basic(SET, REG_C, literal(1))];
+	let passes = |name, instruction, a, b| case(name, op(instruction), vec![(A, a), (B, b)], vec![(At::ProgramCounter, 1)], 2);
+	let fails = |name, instruction, a, b| case(name, op(instruction), vec![(A, a), (B, b)], vec![(At::ProgramCounter, 2), (C, 0)], 3);
+
+	check(vec![
+		passes("ifb", IFB, 0x0110, 0x0100), fails("ifb fails", IFB, 0x0110, 0x0001),
+		passes("ifc", IFC, 0x0110, 0x0001), fails("ifc fails", IFC, 0x0110, 0x0100),
+		passes("ife", IFE, 5, 5), fails("ife fails", IFE, 5, 6),
+		passes("ifn", IFN, 5, 6), fails("ifn fails", IFN, 5, 5),
+		passes("ifg", IFG, 0xffff, 1), fails("ifg fails", IFG, 1, 1),
+		passes("ifa", IFA, 1, 0xffff), fails("ifa fails", IFA, 0xffff, 1),
+		passes("ifl", IFL, 1, 0xffff), fails("ifl fails", IFL, 1, 1),
+		passes("ifu", IFU, 0xffff, 1), fails("ifu fails", IFU, 1, 0xffff),
+
+		// A failed if skips every if chained after it, and the instruction they guard,
+		// at a cycle each. The skipped instructions' next words are skipped too.
+		case("skip chain", vec![
+			basic(IFE, REG_A, REG_B),
+			basic(IFN, REG_A, NEXT), 0x1234,
+			basic(IFG, AT_NEXT, REG_B), 0x0100,
+			basic(SET, REG_C, NEXT), 0x5678,
+		], vec![(A, 1)], vec![(At::ProgramCounter, 7), (C, 0)], 5),
+		case("chain passes", vec![
+			basic(IFE, REG_A, REG_B),
+			basic(IFE, REG_A, REG_B),
+			basic(SET, REG_C, literal(1)),
+		], vec![], vec![(At::ProgramCounter, 1)], 2),
+	]);
+}
+
+
+#[test]
+fn operands() {
+	let read = |a| vec![basic(SET, REG_A, a)];
+	let read_next = |a, next| vec![basic(SET, REG_A, a), next];
+	let write = |b| vec![basic(SET, b, literal(5))];
+	let write_next = |b, next| vec![basic(SET, b, literal(5)), next];
+
+	check(vec![
+		case("register", read(REG_B), vec![(B, 9)], vec![(A, 9)], 1),
+		case("[register]", read(AT_B), vec![(B, 0x100), (At::Memory(0x100), 9)], vec![(A, 9)], 1),
+		case("[register + next]", read_next(AT_B_PLUS, 0x10), vec![(B, 0x100), (At::Memory(0x110), 9)], vec![(A, 9)], 2),
+		case("[register + next] wraps", read_next(AT_B_PLUS, 0xffff), vec![(B, 0x101), (At::Memory(0x100), 9)], vec![(A, 9)], 2),
+		case("pop", read(PUSH_POP), vec![(At::StackPointer, 0xfffe), (At::Memory(0xfffe), 9)], vec![(A, 9), (At::StackPointer, 0xffff)], 1),
+		case("peek", read(PEEK), vec![(At::StackPointer, 0x10), (At::Memory(0x10), 9)], vec![(A, 9), (At::StackPointer, 0x10)], 1),
+		case("pick", read_next(PICK, 2), vec![(At::StackPointer, 0x10), (At::Memory(0x12), 9)], vec![(A, 9)], 2),
+		case("sp", read(SP), vec![(At::StackPointer, 0x20)], vec![(A, 0x20)], 1),
+		case("pc", read(PC), vec![], vec![(A, 1)], 1),
+		case("ex", read(EX), vec![(At::Excess, 9)], vec![(A, 9)], 1),
+		case("[next]", read_next(AT_NEXT, 0x100), vec![(At::Memory(0x100), 9)], vec![(A, 9)], 2),
+		case("next", read_next(NEXT, 0x1234), vec![], vec![(A, 0x1234)], 2),
+		case("literal -1", read(literal(-1)), vec![], vec![(A, 0xffff)], 1),
+		case("literal 30", read(literal(30)), vec![], vec![(A, 30)], 1),
+
+		case("write [register]", write(AT_B), vec![(B, 0x100)], vec![(At::Memory(0x100), 5)], 1),
+		case("write [register + next]", write_next(AT_B_PLUS, 0x10), vec![(B, 0x100)], vec![(At::Memory(0x110), 5)], 2),
+		case("push", write(PUSH_POP), vec![], vec![(At::Memory(0xffff), 5), (At::StackPointer, 0xffff)], 1),
+		case("write peek", write(PEEK), vec![(At::StackPointer, 0x10)], vec![(At::Memory(0x10), 5)], 1),
+		case("write pick", write_next(PICK, 1), vec![(At::StackPointer, 0x10)], vec![(At::Memory(0x11), 5)], 2),
+		case("write sp", write(SP), vec![], vec![(At::StackPointer, 5)], 1),
+		case("write pc", write(PC), vec![], vec![(At::ProgramCounter, 5)], 1),
+		case("write ex", write(EX), vec![], vec![(At::Excess, 5)], 1),
+		case("write [next]", write_next(AT_NEXT, 0x100), vec![], vec![(At::Memory(0x100), 5)], 2),
+
+		// a's next word comes before b's
+		case("both next words", vec![basic(SET, AT_NEXT, NEXT), 0x1234, 0x0100], vec![], vec![(At::Memory(0x100), 0x1234)], 3),
+		case("set push, pop", vec![basic(SET, PUSH_POP, PUSH_POP)], vec![(At::StackPointer, 0xfffe), (At::Memory(0xfffe), 9)],
+			vec![(At::StackPointer, 0xfffe), (At::Memory(0xfffe), 9)], 1),
+		case("sti", vec![basic(STI, AT_I, AT_J)], vec![(I, 0x100), (J, 0x200), (At::Memory(0x200), 9)],
+			vec![(At::Memory(0x100), 9), (I, 0x101), (J, 0x201)], 2),
+		case("std", vec![basic(STD, AT_I, AT_J)], vec![(I, 0x100), (J, 0x200), (At::Memory(0x200), 9)],
+			vec![(At::Memory(0x100), 9), (I, 0xff), (J, 0x1ff)], 2),
+		case("sti to a register", vec![basic(STI, REG_A, literal(3))], vec![], vec![(A, 3), (I, 1), (J, 1)], 2),
+	]);
+}
+
+
+#[test]
+fn special_instructions() {
+	check(vec![
+		case("jsr", vec![special(JSR, NEXT), 0x0010], vec![],
+			vec![(At::ProgramCounter, 0x10), (At::StackPointer, 0xffff), (At::Memory(0xffff), 2)], 4),
+		case("int without ia", vec![special(INT, literal(5))], vec![], vec![(A, 0)], 4),
+		case("iag", vec![special(IAG, REG_A)], vec![(At::InterruptAddress, 0x50)], vec![(A, 0x50)], 1),
+		case("ias", vec![special(IAS, NEXT), 0x0040], vec![], vec![(At::InterruptAddress, 0x40)], 2),
+		case("rfi", vec![special(RFI, literal(0))], vec![(At::StackPointer, 0xfffe), (At::Memory(0xfffe), 7), (At::Memory(0xffff), 0x30), (At::Queueing, 1)],
+			vec![(A, 7), (At::ProgramCounter, 0x30), (At::StackPointer, 0), (At::Queueing, 0)], 3),
+		case("iaq", vec![special(IAQ, literal(1))], vec![], vec![(At::Queueing, 1)], 2),
+		case("iaq off", vec![special(IAQ, literal(0))], vec![(At::Queueing, 1)], vec![(At::Queueing, 0)], 2),
+		case("hwn", vec![special(HWN, REG_A)], vec![(A, 9)], vec![(A, 0)], 2),
+		case("hwq with nothing there", vec![special(HWQ, literal(0))], vec![(A, 9), (B, 9), (C, 9), (X, 9), (Y, 9)],
+			vec![(A, 0), (B, 0), (C, 0), (X, 0), (Y, 0)], 4),
+		case("hwi with nothing there", vec![special(HWI, literal(0))], vec![], vec![], 4),
+	]);
+}
+
+
+
+
+// Run a program on a machine with no devices until it reaches `sub pc, 1`
+fn run_program(program: &[u16]) -> System {
+	let mut system = machine(program, vec![]);
+
+	for _ in 0..100_000 {
+		if system.dcpu.memory[system.dcpu.program_counter as usize] == halt() {
+			return system;
+		}
+		system.step_instruction();
+	}
+
+	panic!("The program never halted. It got to 0x{:04x}.", system.dcpu.program_counter);
+}
+
+
+#[test]
+fn fibonacci() {
+	let system = run_program(&[
+		basic(SET, REG_A, literal(0)),
+		basic(SET, REG_B, literal(1)),
+		basic(SET, REG_C, literal(10)),
+		// loop:
+		basic(SET, 0x03, REG_A),
+		basic(ADD, 0x03, REG_B),
+		basic(SET, REG_A, REG_B),
+		basic(SET, REG_B, 0x03),
+		basic(SUB, REG_C, literal(1)),
+		basic(IFN, REG_C, literal(0)),
+		basic(SET, PC, literal(3)),
+		halt(),
+	]);
+
+	assert_eq!(system.dcpu.registers[dcpu::A], 55);
+	assert_eq!(system.dcpu.registers[dcpu::B], 89);
+}
+
+
+#[test]
+fn multiword_arithmetic() {
+	let system = run_program(&[
+		// 0x0001ffff + 1 in b:a
+		basic(SET, REG_A, NEXT), 0xffff,
+		basic(SET, REG_B, literal(1)),
+		basic(ADD, REG_A, literal(1)),
+		basic(ADX, REG_B, literal(0)),
+		// 0x00020000 - 1 in y:x
+		basic(SET, 0x03, literal(0)),
+		basic(SET, 0x04, literal(2)),
+		basic(SUB, 0x03, literal(1)),
+		basic(SBX, 0x04, literal(0)),
+		halt(),
+	]);
+
+	let registers = system.dcpu.registers;
+	assert_eq!((registers[dcpu::B], registers[dcpu::A]), (0x0002, 0x0000));
+	assert_eq!((registers[dcpu::Y], registers[dcpu::X]), (0x0001, 0xffff));
+}
+
+
+#[test]
+fn copy_and_sum() {
+	let system = run_program(&[
+		// Copy four words from data to 0x200 with sti
+		basic(SET, 0x06, literal(20)),
+		basic(SET, 0x07, NEXT), 0x0200,
+		// copy:
+		basic(STI, AT_J, AT_I),
+		basic(IFL, 0x06, literal(24)),
+		basic(SET, PC, literal(3)),
+		special(JSR, NEXT), 0x000a,
+		halt(),
+		0x0000,
+		// sum: add up the copy into a
+		basic(SET, REG_A, literal(0)),
+		basic(SET, 0x06, NEXT), 0x0200,
+		// sum_loop:
+		basic(ADD, REG_A, AT_I),
+		basic(ADD, 0x06, literal(1)),
+		basic(IFL, 0x06, NEXT), 0x0204,
+		basic(SET, PC, NEXT), 0x000d,
+		basic(SET, PC, PUSH_POP),
+		// data:
+		100, 200, 300, 400,
+	]);
+
+	let cpu = &system.dcpu;
+	assert_eq!(cpu.registers[dcpu::A], 1000);
+	assert_eq!(&cpu.memory[0x200..0x204], &[100, 200, 300, 400]);
+	assert_eq!(cpu.stack_pointer, 0);
+}
+
+
+#[test]
+fn queued_interrupts() {
+	let system = run_program(&[
+		special(IAS, literal(12)),
+		special(IAQ, literal(1)),
+		special(INT, literal(5)),
+		special(INT, literal(6)),
+		basic(SET, REG_B, literal(0)),
+		special(IAQ, literal(0)),
+		// wait: until both interrupts have been handled
+		basic(IFN, REG_C, literal(2)),
+		basic(SET, PC, literal(6)),
+		halt(),
+		0x0000, 0x0000, 0x0000,
+		// handler: add up the messages
+		basic(ADD, REG_C, literal(1)),
+		basic(ADD, REG_B, REG_A),
+		special(RFI, literal(0)),
+	]);
+
+	let cpu = &system.dcpu;
+	assert_eq!(cpu.registers[dcpu::B], 11);
+	assert_eq!(cpu.registers[dcpu::A], 0);
+	assert_eq!(cpu.stack_pointer, 0);
+	assert!(!cpu.interrupt_queueing);
+	assert!(cpu.interrupt_queue.is_empty());
+}
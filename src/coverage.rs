@@ -0,0 +1,332 @@
+use std::io;
+use std::io::prelude::*;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use disassembler;
+use symbols::{self, Symbols};
+use trace::{TraceSink, Entry};
+
+
+/// Where each instruction came from in the assembler's source
+pub struct SourceMap {
+	lines: BTreeMap<u16, (String, u32)>,
+}
+
+impl SourceMap {
+	/// Load a source map with one instruction per line, like `0x0012 main.dasm:14`
+	pub fn load(path: &str) -> io::Result<SourceMap> {
+		let mut text = String::new();
+		File::open(path)?.read_to_string(&mut text)?;
+		SourceMap::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+	}
+
+	pub fn parse(text: &str) -> Result<SourceMap, String> {
+		let mut lines = BTreeMap::new();
+
+		for (number, line) in text.lines().enumerate() {
+			let line = line.split(|c| c == ';' || c == '#').next().unwrap_or("").trim();
+			if line.is_empty() {
+				continue;
+			}
+
+			let mut parts = line.splitn(2, char::is_whitespace);
+			let address = parts.next().and_then(symbols::parse_number);
+			let location = parts.next().and_then(|location| {
+				let mut location = location.trim().rsplitn(2, ':');
+				let line = location.next()?.parse().ok()?;
+				Some((location.next()?.to_string(), line))
+			});
+
+			match (address, location) {
+				(Some(address), Some(location)) => lines.insert(address, location),
+				_ => return Err(format!("Expected an address and a file:line on line {}: {}", number + 1, line)),
+			};
+		}
+
+		Ok(SourceMap {lines: lines})
+	}
+}
+
+
+
+
+/// Which instructions a program ran, and which way each of its `if`s went
+pub struct Coverage {
+	pub executed: HashMap<u16, u64>,
+	/// How many times each `if` passed, so the next instruction ran, and how many times it failed
+	pub branches: HashMap<u16, (u64, u64)>,
+}
+
+impl Coverage {
+	pub fn new() -> Coverage {
+		Coverage {
+			executed: HashMap::new(),
+			branches: HashMap::new(),
+		}
+	}
+
+
+	pub fn record(&mut self, entry: &Entry) {
+		*self.executed.entry(entry.address).or_insert(0) += 1;
+
+		if is_if(entry.words[0]) {
+			let passed = entry.after[9] == entry.address.wrapping_add(entry.words.len() as u16);
+			let branch = self.branches.entry(entry.address).or_insert((0, 0));
+			if passed {
+				branch.0 += 1;
+			} else {
+				branch.1 += 1;
+			}
+		}
+	}
+
+
+	/// Write the coverage out in LCOV's tracefile format. Without a source map, the
+	/// program is read from start to end as instructions, and each is reported as
+	/// if it were on line `address + 1` of the image. Labels are reported as functions.
+	pub fn lcov(&self, image_name: &str, program: &[u16], symbols: Option<&Symbols>, source_map: Option<&SourceMap>) -> String {
+		// Every instruction, and where it is in the source
+		let mut instructions: Vec<(u16, String, u32)> = vec![];
+		match source_map {
+			Some(map) =>
+				for (&address, &(ref file, line)) in &map.lines {
+					instructions.push((address, file.clone(), line));
+				},
+
+			None => {
+				let mut address = 0;
+				while (address as usize) < program.len() {
+					instructions.push((address as u16, image_name.to_string(), address + 1));
+					address += disassembler::instruction_length(program[address as usize]) as u32;
+				}
+			},
+		}
+
+		let location = |address: u16| match source_map {
+			Some(map) => map.lines.get(&address).cloned(),
+			None if (address as usize) < program.len() => Some((image_name.to_string(), address as u32 + 1)),
+			None => None,
+		};
+
+		let mut files: BTreeMap<String, FileCoverage> = BTreeMap::new();
+
+		for (address, file, line) in instructions {
+			let file = files.entry(file).or_insert_with(FileCoverage::default);
+			let count = self.executed.get(&address).cloned().unwrap_or(0);
+			let hits = file.lines.entry(line).or_insert(0);
+			*hits = (*hits).max(count);
+
+			if program.get(address as usize).map_or(false, |&word| is_if(word)) {
+				let outcome = if count > 0 {self.branches.get(&address).cloned()} else {None};
+				file.branches.push((line, address, outcome));
+			}
+		}
+
+		if let Some(symbols) = symbols {
+			for (label, address) in symbols.labels() {
+				if let Some((file, line)) = location(address) {
+					let count = self.executed.get(&address).cloned().unwrap_or(0);
+					files.entry(file).or_insert_with(FileCoverage::default).functions.push((line, label.to_string(), count));
+				}
+			}
+		}
+
+		let mut report = String::new();
+		for (name, file) in files {
+			let _ = writeln!(report, "TN:\nSF:{}", name);
+
+			for &(line, ref label, _) in &file.functions {
+				let _ = writeln!(report, "FN:{},{}", line, label);
+			}
+			for &(_, ref label, count) in &file.functions {
+				let _ = writeln!(report, "FNDA:{},{}", count, label);
+			}
+			let _ = writeln!(report, "FNF:{}\nFNH:{}", file.functions.len(), file.functions.iter().filter(|function| function.2 > 0).count());
+
+			for &(line, address, outcome) in &file.branches {
+				match outcome {
+					Some((passed, failed)) => {
+						let _ = writeln!(report, "BRDA:{},{},0,{}\nBRDA:{},{},1,{}", line, address, passed, line, address, failed);
+					},
+					None => {
+						let _ = writeln!(report, "BRDA:{},{},0,-\nBRDA:{},{},1,-", line, address, line, address);
+					},
+				}
+			}
+			let hit = file.branches.iter().map(|&(_, _, outcome)| outcome.map_or(0, |(passed, failed)| (passed > 0) as usize + (failed > 0) as usize)).sum::<usize>();
+			let _ = writeln!(report, "BRF:{}\nBRH:{}", file.branches.len() * 2, hit);
+
+			for (line, count) in &file.lines {
+				let _ = writeln!(report, "DA:{},{}", line, count);
+			}
+			let _ = writeln!(report, "LF:{}\nLH:{}", file.lines.len(), file.lines.values().filter(|&&count| count > 0).count());
+			let _ = writeln!(report, "end_of_record");
+		}
+
+		report
+	}
+}
+
+
+// What a single source file's part of an LCOV report needs
+#[derive(Default)]
+struct FileCoverage {
+	lines: BTreeMap<u32, u64>,
+	branches: Vec<(u32, u16, Option<(u64, u64)>)>,
+	functions: Vec<(u32, String, u64)>,
+}
+
+
+fn is_if(word: u16) -> bool {
+	match word & 0x1f {
+		0x10...0x17 => true,
+		_ => false,
+	}
+}
+
+
+
+
+/// Records the coverage of the CPU it is attached to, writing an LCOV report out
+/// when the CPU halts, or when the sink is dropped if it never does
+pub struct CoverageSink {
+	coverage: Arc<Mutex<Coverage>>,
+	image_name: String,
+	program: Vec<u16>,
+	symbols: Option<Symbols>,
+	source_map: Option<SourceMap>,
+	output: Option<Box<dyn Write + Send>>,
+}
+
+impl CoverageSink {
+	/// `program` is the image as it was loaded, to find the instructions that never ran
+	pub fn new(image_name: &str, program: Vec<u16>, symbols: Option<Symbols>, source_map: Option<SourceMap>, output: Option<Box<dyn Write + Send>>) -> CoverageSink {
+		CoverageSink {
+			coverage: Arc::new(Mutex::new(Coverage::new())),
+			image_name: image_name.to_string(),
+			program: program,
+			symbols: symbols,
+			source_map: source_map,
+			output: output,
+		}
+	}
+
+	/// The coverage so far, to look at while the CPU keeps running
+	pub fn coverage(&self) -> Arc<Mutex<Coverage>> {
+		self.coverage.clone()
+	}
+
+
+	// Write the report out, the first time only
+	fn write_report(&mut self) {
+		if let Some(mut output) = self.output.take() {
+			let coverage = self.coverage.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+			let report = coverage.lcov(&self.image_name, &self.program, self.symbols.as_ref(), self.source_map.as_ref());
+			let _ = output.write_all(report.as_bytes());
+			let _ = output.flush();
+		}
+	}
+}
+
+impl TraceSink for CoverageSink {
+	fn record(&mut self, entry: &Entry) {
+		self.coverage.lock().unwrap().record(entry);
+
+		if entry.is_halt() {
+			self.write_report();
+		}
+	}
+}
+
+impl Drop for CoverageSink {
+	fn drop(&mut self) {
+		self.write_report();
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dcpu;
+
+	// Count A up to 2, then halt. The ifn passes once and fails once, and the ife never runs.
+	const PROGRAM: [u16; 5] = [
+		0x8802, // add a, 1
+		0x8c13, // ifn a, 2
+		0x8781, // set pc, 0
+		0x8b83, // sub pc, 1
+		0x9012, // ife a, 3
+	];
+
+	fn run() -> Arc<Mutex<Coverage>> {
+		let mut dcpu = dcpu::Dcpu::new();
+		dcpu.memory[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+
+		let sink = CoverageSink::new("test.bin", PROGRAM.to_vec(), None, None, None);
+		let coverage = sink.coverage();
+		dcpu.trace_sinks.push(Box::new(sink));
+
+		for _ in 0..30 {
+			dcpu.step();
+		}
+
+		coverage
+	}
+
+	#[test]
+	fn branches_and_lines() {
+		let coverage = run();
+		let coverage = coverage.lock().unwrap();
+
+		assert_eq!(coverage.branches[&1], (1, 1));
+		assert_eq!(coverage.executed[&2], 1);
+		assert!(!coverage.executed.contains_key(&4));
+
+		let mut symbols = Symbols::new();
+		symbols.insert("start", 0);
+		let report = coverage.lcov("test.bin", &PROGRAM, Some(&symbols), None);
+
+		assert!(report.starts_with("TN:\nSF:test.bin\nFN:1,start\nFNDA:2,start\n"), "{}", report);
+		assert!(report.contains("BRDA:2,1,0,1\nBRDA:2,1,1,1\n"), "{}", report);
+		assert!(report.contains("BRDA:5,4,0,-\n"), "{}", report);
+		assert!(report.contains("DA:4,"), "{}", report);
+		assert!(report.contains("DA:5,0\nLF:5\nLH:4\nend_of_record\n"), "{}", report);
+	}
+
+	#[test]
+	fn source_lines() {
+		let map = SourceMap::parse("0x0000 count.dasm:3\n0x0001 count.dasm:4\n0x0002 count.dasm:4 ; same line\n0x0003 count.dasm:6\n").unwrap();
+		let coverage = run();
+		let report = coverage.lock().unwrap().lcov("test.bin", &PROGRAM, None, Some(&map));
+
+		assert!(report.contains("SF:count.dasm\n"), "{}", report);
+		assert!(report.contains("DA:3,2\nDA:4,2\nDA:6,"), "{}", report);
+		assert!(SourceMap::parse("0x0000 nowhere\n").is_err());
+	}
+
+	#[test]
+	fn report_written_when_dropped() {
+		let path = ::std::env::temp_dir().join(format!("dcpu-coverage-{}.info", ::std::process::id()));
+
+		// add a, 1; set pc, 0 never halts
+		let program = [0x8802, 0x8781];
+		let mut dcpu = dcpu::Dcpu::new();
+		dcpu.memory[..2].copy_from_slice(&program);
+		let output = File::create(&path).unwrap();
+		dcpu.trace_sinks.push(Box::new(CoverageSink::new("test.bin", program.to_vec(), None, None, Some(Box::new(output)))));
+		for _ in 0..10 {
+			dcpu.step();
+		}
+		drop(dcpu);
+
+		let report = ::std::fs::read_to_string(&path).unwrap();
+		::std::fs::remove_file(&path).unwrap();
+		assert!(report.contains("DA:1,"), "{}", report);
+		assert!(report.ends_with("end_of_record\n"), "{}", report);
+	}
+}
@@ -1,3 +1,11 @@
+use std::error;
+use std::fmt;
+use savestate;
+use savestate::SaveState;
+use spec::Spec;
+use decode::Cache;
+use trace::{TraceSink, Entry};
+
 pub const A: usize = 0x0;
 pub const B: usize = 0x1;
 pub const C: usize = 0x2;
@@ -7,6 +15,9 @@ pub const Z: usize = 0x5;
 pub const I: usize = 0x6;
 pub const J: usize = 0x7;
 
+/// Cycles the DCPU runs in a second of emulated time
+pub const CLOCK_RATE: u64 = 100_000;
+
 
 #[derive(Copy, Clone)]
 pub enum HardwareInstruction {
@@ -16,6 +27,127 @@ pub enum HardwareInstruction {
 }
 
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+   Read,
+   Write,
+   Execute,
+}
+
+
+/// A range of memory to keep an eye on. When the CPU accesses it in one of the
+/// watched ways, the access is left in `Dcpu::watchpoint_hit`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+   pub start: u16,
+   pub end: u16,
+   pub read: bool,
+   pub write: bool,
+   pub execute: bool,
+}
+
+impl Watchpoint {
+   fn matches(&self, access: Access, address: u16) -> bool {
+      let watched = match access {
+         Access::Read => self.read,
+         Access::Write => self.write,
+         Access::Execute => self.execute,
+      };
+
+      watched && address >= self.start && address <= self.end
+   }
+}
+
+
+/// A watchpoint that was triggered, along with the access that triggered it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WatchpointHit {
+   pub watchpoint: Watchpoint,
+   pub access: Access,
+   pub address: u16,
+   pub value: u16,
+}
+
+
+/// Why the CPU stopped running a program
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FaultKind {
+   /// hcf ran, with the value of its operand
+   CaughtFire(u16),
+   /// An opcode the CPU's spec version doesn't have
+   IllegalInstruction,
+   /// An instruction tried to write to the operand, which is a literal. Only a
+   /// fault when the CPU has strict operands.
+   InvalidOperand(u16),
+   /// More than 256 interrupts were waiting to be handled at once
+   InterruptOverflow,
+}
+
+
+/// A fault, along with the instruction that caused it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Fault {
+   pub kind: FaultKind,
+   pub address: u16,
+   /// The first word of the instruction
+   pub word: u16,
+}
+
+impl fmt::Display for Fault {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self.kind {
+         FaultKind::CaughtFire(value) => write!(f, "The DCPU caught fire with hcf 0x{:04x}", value)?,
+         FaultKind::IllegalInstruction => write!(f, "Illegal instruction 0x{:04x}", self.word)?,
+         FaultKind::InvalidOperand(operand) => write!(f, "Instruction 0x{:04x} wrote to literal operand 0x{:02x}", self.word, operand)?,
+         FaultKind::InterruptOverflow => write!(f, "More than 256 interrupts were queued, so the DCPU caught fire")?,
+      }
+      write!(f, " at 0x{:04x}", self.address)
+   }
+}
+
+impl error::Error for Fault {}
+
+
+/// What the CPU does when a program faults
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FaultPolicy {
+   /// Stop for good, on fire
+   Halt,
+   /// Stop until a debugger clears the fault
+   Trap,
+   /// Carry on as if nothing happened. Illegal instructions do nothing and cost no cycles,
+   /// and writes to literals are lost, as the spec says they should be.
+   Ignore,
+}
+
+impl FaultPolicy {
+   pub fn parse(text: &str) -> Option<FaultPolicy> {
+      match text {
+         "halt" => Some(FaultPolicy::Halt),
+         "trap" => Some(FaultPolicy::Trap),
+         "ignore" => Some(FaultPolicy::Ignore),
+         _ => None,
+      }
+   }
+}
+
+
+/// Something that is told about every access the CPU makes to a range of
+/// memory, such as a device whose registers are mapped into memory
+pub trait MemoryHook {
+   /// The CPU read `value` from the address. Returns the value it should see instead.
+   fn read(&mut self, _address: u16, value: u16) -> u16 {
+      value
+   }
+
+   /// The CPU wrote the value to the address
+   fn write(&mut self, _address: u16, _value: u16) {}
+
+   /// The CPU is about to execute the instruction at the address
+   fn execute(&mut self, _address: u16) {}
+}
+
+
 pub struct Dcpu {
    pub registers: [u16; 8],
    pub stack_pointer: u16,
@@ -23,11 +155,35 @@ pub struct Dcpu {
    pub excess: u16,
    pub memory: [u16; 0x10000],
    pub cycle_accumulator: u32,
-   pub cycle_count: u32,
+   pub cycle_count: u64,
    pub interrupt_address: u16,
    pub interrupt_queue: Vec<u16>,
    pub interrupt_queueing: bool,
    pub hardware_interrupt: Option<HardwareInstruction>,
+   /// Which version of the specification to follow
+   pub spec: Spec,
+   /// Set by hlt, leaving the CPU idle until an interrupt arrives
+   pub sleeping: bool,
+   /// Set by brk, for a debugger to notice and clear
+   pub break_requested: bool,
+   /// The CPU doesn't run any instructions while it has a fault
+   pub fault: Option<Fault>,
+   pub fault_policy: FaultPolicy,
+   /// Whether writing to a literal is a fault. The spec says the write is quietly lost.
+   pub strict_operands: bool,
+   pub watchpoints: Vec<Watchpoint>,
+   pub watchpoint_hit: Option<WatchpointHit>,
+   /// When set, every word the CPU writes to memory is added along with the value it replaced
+   pub journal: Option<Vec<(u16, u16)>>,
+   /// Told about every instruction that runs
+   pub trace_sinks: Vec<Box<dyn TraceSink>>,
+   /// Told about every value the 2.0 log instruction logs. Without one they are dropped.
+   pub log_sink: Option<Box<dyn FnMut(u16)>>,
+   memory_hooks: Vec<(u16, u16, Box<dyn MemoryHook>)>,
+   // Where the instruction being run started, for faults
+   instruction_address: u16,
+   // Instructions decoded by run_instruction
+   cache: Cache,
 }
 
 fn get_operand_cost(operand: u16) -> u32 {
@@ -44,32 +200,6 @@ fn get_operand_length(operand: u16) -> u16 {
    }
 }
 
-fn get_opcode_length(opcode: u16) -> u16 {
-   let (_, operand_b, operand_a) = get_opcode_parts(opcode);
-   1 + get_operand_length(operand_b) + get_operand_length(operand_a)
-}
-
-fn get_instruction_cost(instruction: u16) -> u32 {
-   match instruction {
-      0x01 | 0x0a | 0x0b | 0x0c | 0x0d | 0x0e | 0x0f => 1,
-      0x02 | 0x03 | 0x04 | 0x05 | 0x1e | 0x1f => 2,
-      0x10 | 0x11 | 0x12 | 0x13 | 0x14 | 0x15 | 0x16 | 0x17 => 2,
-      0x06 | 0x07 | 0x08 | 0x09 | 0x1a | 0x1b => 3,
-      _ => 0
-   }
-}
-
-fn get_special_instruction_cost(instruction: u16) -> u32 {
-   match instruction {
-      0x00 => 0,
-      0x09 | 0x0a => 1,
-      0x0c | 0x10 => 2,
-      0x01 | 0x0b => 3,
-      0x08 | 0x11 | 0x12 => 4,
-      _ => 0 // panic!("Unknown special instruction {}", instruction),
-   }
-}
-
 fn is_if_op_code(op_code: u16) -> bool {
    match op_code & 0x1f {
       0x10 | 0x11 | 0x12 | 0x13 | 0x14 | 0x15 | 0x16 | 0x17 => true,
@@ -102,7 +232,132 @@ impl Dcpu {
          interrupt_queue: vec![],
          interrupt_queueing: false,
          hardware_interrupt: None,
+         spec: Spec::default(),
+         sleeping: false,
+         break_requested: false,
+         fault: None,
+         fault_policy: FaultPolicy::Halt,
+         strict_operands: false,
+         watchpoints: vec![],
+         watchpoint_hit: None,
+         journal: None,
+         trace_sinks: vec![],
+         log_sink: None,
+         memory_hooks: vec![],
+         instruction_address: 0,
+         cache: Cache::new(),
+      }
+   }
+
+
+   /// Tell the hook about every access to the addresses from start to end, inclusive
+   pub fn hook_memory(&mut self, start: u16, end: u16, hook: Box<dyn MemoryHook>) {
+      self.memory_hooks.push((start, end, hook));
+   }
+
+
+   /// Put the whole state of the CPU into a save state. Watchpoints and memory
+   /// hooks belong to whoever set them up, so they aren't saved.
+   pub fn save(&self, state: &mut SaveState) {
+      let mut cpu = self.registers.to_vec();
+      cpu.extend_from_slice(&[self.stack_pointer, self.program_counter, self.excess, self.interrupt_address]);
+      state.put("cpu", cpu);
+
+      let mut cycles = savestate::split(self.cycle_accumulator).to_vec();
+      cycles.extend_from_slice(&savestate::split_long(self.cycle_count));
+      state.put("cycles", cycles);
+
+      let mut interrupts = vec![self.interrupt_queueing as u16];
+      interrupts.extend_from_slice(&self.interrupt_queue);
+      state.put("interrupts", interrupts);
+
+      state.put("hardware", match self.hardware_interrupt {
+         None => vec![],
+         Some(HardwareInstruction::GetCount(operand)) => vec![0, operand],
+         Some(HardwareInstruction::GetInfo(index)) => vec![1, index],
+         Some(HardwareInstruction::Interrupt(index)) => vec![2, index],
+      });
+
+      state.put("memory", self.memory.to_vec());
+      state.put("spec", vec![self.spec.code(), self.sleeping as u16]);
+
+      state.put("fault", match self.fault {
+         None => vec![],
+         Some(fault) => {
+            let (kind, detail) = match fault.kind {
+               FaultKind::CaughtFire(value) => (0, value),
+               FaultKind::IllegalInstruction => (1, 0),
+               FaultKind::InvalidOperand(operand) => (2, operand),
+               FaultKind::InterruptOverflow => (3, 0),
+            };
+            vec![kind, detail, fault.address, fault.word]
+         },
+      });
+   }
+
+
+   /// Put the CPU back the way it was when the save state was made
+   pub fn restore(&mut self, state: &SaveState) -> Result<(), String> {
+      let cpu = savestate::section(state, "cpu")?;
+      let cycles = savestate::section(state, "cycles")?;
+      let interrupts = savestate::section(state, "interrupts")?;
+      let hardware = savestate::section(state, "hardware")?;
+      let memory = savestate::section(state, "memory")?;
+
+      if cpu.len() != 12 || (cycles.len() != 4 && cycles.len() != 6) || interrupts.is_empty() || memory.len() != 0x10000 {
+         return Err("The CPU in the save state is incomplete".to_string());
       }
+
+      self.hardware_interrupt = match hardware {
+         &[] => None,
+         &[0, operand] => Some(HardwareInstruction::GetCount(operand)),
+         &[1, index] => Some(HardwareInstruction::GetInfo(index)),
+         &[2, index] => Some(HardwareInstruction::Interrupt(index)),
+         _ => return Err("The save state has an unknown hardware instruction waiting".to_string()),
+      };
+
+      self.registers.copy_from_slice(&cpu[..8]);
+      self.stack_pointer = cpu[8];
+      self.program_counter = cpu[9];
+      self.excess = cpu[10];
+      self.interrupt_address = cpu[11];
+      self.cycle_accumulator = savestate::join(cycles[0], cycles[1]);
+      // Save states from before the cycle count was widened only have 32 bits of it
+      self.cycle_count = savestate::join_long(&cycles[2..]);
+      self.interrupt_queueing = interrupts[0] != 0;
+      self.interrupt_queue = interrupts[1..].to_vec();
+      self.memory.copy_from_slice(memory);
+      self.watchpoint_hit = None;
+
+      // Save states from before the spec could be chosen were all made following 1.7
+      match state.get("spec") {
+         Some(&[code, sleeping]) => {
+            self.spec = Spec::from_code(code).ok_or_else(|| format!("The save state follows an unknown spec, {:04x}", code))?;
+            self.sleeping = sleeping != 0;
+         },
+         Some(_) => return Err("The save state's spec is incomplete".to_string()),
+         None => {
+            self.spec = Spec::V1_7;
+            self.sleeping = false;
+         },
+      }
+
+      self.fault = match state.get("fault") {
+         None | Some(&[]) => None,
+         Some(&[kind, detail, address, word]) => {
+            let kind = match kind {
+               0 => FaultKind::CaughtFire(detail),
+               1 => FaultKind::IllegalInstruction,
+               2 => FaultKind::InvalidOperand(detail),
+               3 => FaultKind::InterruptOverflow,
+               _ => return Err("The save state has an unknown fault".to_string()),
+            };
+            Some(Fault {kind: kind, address: address, word: word})
+         },
+         Some(_) => return Err("The save state's fault is incomplete".to_string()),
+      };
+
+      Ok(())
    }
 
 
@@ -120,10 +375,177 @@ impl Dcpu {
          return;
       }
 
+      // Nothing runs while the CPU is on fire
+      if self.fault.is_some() {
+         self.cycle_count += 1;
+         return;
+      }
+
+      if self.interrupt_queue.len() > 256 {
+         self.interrupt_queue.clear();
+         self.instruction_address = self.program_counter;
+         self.raise(FaultKind::InterruptOverflow);
+         if self.fault.is_some() {
+            self.cycle_count += 1;
+            return;
+         }
+      }
+
+      if self.spec.has_interrupts() {
+         self.trigger_interrupt();
+      } else {
+         self.interrupt_queue.clear();
+      }
+
+      if self.sleeping {
+         self.cycle_count += 1;
+         return;
+      }
+
+
+      // Fetch the instruction
+      let program_counter = self.program_counter;
+      self.instruction_address = program_counter;
+      let op_code = self.read_memory(Access::Execute, program_counter);
+
+      // Note what the instruction looks like and the registers before it runs, if it is being traced
+      let traced = if !self.trace_sinks.is_empty() {
+         let words = (0..self.spec.instruction_length(op_code)).map(|i| self.memory[program_counter.wrapping_add(i) as usize]).collect();
+         Some((words, self.trace_registers()))
+      } else {
+         None
+      };
+
+      let (value_a, value_b) = match self.spec {
+         Spec::V1_1 => self.execute_1_1(op_code),
+         Spec::V1_7 | Spec::V2_0 => self.execute(op_code),
+      };
+
+
+      // Decrement a cycle for this step. Special instructions don't have a b operand.
+      if value_b.is_some() {
+         // If the instruction just executed was not actually valid, it was
+         // probably given a 0 cost, which means that no cost was actually
+         // added to the cycle accumulator. To prevent an exception, check
+         // to ensure there are actually cycles to remove.
+         if self.cycle_accumulator > 0 {
+            self.cycle_accumulator -= 1;
+         }
+         self.cycle_count += 1;
+      }
+
+      if let Some((words, before)) = traced {
+         // Special instructions don't use up a cycle in the step that runs them
+         let cycles = self.cycle_accumulator + if value_b.is_some() {1} else {0};
+         let entry = Entry {
+            address: program_counter,
+            words: words,
+            value_a: value_a,
+            value_b: value_b,
+            before: before,
+            after: self.trace_registers(),
+            cycle_count: self.cycle_count,
+            cycles: cycles,
+            fault: self.fault,
+         };
+
+         for sink in &mut self.trace_sinks {
+            sink.record(&entry);
+         }
+      }
+   }
+
 
-      // Decode the instruction
-      let op_code = self.memory[self.program_counter as usize];
+   /// Run one whole instruction, first using up any cycles still owed by the last
+   /// one, and return how many cycles passed. Instructions are decoded once and
+   /// kept, and their cycles are counted all at once, so this is much quicker than
+   /// calling `step` for every cycle. Special instructions, and anything watched,
+   /// hooked, traced or interrupted, still go through `step`.
+   pub fn run_instruction(&mut self) -> u32 {
+      let start = self.cycle_count;
+      self.cycle_count += self.cycle_accumulator as u64;
+      self.cycle_accumulator = 0;
+
+      let unusual = self.spec == Spec::V1_1
+         || self.fault.is_some()
+         || self.sleeping
+         || self.hardware_interrupt.is_some()
+         || !self.interrupt_queue.is_empty()
+         || !self.watchpoints.is_empty()
+         || !self.memory_hooks.is_empty()
+         || !self.trace_sinks.is_empty()
+         || self.journal.is_some();
+
+      let ran = !unusual && {
+         let instruction = self.cache.get(&self.memory, self.spec, self.program_counter);
+         instruction.run(self)
+      };
+
+      if !ran {
+         self.step();
+         self.cycle_count += self.cycle_accumulator as u64;
+         self.cycle_accumulator = 0;
+      }
+
+      (self.cycle_count - start) as u32
+   }
+
+
+   // Put the CPU on fire for the instruction being run, unless faults are ignored.
+   // The first fault is the one that counts.
+   fn raise(&mut self, kind: FaultKind) {
+      if self.fault_policy == FaultPolicy::Ignore || self.fault.is_some() {
+         return;
+      }
+
+      self.fault = Some(Fault {
+         kind: kind,
+         address: self.instruction_address,
+         word: self.memory[self.instruction_address as usize],
+      });
+   }
+
+
+   // Start handling the next interrupt in the queue, unless interrupts are being
+   // queued up. Interrupts are dropped while the interrupt address is zero.
+   fn trigger_interrupt(&mut self) {
+      if self.interrupt_queueing || self.interrupt_queue.is_empty() {
+         return;
+      }
+
+      let message = self.interrupt_queue.remove(0);
+      self.sleeping = false;
+
+      if self.interrupt_address != 0 {
+         self.interrupt_queueing = true;
+         let (program_counter, register_a) = (self.program_counter, self.registers[A]);
+         self.push(program_counter);
+         self.push(register_a);
+         self.program_counter = self.interrupt_address;
+         self.registers[A] = message;
+      }
+   }
+
+
+   fn push(&mut self, value: u16) {
+      self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+      let stack_pointer = self.stack_pointer;
+      self.write_memory(stack_pointer, value);
+   }
+
+
+   fn pop(&mut self) -> u16 {
+      let stack_pointer = self.stack_pointer;
+      self.stack_pointer = self.stack_pointer.wrapping_add(1);
+      self.read_memory(Access::Read, stack_pointer)
+   }
+
+
+   // Run an instruction in the 1.7 encoding, returning the values of its a and b operands
+   fn execute(&mut self, op_code: u16) -> (u16, Option<u16>) {
       let (instruction, operand_b, operand_a) = get_opcode_parts(op_code);
+      let mut traced_b = None;
+
       self.program_counter = self.program_counter.wrapping_add(1);
 
       // Get the value of operand a, updating the various states
@@ -138,41 +560,42 @@ impl Dcpu {
       /* Handle a Special Instruction */
       if instruction == 0x0 {
          // Increment the cycle counters with the cost of the instruction
-         self.cycle_accumulator += get_special_instruction_cost(operand_b);
+         match self.spec.special_cost(operand_b) {
+            Some(cost) => self.cycle_accumulator += cost,
+            None => self.raise(FaultKind::IllegalInstruction),
+         }
 
          // Execute the instruction
          match operand_b {
             0x01 => { // jsr
-               self.stack_pointer = self.stack_pointer.wrapping_sub(1);
-               self.memory[self.stack_pointer as usize] = self.program_counter;
+               let program_counter = self.program_counter;
+               self.push(program_counter);
                self.program_counter = value_a;
             },
 
-            0x08 => { // int
-               if self. interrupt_address != 0 {
-                  self.interrupt_queueing = true;
-                  self.stack_pointer.wrapping_sub(1);
-                  self.memory[self.stack_pointer as usize] = self.program_counter;
-                  self.stack_pointer.wrapping_sub(1);
-                  self.memory[self.stack_pointer as usize] = self.registers[0];
-                  self.program_counter = self.interrupt_address;
-                  self.registers[0] = value_a;
+            0x08 => { // int, handled along with hardware interrupts from the next step
+               if self.interrupt_address != 0 {
+                  self.interrupt_queue.push(value_a);
                }
             },
 
+            0x07 => self.raise(FaultKind::CaughtFire(value_a)), // hcf
+
             0x09 => { // iag
+               // Pop has already moved the stack pointer past where it points
                let interrupt_address = self.interrupt_address;
-               if let Some(pointer_a) = self.get_pointer(value_a) {
-                  *pointer_a = interrupt_address;
+               if operand_a == 0x18 {
+                  let address = self.stack_pointer.wrapping_sub(1);
+                  self.write_memory(address, interrupt_address);
+               } else {
+                  self.set_value(operand_a, interrupt_address);
                }
             },
 
             0x0b => { // rfi
                self.interrupt_queueing = false;
-               self.registers[0] = self.memory[self.stack_pointer as usize];
-               self.stack_pointer.wrapping_add(1);
-               self.program_counter = self.memory[self.stack_pointer as usize];
-               self.stack_pointer.wrapping_add(1);
+               self.registers[A] = self.pop();
+               self.program_counter = self.pop();
             },
 
             0x0a => self.interrupt_address = value_a, // ias
@@ -181,23 +604,41 @@ impl Dcpu {
             0x11 => self.hardware_interrupt = Some(HardwareInstruction::GetInfo(value_a)), // hwq
             0x12 => self.hardware_interrupt = Some(HardwareInstruction::Interrupt(value_a)), // hwi
 
+            // The community's extensions
+            0x13 if self.spec == Spec::V2_0 => if let Some(ref mut sink) = self.log_sink {sink(value_a)}, // log
+            0x14 if self.spec == Spec::V2_0 => self.break_requested = true, // brk
+            0x15 if self.spec == Spec::V2_0 => self.sleeping = true, // hlt
+
             _ => ()
          }
       }
 
       /* Handle a Regular Instruction */
       else {
-         // Get the value of operand b, updating the state as necessary
-         let value_b = self.get_value(operand_b);
+         // Get the value of operand b, updating the state as necessary. Push
+         // is [--SP], so its value is what's under the new top of the stack,
+         // but it doesn't count as a read.
          if operand_b == 0x18 {
             self.stack_pointer = self.stack_pointer.wrapping_sub(1);
          }
+         let value_b = if operand_b == 0x18 {
+            self.memory[self.stack_pointer as usize]
+         } else {
+            self.get_value(operand_b)
+         };
+         traced_b = Some(value_b);
          self.program_counter = self.program_counter.wrapping_add(get_operand_length(operand_b));
          self.cycle_accumulator += get_operand_cost(operand_b);
 
 
+         // ADX and SBX use EX from before the instruction, even if they write to it
+         let excess = self.excess;
+
          // Update the cycle counters with the cost of the instruction
-         self.cycle_accumulator += get_instruction_cost(instruction);
+         match self.spec.basic_cost(instruction) {
+            Some(cost) => self.cycle_accumulator += cost,
+            None => self.raise(FaultKind::IllegalInstruction),
+         }
 
          // Execute the instruction
          // Handle branching instructions
@@ -210,69 +651,76 @@ impl Dcpu {
                0x14 => value_b > value_a,
                0x15 => (value_b as i16) > (value_a as i16),
                0x16 => value_b < value_a,
-               0x17 => (value_b as i16) < (value_a as i16),
-               _ => panic!("Unexpected if instruction! This should have been impossible.")
+               _ => (value_b as i16) < (value_a as i16),
             };
 
             if !is_valid {
                // Skip chained if instructions
                while is_if_op_code(self.memory[self.program_counter as usize]) {
-                  self.program_counter = self.program_counter.wrapping_add(get_opcode_length(self.memory[self.program_counter as usize]));
+                  self.program_counter = self.program_counter.wrapping_add(self.spec.instruction_length(self.memory[self.program_counter as usize]));
                   self.cycle_accumulator += 1;
                }
 
                // Skip the final instruction the ifs are protecting
                self.cycle_accumulator += 1;
-               self.program_counter = self.program_counter.wrapping_add(get_opcode_length(self.memory[self.program_counter as usize]));
+               self.program_counter = self.program_counter.wrapping_add(self.spec.instruction_length(self.memory[self.program_counter as usize]));
             }
          }
 
          // Handle non-branching instructions
          else {
-            let excess = self.excess;
-            if let Some(pointer_b) = self.get_pointer(operand_b) {
-               *pointer_b = match instruction {
-                  0x00 => *pointer_b,
-                  0x01 => value_a,
-                  0x02 => value_b.wrapping_add(value_a),
-                  0x03 => value_b.wrapping_sub(value_a),
-                  0x04 => value_b.wrapping_mul(value_a),
-                  0x05 => (value_b as i16).wrapping_mul(value_a as i16) as u16,
-                  0x06 => if value_a == 0 {0} else {value_b / value_a},
-                  0x07 => if value_a == 0 {0} else {(value_b as i16).wrapping_div(value_a as i16) as u16},
-                  0x08 => if value_a == 0 {0} else {value_b % value_a},
-                  0x09 => if value_a == 0 {0} else {(value_b as i16).wrapping_rem(value_a as i16) as u16},
-                  0x0a => value_b & value_a,
-                  0x0b => value_b | value_a,
-                  0x0c => value_b ^ value_a,
-                  0x0d => value_b.wrapping_shr(value_a as u32),
-                  0x0e => ((value_b as i16).wrapping_shr(value_a as u32)) as u16,
-                  0x0f => value_b.wrapping_shl(value_a as u32),
-                  0x1a => value_b.wrapping_add(value_a).wrapping_add(excess),
-                  0x1b => value_b.wrapping_sub(value_a).wrapping_add(excess),
-                  0x1e => value_a,
-                  0x1f => value_a,
-                  _ => *pointer_b
-               };
+            let result = match instruction {
+               0x01 => Some(value_a),
+               0x02 => Some(value_b.wrapping_add(value_a)),
+               0x03 => Some(value_b.wrapping_sub(value_a)),
+               0x04 => Some(value_b.wrapping_mul(value_a)),
+               0x05 => Some((value_b as i16).wrapping_mul(value_a as i16) as u16),
+               0x06 => Some(if value_a == 0 {0} else {value_b / value_a}),
+               0x07 => Some(if value_a == 0 {0} else {(value_b as i16).wrapping_div(value_a as i16) as u16}),
+               0x08 => Some(if value_a == 0 {0} else {value_b % value_a}),
+               0x09 => Some(if value_a == 0 {0} else {(value_b as i16).wrapping_rem(value_a as i16) as u16}),
+               0x0a => Some(value_b & value_a),
+               0x0b => Some(value_b | value_a),
+               0x0c => Some(value_b ^ value_a),
+               // Shifting by 16 or more shifts everything out, rather than wrapping the count
+               0x0d => Some(((value_b as u64) >> (value_a as u32).min(48)) as u16),
+               0x0e => Some(((value_b as i16 as i64) >> (value_a as u32).min(63)) as u16),
+               0x0f => Some(((value_b as u64) << (value_a as u32).min(48)) as u16),
+               0x1a => Some(value_b.wrapping_add(value_a).wrapping_add(excess)),
+               0x1b => Some(value_b.wrapping_sub(value_a).wrapping_add(excess)),
+               0x1e => Some(value_a),
+               0x1f => Some(value_a),
+               _ => None
+            };
+
+            if let Some(result) = result {
+               self.set_value(operand_b, result);
             }
          }
 
 
-         // Update the overflow register
-         let value_a_signed = value_a as i16;
-         let value_b_signed = value_b as i16;
+         // Update the overflow register. Signed values are widened before anything
+         // is done to them, so -32768 / -1 and big shifts can't overflow.
+         let value_a_signed = value_a as i16 as i64;
+         let value_b_signed = value_b as i16 as i64;
+         let shift = value_a as u32;
          self.excess = match instruction {
             0x02 => if (value_b as u32 + value_a as u32) > 0xffff {1} else {0},
             0x03 => if (value_b as i32 - value_a as i32) < 0 {0xffff} else {0},
             0x04 => ((value_b as u32 * value_a as u32) >> 16) as u16,
-            0x05 => ((value_b as i32 * value_a as i32) >> 16) as u16,
-            0x06 => if value_a == 0 {0} else {(((value_b as i32) << 16i32) / (value_a as i32)) as u16},
-            0x07 => if value_a == 0 {0} else {(((value_b_signed as i32) << 16) / (value_a_signed as i32)) as u16},
-            0x0d => (((value_b as u32) << 16) >> value_a) as u16,
-            0x0e => (((value_b as i32) << 16) >> value_a) as u16,
-            0x0f => (((value_b as u32) << value_a) >> 16) as u16,
-            0x1a => if (value_b as u32 + value_a as u32 + self.excess as u32) > 0xffff {1} else {0},
-            0x1b => if (value_b as i32 - value_a as i32 + self.excess as i32) < 0 {0xffff} else {0},
+            0x05 => ((value_b_signed * value_a_signed) >> 16) as u16,
+            0x06 => if value_a == 0 {0} else {(((value_b as u64) << 16) / (value_a as u64)) as u16},
+            0x07 => if value_a == 0 {0} else {((value_b_signed << 16) / value_a_signed) as u16},
+            0x0d => (((value_b as u64) << 16) >> shift.min(48)) as u16,
+            0x0e => ((value_b_signed << 16) >> shift.min(63)) as u16,
+            0x0f => (((value_b as u64) << shift.min(48)) >> 16) as u16,
+            0x1a => if (value_b as u32 + value_a as u32 + excess as u32) > 0xffff {1} else {0},
+            // A borrow from SUB leaves EX at 0xffff, which SBX takes as -1
+            0x1b => match value_b as i32 - value_a as i32 + excess as i16 as i32 {
+               difference if difference < 0 => 0xffff,
+               difference if difference > 0xffff => 0x0001,
+               _ => 0,
+            },
             _ => self.excess,
          };
 
@@ -293,29 +741,248 @@ impl Dcpu {
          }
       }
 
+      (value_a, traced_b)
+   }
 
-      // Decrement a cycle for this step, and increment the program counter
-      if instruction != 0x0 {
-         // If the instruction just executed was not actually valid, it was
-         // probably given a 0 cost, which means that no cost was actually
-         // added to the cycle accumulator. To prevent an exception, check
-         // to ensure there are actually cycles to remove.
-         if self.cycle_accumulator > 0 {
-            self.cycle_accumulator -= 1;
+
+   // Run an instruction in the 1.1 encoding, returning the values of its a and b
+   // operands. Its opcode is only four bits, and a is the operand written to,
+   // coming before b both in the instruction and in the next words.
+   fn execute_1_1(&mut self, op_code: u16) -> (u16, Option<u16>) {
+      let opcode = op_code & 0xf;
+      let operand_a = (op_code >> 4) & 0x3f;
+      let operand_b = op_code >> 10;
+      self.program_counter = self.program_counter.wrapping_add(1);
+
+      // Non-basic instructions keep their opcode where a usually goes. Only jsr exists.
+      if opcode == 0x0 {
+         let (value, _) = self.get_operand_1_1(operand_b);
+
+         match self.spec.special_cost(operand_a) {
+            Some(cost) => self.cycle_accumulator += cost,
+            None => self.raise(FaultKind::IllegalInstruction),
          }
-         self.cycle_count += 1;
+
+         if operand_a == 0x01 {
+            let program_counter = self.program_counter;
+            self.push(program_counter);
+            self.program_counter = value;
+         }
+
+         return (value, None);
       }
+
+      let (value_a, address_a) = self.get_operand_1_1(operand_a);
+      let (value_b, _) = self.get_operand_1_1(operand_b);
+
+      // The cost tables number instructions as 1.7 does
+      let instruction = [0x00, 0x01, 0x02, 0x03, 0x04, 0x06, 0x08, 0x0f, 0x0d, 0x0a, 0x0b, 0x0c, 0x12, 0x13, 0x14, 0x10][opcode as usize];
+      self.cycle_accumulator += self.spec.basic_cost(instruction).unwrap_or(0);
+
+      let (a, b) = (value_a as u64, value_b as u64);
+      let (result, overflow) = match opcode {
+         0x1 => (value_b, None),
+         0x2 => ((a + b) as u16, Some(((a + b) >> 16) as u16)),
+         0x3 => (value_a.wrapping_sub(value_b), Some(if value_b > value_a {0xffff} else {0})),
+         0x4 => ((a * b) as u16, Some(((a * b) >> 16) as u16)),
+         0x5 => if b == 0 {(0, Some(0))} else {((a / b) as u16, Some(((a << 16) / b) as u16))},
+         0x6 => (if b == 0 {0} else {value_a % value_b}, None),
+         0x7 => ((a << b.min(32)) as u16, Some(((a << b.min(32)) >> 16) as u16)),
+         0x8 => ((a >> b.min(32)) as u16, Some(((a << 16) >> b.min(48)) as u16)),
+         0x9 => (value_a & value_b, None),
+         0xa => (value_a | value_b, None),
+         0xb => (value_a ^ value_b, None),
+
+         // ife, ifn, ifg and ifb skip just the next instruction when they fail
+         _ => {
+            let passed = match opcode {
+               0xc => value_a == value_b,
+               0xd => value_a != value_b,
+               0xe => value_a > value_b,
+               _ => value_a & value_b != 0,
+            };
+
+            if !passed {
+               self.cycle_accumulator += 1;
+               let next = self.memory[self.program_counter as usize];
+               self.program_counter = self.program_counter.wrapping_add(self.spec.instruction_length(next));
+            }
+
+            return (value_a, Some(value_b));
+         },
+      };
+
+      match (address_a, operand_a) {
+         (Some(address), _) => self.write_memory(address, result),
+         (None, 0x00...0x07) => self.registers[operand_a as usize] = result,
+         (None, 0x1b) => self.stack_pointer = result,
+         (None, 0x1c) => self.program_counter = result,
+         (None, 0x1d) => self.excess = result,
+         _ => self.write_literal(operand_a),
+      }
+
+      if let Some(overflow) = overflow {
+         self.excess = overflow;
+      }
+
+      (value_a, Some(value_b))
+   }
+
+
+   // Read an operand in the 1.1 encoding, returning its value and the address of
+   // the memory it refers to, if any. Next words cost a cycle each.
+   fn get_operand_1_1(&mut self, operand: u16) -> (u16, Option<u16>) {
+      let address = match operand {
+         0x08...0x0f => self.get_address(operand, 0),
+         0x10...0x17 | 0x1e => {
+            let next_word = self.memory[self.program_counter as usize];
+            self.program_counter = self.program_counter.wrapping_add(1);
+            self.cycle_accumulator += 1;
+            self.get_address(operand, next_word)
+         },
+         0x18 => { // pop
+            let stack_pointer = self.stack_pointer;
+            self.stack_pointer = self.stack_pointer.wrapping_add(1);
+            Some(stack_pointer)
+         },
+         0x19 => Some(self.stack_pointer), // peek
+         0x1a => { // push
+            self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+            Some(self.stack_pointer)
+         },
+         _ => None,
+      };
+
+      let value = match (address, operand) {
+         (Some(address), _) => self.read_memory(Access::Read, address),
+         (None, 0x00...0x07) => self.registers[operand as usize],
+         (None, 0x1b) => self.stack_pointer,
+         (None, 0x1c) => self.program_counter,
+         (None, 0x1d) => self.excess,
+         (None, 0x1f) => {
+            let next_word = self.memory[self.program_counter as usize];
+            self.program_counter = self.program_counter.wrapping_add(1);
+            self.cycle_accumulator += 1;
+            next_word
+         },
+         (None, _) => operand - 0x20,
+      };
+
+      (value, address)
+   }
+
+
+   // A, B, C, X, Y, Z, I, J, SP, PC and EX, as a trace wants them
+   fn trace_registers(&self) -> [u16; 11] {
+      let mut registers = [0; 11];
+      registers[..8].copy_from_slice(&self.registers);
+      registers[8] = self.stack_pointer;
+      registers[9] = self.program_counter;
+      registers[10] = self.excess;
+      registers
    }
 
 
    pub fn set_value(&mut self, operand: u16, value: u16) {
-      if let Some(pointer) = self.get_pointer(operand) {
+      let next_word: u16 = self.memory[self.program_counter.wrapping_sub(1) as usize];
+
+      if let Some(address) = self.get_address(operand, next_word) {
+         self.write_memory(address, value);
+      } else if let Some(pointer) = self.get_pointer(operand) {
          *pointer = value;
+      } else {
+         self.write_literal(operand);
+      }
+   }
+
+
+   // Lose a write to a literal, as the spec says, unless operands are strict
+   fn write_literal(&mut self, operand: u16) {
+      if self.strict_operands {
+         self.raise(FaultKind::InvalidOperand(operand));
+      }
+   }
+
+
+   // Read a word of memory, letting any watchpoints and hooks see the access
+   fn read_memory(&mut self, access: Access, address: u16) -> u16 {
+      let value = self.memory[address as usize];
+
+      // Keep the common case of nothing watching memory as quick as possible
+      if self.watchpoints.is_empty() && self.memory_hooks.is_empty() {
+         return value;
+      }
+
+      self.watch(access, address, value);
+
+      let mut value = value;
+      for &mut (start, end, ref mut hook) in &mut self.memory_hooks {
+         if address >= start && address <= end {
+            match access {
+               Access::Read => value = hook.read(address, value),
+               Access::Execute => hook.execute(address),
+               Access::Write => (),
+            }
+         }
+      }
+
+      value
+   }
+
+
+   // Write a word of memory, letting any watchpoints and hooks see the access
+   fn write_memory(&mut self, address: u16, value: u16) {
+      if let Some(ref mut journal) = self.journal {
+         journal.push((address, self.memory[address as usize]));
+      }
+
+      self.memory[address as usize] = value;
+
+      if self.watchpoints.is_empty() && self.memory_hooks.is_empty() {
+         return;
+      }
+
+      self.watch(Access::Write, address, value);
+
+      for &mut (start, end, ref mut hook) in &mut self.memory_hooks {
+         if address >= start && address <= end {
+            hook.write(address, value);
+         }
+      }
+   }
+
+
+   // Record the first watchpoint the access triggers, if nothing has been recorded yet
+   fn watch(&mut self, access: Access, address: u16, value: u16) {
+      if self.watchpoint_hit.is_some() {
+         return;
+      }
+
+      if let Some(&watchpoint) = self.watchpoints.iter().find(|watchpoint| watchpoint.matches(access, address)) {
+         self.watchpoint_hit = Some(WatchpointHit {
+            watchpoint: watchpoint,
+            access: access,
+            address: address,
+            value: value,
+         });
       }
    }
 
 
-   // Get a pointer to the location represented by the given operand
+   // Get the memory address the given operand refers to, if it refers to memory
+   fn get_address(&self, operand: u16, next_word: u16) -> Option<u16> {
+      match operand {
+         0x08...0x0f => Some(self.registers[(operand - 0x08) as usize]),
+         0x10...0x17 => Some(self.registers[(operand - 0x10) as usize].wrapping_add(next_word)),
+         0x18 | 0x19 => Some(self.stack_pointer),
+         0x1a => Some(self.stack_pointer.wrapping_add(next_word)),
+         0x1e => Some(next_word),
+         _ => None,
+      }
+   }
+
+
+   // Get a pointer to the location represented by the given operand, if it isn't a literal
    fn get_pointer(&mut self, operand: u16) -> Option<&mut u16> {
       // The program counter has already been incremented past the "next word"
       // and is pointing to the next instruction. Therefore, to get the next word
@@ -333,32 +1000,238 @@ impl Dcpu {
          0x1c => Some(&mut self.program_counter),
          0x1d => Some(&mut self.excess),
          0x1e => Some(&mut self.memory[next_word as usize]),
-         _ => {
-            println!("Warning: Tried to get the value of a non-value operand: {}", operand);
-            None
-         },
+         _ => None,
       }
    }
 
 
 
    // Get the value of the given operand, incrementing the cycle_accumulator and program_counter as necessary
-   fn get_value(&self, operand: u16) -> u16 {
+   fn get_value(&mut self, operand: u16) -> u16 {
       let next_word: u16 = self.memory[self.program_counter as usize];
 
+      if let Some(address) = self.get_address(operand, next_word) {
+         return self.read_memory(Access::Read, address);
+      }
+
       match operand {
          0x00...0x07 => self.registers[operand as usize],
-         0x08...0x0f => self.memory[self.registers[(operand - 0x08) as usize] as usize],
-         0x10...0x17 => self.memory[self.registers[(operand - 0x10) as usize].wrapping_add(next_word) as usize],
-         0x18 | 0x19 => self.memory[self.stack_pointer as usize],
-         0x1a => self.memory[self.stack_pointer.wrapping_add(next_word) as usize],
          0x1b => self.stack_pointer,
          0x1c => self.program_counter,
          0x1d => self.excess,
-         0x1e => self.memory[next_word as usize],
          0x1f => next_word,
-         0x20...0x3f => operand.wrapping_sub(0x21),
-         _ => panic!("Invalid operand! This probably shouldn't have happened.")
+         // Operands are only six bits, so everything else is a literal from 0x20 to 0x3f
+         _ => operand.wrapping_sub(0x21),
       }
    }
-}
\ No newline at end of file
+}
+
+
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::sync::{Arc, Mutex};
+
+   // Remembers what was written to it and reads back as 0x1234
+   struct Register {
+      written: Arc<Mutex<Vec<(u16, u16)>>>,
+   }
+
+   impl MemoryHook for Register {
+      fn read(&mut self, _address: u16, _value: u16) -> u16 {
+         0x1234
+      }
+
+      fn write(&mut self, address: u16, value: u16) {
+         self.written.lock().unwrap().push((address, value));
+      }
+   }
+
+   fn run(dcpu: &mut Dcpu, program: &[u16]) {
+      dcpu.memory[..program.len()].copy_from_slice(program);
+      while (dcpu.program_counter as usize) < program.len() {
+         dcpu.step();
+      }
+   }
+
+   #[test]
+   fn watch_writes() {
+      let mut dcpu = Dcpu::new();
+      dcpu.watchpoints.push(Watchpoint {start: 0x8000, end: 0x817f, read: false, write: true, execute: false});
+
+      // set a, [0x8000]  then  set [0x8001], 5
+      run(&mut dcpu, &[0x7801, 0x8000, 0x9bc1, 0x8001]);
+
+      let hit = dcpu.watchpoint_hit.expect("The write should have been seen");
+      assert_eq!((hit.access, hit.address, hit.value), (Access::Write, 0x8001, 5));
+   }
+
+   #[test]
+   fn save_and_restore() {
+      let mut dcpu = Dcpu::new();
+      dcpu.registers[J] = 0xbeef;
+      dcpu.program_counter = 0x1234;
+      dcpu.cycle_count = 0x0001_0002_0003;
+      dcpu.interrupt_queue = vec![3, 4];
+      dcpu.hardware_interrupt = Some(HardwareInstruction::Interrupt(2));
+      dcpu.memory[0xffff] = 0x5555;
+      dcpu.spec = Spec::V2_0;
+
+      let mut state = SaveState::new();
+      dcpu.save(&mut state);
+
+      let mut restored = Dcpu::new();
+      restored.restore(&state).unwrap();
+      assert_eq!(restored.registers[J], 0xbeef);
+      assert_eq!(restored.program_counter, 0x1234);
+      assert_eq!(restored.cycle_count, 0x0001_0002_0003);
+      assert_eq!(restored.interrupt_queue, vec![3, 4]);
+      assert!(match restored.hardware_interrupt {Some(HardwareInstruction::Interrupt(2)) => true, _ => false});
+      assert_eq!(restored.memory[0xffff], 0x5555);
+      assert_eq!(restored.spec, Spec::V2_0);
+   }
+
+   #[test]
+   fn restore_32_bit_cycle_count() {
+      let mut state = SaveState::new();
+      Dcpu::new().save(&mut state);
+      state.put("cycles", vec![0, 0, 0x0001, 0x0002]);
+
+      let mut restored = Dcpu::new();
+      restored.restore(&state).unwrap();
+      assert_eq!(restored.cycle_count, 0x0001_0002);
+   }
+
+   #[test]
+   fn memory_mapped_register() {
+      let written = Arc::new(Mutex::new(vec![]));
+      let mut dcpu = Dcpu::new();
+      dcpu.hook_memory(0x9000, 0x9000, Box::new(Register {written: written.clone()}));
+
+      // set [0x9000], 7  then  set b, [0x9000]
+      run(&mut dcpu, &[0xa3c1, 0x9000, 0x7821, 0x9000]);
+
+      assert_eq!(*written.lock().unwrap(), vec![(0x9000, 7)]);
+      assert_eq!(dcpu.registers[B], 0x1234);
+      assert_eq!(dcpu.memory[0x9000], 7);
+   }
+
+   #[test]
+   fn faults() {
+      let run_with = |policy: FaultPolicy, program: &[u16]| {
+         let mut dcpu = Dcpu::new();
+         dcpu.fault_policy = policy;
+         dcpu.memory[..program.len()].copy_from_slice(program);
+         for _ in 0..30 {
+            dcpu.step();
+         }
+         dcpu
+      };
+
+      // hcf 3  then  add a, 1
+      let dcpu = run_with(FaultPolicy::Halt, &[0x90e0, 0x8802]);
+      assert_eq!(dcpu.fault, Some(Fault {kind: FaultKind::CaughtFire(3), address: 0, word: 0x90e0}));
+      assert_eq!(dcpu.registers[A], 0);
+
+      // An instruction with the unused opcode 0x18
+      let dcpu = run_with(FaultPolicy::Trap, &[0x8802, 0x0018]);
+      assert_eq!(dcpu.fault.map(|fault| (fault.kind, fault.address)), Some((FaultKind::IllegalInstruction, 1)));
+
+      // set 5, a  then  add a, 1
+      let dcpu = run_with(FaultPolicy::Halt, &[0x03e1, 0x0005, 0x8802, 0x8b83]);
+      assert_eq!(dcpu.fault, None);
+      assert_eq!(dcpu.registers[A], 1);
+
+      let mut dcpu = Dcpu::new();
+      dcpu.strict_operands = true;
+      dcpu.memory[..2].copy_from_slice(&[0x03e1, 0x0005]);
+      for _ in 0..30 {
+         dcpu.step();
+      }
+      assert_eq!(dcpu.fault.map(|fault| fault.kind), Some(FaultKind::InvalidOperand(0x1f)));
+
+      // Ignoring faults carries on to the add
+      let dcpu = run_with(FaultPolicy::Ignore, &[0x0018, 0x90e0, 0x03e1, 0x0005, 0x8802, 0x8b83]);
+      assert_eq!(dcpu.fault, None);
+      assert_eq!(dcpu.registers[A], 1);
+   }
+
+   #[test]
+   fn spec_1_1() {
+      let mut dcpu = Dcpu::new();
+      dcpu.spec = Spec::V1_1;
+      let program = [
+         0x7c01, 0x0030, // set a, 0x30
+         0xc011,         // set b, 16
+         0x0402,         // add a, b
+         0xb017,         // shl b, 12
+         0x7c0d, 0x0040, // ifn a, 0x40
+         0x8421,         // set c, 1
+         0xa1c1,         // set pc, 8
+      ];
+      dcpu.memory[..program.len()].copy_from_slice(&program);
+
+      for _ in 0..30 {
+         dcpu.step();
+      }
+
+      assert_eq!(dcpu.registers[A], 0x40);
+      assert_eq!(dcpu.registers[B], 0);
+      assert_eq!(dcpu.registers[C], 0);
+      assert_eq!(dcpu.excess, 1);
+      assert_eq!(dcpu.program_counter, 8);
+   }
+
+   #[test]
+   fn interrupt_wakes_hlt() {
+      let mut dcpu = Dcpu::new();
+      dcpu.spec = Spec::V2_0;
+      let program = [
+         0x7d40, 0x0005, // ias handler
+         0x86a0,         // hlt 0
+         0x8b83,         // sub pc, 1
+         0x0000,
+         0x0021,         // handler: set b, a
+         0x8560,         //          rfi 0
+      ];
+      dcpu.memory[..program.len()].copy_from_slice(&program);
+
+      for _ in 0..20 {
+         dcpu.step();
+      }
+      assert!(dcpu.sleeping);
+      assert_eq!(dcpu.program_counter, 3);
+
+      dcpu.interrupt_queue.push(0x77);
+      for _ in 0..20 {
+         dcpu.step();
+      }
+      assert!(!dcpu.sleeping);
+      assert!(!dcpu.interrupt_queueing);
+      assert_eq!(dcpu.registers[B], 0x77);
+      assert_eq!(dcpu.program_counter, 3);
+      assert_eq!(dcpu.stack_pointer, 0);
+   }
+
+   #[test]
+   fn log_goes_to_the_sink() {
+      let logged = Arc::new(Mutex::new(vec![]));
+      let sink_logged = logged.clone();
+
+      let mut dcpu = Dcpu::new();
+      dcpu.spec = Spec::V2_0;
+      dcpu.log_sink = Some(Box::new(move |value| sink_logged.lock().unwrap().push(value)));
+      let program = [
+         0x9a60, // log 5
+         0x8260, // log 0xffff
+         0x8b83, // sub pc, 1
+      ];
+      dcpu.memory[..program.len()].copy_from_slice(&program);
+
+      for _ in 0..10 {
+         dcpu.step();
+      }
+      assert_eq!(*logged.lock().unwrap(), vec![5, 0xffff]);
+   }
+}
@@ -0,0 +1,578 @@
+use std;
+use std::io;
+use std::io::prelude::*;
+use std::collections::BTreeSet;
+use dcpu;
+use system::System;
+use symbols::Symbols;
+use disassembler;
+use history::History;
+
+
+const HELP: &'static str = "
+Addresses and values can be labels, hex numbers starting with 0x, or decimal numbers.
+
+	b, break <address>             Stop when the instruction at the address is about to run
+	delete <address>               Remove a breakpoint
+	breakpoints                    List the breakpoints
+	watch <rwx> <start> [end]      Stop after an instruction reads, writes or executes the memory
+	unwatch <start>                Remove the watchpoints starting at an address
+	watchpoints                    List the watchpoints
+	s, step [count]                Run one instruction, or the given number of instructions
+	n, next                        Run one instruction, running a whole subroutine if it is a jsr
+	c, continue                    Run until a breakpoint is reached
+	back [count]                   Undo the last instruction, or the given number of instructions
+	rc, reverse-continue           Run backwards until a breakpoint or write watchpoint is reached
+	r, registers                   Show the registers
+	set <register> <value>         Change a register (a b c x y z i j sp pc ex ia)
+	x, memory <address> [count]    Show memory
+	poke <address> <value>...      Change memory, starting at the address
+	d, disassemble [address] [count]
+	                               Show instructions, by default around PC
+	q, quit                        Stop debugging
+";
+
+
+/// An interactive debugger for a whole system, devices and all
+pub struct Debugger {
+	system: System,
+	symbols: Symbols,
+	breakpoints: BTreeSet<u16>,
+	history: History,
+}
+
+impl Debugger {
+	pub fn new(system: System, symbols: Symbols) -> Debugger {
+		Debugger {
+			system: system,
+			symbols: symbols,
+			breakpoints: BTreeSet::new(),
+			history: History::new(0),
+		}
+	}
+
+
+	/// Remember the last `length` instructions so they can be run backwards
+	pub fn keep_history(&mut self, length: usize) {
+		self.history = History::new(length);
+	}
+
+
+	/// Read commands from standard input until the user quits
+	pub fn run(&mut self) {
+		self.print_location();
+
+		let stdin = io::stdin();
+		loop {
+			print!("(dcpu) ");
+			let _ = io::stdout().flush();
+
+			let mut line = String::new();
+			match stdin.lock().read_line(&mut line) {
+				Ok(0) | Err(_) => break,
+				Ok(_) => (),
+			}
+
+			if !self.execute(line.trim()) {
+				break;
+			}
+		}
+	}
+
+
+	/// Run a single command, returning false if the user asked to quit
+	pub fn execute(&mut self, line: &str) -> bool {
+		let words: Vec<&str> = line.split_whitespace().collect();
+		let arguments = if words.is_empty() {&words[..]} else {&words[1..]};
+
+		let result = match words.get(0).cloned().unwrap_or("") {
+			"" => Ok(()),
+			"b" | "break" => self.add_breakpoint(arguments),
+			"delete" => self.delete_breakpoint(arguments),
+			"breakpoints" => Ok(self.print_breakpoints()),
+			"watch" => self.add_watchpoint(arguments),
+			"unwatch" => self.delete_watchpoint(arguments),
+			"watchpoints" => Ok(self.print_watchpoints()),
+			"s" | "step" => self.step(arguments),
+			"n" | "next" => Ok(self.next()),
+			"c" | "continue" => Ok(self.continue_running()),
+			"back" => self.step_back(arguments),
+			"rc" | "reverse-continue" => Ok(self.reverse_continue()),
+			"r" | "registers" => Ok(self.print_registers()),
+			"set" => self.set_register(arguments),
+			"x" | "memory" => self.print_memory(arguments),
+			"poke" => self.poke(arguments),
+			"d" | "disassemble" => self.print_disassembly(arguments),
+			"h" | "help" => Ok(println!("{}", HELP)),
+			"q" | "quit" => return false,
+			command => Err(format!("Unknown command '{}', try 'help'", command)),
+		};
+
+		if let Err(message) = result {
+			println!("{}", message);
+		}
+
+		true
+	}
+
+
+	// Turn a label or number typed by the user into a value
+	fn resolve(&self, text: Option<&&str>) -> Result<u16, String> {
+		match text {
+			Some(text) => self.symbols.resolve(text).ok_or_else(|| format!("'{}' is not a label or a number", text)),
+			None => Err("Expected an address or value, try 'help'".to_string()),
+		}
+	}
+
+
+	// Write an address along with the label it is in, if there is one
+	fn describe(&self, address: u16) -> String {
+		match self.symbols.nearest(address) {
+			Some((label, 0)) => format!("0x{:04x} <{}>", address, label),
+			Some((label, offset)) => format!("0x{:04x} <{}+{}>", address, label, offset),
+			None => format!("0x{:04x}", address),
+		}
+	}
+
+
+	// Run whole instructions until `done` says to stop or a breakpoint or watchpoint is reached
+	fn run_until<F>(&mut self, mut done: F) where F: FnMut(&dcpu::Dcpu) -> bool {
+		self.system.dcpu.watchpoint_hit = None;
+		self.system.dcpu.break_requested = false;
+
+		// A trapped fault only stops the CPU until it is told to carry on
+		if self.system.dcpu.fault_policy == dcpu::FaultPolicy::Trap {
+			self.system.dcpu.fault = None;
+		}
+
+		loop {
+			let program_counter = self.system.dcpu.program_counter;
+			self.history.step(&mut self.system);
+
+			if let Some(hit) = self.system.dcpu.watchpoint_hit.take() {
+				println!("Watchpoint {}: {:?} of 0x{:04x} at {} by the instruction at {}",
+					describe_watchpoint(&hit.watchpoint), hit.access, hit.value,
+					self.describe(hit.address), self.describe(program_counter));
+				break;
+			}
+
+			if let Some(fault) = self.system.dcpu.fault {
+				println!("{}", fault);
+				break;
+			}
+
+			if self.system.dcpu.break_requested {
+				println!("brk at {}", self.describe(program_counter));
+				break;
+			}
+
+			if done(&self.system.dcpu) {
+				break;
+			}
+
+			let program_counter = self.system.dcpu.program_counter;
+			if self.breakpoints.contains(&program_counter) {
+				println!("Breakpoint at {}", self.describe(program_counter));
+				break;
+			}
+		}
+
+		self.print_location();
+	}
+
+
+	fn add_breakpoint(&mut self, arguments: &[&str]) -> Result<(), String> {
+		let address = self.resolve(arguments.get(0))?;
+		self.breakpoints.insert(address);
+		println!("Breakpoint at {}", self.describe(address));
+		Ok(())
+	}
+
+
+	fn delete_breakpoint(&mut self, arguments: &[&str]) -> Result<(), String> {
+		let address = self.resolve(arguments.get(0))?;
+		if self.breakpoints.remove(&address) {
+			Ok(())
+		} else {
+			Err(format!("There is no breakpoint at {}", self.describe(address)))
+		}
+	}
+
+
+	fn print_breakpoints(&self) {
+		for &address in &self.breakpoints {
+			println!("{}", self.describe(address));
+		}
+	}
+
+
+	fn add_watchpoint(&mut self, arguments: &[&str]) -> Result<(), String> {
+		let kinds = arguments.get(0).cloned().unwrap_or("");
+		if kinds.is_empty() || kinds.chars().any(|c| c != 'r' && c != 'w' && c != 'x') {
+			return Err("Expected some of r, w and x to say which accesses to watch, try 'help'".to_string());
+		}
+
+		let start = self.resolve(arguments.get(1))?;
+		let end = match arguments.get(2) {
+			Some(_) => self.resolve(arguments.get(2))?,
+			None => start,
+		};
+
+		if end < start {
+			return Err("The end of a watchpoint can't come before its start".to_string());
+		}
+
+		let watchpoint = dcpu::Watchpoint {
+			start: start,
+			end: end,
+			read: kinds.contains('r'),
+			write: kinds.contains('w'),
+			execute: kinds.contains('x'),
+		};
+
+		println!("Watchpoint {}", describe_watchpoint(&watchpoint));
+		self.system.dcpu.watchpoints.push(watchpoint);
+		Ok(())
+	}
+
+
+	fn delete_watchpoint(&mut self, arguments: &[&str]) -> Result<(), String> {
+		let start = self.resolve(arguments.get(0))?;
+		let watchpoints = &mut self.system.dcpu.watchpoints;
+		let count = watchpoints.len();
+
+		watchpoints.retain(|watchpoint| watchpoint.start != start);
+		if watchpoints.len() < count {
+			Ok(())
+		} else {
+			Err(format!("There is no watchpoint starting at 0x{:04x}", start))
+		}
+	}
+
+
+	fn print_watchpoints(&self) {
+		for watchpoint in &self.system.dcpu.watchpoints {
+			println!("{}", describe_watchpoint(watchpoint));
+		}
+	}
+
+
+	fn step(&mut self, arguments: &[&str]) -> Result<(), String> {
+		let mut count = match arguments.get(0) {
+			Some(_) => self.resolve(arguments.get(0))?,
+			None => 1,
+		};
+
+		self.run_until(|_| {
+			count = count.saturating_sub(1);
+			count == 0
+		});
+		Ok(())
+	}
+
+
+	// Step over subroutine calls, stopping once the call returns to the next instruction
+	fn next(&mut self) {
+		let dcpu = &self.system.dcpu;
+		let word = dcpu.memory[dcpu.program_counter as usize];
+		let is_jsr = word & 0x1f == 0 && (word >> 5) & 0x1f == 0x01;
+
+		if is_jsr {
+			let return_address = dcpu.program_counter.wrapping_add(disassembler::instruction_length(word));
+			let stack_pointer = dcpu.stack_pointer;
+			self.run_until(|dcpu| dcpu.program_counter == return_address && dcpu.stack_pointer == stack_pointer);
+		} else {
+			self.run_until(|_| true);
+		}
+	}
+
+
+	fn continue_running(&mut self) {
+		self.run_until(|_| false);
+	}
+
+
+	fn step_back(&mut self, arguments: &[&str]) -> Result<(), String> {
+		let count = match arguments.get(0) {
+			Some(_) => self.resolve(arguments.get(0))?,
+			None => 1,
+		};
+
+		let undone = self.history.go_back(&mut self.system.dcpu, count as usize);
+		if undone < count as usize {
+			println!("Only {} instructions were remembered", undone);
+		}
+
+		self.print_location();
+		Ok(())
+	}
+
+
+	// Undo instructions until one at a breakpoint is reached, or one writes to a watched address
+	fn reverse_continue(&mut self) {
+		loop {
+			let written = match self.history.step_back(&mut self.system.dcpu) {
+				Some(written) => written,
+				None => {
+					println!("Reached the start of the history");
+					break;
+				},
+			};
+
+			let program_counter = self.system.dcpu.program_counter;
+			if self.breakpoints.contains(&program_counter) {
+				println!("Breakpoint at {}", self.describe(program_counter));
+				break;
+			}
+
+			let watched = self.system.dcpu.watchpoints.iter()
+				.find(|watchpoint| watchpoint.write && written.iter().any(|&address| address >= watchpoint.start && address <= watchpoint.end));
+
+			if let Some(watchpoint) = watched {
+				println!("Watchpoint {}: written by the instruction at {}", describe_watchpoint(watchpoint), self.describe(program_counter));
+				break;
+			}
+		}
+
+		self.print_location();
+	}
+
+
+	fn print_location(&self) {
+		let dcpu = &self.system.dcpu;
+		let (text, _) = disassembler::disassemble(&dcpu.memory, dcpu.program_counter, Some(&self.symbols));
+		println!("{}: {}", self.describe(dcpu.program_counter), text);
+	}
+
+
+	fn print_registers(&self) {
+		let dcpu = &self.system.dcpu;
+		let names = ["A", "B", "C", "X", "Y", "Z", "I", "J"];
+		let registers: Vec<String> = names.iter().zip(dcpu.registers.iter())
+			.map(|(name, value)| format!("{} {:04x}", name, value))
+			.collect();
+
+		println!("{}", registers.join("  "));
+		println!("SP {:04x}  PC {:04x}  EX {:04x}  IA {:04x}  cycles {}",
+			dcpu.stack_pointer, dcpu.program_counter, dcpu.excess, dcpu.interrupt_address, dcpu.cycle_count);
+
+		if let Some(fault) = dcpu.fault {
+			println!("{}", fault);
+		}
+	}
+
+
+	fn set_register(&mut self, arguments: &[&str]) -> Result<(), String> {
+		let value = self.resolve(arguments.get(1))?;
+		let dcpu = &mut self.system.dcpu;
+
+		let register = match arguments.get(0).map(|name| name.to_lowercase()) {
+			Some(ref name) if name == "a" => &mut dcpu.registers[dcpu::A],
+			Some(ref name) if name == "b" => &mut dcpu.registers[dcpu::B],
+			Some(ref name) if name == "c" => &mut dcpu.registers[dcpu::C],
+			Some(ref name) if name == "x" => &mut dcpu.registers[dcpu::X],
+			Some(ref name) if name == "y" => &mut dcpu.registers[dcpu::Y],
+			Some(ref name) if name == "z" => &mut dcpu.registers[dcpu::Z],
+			Some(ref name) if name == "i" => &mut dcpu.registers[dcpu::I],
+			Some(ref name) if name == "j" => &mut dcpu.registers[dcpu::J],
+			Some(ref name) if name == "sp" => &mut dcpu.stack_pointer,
+			Some(ref name) if name == "pc" => &mut dcpu.program_counter,
+			Some(ref name) if name == "ex" => &mut dcpu.excess,
+			Some(ref name) if name == "ia" => &mut dcpu.interrupt_address,
+			_ => return Err("Expected a register name, try 'help'".to_string()),
+		};
+
+		*register = value;
+		Ok(())
+	}
+
+
+	fn print_memory(&self, arguments: &[&str]) -> Result<(), String> {
+		let start = self.resolve(arguments.get(0))?;
+		let count = match arguments.get(1) {
+			Some(_) => self.resolve(arguments.get(1))?,
+			None => 16,
+		};
+
+		let memory = &self.system.dcpu.memory;
+		for row in 0..(count as u32 + 7) / 8 {
+			let address = start.wrapping_add((row * 8) as u16);
+			let words: Vec<String> = (0..std::cmp::min(8, count as u32 - row * 8))
+				.map(|i| format!("{:04x}", memory[address.wrapping_add(i as u16) as usize]))
+				.collect();
+			println!("{:04x}: {}", address, words.join(" "));
+		}
+
+		Ok(())
+	}
+
+
+	fn poke(&mut self, arguments: &[&str]) -> Result<(), String> {
+		let start = self.resolve(arguments.get(0))?;
+		if arguments.len() < 2 {
+			return Err("Expected values to write, try 'help'".to_string());
+		}
+
+		for (offset, argument) in arguments[1..].iter().enumerate() {
+			let value = self.resolve(Some(argument))?;
+			self.system.dcpu.memory[start.wrapping_add(offset as u16) as usize] = value;
+		}
+
+		Ok(())
+	}
+
+
+	fn print_disassembly(&self, arguments: &[&str]) -> Result<(), String> {
+		let dcpu = &self.system.dcpu;
+		let mut address = match arguments.get(0) {
+			Some(_) => self.resolve(arguments.get(0))?,
+			None => instructions_before(&dcpu.memory, dcpu.program_counter, 3),
+		};
+		let count = match arguments.get(1) {
+			Some(_) => self.resolve(arguments.get(1))?,
+			None => 10,
+		};
+
+		for _ in 0..count {
+			if let Some(label) = self.symbols.label(address) {
+				println!("{}:", label);
+			}
+
+			let (text, length) = disassembler::disassemble(&dcpu.memory, address, Some(&self.symbols));
+			let marker = if address == dcpu.program_counter {"=>"} else {"  "};
+			println!("{} {:04x}: {}", marker, address, text);
+			address = address.wrapping_add(length);
+		}
+
+		Ok(())
+	}
+}
+
+
+// Write out the range and kinds of access a watchpoint watches, like `0x8000-0x817f rw`
+fn describe_watchpoint(watchpoint: &dcpu::Watchpoint) -> String {
+	format!("0x{:04x}-0x{:04x} {}{}{}", watchpoint.start, watchpoint.end,
+		if watchpoint.read {"r"} else {""},
+		if watchpoint.write {"w"} else {""},
+		if watchpoint.execute {"x"} else {""})
+}
+
+
+// Find an address up to `count` instructions before `address` that disassembles
+// cleanly into it. Instructions can't be decoded backwards, so try each starting
+// point that could be `count` instructions back and take the furthest that works.
+fn instructions_before(memory: &[u16], address: u16, count: u16) -> u16 {
+	for back in (1..count * 3 + 1).rev() {
+		let start = address.wrapping_sub(back);
+		let mut current = start;
+		let mut instructions = 0;
+
+		while current != address && address.wrapping_sub(current) <= back && instructions < count {
+			current = current.wrapping_add(disassembler::instruction_length(memory[current as usize]));
+			instructions += 1;
+		}
+
+		if current == address {
+			return start;
+		}
+	}
+
+	address
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use test_support::machine;
+
+	// A program that calls a subroutine which sets A, then loops forever
+	fn debugger() -> Debugger {
+		let mut system = machine(&[
+			0x7c20, 0x0004, // jsr 4
+			0x8c01,         // set a, 2
+			0x8b83,         // sub pc, 1
+			0x8801,         // set a, 1
+			0x6381,         // set pc, pop
+		], vec![]);
+		system.dcpu.stack_pointer = 0;
+
+		let mut symbols = Symbols::new();
+		symbols.insert("subroutine", 4);
+		Debugger::new(system, symbols)
+	}
+
+	#[test]
+	fn step_over_subroutine() {
+		let mut debugger = debugger();
+
+		debugger.execute("next");
+		assert_eq!(debugger.system.dcpu.program_counter, 2);
+		assert_eq!(debugger.system.dcpu.registers[dcpu::A], 1);
+		assert_eq!(debugger.system.dcpu.stack_pointer, 0);
+	}
+
+	#[test]
+	fn break_on_label() {
+		let mut debugger = debugger();
+
+		debugger.execute("break subroutine");
+		debugger.execute("continue");
+		assert_eq!(debugger.system.dcpu.program_counter, 4);
+
+		debugger.execute("step 2");
+		assert_eq!(debugger.system.dcpu.program_counter, 2);
+	}
+
+	#[test]
+	fn edit_registers_and_memory() {
+		let mut debugger = debugger();
+
+		debugger.execute("set x 0x1234");
+		debugger.execute("set pc subroutine");
+		debugger.execute("poke 0x100 1 2 0x3");
+		assert_eq!(debugger.system.dcpu.registers[dcpu::X], 0x1234);
+		assert_eq!(debugger.system.dcpu.program_counter, 4);
+		assert_eq!(&debugger.system.dcpu.memory[0x100..0x103], &[1, 2, 3]);
+	}
+
+	#[test]
+	fn stop_on_write() {
+		let mut debugger = debugger();
+
+		debugger.execute("watch w 0xfffe 0xffff");
+		debugger.execute("continue");
+		assert_eq!(debugger.system.dcpu.program_counter, 4);
+
+		debugger.execute("unwatch 0xfffe");
+		assert!(debugger.system.dcpu.watchpoints.is_empty());
+	}
+
+	#[test]
+	fn run_backwards() {
+		let mut debugger = debugger();
+		debugger.keep_history(100);
+
+		debugger.execute("break 0");
+		debugger.execute("step 4");
+		assert_eq!(debugger.system.dcpu.program_counter, 3);
+
+		debugger.execute("back 2");
+		assert_eq!(debugger.system.dcpu.program_counter, 5);
+		assert_eq!(debugger.system.dcpu.registers[dcpu::A], 1);
+
+		debugger.execute("reverse-continue");
+		assert_eq!(debugger.system.dcpu.program_counter, 0);
+		assert_eq!(debugger.system.dcpu.registers[dcpu::A], 0);
+		assert_eq!(debugger.system.dcpu.memory[0xffff], 0);
+	}
+
+	#[test]
+	fn disassemble_around_program_counter() {
+		let debugger = debugger();
+		assert_eq!(instructions_before(&debugger.system.dcpu.memory, 5, 3), 2);
+		assert_eq!(instructions_before(&debugger.system.dcpu.memory, 2, 1), 0);
+	}
+}
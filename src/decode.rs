@@ -0,0 +1,448 @@
+use dcpu::Dcpu;
+use spec::Spec;
+
+
+// An operand with its next word already read. Pop and push are told apart
+// because 0x18 means pop as a and push as b.
+#[derive(Copy, Clone)]
+enum Operand {
+	Register(u8),
+	Indirect(u8),
+	Offset(u8, u16),
+	Pop,
+	Push,
+	Peek,
+	Pick(u16),
+	StackPointer,
+	ProgramCounter,
+	Excess,
+	Address(u16),
+	Literal(u16),
+}
+
+impl Operand {
+	fn decode(operand: u16, next_word: u16, is_a: bool) -> Operand {
+		match operand {
+			0x00...0x07 => Operand::Register(operand as u8),
+			0x08...0x0f => Operand::Indirect(operand as u8 - 0x08),
+			0x10...0x17 => Operand::Offset(operand as u8 - 0x10, next_word),
+			0x18 if is_a => Operand::Pop,
+			0x18 => Operand::Push,
+			0x19 => Operand::Peek,
+			0x1a => Operand::Pick(next_word),
+			0x1b => Operand::StackPointer,
+			0x1c => Operand::ProgramCounter,
+			0x1d => Operand::Excess,
+			0x1e => Operand::Address(next_word),
+			0x1f => Operand::Literal(next_word),
+			_ => Operand::Literal(operand.wrapping_sub(0x21)),
+		}
+	}
+}
+
+
+// Where an operand's value is, once its registers and the stack pointer are known
+#[derive(Copy, Clone)]
+enum Location {
+	Register(usize),
+	Memory(u16),
+	StackPointer,
+	ProgramCounter,
+	Excess,
+	Literal(u16),
+}
+
+
+/// An instruction decoded ahead of time, along with the words it was decoded
+/// from and the handler that runs it
+#[derive(Copy, Clone)]
+pub struct Decoded {
+	words: [u16; 3],
+	length: u16,
+	a_length: u16,
+	cost: u32,
+	a: Operand,
+	b: Operand,
+	// None for instructions left to `Dcpu::step`
+	handler: Option<fn(&mut Dcpu, &Decoded)>,
+}
+
+impl Decoded {
+	const EMPTY: Decoded = Decoded {
+		words: [0; 3],
+		length: 0,
+		a_length: 0,
+		cost: 0,
+		a: Operand::Register(0),
+		b: Operand::Register(0),
+		handler: None,
+	};
+
+
+	/// Decode the instruction at the address. Only basic instructions get a
+	/// handler. Special instructions, illegal ones and writes to literals are
+	/// left to `Dcpu::step`, which knows how to fault.
+	pub fn new(memory: &[u16], spec: Spec, address: u16) -> Decoded {
+		let word = memory[address as usize];
+		let next = |offset: u16| memory[address.wrapping_add(offset) as usize];
+		let (opcode, operand_b, operand_a) = (word & 0x1f, (word >> 5) & 0x1f, word >> 10);
+
+		let length = spec.instruction_length(word);
+		let a_length = if spec.uses_next_word(operand_a) {1} else {0};
+		let a = Operand::decode(operand_a, next(1), true);
+		let b = Operand::decode(operand_b, next(1 + a_length), false);
+
+		let handler: Option<fn(&mut Dcpu, &Decoded)> = match opcode {
+			0x00 => None,
+			0x10...0x17 => Some(branch),
+			_ if operand_b == 0x1f => None,
+			0x01 => Some(set),
+			0x02 => Some(add),
+			0x03 => Some(sub),
+			0x04 => Some(mul),
+			0x05 => Some(mli),
+			0x06 => Some(div),
+			0x07 => Some(dvi),
+			0x08 => Some(modulo),
+			0x09 => Some(mdi),
+			0x0a => Some(and),
+			0x0b => Some(bor),
+			0x0c => Some(xor),
+			0x0d => Some(shr),
+			0x0e => Some(asr),
+			0x0f => Some(shl),
+			0x1a => Some(adx),
+			0x1b => Some(sbx),
+			0x1e | 0x1f => Some(copy),
+			_ => None,
+		};
+
+		let mut words = [0; 3];
+		for (offset, word) in words.iter_mut().enumerate().take(length as usize) {
+			*word = next(offset as u16);
+		}
+
+		Decoded {
+			words: words,
+			length: length,
+			a_length: a_length,
+			cost: spec.basic_cost(opcode).unwrap_or(0) + length as u32 - 1,
+			a: a,
+			b: b,
+			handler: handler,
+		}
+	}
+
+
+	// Whether memory still holds the words this was decoded from
+	fn matches(&self, memory: &[u16], address: u16) -> bool {
+		self.length > 0 && memory[address as usize] == self.words[0]
+			&& (1..self.length).all(|offset| memory[address.wrapping_add(offset) as usize] == self.words[offset as usize])
+	}
+
+
+	/// Run the instruction at the program counter, returning false if it has to be
+	/// left to `Dcpu::step`. Its cycles go straight onto the cycle count.
+	pub fn run(&self, dcpu: &mut Dcpu) -> bool {
+		match self.handler {
+			Some(handler) => {
+				handler(dcpu, self);
+				true
+			},
+			None => false,
+		}
+	}
+}
+
+
+/// Every address's decoded instruction. Rather than every write having to find
+/// the instructions it changes, each one keeps the words it was decoded from
+/// and is decoded again once they no longer match. That also catches devices
+/// and debuggers writing to `Dcpu::memory` directly.
+pub struct Cache {
+	entries: Vec<Decoded>,
+}
+
+impl Cache {
+	pub fn new() -> Cache {
+		Cache {
+			entries: vec![],
+		}
+	}
+
+
+	/// The instruction at the address, decoded again if memory has changed under it
+	pub fn get(&mut self, memory: &[u16], spec: Spec, address: u16) -> Decoded {
+		// Only allocate for CPUs that use the cache
+		if self.entries.is_empty() {
+			self.entries = vec![Decoded::EMPTY; 0x10000];
+		}
+
+		let entry = &mut self.entries[address as usize];
+		if !entry.matches(memory, address) {
+			*entry = Decoded::new(memory, spec, address);
+		}
+		*entry
+	}
+}
+
+
+
+
+// Work out where an operand is, pushing or popping as it says
+fn locate(dcpu: &mut Dcpu, operand: Operand) -> Location {
+	match operand {
+		Operand::Register(index) => Location::Register(index as usize),
+		Operand::Indirect(index) => Location::Memory(dcpu.registers[index as usize]),
+		Operand::Offset(index, offset) => Location::Memory(dcpu.registers[index as usize].wrapping_add(offset)),
+		Operand::Pop => {
+			let stack_pointer = dcpu.stack_pointer;
+			dcpu.stack_pointer = stack_pointer.wrapping_add(1);
+			Location::Memory(stack_pointer)
+		},
+		Operand::Push => {
+			dcpu.stack_pointer = dcpu.stack_pointer.wrapping_sub(1);
+			Location::Memory(dcpu.stack_pointer)
+		},
+		Operand::Peek => Location::Memory(dcpu.stack_pointer),
+		Operand::Pick(offset) => Location::Memory(dcpu.stack_pointer.wrapping_add(offset)),
+		Operand::StackPointer => Location::StackPointer,
+		Operand::ProgramCounter => Location::ProgramCounter,
+		Operand::Excess => Location::Excess,
+		Operand::Address(address) => Location::Memory(address),
+		Operand::Literal(value) => Location::Literal(value),
+	}
+}
+
+
+fn read(dcpu: &Dcpu, location: Location) -> u16 {
+	match location {
+		Location::Register(index) => dcpu.registers[index],
+		Location::Memory(address) => dcpu.memory[address as usize],
+		Location::StackPointer => dcpu.stack_pointer,
+		Location::ProgramCounter => dcpu.program_counter,
+		Location::Excess => dcpu.excess,
+		Location::Literal(value) => value,
+	}
+}
+
+
+fn write(dcpu: &mut Dcpu, location: Location, value: u16) {
+	match location {
+		Location::Register(index) => dcpu.registers[index] = value,
+		Location::Memory(address) => dcpu.memory[address as usize] = value,
+		Location::StackPointer => dcpu.stack_pointer = value,
+		Location::ProgramCounter => dcpu.program_counter = value,
+		Location::Excess => dcpu.excess = value,
+		// Instructions that write to a literal aren't given a handler
+		Location::Literal(_) => (),
+	}
+}
+
+
+// Do everything a basic instruction does before its operation: read a, then b,
+// moving the program counter along as `Dcpu::step` does so that reading PC
+// gives the same value, and pay for the instruction. Returns b's location and
+// value, and a's value.
+fn operands(dcpu: &mut Dcpu, instruction: &Decoded) -> (Location, u16, u16) {
+	let address = dcpu.program_counter;
+
+	dcpu.program_counter = address.wrapping_add(1);
+	let a = locate(dcpu, instruction.a);
+	let value_a = read(dcpu, a);
+
+	dcpu.program_counter = address.wrapping_add(1 + instruction.a_length);
+	let b = locate(dcpu, instruction.b);
+	let value_b = read(dcpu, b);
+
+	dcpu.program_counter = address.wrapping_add(instruction.length);
+	dcpu.cycle_count += instruction.cost as u64;
+	(b, value_b, value_a)
+}
+
+
+fn set(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, _, a) = operands(dcpu, instruction);
+	write(dcpu, b, a);
+}
+
+fn add(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	let sum = value_b as u32 + a as u32;
+	write(dcpu, b, sum as u16);
+	dcpu.excess = (sum >> 16) as u16;
+}
+
+fn sub(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	write(dcpu, b, value_b.wrapping_sub(a));
+	dcpu.excess = if a > value_b {0xffff} else {0};
+}
+
+fn mul(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	let product = value_b as u32 * a as u32;
+	write(dcpu, b, product as u16);
+	dcpu.excess = (product >> 16) as u16;
+}
+
+fn mli(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	let product = value_b as i16 as i64 * a as i16 as i64;
+	write(dcpu, b, product as u16);
+	dcpu.excess = (product >> 16) as u16;
+}
+
+fn div(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	if a == 0 {
+		write(dcpu, b, 0);
+		dcpu.excess = 0;
+	} else {
+		write(dcpu, b, value_b / a);
+		dcpu.excess = (((value_b as u64) << 16) / a as u64) as u16;
+	}
+}
+
+fn dvi(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	if a == 0 {
+		write(dcpu, b, 0);
+		dcpu.excess = 0;
+	} else {
+		write(dcpu, b, (value_b as i16).wrapping_div(a as i16) as u16);
+		dcpu.excess = (((value_b as i16 as i64) << 16) / a as i16 as i64) as u16;
+	}
+}
+
+fn modulo(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	write(dcpu, b, if a == 0 {0} else {value_b % a});
+}
+
+fn mdi(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	write(dcpu, b, if a == 0 {0} else {(value_b as i16).wrapping_rem(a as i16) as u16});
+}
+
+fn and(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	write(dcpu, b, value_b & a);
+}
+
+fn bor(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	write(dcpu, b, value_b | a);
+}
+
+fn xor(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	write(dcpu, b, value_b ^ a);
+}
+
+fn shr(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	let shift = (a as u32).min(48);
+	write(dcpu, b, ((value_b as u64) >> shift) as u16);
+	dcpu.excess = (((value_b as u64) << 16) >> shift) as u16;
+}
+
+fn asr(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	let shift = (a as u32).min(63);
+	write(dcpu, b, ((value_b as i16 as i64) >> shift) as u16);
+	dcpu.excess = (((value_b as i16 as i64) << 16) >> shift) as u16;
+}
+
+fn shl(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, value_b, a) = operands(dcpu, instruction);
+	let shifted = (value_b as u64) << (a as u32).min(48);
+	write(dcpu, b, shifted as u16);
+	dcpu.excess = (shifted >> 16) as u16;
+}
+
+fn adx(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let excess = dcpu.excess;
+	let (b, value_b, a) = operands(dcpu, instruction);
+	let sum = value_b as u32 + a as u32 + excess as u32;
+	write(dcpu, b, sum as u16);
+	dcpu.excess = if sum > 0xffff {1} else {0};
+}
+
+fn sbx(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let excess = dcpu.excess;
+	let (b, value_b, a) = operands(dcpu, instruction);
+	let difference = value_b as i32 - a as i32 + excess as i16 as i32;
+	write(dcpu, b, difference as u16);
+	dcpu.excess = if difference < 0 {0xffff} else if difference > 0xffff {1} else {0};
+}
+
+// sti and std
+fn copy(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (b, _, a) = operands(dcpu, instruction);
+	write(dcpu, b, a);
+
+	let step = if instruction.words[0] & 0x1f == 0x1e {1} else {0xffff};
+	dcpu.registers[6] = dcpu.registers[6].wrapping_add(step);
+	dcpu.registers[7] = dcpu.registers[7].wrapping_add(step);
+}
+
+fn branch(dcpu: &mut Dcpu, instruction: &Decoded) {
+	let (_, value_b, a) = operands(dcpu, instruction);
+	let passed = match instruction.words[0] & 0x1f {
+		0x10 => value_b & a != 0,
+		0x11 => value_b & a == 0,
+		0x12 => value_b == a,
+		0x13 => value_b != a,
+		0x14 => value_b > a,
+		0x15 => (value_b as i16) > (a as i16),
+		0x16 => value_b < a,
+		_ => (value_b as i16) < (a as i16),
+	};
+
+	if !passed {
+		// Skip chained ifs, then the instruction they protect, a cycle each
+		loop {
+			let word = dcpu.memory[dcpu.program_counter as usize];
+			dcpu.program_counter = dcpu.program_counter.wrapping_add(dcpu.spec.instruction_length(word));
+			dcpu.cycle_count += 1;
+
+			if !(0x10..0x18).contains(&(word & 0x1f)) {
+				break;
+			}
+		}
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn cache_sees_writes() {
+		let mut dcpu = Dcpu::new();
+		// set a, 1 then set pc, 0
+		dcpu.memory[..2].copy_from_slice(&[0x8801, 0x8781]);
+		dcpu.run_instruction();
+		assert_eq!(dcpu.registers[0], 1);
+		dcpu.run_instruction();
+
+		// Change the first instruction to set a, 2 behind the CPU's back
+		dcpu.memory[0] = 0x8c01;
+		dcpu.run_instruction();
+		assert_eq!(dcpu.registers[0], 2);
+		assert_eq!(dcpu.cycle_count, 3);
+
+		// A next word changing counts too: set a, 0x1234
+		dcpu.memory[..3].copy_from_slice(&[0x7c01, 0x1234, 0x8781]);
+		dcpu.program_counter = 0;
+		dcpu.run_instruction();
+		dcpu.memory[1] = 0x5678;
+		dcpu.program_counter = 0;
+		dcpu.run_instruction();
+		assert_eq!(dcpu.registers[0], 0x5678);
+	}
+}
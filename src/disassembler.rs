@@ -0,0 +1,150 @@
+use symbols::Symbols;
+
+
+const REGISTER_NAMES: [&'static str; 8] = ["a", "b", "c", "x", "y", "z", "i", "j"];
+
+
+/// The mnemonic of a basic instruction
+pub fn basic_name(instruction: u16) -> Option<&'static str> {
+	match instruction {
+		0x01 => Some("set"), 0x02 => Some("add"), 0x03 => Some("sub"), 0x04 => Some("mul"),
+		0x05 => Some("mli"), 0x06 => Some("div"), 0x07 => Some("dvi"), 0x08 => Some("mod"),
+		0x09 => Some("mdi"), 0x0a => Some("and"), 0x0b => Some("bor"), 0x0c => Some("xor"),
+		0x0d => Some("shr"), 0x0e => Some("asr"), 0x0f => Some("shl"), 0x10 => Some("ifb"),
+		0x11 => Some("ifc"), 0x12 => Some("ife"), 0x13 => Some("ifn"), 0x14 => Some("ifg"),
+		0x15 => Some("ifa"), 0x16 => Some("ifl"), 0x17 => Some("ifu"), 0x1a => Some("adx"),
+		0x1b => Some("sbx"), 0x1e => Some("sti"), 0x1f => Some("std"),
+		_ => None,
+	}
+}
+
+
+/// The mnemonic of a special instruction
+pub fn special_name(instruction: u16) -> Option<&'static str> {
+	match instruction {
+		0x01 => Some("jsr"), 0x07 => Some("hcf"), 0x08 => Some("int"), 0x09 => Some("iag"),
+		0x0a => Some("ias"), 0x0b => Some("rfi"), 0x0c => Some("iaq"), 0x10 => Some("hwn"),
+		0x11 => Some("hwq"), 0x12 => Some("hwi"),
+		// The community's extensions in 2.0
+		0x13 => Some("log"), 0x14 => Some("brk"), 0x15 => Some("hlt"),
+		_ => None,
+	}
+}
+
+
+// Whether the operand is followed by a "next word"
+fn uses_next_word(operand: u16) -> bool {
+	match operand {
+		0x10...0x17 | 0x1a | 0x1e | 0x1f => true,
+		_ => false,
+	}
+}
+
+
+/// The number of words taken by the instruction starting with the given word
+pub fn instruction_length(word: u16) -> u16 {
+	let operand_b = (word >> 5) & 0x1f;
+	let operand_a = word >> 10;
+	let mut length = 1;
+
+	if uses_next_word(operand_a) {
+		length += 1;
+	}
+
+	if word & 0x1f != 0 && uses_next_word(operand_b) {
+		length += 1;
+	}
+
+	length
+}
+
+
+// Write out a next word, using a label for it if there is one
+fn format_word(word: u16, symbols: Option<&Symbols>) -> String {
+	match symbols.and_then(|symbols| symbols.label(word)) {
+		Some(label) => label.to_string(),
+		None => format!("0x{:04x}", word),
+	}
+}
+
+
+// Write out an operand. `is_a` decides between push and pop for 0x18.
+fn format_operand(operand: u16, is_a: bool, next_word: u16, symbols: Option<&Symbols>) -> String {
+	match operand {
+		0x00...0x07 => REGISTER_NAMES[operand as usize].to_string(),
+		0x08...0x0f => format!("[{}]", REGISTER_NAMES[(operand - 0x08) as usize]),
+		0x10...0x17 => format!("[{} + {}]", REGISTER_NAMES[(operand - 0x10) as usize], format_word(next_word, symbols)),
+		0x18 => if is_a {"pop".to_string()} else {"push".to_string()},
+		0x19 => "peek".to_string(),
+		0x1a => format!("pick {}", format_word(next_word, symbols)),
+		0x1b => "sp".to_string(),
+		0x1c => "pc".to_string(),
+		0x1d => "ex".to_string(),
+		0x1e => format!("[{}]", format_word(next_word, symbols)),
+		0x1f => format_word(next_word, symbols),
+		_ => format!("{}", (operand as i16) - 0x21),
+	}
+}
+
+
+/// Disassemble the instruction at the given address, returning its text and length in words
+pub fn disassemble(memory: &[u16], address: u16, symbols: Option<&Symbols>) -> (String, u16) {
+	let word = memory[address as usize];
+	let instruction = word & 0x1f;
+	let operand_b = (word >> 5) & 0x1f;
+	let operand_a = word >> 10;
+
+	// Operand a's next word comes before operand b's
+	let mut next = address.wrapping_add(1);
+	let next_word_a = memory.get(next as usize).cloned().unwrap_or(0);
+	if uses_next_word(operand_a) {
+		next = next.wrapping_add(1);
+	}
+	let next_word_b = memory.get(next as usize).cloned().unwrap_or(0);
+
+	let a = format_operand(operand_a, true, next_word_a, symbols);
+
+	let text = if instruction == 0 {
+		match special_name(operand_b) {
+			Some(name) => format!("{} {}", name, a),
+			None => return (format!("dat 0x{:04x}", word), 1),
+		}
+	} else {
+		match basic_name(instruction) {
+			Some(name) => format!("{} {}, {}", name, format_operand(operand_b, false, next_word_b, symbols), a),
+			None => return (format!("dat 0x{:04x}", word), 1),
+		}
+	};
+
+	(text, instruction_length(word))
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn disassemble_instructions() {
+		let mut symbols = Symbols::new();
+		symbols.insert("print", 0x0020);
+
+		let memory = [
+			0x8801,                 // set a, 1
+			0x7f81, 0x0020,         // set pc, print
+			0x7fc1, 0x0005, 0x1234, // set [0x1234], 0x0005
+			0x6381,                 // set pc, pop
+			0x7c20, 0x0020,         // jsr print
+			0x0000,                 // dat 0x0000
+		];
+
+		assert_eq!(disassemble(&memory, 0, Some(&symbols)), ("set a, 1".to_string(), 1));
+		assert_eq!(disassemble(&memory, 1, Some(&symbols)), ("set pc, print".to_string(), 2));
+		assert_eq!(disassemble(&memory, 3, None), ("set [0x1234], 0x0005".to_string(), 3));
+		assert_eq!(disassemble(&memory, 6, None), ("set pc, pop".to_string(), 1));
+		assert_eq!(disassemble(&memory, 7, Some(&symbols)), ("jsr print".to_string(), 2));
+		assert_eq!(disassemble(&memory, 9, None), ("dat 0x0000".to_string(), 1));
+	}
+}
@@ -0,0 +1,129 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+
+/// When each device next wants to be stepped, in emulated cycles. Devices are
+/// only stepped when they are due, rather than after every cycle, and say how
+/// long they can be left before they need stepping again.
+pub struct EventQueue {
+	// Wake ups as (cycle, device), soonest first. A device that is rescheduled
+	// leaves its old wake up behind, which is skipped when it comes up.
+	wake_ups: BinaryHeap<Reverse<(u64, usize)>>,
+	// The cycle each device is really due at
+	due: Vec<Option<u64>>,
+}
+
+impl EventQueue {
+	pub fn new() -> EventQueue {
+		EventQueue {
+			wake_ups: BinaryHeap::new(),
+			due: vec![],
+		}
+	}
+
+
+	/// Step the device at the given cycle, instead of whenever it was due before
+	pub fn schedule(&mut self, device: usize, cycle: u64) {
+		if device >= self.due.len() {
+			self.due.resize(device + 1, None);
+		}
+
+		if self.due[device] != Some(cycle) {
+			self.due[device] = Some(cycle);
+			self.wake_ups.push(Reverse((cycle, device)));
+		}
+	}
+
+
+	/// Step the device as soon as possible, unless it is already due sooner
+	pub fn wake(&mut self, device: usize, cycle: u64) {
+		match self.due.get(device) {
+			Some(&Some(due)) if due <= cycle => (),
+			_ => self.schedule(device, cycle),
+		}
+	}
+
+
+	/// The number of devices the queue has heard of
+	pub fn devices(&self) -> usize {
+		self.due.len()
+	}
+
+
+	/// The cycle the next device is due at
+	pub fn next(&mut self) -> Option<u64> {
+		self.skip_stale();
+		self.wake_ups.peek().map(|&Reverse((cycle, _))| cycle)
+	}
+
+
+	/// Take the next device due at or before the given cycle. It won't be due
+	/// again until it is rescheduled.
+	pub fn pop(&mut self, now: u64) -> Option<usize> {
+		if self.next().map_or(true, |cycle| cycle > now) {
+			return None;
+		}
+
+		let Reverse((_, device)) = self.wake_ups.pop().unwrap();
+		self.due[device] = None;
+		Some(device)
+	}
+
+
+	/// Forget every wake up
+	pub fn clear(&mut self) {
+		self.wake_ups.clear();
+		self.due.clear();
+	}
+
+
+	// Drop wake ups left behind by devices that were rescheduled
+	fn skip_stale(&mut self) {
+		while let Some(&Reverse((cycle, device))) = self.wake_ups.peek() {
+			if self.due[device] == Some(cycle) {
+				break;
+			}
+			self.wake_ups.pop();
+		}
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn soonest_first() {
+		let mut queue = EventQueue::new();
+		queue.schedule(0, 30);
+		queue.schedule(1, 10);
+		queue.schedule(2, 20);
+
+		assert_eq!(queue.next(), Some(10));
+		assert_eq!(queue.pop(5), None);
+		assert_eq!(queue.pop(25), Some(1));
+		assert_eq!(queue.pop(25), Some(2));
+		assert_eq!(queue.pop(25), None);
+		assert_eq!(queue.pop(30), Some(0));
+		assert_eq!(queue.next(), None);
+	}
+
+	#[test]
+	fn rescheduling_replaces_the_old_wake_up() {
+		let mut queue = EventQueue::new();
+		queue.schedule(0, 10);
+		queue.schedule(0, 50);
+		assert_eq!(queue.pop(20), None);
+		assert_eq!(queue.next(), Some(50));
+
+		// Waking only ever brings a device forward
+		queue.wake(0, 60);
+		assert_eq!(queue.next(), Some(50));
+		queue.wake(0, 40);
+		assert_eq!(queue.pop(40), Some(0));
+		assert_eq!(queue.pop(100), None);
+	}
+}
@@ -0,0 +1,528 @@
+//! A stub speaking GDB's remote serial protocol, so existing debugger frontends can
+//! drive the emulator. The registers are A, B, C, X, Y, Z, I, J, SP, PC and EX, in
+//! that order. GDB thinks in bytes, so word `n` of memory is at byte address `2n`,
+//! and words are sent low byte first like the registers.
+
+use std;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpListener;
+use std::collections::BTreeSet;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use dcpu;
+use system::System;
+
+
+const TARGET_DESCRIPTION: &'static str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target version=\"1.0\">\
+<feature name=\"org.dcpu16.core\">\
+<reg name=\"a\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"b\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"c\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"x\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"y\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"z\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"i\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"j\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"sp\" bitsize=\"16\" type=\"data_ptr\"/>\
+<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\
+<reg name=\"ex\" bitsize=\"16\" type=\"int\"/>\
+</feature>\
+</target>";
+
+const REGISTER_COUNT: usize = 11;
+
+// The byte GDB sends to interrupt a running target
+const INTERRUPT: u8 = 0x03;
+
+// How many instructions to run between checking whether GDB wants the target to stop
+const INTERRUPT_CHECK_INTERVAL: u32 = 1024;
+
+// GDB addresses memory in bytes, two to a word
+const MEMORY_BYTES: u32 = 0x20000;
+
+
+/// Frame a packet as `$data#checksum`
+fn frame(data: &str) -> String {
+	let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+	format!("${}#{:02x}", data, checksum)
+}
+
+
+// Write a word low byte first, as GDB expects registers and memory
+fn hex_word(word: u16) -> String {
+	format!("{:02x}{:02x}", word & 0xff, word >> 8)
+}
+
+
+// Read a word written low byte first
+fn parse_word(text: &str) -> Option<u16> {
+	if text.len() != 4 {
+		return None;
+	}
+
+	let low = u16::from_str_radix(text.get(0..2)?, 16).ok()?;
+	let high = u16::from_str_radix(text.get(2..4)?, 16).ok()?;
+	Some(high << 8 | low)
+}
+
+
+// Parse a `address,length` pair of hex numbers
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+	let mut parts = text.splitn(2, ',');
+	let address = u32::from_str_radix(parts.next()?, 16).ok()?;
+	let length = u32::from_str_radix(parts.next()?, 16).ok()?;
+	Some((address, length))
+}
+
+
+
+
+/// A GDB remote stub debugging a whole system
+pub struct GdbStub {
+	system: System,
+	breakpoints: BTreeSet<u16>,
+	input: Receiver<u8>,
+	output: Box<dyn Write>,
+}
+
+impl GdbStub {
+	/// Speak the protocol over the given streams. The input is read on its own
+	/// thread so that GDB can interrupt the target while it runs.
+	pub fn new<R: Read + Send + 'static>(system: System, input: R, output: Box<dyn Write>) -> GdbStub {
+		let (sender, receiver) = channel();
+
+		thread::spawn(move || {
+			for byte in input.bytes() {
+				match byte {
+					Ok(byte) => if sender.send(byte).is_err() {break},
+					Err(_) => break,
+				}
+			}
+		});
+
+		GdbStub {
+			system: system,
+			breakpoints: BTreeSet::new(),
+			input: receiver,
+			output: output,
+		}
+	}
+
+
+	/// Wait for GDB to connect to the given port on this machine
+	pub fn listen(system: System, port: u16) -> io::Result<GdbStub> {
+		let listener = TcpListener::bind(("127.0.0.1", port))?;
+		eprintln!("Waiting for GDB to connect to port {}", port);
+
+		let (stream, _) = listener.accept()?;
+		stream.set_nodelay(true)?;
+		Ok(GdbStub::new(system, stream.try_clone()?, Box::new(stream)))
+	}
+
+
+	/// Speak the protocol over standard input and output, for `target remote | dcpu ...`
+	pub fn stdio(system: System) -> GdbStub {
+		GdbStub::new(system, io::stdin(), Box::new(io::stdout()))
+	}
+
+
+	/// Answer packets until GDB detaches, kills the target or goes away
+	pub fn run(&mut self) -> io::Result<()> {
+		while let Some(packet) = self.read_packet()? {
+			match self.handle(&packet) {
+				Some(reply) => self.send(&reply)?,
+				None => break,
+			}
+		}
+
+		Ok(())
+	}
+
+
+	// Read the next well formed packet, acknowledging it. None means GDB has gone away.
+	fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+		loop {
+			// Anything outside a packet is an acknowledgement or a stray interrupt
+			match self.input.recv() {
+				Ok(b'$') => (),
+				Ok(_) => continue,
+				Err(_) => return Ok(None),
+			}
+
+			let mut data = Vec::new();
+			loop {
+				match self.input.recv() {
+					Ok(b'#') => break,
+					Ok(byte) => data.push(byte),
+					Err(_) => return Ok(None),
+				}
+			}
+
+			let checksum = match (self.input.recv(), self.input.recv()) {
+				(Ok(high), Ok(low)) => String::from_utf8_lossy(&[high, low]).into_owned(),
+				_ => return Ok(None),
+			};
+
+			if Some(data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))) == u8::from_str_radix(&checksum, 16).ok() {
+				self.output.write_all(b"+")?;
+				return Ok(Some(data));
+			}
+
+			self.output.write_all(b"-")?;
+			self.output.flush()?;
+		}
+	}
+
+
+	fn send(&mut self, data: &str) -> io::Result<()> {
+		self.output.write_all(frame(data).as_bytes())?;
+		self.output.flush()
+	}
+
+
+	/// Carry out a single packet's command, returning the reply, or None if the session is over
+	pub fn handle(&mut self, packet: &[u8]) -> Option<String> {
+		let (command, arguments) = match packet.split_first() {
+			Some((&command, arguments)) => (command, arguments),
+			None => return Some(String::new()),
+		};
+
+		// Every packet the stub understands is written in ASCII
+		let arguments = match std::str::from_utf8(arguments) {
+			Ok(arguments) if arguments.is_ascii() => arguments,
+			_ => return Some("E01".to_string()),
+		};
+
+		let reply = match command {
+			b'?' => "S05".to_string(),
+			b'g' => self.read_registers(),
+			b'G' => self.write_registers(arguments),
+			b'p' => self.read_register(arguments),
+			b'P' => self.write_register(arguments),
+			b'm' => self.read_memory(arguments),
+			b'M' => self.write_memory(arguments),
+			b'Z' => self.insert_breakpoint(arguments),
+			b'z' => self.remove_breakpoint(arguments),
+			b's' => self.resume(true),
+			b'c' => self.resume(false),
+			b'H' => "OK".to_string(),
+			b'D' => {
+				let _ = self.send("OK");
+				return None;
+			},
+			b'k' => return None,
+			b'q' => self.query(arguments),
+			_ => String::new(),
+		};
+
+		Some(reply)
+	}
+
+
+	fn register(&mut self, index: usize) -> Option<&mut u16> {
+		let dcpu = &mut self.system.dcpu;
+		match index {
+			0...7 => Some(&mut dcpu.registers[index]),
+			8 => Some(&mut dcpu.stack_pointer),
+			9 => Some(&mut dcpu.program_counter),
+			10 => Some(&mut dcpu.excess),
+			_ => None,
+		}
+	}
+
+
+	fn read_registers(&mut self) -> String {
+		(0..REGISTER_COUNT).map(|index| hex_word(*self.register(index).unwrap())).collect()
+	}
+
+
+	fn write_registers(&mut self, arguments: &str) -> String {
+		if arguments.len() != REGISTER_COUNT * 4 {
+			return "E01".to_string();
+		}
+
+		for index in 0..REGISTER_COUNT {
+			match arguments.get(index * 4..index * 4 + 4).and_then(parse_word) {
+				Some(value) => *self.register(index).unwrap() = value,
+				None => return "E01".to_string(),
+			}
+		}
+
+		"OK".to_string()
+	}
+
+
+	fn read_register(&mut self, arguments: &str) -> String {
+		let index = usize::from_str_radix(arguments, 16).unwrap_or(REGISTER_COUNT);
+		match self.register(index) {
+			Some(value) => hex_word(*value),
+			None => "E01".to_string(),
+		}
+	}
+
+
+	fn write_register(&mut self, arguments: &str) -> String {
+		let mut parts = arguments.splitn(2, '=');
+		let index = parts.next().and_then(|index| usize::from_str_radix(index, 16).ok());
+		let value = parts.next().and_then(parse_word);
+
+		match (index, value) {
+			(Some(index), Some(value)) => match self.register(index) {
+				Some(register) => {
+					*register = value;
+					"OK".to_string()
+				},
+				None => "E01".to_string(),
+			},
+			_ => "E01".to_string(),
+		}
+	}
+
+
+	fn read_memory(&mut self, arguments: &str) -> String {
+		let (address, length) = match parse_range(arguments) {
+			Some(range) => range,
+			None => return "E01".to_string(),
+		};
+
+		let memory = &self.system.dcpu.memory;
+		(address..address.saturating_add(length))
+			.map(|byte| {
+				let word = memory[(byte / 2 % 0x10000) as usize];
+				format!("{:02x}", if byte % 2 == 0 {word & 0xff} else {word >> 8})
+			})
+			.collect()
+	}
+
+
+	fn write_memory(&mut self, arguments: &str) -> String {
+		let mut parts = arguments.splitn(2, ':');
+		let range = parts.next().and_then(parse_range);
+		let data = parts.next().unwrap_or("");
+
+		let (address, length) = match range {
+			Some(range) if data.len() == range.1 as usize * 2 => range,
+			_ => return "E01".to_string(),
+		};
+
+		for offset in 0..length {
+			let start = offset as usize * 2;
+			let byte = match data.get(start..start + 2).and_then(|byte| u16::from_str_radix(byte, 16).ok()) {
+				Some(byte) => byte,
+				None => return "E01".to_string(),
+			};
+
+			let address = address + offset;
+			let word = &mut self.system.dcpu.memory[(address / 2 % 0x10000) as usize];
+			*word = if address % 2 == 0 {*word & 0xff00 | byte} else {*word & 0x00ff | byte << 8};
+		}
+
+		"OK".to_string()
+	}
+
+
+	// Turn the arguments of a Z or z packet into its type, and the words it covers.
+	// None if the bytes aren't all in memory.
+	fn breakpoint_range(arguments: &str) -> Option<(u8, u16, u16)> {
+		let mut parts = arguments.splitn(3, ',');
+		let kind = parts.next()?.parse().ok()?;
+		let address = u32::from_str_radix(parts.next()?, 16).ok()?;
+		let length = std::cmp::max(1, u32::from_str_radix(parts.next()?, 16).ok()?);
+
+		let last = address.checked_add(length - 1)?;
+		if last >= MEMORY_BYTES {
+			return None;
+		}
+
+		Some((kind, (address / 2) as u16, (last / 2) as u16))
+	}
+
+
+	fn insert_breakpoint(&mut self, arguments: &str) -> String {
+		let (kind, start, end) = match GdbStub::breakpoint_range(arguments) {
+			Some(breakpoint) => breakpoint,
+			None => return "E01".to_string(),
+		};
+
+		match kind {
+			0 | 1 => {
+				self.breakpoints.insert(start);
+			},
+
+			2 | 3 | 4 => self.system.dcpu.watchpoints.push(dcpu::Watchpoint {
+				start: start,
+				end: end,
+				read: kind != 2,
+				write: kind != 3,
+				execute: false,
+			}),
+
+			_ => return String::new(),
+		}
+
+		"OK".to_string()
+	}
+
+
+	fn remove_breakpoint(&mut self, arguments: &str) -> String {
+		let (kind, start, end) = match GdbStub::breakpoint_range(arguments) {
+			Some(breakpoint) => breakpoint,
+			None => return "E01".to_string(),
+		};
+
+		match kind {
+			0 | 1 => {
+				self.breakpoints.remove(&start);
+			},
+
+			2 | 3 | 4 => self.system.dcpu.watchpoints.retain(|watchpoint|
+				!(watchpoint.start == start && watchpoint.end == end && watchpoint.read == (kind != 2) && watchpoint.write == (kind != 3))),
+
+			_ => return String::new(),
+		}
+
+		"OK".to_string()
+	}
+
+
+	// Run one instruction, or until something stops the target, and describe why it stopped
+	fn resume(&mut self, single_step: bool) -> String {
+		self.system.dcpu.watchpoint_hit = None;
+		self.system.dcpu.break_requested = false;
+		if self.system.dcpu.fault_policy == dcpu::FaultPolicy::Trap {
+			self.system.dcpu.fault = None;
+		}
+		let mut instructions = 0u32;
+
+		loop {
+			self.system.step_instruction();
+
+			if let Some(hit) = self.system.dcpu.watchpoint_hit.take() {
+				let kind = match (hit.watchpoint.read, hit.watchpoint.write) {
+					(true, true) => "awatch",
+					(false, true) => "watch",
+					_ => "rwatch",
+				};
+				return format!("T05{}:{:x};", kind, hit.address as u32 * 2);
+			}
+
+			if let Some(fault) = self.system.dcpu.fault {
+				return match fault.kind {
+					dcpu::FaultKind::IllegalInstruction => "S04",
+					dcpu::FaultKind::InvalidOperand(_) => "S0b",
+					dcpu::FaultKind::CaughtFire(_) | dcpu::FaultKind::InterruptOverflow => "S06",
+				}.to_string();
+			}
+
+			if single_step || self.system.dcpu.break_requested || self.breakpoints.contains(&self.system.dcpu.program_counter) {
+				return "S05".to_string();
+			}
+
+			instructions = instructions.wrapping_add(1);
+			if instructions % INTERRUPT_CHECK_INTERVAL == 0 {
+				match self.input.try_recv() {
+					Ok(INTERRUPT) => return "S02".to_string(),
+					Ok(_) | Err(TryRecvError::Empty) => (),
+					Err(TryRecvError::Disconnected) => return "S09".to_string(),
+				}
+			}
+		}
+	}
+
+
+	fn query(&mut self, arguments: &str) -> String {
+		if arguments.starts_with("Supported") {
+			"PacketSize=4000;qXfer:features:read+".to_string()
+		} else if arguments == "Attached" {
+			"1".to_string()
+		} else if arguments.starts_with("Xfer:features:read:target.xml:") {
+			let range = parse_range(&arguments["Xfer:features:read:target.xml:".len()..]);
+			let (offset, length) = match range {
+				Some((offset, length)) => (offset as usize, length as usize),
+				None => return "E01".to_string(),
+			};
+
+			let start = std::cmp::min(offset, TARGET_DESCRIPTION.len());
+			let end = std::cmp::min(start.saturating_add(length), TARGET_DESCRIPTION.len());
+			let more = if end < TARGET_DESCRIPTION.len() {"m"} else {"l"};
+			format!("{}{}", more, &TARGET_DESCRIPTION[start..end])
+		} else {
+			String::new()
+		}
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use test_support::{machine, SharedOutput};
+
+	// A stub with a program that counts A up forever, and the output GDB would see
+	fn setup(input: &'static [u8]) -> (GdbStub, SharedOutput) {
+		let system = machine(&[
+			0x8802, // add a, 1
+			0x8781, // set pc, 0
+		], vec![]);
+
+		let output = SharedOutput::new();
+		(GdbStub::new(system, input, Box::new(output.clone())), output)
+	}
+
+	#[test]
+	fn packets_are_framed_and_acknowledged() {
+		let (mut stub, output) = setup(b"+$?#3f$g#00$p9#a9");
+		stub.run().unwrap();
+
+		let expected = format!("+{}-+{}", frame("S05"), frame("0000"));
+		assert_eq!(output.text(), expected);
+	}
+
+	#[test]
+	fn registers_and_memory() {
+		let (mut stub, _) = setup(b"");
+
+		assert_eq!(stub.handle(b"P0=3412"), Some("OK".to_string()));
+		assert_eq!(stub.handle(b"p0"), Some("3412".to_string()));
+		assert_eq!(stub.handle(b"g").unwrap().len(), REGISTER_COUNT * 4);
+		assert_eq!(stub.system.dcpu.registers[dcpu::A], 0x1234);
+
+		assert_eq!(stub.handle(b"M201,3:abcdef"), Some("OK".to_string()));
+		assert_eq!(&stub.system.dcpu.memory[0x100..0x102], &[0xab00, 0xefcd]);
+		assert_eq!(stub.handle(b"m200,4"), Some("00abcdef".to_string()));
+	}
+
+	#[test]
+	fn breakpoints_and_stepping() {
+		let (mut stub, _) = setup(b"");
+
+		assert_eq!(stub.handle(b"s"), Some("S05".to_string()));
+		assert_eq!(stub.system.dcpu.program_counter, 1);
+
+		assert_eq!(stub.handle(b"Z0,0,2"), Some("OK".to_string()));
+		assert_eq!(stub.handle(b"c"), Some("S05".to_string()));
+		assert_eq!(stub.system.dcpu.program_counter, 0);
+		assert_eq!(stub.system.dcpu.registers[dcpu::A], 1);
+		assert_eq!(stub.handle(b"z0,0,2"), Some("OK".to_string()));
+
+		assert_eq!(stub.handle(b"Z2,1fffe,2"), Some("OK".to_string()));
+		assert_eq!(stub.handle(b"Z2,1fffe,4"), Some("E01".to_string()));
+		assert_eq!(stub.handle(b"Z0,ffffffff,2"), Some("E01".to_string()));
+	}
+
+	#[test]
+	fn malformed_packets() {
+		let (mut stub, _) = setup(b"");
+
+		assert_eq!(stub.handle(b""), Some(String::new()));
+		assert_eq!(stub.handle(b"\xc3"), Some(String::new()));
+		assert_eq!(stub.handle(b"G\xc3\xa9"), Some("E01".to_string()));
+		assert_eq!(stub.handle(&[b'P', b'0', b'=', 0xff, 0xfe]), Some("E01".to_string()));
+		assert_eq!(stub.handle(b"Gzz"), Some("E01".to_string()));
+	}
+}
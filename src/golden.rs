@@ -0,0 +1,183 @@
+use std::fs;
+use screen::{self, Screen};
+
+
+/// What the screen shows, as it is written to a golden file: a row of text for
+/// each row of the screen, between bars so that trailing spaces can be seen,
+/// then a blank line and the colours of each character. A character's colours
+/// are its foreground and background indices into the pallet, followed by a star
+/// if it blinks. Every cell is `--` while the screen is disconnected.
+pub fn snapshot(screen: &Screen, memory: &[u16]) -> String {
+	let mut snapshot = String::new();
+	for line in screen.text(memory).lines() {
+		snapshot.push_str(&format!("|{}|\n", line));
+	}
+
+	snapshot.push('\n');
+
+	for row in 0..screen::ROWS {
+		for column in 0..screen::COLUMNS {
+			let cell = screen.cell(memory, column, row);
+			match cell {
+				Some(cell) => snapshot.push_str(&format!("{:x}{:x}", cell.foreground, cell.background)),
+				None => snapshot.push_str("--"),
+			}
+
+			if cell.map_or(false, |cell| cell.blink) {
+				snapshot.push('*');
+			} else if column + 1 < screen::COLUMNS {
+				snapshot.push(' ');
+			}
+		}
+		snapshot.push('\n');
+	}
+
+	snapshot
+}
+
+
+/// A description of every line that differs between two snapshots, with the
+/// characters that differ marked, or `None` if they are the same
+pub fn diff(expected: &str, actual: &str) -> Option<String> {
+	let expected: Vec<&str> = expected.lines().collect();
+	let actual: Vec<&str> = actual.lines().collect();
+	if expected == actual {
+		return None;
+	}
+
+	let mut differences = vec![];
+	for index in 0..expected.len().max(actual.len()) {
+		let wanted = expected.get(index).cloned().unwrap_or("");
+		let got = actual.get(index).cloned().unwrap_or("");
+		if wanted == got {
+			continue;
+		}
+
+		// Mark each character that differs, up to the last one
+		let wanted_chars: Vec<char> = wanted.chars().collect();
+		let got_chars: Vec<char> = got.chars().collect();
+		let mut marks: String = (0..wanted_chars.len().max(got_chars.len()))
+			.map(|i| if wanted_chars.get(i) == got_chars.get(i) {' '} else {'^'})
+			.collect();
+		let last_mark = marks.rfind('^').map_or(0, |i| i + 1);
+		marks.truncate(last_mark);
+
+		differences.push(format!("{}:\n  expected {}\n  actual   {}\n           {}", describe_line(index), wanted, got, marks));
+	}
+
+	Some(differences.join("\n"))
+}
+
+
+// Where a line of a snapshot is on the screen
+fn describe_line(index: usize) -> String {
+	let rows = screen::ROWS as usize;
+	if index < rows {
+		format!("Row {} of the text", index)
+	} else if index > rows && index <= rows * 2 {
+		format!("Row {} of the colours", index - rows - 1)
+	} else {
+		format!("Line {}", index + 1)
+	}
+}
+
+
+/// Check a snapshot against the golden file at the path, or write it there if
+/// `update` is set. Anything that differs is described in the error.
+pub fn check(path: &str, actual: &str, update: bool) -> Result<(), String> {
+	if update {
+		return fs::write(path, actual).map_err(|e| format!("Unable to write the golden screen {}: {}", path, e));
+	}
+
+	let expected = fs::read_to_string(path)
+		.map_err(|e| format!("Unable to read the golden screen {}: {}. Update it to make one.", path, e))?;
+
+	match diff(&expected, actual) {
+		Some(diff) => Err(format!("The screen doesn't match {}\n{}", path, diff)),
+		None => Ok(()),
+	}
+}
+
+
+/// Panic unless the screen matches the golden file at the path. Setting the
+/// UPDATE_GOLDEN environment variable writes the golden file instead.
+#[cfg(test)]
+pub fn assert_screen(screen: &Screen, memory: &[u16], path: &str) {
+	let update = ::std::env::var_os("UPDATE_GOLDEN").is_some();
+	if let Err(message) = check(path, &snapshot(screen, memory), update) {
+		panic!("{}", message);
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dcpu;
+	use dcpu::Dcpu;
+
+	fn hello() -> (Screen, Dcpu) {
+		let mut dcpu = Dcpu::new();
+		let mut screen = Screen::new();
+		dcpu.registers[dcpu::A] = 0;
+		dcpu.registers[dcpu::B] = 0x8000;
+		screen.interrupt(&mut dcpu);
+
+		for (offset, character) in "Hello".bytes().enumerate() {
+			dcpu.memory[0x8000 + offset] = 0xf000 | character as u16;
+		}
+		dcpu.memory[0x8000 + 4] |= 0x0180;
+		(screen, dcpu)
+	}
+
+	#[test]
+	fn snapshot_has_text_and_colours() {
+		let (screen, dcpu) = hello();
+		let hello = snapshot(&screen, &dcpu.memory);
+		let lines: Vec<&str> = hello.lines().collect();
+
+		assert_eq!(lines.len(), 25);
+		assert_eq!(lines[0], format!("|{:32}|", "Hello"));
+		assert_eq!(lines[11], format!("|{:32}|", ""));
+		assert_eq!(lines[12], "");
+		assert_eq!(lines[13], format!("f0 f0 f0 f0 f1*00{}", " 00".repeat(26)));
+		assert_eq!(lines[14], format!("00{}", " 00".repeat(31)));
+
+		let disconnected = snapshot(&Screen::new(), &dcpu.memory);
+		assert!(disconnected.lines().nth(24).unwrap().starts_with("-- -- --"));
+	}
+
+	#[test]
+	fn diff_marks_what_changed() {
+		let (screen, mut dcpu) = hello();
+		let expected = snapshot(&screen, &dcpu.memory);
+		assert_eq!(diff(&expected, &expected), None);
+
+		dcpu.memory[0x8001] = 0x2061;
+		let diff = diff(&expected, &snapshot(&screen, &dcpu.memory)).unwrap();
+		let rest = " 00".repeat(26);
+		assert_eq!(diff, format!(
+			"Row 0 of the text:\n  expected |{:32}|\n  actual   |{:32}|\n{:13}^\n\
+			Row 0 of the colours:\n  expected f0 f0 f0 f0 f1*00{}\n  actual   f0 20 f0 f0 f1*00{}\n{:14}^",
+			"Hello", "Hallo", "", rest, rest, ""));
+	}
+
+	#[test]
+	fn golden_files() {
+		let (screen, mut dcpu) = hello();
+		let path = ::std::env::temp_dir().join(format!("dcpu-golden-{}.screen", ::std::process::id()));
+		let path = path.to_str().unwrap();
+
+		check(path, &snapshot(&screen, &dcpu.memory), true).unwrap();
+		assert_screen(&screen, &dcpu.memory, path);
+
+		dcpu.memory[0x8000] = 0;
+		let error = check(path, &snapshot(&screen, &dcpu.memory), false).unwrap_err();
+		assert!(error.contains("Row 0 of the text"), "{}", error);
+
+		fs::remove_file(path).unwrap();
+		assert!(check(path, "", false).is_err());
+	}
+}
@@ -0,0 +1,270 @@
+use std;
+use std::collections::VecDeque;
+use dcpu;
+use dcpu::{Dcpu, Fault, HardwareInstruction};
+use system::System;
+
+
+/// How many instructions go by between full snapshots of memory
+const SNAPSHOT_INTERVAL: u64 = 10_000;
+
+
+// Everything about the CPU apart from its memory
+#[derive(Clone)]
+struct Registers {
+	registers: [u16; 8],
+	stack_pointer: u16,
+	program_counter: u16,
+	excess: u16,
+	interrupt_address: u16,
+	cycle_accumulator: u32,
+	cycle_count: u64,
+	interrupt_queue: Vec<u16>,
+	interrupt_queueing: bool,
+	hardware_interrupt: Option<HardwareInstruction>,
+	sleeping: bool,
+	fault: Option<Fault>,
+}
+
+impl Registers {
+	fn of(dcpu: &Dcpu) -> Registers {
+		Registers {
+			registers: dcpu.registers,
+			stack_pointer: dcpu.stack_pointer,
+			program_counter: dcpu.program_counter,
+			excess: dcpu.excess,
+			interrupt_address: dcpu.interrupt_address,
+			cycle_accumulator: dcpu.cycle_accumulator,
+			cycle_count: dcpu.cycle_count,
+			interrupt_queue: dcpu.interrupt_queue.clone(),
+			interrupt_queueing: dcpu.interrupt_queueing,
+			hardware_interrupt: dcpu.hardware_interrupt,
+			sleeping: dcpu.sleeping,
+			fault: dcpu.fault,
+		}
+	}
+
+	fn restore(&self, dcpu: &mut Dcpu) {
+		dcpu.registers = self.registers;
+		dcpu.stack_pointer = self.stack_pointer;
+		dcpu.program_counter = self.program_counter;
+		dcpu.excess = self.excess;
+		dcpu.interrupt_address = self.interrupt_address;
+		dcpu.cycle_accumulator = self.cycle_accumulator;
+		dcpu.cycle_count = self.cycle_count;
+		dcpu.interrupt_queue = self.interrupt_queue.clone();
+		dcpu.interrupt_queueing = self.interrupt_queueing;
+		dcpu.hardware_interrupt = self.hardware_interrupt;
+		dcpu.sleeping = self.sleeping;
+		dcpu.fault = self.fault;
+	}
+}
+
+
+// How to undo one instruction: the registers from before it ran, and the
+// memory it wrote along with the values that were there before
+struct Undo {
+	registers: Registers,
+	writes: Vec<(u16, u16)>,
+}
+
+
+// The whole CPU as it was after a given number of instructions
+struct Snapshot {
+	position: u64,
+	registers: Registers,
+	memory: Vec<u16>,
+}
+
+
+/// The last few instructions a system ran, so that they can be run backwards.
+/// Memory written by devices rather than the CPU isn't undone.
+pub struct History {
+	length: usize,
+	position: u64,
+	undo: VecDeque<Undo>,
+	snapshots: VecDeque<Snapshot>,
+}
+
+impl History {
+	/// Remember up to `length` instructions. Nothing is remembered if it is zero.
+	pub fn new(length: usize) -> History {
+		History {
+			length: length,
+			position: 0,
+			undo: VecDeque::new(),
+			snapshots: VecDeque::new(),
+		}
+	}
+
+
+	/// How many instructions can currently be stepped back over
+	pub fn len(&self) -> usize {
+		self.undo.len()
+	}
+
+
+	/// Run one whole instruction, remembering how to undo it
+	pub fn step(&mut self, system: &mut System) {
+		if self.length == 0 {
+			system.step_instruction();
+			return;
+		}
+
+		let registers = Registers::of(&system.dcpu);
+		system.dcpu.journal = Some(vec![]);
+		system.step_instruction();
+		let writes = system.dcpu.journal.take().unwrap_or_default();
+
+		self.undo.push_back(Undo {registers: registers, writes: writes});
+		self.position += 1;
+
+		if self.position % SNAPSHOT_INTERVAL == 0 {
+			self.snapshots.push_back(Snapshot {
+				position: self.position,
+				registers: Registers::of(&system.dcpu),
+				memory: system.dcpu.memory.to_vec(),
+			});
+		}
+
+		// Forget anything older than the history, including snapshots nothing can go back to
+		if self.undo.len() > self.length {
+			self.undo.pop_front();
+		}
+
+		let oldest = self.position - self.undo.len() as u64;
+		while self.snapshots.front().map_or(false, |snapshot| snapshot.position < oldest) {
+			self.snapshots.pop_front();
+		}
+	}
+
+
+	/// Undo the last instruction, returning the addresses it wrote to, or None if
+	/// there is no more history
+	pub fn step_back(&mut self, dcpu: &mut Dcpu) -> Option<Vec<u16>> {
+		let undo = self.undo.pop_back()?;
+
+		for &(address, value) in undo.writes.iter().rev() {
+			dcpu.memory[address as usize] = value;
+		}
+
+		undo.registers.restore(dcpu);
+		self.position -= 1;
+		self.forget_future();
+
+		Some(undo.writes.iter().map(|&(address, _)| address).collect())
+	}
+
+
+	/// Undo up to `count` instructions, returning how many were undone. Long jumps
+	/// start from the nearest snapshot rather than undoing every instruction.
+	pub fn go_back(&mut self, dcpu: &mut Dcpu, count: usize) -> usize {
+		let count = std::cmp::min(count, self.undo.len());
+		let target = self.position - count as u64;
+
+		let nearest = self.snapshots.iter()
+			.find(|snapshot| snapshot.position >= target && snapshot.position < self.position)
+			.map(|snapshot| (snapshot.position, snapshot.registers.clone(), snapshot.memory.clone()));
+
+		if let Some((position, registers, memory)) = nearest {
+			registers.restore(dcpu);
+			dcpu.memory.copy_from_slice(&memory);
+
+			let skipped = (self.position - position) as usize;
+			let remaining = self.undo.len() - skipped;
+			self.undo.truncate(remaining);
+			self.position = position;
+		}
+
+		while self.position > target {
+			self.step_back(dcpu);
+		}
+
+		self.forget_future();
+		count
+	}
+
+
+	// Snapshots from after the current position are of a future that won't happen now
+	fn forget_future(&mut self) {
+		let position = self.position;
+		while self.snapshots.back().map_or(false, |snapshot| snapshot.position > position) {
+			self.snapshots.pop_back();
+		}
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use test_support::machine;
+
+	// A system running a loop that pushes an increasing count onto the stack forever
+	fn system() -> System {
+		machine(&[
+			0x8802, // add a, 1
+			0x0301, // set push, a
+			0x8781, // set pc, 0
+		], vec![])
+	}
+
+	#[test]
+	fn step_back_over_writes() {
+		let mut system = system();
+		let mut history = History::new(10);
+
+		for _ in 0..3 {
+			history.step(&mut system);
+		}
+		assert_eq!(system.dcpu.memory[0xffff], 1);
+
+		assert_eq!(history.step_back(&mut system.dcpu), Some(vec![]));
+		assert_eq!(history.step_back(&mut system.dcpu), Some(vec![0xffff]));
+		assert_eq!(system.dcpu.memory[0xffff], 0);
+		assert_eq!(system.dcpu.stack_pointer, 0);
+		assert_eq!(system.dcpu.program_counter, 1);
+		assert_eq!(system.dcpu.registers[dcpu::A], 1);
+	}
+
+	#[test]
+	fn history_is_bounded() {
+		let mut system = system();
+		let mut history = History::new(5);
+
+		for _ in 0..20 {
+			history.step(&mut system);
+		}
+
+		assert_eq!(history.len(), 5);
+		assert_eq!(history.go_back(&mut system.dcpu, 100), 5);
+		assert_eq!(history.step_back(&mut system.dcpu), None);
+	}
+
+	#[test]
+	fn go_back_through_snapshots() {
+		let mut system = system();
+		let mut history = History::new(SNAPSHOT_INTERVAL as usize * 3);
+		let total = SNAPSHOT_INTERVAL as usize * 2 + 10;
+		let count = SNAPSHOT_INTERVAL as usize + 20;
+
+		for _ in 0..total {
+			history.step(&mut system);
+		}
+		assert_eq!(history.go_back(&mut system.dcpu, count), count);
+
+		// Compare against a system that only ever went forwards
+		let mut expected = self::system();
+		for _ in 0..total - count {
+			expected.step_instruction();
+		}
+
+		assert_eq!(system.dcpu.registers, expected.dcpu.registers);
+		assert_eq!(system.dcpu.stack_pointer, expected.dcpu.stack_pointer);
+		assert_eq!(system.dcpu.program_counter, expected.dcpu.program_counter);
+		assert_eq!(system.dcpu.cycle_count, expected.dcpu.cycle_count);
+		assert!(system.dcpu.memory.iter().eq(expected.dcpu.memory.iter()));
+	}
+}
@@ -0,0 +1,312 @@
+use glium;
+use dcpu;
+use std;
+use std::collections::{HashMap, VecDeque};
+use session::{Input, Recorder, Player};
+use lem1820::WindowEvents;
+
+/// Generic keyboard's hardware id, version and manufacturer
+pub const HARDWARE_ID: u32 = 0x30cf_7406;
+pub const VERSION: u16 = 0x0001;
+pub const MANUFACTURER: u32 = 0x0000_0000;
+
+// Cycles between looking for key presses, twenty times a second
+const POLL_INTERVAL: u64 = dcpu::CLOCK_RATE / 20;
+
+pub struct Keyboard {
+	// What is typed in the monitor's window
+	window: Option<WindowEvents>,
+	keyboard_buffer: VecDeque<u16>,
+	keyboard_interrupt: u16,
+	save_requested: bool,
+	recorder: Option<Recorder>,
+	player: Option<Player>,
+	// Keys still to be typed by type_text
+	script: VecDeque<u16>,
+	// The key the script typed last, held down until the next poll
+	scripted: Option<u16>,
+	// Keys held down in the window, by scancode, so that releasing one lets go of the right key
+	held: HashMap<u32, u16>,
+	// A key pressed in the window that is waiting for the character it types
+	typing: Option<u32>,
+	// The keys the DCPU sees held down
+	pressed: Vec<u16>,
+}
+
+
+impl Keyboard {
+	/// A keyboard that types what is pressed in a monitor's window, taken from `Lem1820::window_events`
+	pub fn new(window: WindowEvents) -> Keyboard {
+		Keyboard {
+			window: Some(window),
+			keyboard_buffer: VecDeque::new(),
+			keyboard_interrupt: 0,
+			save_requested: false,
+			recorder: None,
+			player: None,
+			script: VecDeque::new(),
+			scripted: None,
+			held: HashMap::new(),
+			typing: None,
+			pressed: vec![],
+		}
+	}
+
+
+	/// A keyboard with no window, which only types what it is given with `type_text`
+	pub fn headless() -> Keyboard {
+		Keyboard {
+			window: None,
+			keyboard_buffer: VecDeque::new(),
+			keyboard_interrupt: 0,
+			save_requested: false,
+			recorder: None,
+			player: None,
+			script: VecDeque::new(),
+			scripted: None,
+			held: HashMap::new(),
+			typing: None,
+			pressed: vec![],
+		}
+	}
+
+
+	/// Type the text, a key each time the keyboard is polled. New lines press
+	/// return, and anything that isn't printable ASCII is left out.
+	pub fn type_text(&mut self, text: &str) {
+		for c in text.chars() {
+			match c {
+				'\n' => self.script.push_back(0x11),
+				' '...'~' => self.script.push_back(c as u16),
+				_ => (),
+			}
+		}
+	}
+
+
+	/// Write down every key pressed from now on
+	pub fn record_session(&mut self, recorder: Recorder) {
+		self.recorder = Some(recorder);
+	}
+
+
+	/// Type the keys pressed in a recorded session instead of the ones pressed in the window
+	pub fn replay_session(&mut self, player: Player) {
+		self.player = Some(player);
+	}
+
+
+	/// Whether F5 was pressed to save the machine since this was last asked
+	pub fn take_save_request(&mut self) -> bool {
+		std::mem::replace(&mut self.save_requested, false)
+	}
+
+
+	/// Take any key presses from the window or script, returning the cycles until it should be done again
+	pub fn step(&mut self, dcpu: &mut dcpu::Dcpu) -> u64 {
+		let mut character = None;
+		let mut save = false;
+		// Keys that went down (true) or came up (false)
+		let mut changes = vec![];
+
+		// A headless keyboard has no window to take key presses from
+		if let Some(ref window) = self.window {
+			for event in window.lock().unwrap().drain(..) {
+				match event {
+					glium::glutin::WindowEvent::ReceivedCharacter(c) => {
+						if c.is_ascii() {
+							let converted: u8 = c as u8;
+
+							if converted >= 0x20 && converted < 0x7f {
+								character = Some(converted as u16);
+
+								// The key that typed it is held down until it is released
+								if let Some(scancode) = self.typing.take() {
+									self.held.insert(scancode, converted as u16);
+									changes.push((converted as u16, true));
+								}
+							}
+						}
+					},
+
+					glium::glutin::WindowEvent::KeyboardInput {input: glium::glutin::KeyboardInput {scancode, virtual_keycode, state: glium::glutin::ElementState::Pressed, ..}, ..} => {
+						use glium::glutin::VirtualKeyCode as Vk;
+						let key = match virtual_keycode {
+							Some(Vk::Back) => Some(0x10),
+							Some(Vk::Return) => Some(0x11),
+							Some(Vk::Insert) => Some(0x12),
+							Some(Vk::Delete) => Some(0x13),
+							Some(Vk::Up) => Some(0x80),
+							Some(Vk::Down) => Some(0x81),
+							Some(Vk::Left) => Some(0x82),
+							Some(Vk::Right) => Some(0x83),
+							Some(Vk::RShift) | Some(Vk::LShift) => Some(0x90),
+							Some(Vk::RControl) | Some(Vk::LControl) => Some(0x91),
+							Some(Vk::F5) => {
+								save = true;
+								None
+							},
+							_ => None,
+						};
+
+						match key {
+							Some(key) => {
+								character = Some(key);
+								self.held.insert(scancode, key);
+								changes.push((key, true));
+							},
+
+							// Anything else is known by the character it types
+							None => self.typing = Some(scancode),
+						}
+					},
+
+					glium::glutin::WindowEvent::KeyboardInput {input: glium::glutin::KeyboardInput {scancode, state: glium::glutin::ElementState::Released, ..}, ..} => {
+						if let Some(key) = self.held.remove(&scancode) {
+							changes.push((key, false));
+						}
+					},
+
+					_ => (),
+				}
+			}
+		}
+
+		// Scripted keys are typed one at a time, as if someone were typing them,
+		// and each is held down until the next poll
+		if let Some(key) = self.scripted.take() {
+			changes.push((key, false));
+		}
+		if character.is_none() {
+			character = self.script.pop_front();
+			if let Some(key) = character {
+				self.scripted = Some(key);
+				changes.push((key, true));
+			}
+		}
+
+		let cycle = dcpu.cycle_count;
+		if self.player.is_some() {
+			let is_key = |input: &Input| match *input {Input::Key(_) | Input::KeyDown(_) | Input::KeyUp(_) => true, _ => false};
+			while let Some(input) = self.player.as_ref().and_then(|player| player.take(cycle, is_key)) {
+				self.press(input);
+			}
+		} else {
+			for (key, down) in changes {
+				self.input(cycle, if down {Input::KeyDown(key)} else {Input::KeyUp(key)});
+			}
+			if let Some(c) = character {
+				self.input(cycle, Input::Key(c));
+			}
+		}
+
+		self.save_requested |= save;
+		POLL_INTERVAL
+	}
+
+
+	// Take a key from the window or script, writing it down if the session is being recorded
+	fn input(&mut self, cycle: u64, input: Input) {
+		if let Some(ref recorder) = self.recorder {
+			recorder.record(cycle, &input);
+		}
+		self.press(input);
+	}
+
+
+	fn press(&mut self, input: Input) {
+		match input {
+			Input::Key(key) => self.keyboard_buffer.push_back(key),
+			Input::KeyDown(key) => if !self.pressed.contains(&key) {
+				self.pressed.push(key);
+			},
+			Input::KeyUp(key) => self.pressed.retain(|&pressed| pressed != key),
+			Input::Line(_) => (),
+		}
+	}
+
+
+	/// The keyboard's part of a save state: its interrupt message then the keys waiting to be read
+	pub fn save(&self) -> Vec<u16> {
+		let mut words = vec![self.keyboard_interrupt];
+		words.extend(self.keyboard_buffer.iter());
+		words
+	}
+
+	pub fn restore(&mut self, words: &[u16]) -> Result<(), String> {
+		match words.split_first() {
+			Some((&interrupt, buffer)) => {
+				self.keyboard_interrupt = interrupt;
+				self.keyboard_buffer = buffer.iter().cloned().collect();
+				Ok(())
+			},
+
+			None => Err("The keyboard in the save state is incomplete".to_string()),
+		}
+	}
+
+
+	pub fn interrupt(&mut self, dcpu: &mut dcpu::Dcpu) {
+		match dcpu.registers[dcpu::A] {
+			0 => self.keyboard_buffer.clear(),
+			1 => dcpu.registers[dcpu::C] = self.keyboard_buffer.pop_front().unwrap_or(0),
+			2 => dcpu.registers[dcpu::C] = self.pressed.contains(&dcpu.registers[dcpu::B]) as u16,
+			3 => self.keyboard_interrupt = dcpu.registers[dcpu::B],
+			_ => (),
+		}
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use test_support::SharedOutput;
+
+	// Ask the keyboard whether a key is held down
+	fn held(keyboard: &mut Keyboard, dcpu: &mut dcpu::Dcpu, key: char) -> bool {
+		dcpu.registers[dcpu::A] = 2;
+		dcpu.registers[dcpu::B] = key as u16;
+		keyboard.interrupt(dcpu);
+		dcpu.registers[dcpu::C] == 1
+	}
+
+	// Poll the keyboard a few times, noting which of a and b are held down each time
+	fn type_ab(keyboard: &mut Keyboard) -> Vec<(bool, bool)> {
+		let mut dcpu = dcpu::Dcpu::new();
+		let mut states = vec![];
+		for poll in 0..3 {
+			dcpu.cycle_count = poll * POLL_INTERVAL;
+			keyboard.step(&mut dcpu);
+			states.push((held(keyboard, &mut dcpu, 'a'), held(keyboard, &mut dcpu, 'b')));
+		}
+		states
+	}
+
+	#[test]
+	fn scripted_keys_are_held_until_the_next_poll() {
+		let mut keyboard = Keyboard::headless();
+		keyboard.type_text("ab");
+		assert_eq!(type_ab(&mut keyboard), vec![(true, false), (false, true), (false, false)]);
+
+		let mut dcpu = dcpu::Dcpu::new();
+		dcpu.registers[dcpu::A] = 1;
+		keyboard.interrupt(&mut dcpu);
+		assert_eq!(dcpu.registers[dcpu::C], 'a' as u16);
+	}
+
+	#[test]
+	fn held_keys_replay() {
+		let output = SharedOutput::new();
+		let mut keyboard = Keyboard::headless();
+		keyboard.record_session(Recorder::new(Box::new(output.clone())).unwrap());
+		keyboard.type_text("ab");
+		let recorded = type_ab(&mut keyboard);
+
+		let mut keyboard = Keyboard::headless();
+		keyboard.replay_session(Player::parse(&output.text()).unwrap());
+		assert_eq!(type_ab(&mut keyboard), recorded);
+	}
+}
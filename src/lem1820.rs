@@ -1,19 +1,31 @@
 use glium;
 use dcpu;
-use std;
+use screen::{self, Screen};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// LEM1802's hardware id, version and manufacturer
+pub const HARDWARE_ID: u32 = 0x7349_f615;
+pub const VERSION: u16 = 0x1802;
+pub const MANUFACTURER: u32 = 0x1c6c_8b36;
+
+// Cycles between redrawing the screen, sixty times a second
+const REFRESH_INTERVAL: u64 = dcpu::CLOCK_RATE / 60;
+
+/// What happens in a monitor's window, queued for a keyboard to read
+pub type WindowEvents = Arc<Mutex<VecDeque<glium::glutin::WindowEvent>>>;
 
 pub struct Lem1820 {
 	// Dcpu State
-	font_ram: [u16; 256],
-	pallet_ram: [u16; 16],
-	video_ram: u16,
-	border_color: u16,
+	screen: Screen,
 
 	// Window State
 	display: glium::Display,
+	events_loop: glium::glutin::EventsLoop,
+	// Only kept once a keyboard asks for them
+	window_events: Option<WindowEvents>,
 
 	// OpenGL State
-	last_refresh: std::time::Instant,
 	font_texture: glium::texture::texture2d::Texture2d,
 	character_buffer: glium::VertexBuffer<Character>,
 	character_shape_buffer: glium::VertexBuffer<Vertex>,
@@ -29,17 +41,23 @@ pub struct Lem1820 {
 
 
 impl Lem1820 {
-	pub fn new() -> (Lem1820, glium::glutin::EventsLoop) {
+	pub fn new() -> Lem1820 {
+		Lem1820::with_scale(5)
+	}
+
+	/// Open the monitor in a window the given multiple of the LEM1802's 128 by 96 pixels
+	pub fn with_scale(scale: u32) -> Lem1820 {
 		// Create window and OpenGL Context
 		let events_loop = glium::glutin::EventsLoop::new();
 		let window = glium::glutin::WindowBuilder::new()
-			.with_dimensions(640, 480)
+			.with_dimensions(128 * scale, 96 * scale)
 			.with_title("LEM 1802 - Low Energy Monitor - Nya Elektriska");
 		let context = glium::glutin::ContextBuilder::new();
 		let display = glium::Display::new(window, context, &events_loop).unwrap();
 
 
-		let font_texture = create_font_texture(&display, &DEFAULT_FONT);
+		let screen = Screen::new();
+		let font_texture = create_font_texture(&display, &screen.font_ram);
 
 		let character_buffer = glium::VertexBuffer::empty_dynamic(&display, (WIDTH * HEIGHT) as usize).unwrap();
 
@@ -67,15 +85,13 @@ impl Lem1820 {
 
 		let post_process_shader = load_shader(&display).unwrap();
 
-		(Lem1820 {
-			font_ram: DEFAULT_FONT,
-			pallet_ram: DEFAULT_PALLET,
-			video_ram: 0,
-			border_color: 7,
+		Lem1820 {
+			screen: screen,
 
 			display: display,
+			events_loop: events_loop,
+			window_events: None,
 
-			last_refresh: std::time::Instant::now(),
 			font_texture: font_texture,
 			character_buffer: character_buffer,
 			character_shape_buffer: character_shape_buffer,
@@ -85,34 +101,60 @@ impl Lem1820 {
 			post_process_buffer: post_process_buffer,
 			post_process_indicies: post_process_indicies,
 			post_process_shader: post_process_shader,
-		}, events_loop)
+		}
+	}
+
+	/// Pass what happens in the window on to a keyboard from now on
+	pub fn window_events(&mut self) -> WindowEvents {
+		self.window_events.get_or_insert_with(|| Arc::new(Mutex::new(VecDeque::new()))).clone()
+	}
+
+	/// What the DCPU has set the monitor to show
+	pub fn screen(&self) -> &Screen {
+		&self.screen
+	}
+
+	/// The monitor's part of a save state: video RAM, border color, pallet and font
+	pub fn save(&self) -> Vec<u16> {
+		self.screen.save()
+	}
+
+	pub fn restore(&mut self, words: &[u16]) -> Result<(), String> {
+		self.screen.restore(words)?;
+		self.font_texture = create_font_texture(&self.display, &self.screen.font_ram);
+		Ok(())
 	}
 
 	pub fn interrupt(&mut self, dcpu: &mut dcpu::Dcpu) {
-		match dcpu.registers[dcpu::A] {
-			0 => self.mem_map_screen(dcpu),
-			1 => self.mem_map_font(dcpu),
-			2 => self.mem_map_pallet(dcpu),
-			3 => self.set_border_color(dcpu),
-			4 => self.mem_dump_font(dcpu),
-			5 => self.mem_dump_pallet(dcpu),
-			_ => (),
+		self.screen.interrupt(dcpu);
+
+		// Mapping a font needs it uploading again
+		if dcpu.registers[dcpu::A] == 1 {
+			self.font_texture = create_font_texture(&self.display, &self.screen.font_ram);
 		}
 	}
 
-	pub fn step(&mut self, dcpu: &mut dcpu::Dcpu) {
+	/// Redraw the screen, returning the cycles until it should be redrawn again
+	pub fn step(&mut self, dcpu: &mut dcpu::Dcpu) -> u64 {
 		use glium::Surface;
 
-		if let None = self.last_refresh.elapsed().checked_sub(std::time::Duration::new(0, 10_000_000)) {
-			return;
-		}
+		// The window only stays responsive while its events are taken, whether or not a keyboard wants them
+		let window_events = &self.window_events;
+		self.events_loop.poll_events(|e| {
+			if let glium::glutin::Event::WindowEvent {event, ..} = e {
+				if let Some(ref window_events) = *window_events {
+					window_events.lock().unwrap().push_back(event);
+				}
+			}
+		});
 
-		if self.video_ram != 0 {
+		if self.screen.video_ram != 0 {
+			let pallet = &self.screen.pallet_ram;
 			let mut character_data: Vec<Character> = Vec::with_capacity(500);
 			let border_character = Character {
 				character: 0,
-				foreground: to_float_color(&self.pallet_ram, self.border_color as usize),
-				background: to_float_color(&self.pallet_ram, self.border_color as usize),
+				foreground: to_float_color(pallet, self.screen.border_color as usize),
+				background: to_float_color(pallet, self.screen.border_color as usize),
 			};
 
 			// Push top border
@@ -120,21 +162,17 @@ impl Lem1820 {
 				character_data.push(border_character);
 			}
 
-			for y in 0..HEIGHT - 2 {
+			for y in 0..screen::ROWS {
 				// Push left border
 				character_data.push(border_character);
 
-				for x in 0..WIDTH - 2 {
-					let character_index = (self.video_ram + x + (y * (WIDTH - 2))) as usize;
-					let character_value = dcpu.memory[character_index];
-					let character = (character_value & 0b0000_0000_0111_1111) as u8;
-					let foreground = (character_value & 0b1111_0000_0000_0000) >> 12;
-					let background = (character_value & 0b0000_1111_0000_0000) >> 8;
+				for x in 0..screen::COLUMNS {
+					let cell = self.screen.cell(&dcpu.memory, x, y).unwrap();
 
 					character_data.push(Character {
-						character: character,
-						foreground: to_float_color(&self.pallet_ram, foreground as usize),
-						background: to_float_color(&self.pallet_ram, background as usize),
+						character: cell.character,
+						foreground: to_float_color(pallet, cell.foreground as usize),
+						background: to_float_color(pallet, cell.background as usize),
 					});
 				}
 
@@ -180,38 +218,8 @@ impl Lem1820 {
 			target.clear_color(0.0, 0.0, 0.0, 1.0);
 			target.finish().unwrap();
 		}
-	}
-
-	fn mem_map_screen(&mut self, dcpu: &mut dcpu::Dcpu) {
-		self.video_ram = dcpu.registers[dcpu::B];
-	}
-
-	fn mem_map_font(&mut self, dcpu: &mut dcpu::Dcpu) {
-		let ram_begin = dcpu.registers[dcpu::B] as usize;
-		let ram_end = ram_begin + 384;
-		self.font_texture = create_font_texture(&self.display, &dcpu.memory[ram_begin..ram_end]);
-	}
 
-	fn mem_map_pallet(&mut self, dcpu: &mut dcpu::Dcpu) {
-		let ram_begin = dcpu.registers[dcpu::B] as usize;
-		let ram_end = ram_begin + 16;
-		self.pallet_ram.clone_from_slice(&dcpu.memory[ram_begin..ram_end]);
-	}
-
-	fn set_border_color(&mut self, dcpu: &mut dcpu::Dcpu) {
-		self.border_color = dcpu.registers[dcpu::B] & 0xf;
-	}
-
-	fn mem_dump_font(&mut self, dcpu: &mut dcpu::Dcpu) {
-		let ram_begin = dcpu.registers[dcpu::B] as usize;
-		let ram_end = ram_begin + 384;
-		dcpu.memory[ram_begin..ram_end].clone_from_slice(&self.pallet_ram);
-	}
-
-	fn mem_dump_pallet(&mut self, dcpu: &mut dcpu::Dcpu) {
-		let ram_begin = dcpu.registers[dcpu::B] as usize;
-		let ram_end = ram_begin + 16;
-		dcpu.memory[ram_begin..ram_end].clone_from_slice(&self.pallet_ram);
+		REFRESH_INTERVAL
 	}
 }
 
@@ -359,48 +367,6 @@ void main() {
 "#;
 
 
-const DEFAULT_PALLET: [u16; 16] = [
-	0x000, 0x00a, 0x0a0, 0x0aa, 0xa00, 0xa0a, 0xa50, 0xaaa,
-	0x555, 0x55f, 0x5f5, 0x5ff, 0xf55, 0xf5f, 0xff5, 0xfff,
-];
-
-
-const DEFAULT_FONT: [u16; 256] = [
-	0x000f, 0x0808, 0x080f, 0x0808, 0x08f8, 0x0808, 0x00ff, 0x0808,
-	0x0808, 0x0808, 0x08ff, 0x0808, 0x00ff, 0x1414, 0xff00, 0xff08,
-	0x1f10, 0x1714, 0xfc04, 0xf414, 0x1710, 0x1714, 0xf404, 0xf414,
-	0xff00, 0xf714, 0x1414, 0x1414, 0xf700, 0xf714, 0x1417, 0x1414,
-	0x0f08, 0x0f08, 0x14f4, 0x1414, 0xf808, 0xf808, 0x0f08, 0x0f08,
-	0x001f, 0x1414, 0x00fc, 0x1414, 0xf808, 0xf808, 0xff08, 0xff08,
-	0x14ff, 0x1414, 0x080f, 0x0000, 0x00f8, 0x0808, 0xffff, 0xffff,
-	0xf0f0, 0xf0f0, 0xffff, 0x0000, 0x0000, 0xffff, 0x0f0f, 0x0f0f,
-	0x0000, 0x0000, 0x005f, 0x0000, 0x0300, 0x0300, 0x3e14, 0x3e00,
-	0x266b, 0x3200, 0x611c, 0x4300, 0x3629, 0x7650, 0x0002, 0x0100,
-	0x1c22, 0x4100, 0x4122, 0x1c00, 0x2a1c, 0x2a00, 0x083e, 0x0800,
-	0x4020, 0x0000, 0x0808, 0x0800, 0x0040, 0x0000, 0x601c, 0x0300,
-	0x3e41, 0x3e00, 0x427f, 0x4000, 0x6259, 0x4600, 0x2249, 0x3600,
-	0x0f08, 0x7f00, 0x2745, 0x3900, 0x3e49, 0x3200, 0x6119, 0x0700,
-	0x3649, 0x3600, 0x2649, 0x3e00, 0x0024, 0x0000, 0x4024, 0x0000,
-	0x0814, 0x2241, 0x1414, 0x1400, 0x4122, 0x1408, 0x0259, 0x0600,
-	0x3e59, 0x5e00, 0x7e09, 0x7e00, 0x7f49, 0x3600, 0x3e41, 0x2200,
-	0x7f41, 0x3e00, 0x7f49, 0x4100, 0x7f09, 0x0100, 0x3e49, 0x3a00,
-	0x7f08, 0x7f00, 0x417f, 0x4100, 0x2040, 0x3f00, 0x7f0c, 0x7300,
-	0x7f40, 0x4000, 0x7f06, 0x7f00, 0x7f01, 0x7e00, 0x3e41, 0x3e00,
-	0x7f09, 0x0600, 0x3e41, 0xbe00, 0x7f09, 0x7600, 0x2649, 0x3200,
-	0x017f, 0x0100, 0x7f40, 0x7f00, 0x1f60, 0x1f00, 0x7f30, 0x7f00,
-	0x7708, 0x7700, 0x0778, 0x0700, 0x7149, 0x4700, 0x007f, 0x4100,
-	0x031c, 0x6000, 0x0041, 0x7f00, 0x0201, 0x0200, 0x8080, 0x8000,
-	0x0001, 0x0200, 0x2454, 0x7800, 0x7f44, 0x3800, 0x3844, 0x2800,
-	0x3844, 0x7f00, 0x3854, 0x5800, 0x087e, 0x0900, 0x4854, 0x3c00,
-	0x7f04, 0x7800, 0x447d, 0x4000, 0x2040, 0x3d00, 0x7f10, 0x6c00,
-	0x417f, 0x4000, 0x7c18, 0x7c00, 0x7c04, 0x7800, 0x3844, 0x3800,
-	0x7c14, 0x0800, 0x0814, 0x7c00, 0x7c04, 0x0800, 0x4854, 0x2400,
-	0x043e, 0x4400, 0x3c40, 0x7c00, 0x1c60, 0x1c00, 0x7c30, 0x7c00,
-	0x6c10, 0x6c00, 0x4c50, 0x3c00, 0x6454, 0x4c00, 0x0836, 0x4100,
-	0x0077, 0x0000, 0x4136, 0x0800, 0x0201, 0x0201, 0x704c, 0x7000,
-];
-
-
 
 
 #[derive(Copy, Clone)]
@@ -0,0 +1,185 @@
+use std::cmp;
+use std::collections::VecDeque;
+
+
+/// How badly a modem's telephone line is behaving
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Impairment {
+	/// Chance that each received word has one of its bits flipped
+	pub bit_error_rate: f64,
+	/// Number of emulated cycles each word spends travelling down the line
+	pub latency: u32,
+	/// Up to this many extra cycles are randomly added to the latency
+	pub jitter: u32,
+	/// Chance per cycle that the carrier drops while connected
+	pub carrier_drop_rate: f64,
+	/// Seed for the random faults, so a run can be reproduced
+	pub seed: u64,
+}
+
+impl Impairment {
+	/// Whether the line behaves exactly like an unimpaired line
+	pub fn is_perfect(&self) -> bool {
+		self.bit_error_rate <= 0.0 && self.latency == 0 && self.jitter == 0 && self.carrier_drop_rate <= 0.0
+	}
+}
+
+
+
+/// A small xorshift* generator so that line faults are reproducible from a seed
+pub struct Rng {
+	state: u64,
+}
+
+impl Rng {
+	pub fn new(seed: u64) -> Rng {
+		// Xorshift gets stuck on a zero state
+		let state = seed ^ 0x9e37_79b9_7f4a_7c15;
+		Rng {state: if state == 0 {0x9e37_79b9_7f4a_7c15} else {state}}
+	}
+
+	pub fn next(&mut self) -> u64 {
+		self.state ^= self.state >> 12;
+		self.state ^= self.state << 25;
+		self.state ^= self.state >> 27;
+		self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+	}
+
+	/// Return true with the given probability
+	pub fn chance(&mut self, probability: f64) -> bool {
+		if probability <= 0.0 {
+			return false;
+		}
+
+		((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+	}
+
+	/// Return a number in `0..limit`
+	pub fn below(&mut self, limit: u32) -> u32 {
+		if limit == 0 {
+			0
+		} else {
+			(self.next() % limit as u64) as u32
+		}
+	}
+}
+
+
+
+/// The words currently travelling down an impaired line
+pub struct LineNoise {
+	impairment: Impairment,
+	rng: Rng,
+	in_flight: VecDeque<(u64, u16)>,
+}
+
+impl LineNoise {
+	pub fn new(impairment: Impairment) -> LineNoise {
+		LineNoise {
+			impairment: impairment,
+			rng: Rng::new(impairment.seed),
+			in_flight: VecDeque::new(),
+		}
+	}
+
+	/// Send words down the line at the given cycle, corrupting and delaying them as they go
+	pub fn transmit(&mut self, words: Vec<u16>, cycle: u64) {
+		for mut word in words {
+			if self.rng.chance(self.impairment.bit_error_rate) {
+				word ^= 1 << self.rng.below(16);
+			}
+
+			// Words can be delayed by different amounts but never overtake each other
+			let delay = self.impairment.latency + self.rng.below(self.impairment.jitter.saturating_add(1));
+			let mut arrival = cycle.saturating_add(delay as u64);
+			if let Some(&(previous, _)) = self.in_flight.back() {
+				arrival = cmp::max(arrival, previous);
+			}
+
+			self.in_flight.push_back((arrival, word));
+		}
+	}
+
+	/// Take every word that has reached the end of the line by the given cycle
+	pub fn arrived(&mut self, cycle: u64) -> Vec<u16> {
+		let mut words = Vec::new();
+		while self.in_flight.front().map_or(false, |&(arrival, _)| arrival <= cycle) {
+			words.push(self.in_flight.pop_front().unwrap().1);
+		}
+		words
+	}
+
+	/// When the next word still on the line reaches the end of it
+	pub fn next_arrival(&self) -> Option<u64> {
+		self.in_flight.front().map(|&(arrival, _)| arrival)
+	}
+
+	/// Decide whether the carrier dropped at some point during the given number of cycles
+	pub fn carrier_lost(&mut self, cycles: u64) -> bool {
+		let rate = self.impairment.carrier_drop_rate;
+		if rate <= 0.0 {
+			return false;
+		}
+
+		self.rng.chance(1.0 - (1.0 - rate.min(1.0)).powf(cycles as f64))
+	}
+
+	/// Forget any words still on the line from a previous call
+	pub fn clear(&mut self) {
+		self.in_flight.clear();
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn same_seed_same_faults() {
+		let impairment = Impairment {bit_error_rate: 0.5, jitter: 20, seed: 42, ..Impairment::default()};
+		let mut first = LineNoise::new(impairment);
+		let mut second = LineNoise::new(impairment);
+
+		first.transmit((0..100).collect(), 0);
+		second.transmit((0..100).collect(), 0);
+
+		let received = first.arrived(100);
+		assert_eq!(received, second.arrived(100));
+		assert_eq!(received.len(), 100);
+		assert!(received.iter().zip(0..100).any(|(&a, b)| a != b));
+	}
+
+	#[test]
+	fn latency_delays_words_in_order() {
+		let mut noise = LineNoise::new(Impairment {latency: 10, jitter: 5, seed: 7, ..Impairment::default()});
+		noise.transmit(vec![1, 2, 3], 100);
+
+		assert!(noise.arrived(109).is_empty());
+		assert!(noise.next_arrival().map_or(false, |arrival| arrival >= 110 && arrival <= 115));
+		assert_eq!(noise.arrived(115), vec![1, 2, 3]);
+		assert_eq!(noise.next_arrival(), None);
+	}
+
+	#[test]
+	fn carrier_drop_over_many_cycles() {
+		let impairment = Impairment {carrier_drop_rate: 0.001, seed: 3, ..Impairment::default()};
+		let mut noise = LineNoise::new(impairment);
+
+		// A drop is all but certain over ten thousand cycles, and very unlikely over one
+		assert_eq!((0..100).filter(|_| noise.carrier_lost(10_000)).count(), 100);
+		assert!((0..100).filter(|_| noise.carrier_lost(1)).count() < 5);
+	}
+
+	#[test]
+	fn perfect_line() {
+		let mut noise = LineNoise::new(Impairment::default());
+		noise.transmit(vec![0xffff, 0x0000], 5);
+
+		assert!(Impairment::default().is_perfect());
+		assert!(!noise.carrier_lost(1_000_000));
+		assert_eq!(noise.arrived(5), vec![0xffff, 0x0000]);
+	}
+}
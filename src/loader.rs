@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::fs;
+use symbols;
+
+// Words of memory an image has to fit in
+const MEMORY_WORDS: u32 = 0x10000;
+
+
+/// How the words of an image are stored
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+	/// Raw words, high byte first. An odd byte at the end is the high byte of the last word.
+	BigEndian,
+	/// Raw words, low byte first
+	LittleEndian,
+	/// Text of hexadecimal words separated by spaces or commas. A number followed
+	/// by a colon, like `1000:`, is the address the next words go at.
+	/// Anything after a `;` or `#` is a comment.
+	Hex,
+	/// Intel HEX records, addressed in bytes, with each word's high byte first
+	IntelHex,
+}
+
+impl Format {
+	/// The format with the given name, or `None` for auto, which works it out from the image
+	pub fn parse(name: &str) -> Result<Option<Format>, String> {
+		match name {
+			"auto" => Ok(None),
+			"big-endian" => Ok(Some(Format::BigEndian)),
+			"little-endian" => Ok(Some(Format::LittleEndian)),
+			"hex" => Ok(Some(Format::Hex)),
+			"intel-hex" => Ok(Some(Format::IntelHex)),
+			_ => Err(format!("Unknown image format {}. Choose auto, big-endian, little-endian, hex or intel-hex.", name)),
+		}
+	}
+
+
+	/// Work out an image's format from what is in it. Images of raw words can't
+	/// tell which way round their bytes are, so they are taken to be big endian.
+	pub fn detect(bytes: &[u8]) -> Format {
+		let text = match ::std::str::from_utf8(bytes) {
+			Ok(text) if !text.trim().is_empty() => text,
+			_ => return Format::BigEndian,
+		};
+
+		if text.lines().map(str::trim).filter(|line| !line.is_empty()).all(|line| line.starts_with(':')) {
+			Format::IntelHex
+		} else if decode_hex(text).is_ok() {
+			Format::Hex
+		} else {
+			Format::BigEndian
+		}
+	}
+}
+
+
+/// Words to be loaded into memory starting at an address
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+	pub address: u16,
+	pub words: Vec<u16>,
+}
+
+
+/// Split where an image is to be loaded, given like `ship.bin@0x1000`, into the
+/// path and the address. Images without an address are loaded at zero.
+pub fn parse_location(location: &str) -> (&str, u16) {
+	let address = location.rfind('@')
+		.and_then(|at| symbols::parse_number(&location[at + 1..]).map(|address| (at, address)));
+
+	match address {
+		Some((at, address)) => (&location[..at], address),
+		None => (location, 0),
+	}
+}
+
+
+/// Read the image at the path, in the given format or whichever it looks like,
+/// moved along memory by the offset
+pub fn read(path: &str, format: Option<Format>, offset: u16) -> Result<Vec<Segment>, String> {
+	let bytes = fs::read(path).map_err(|e| e.to_string())?;
+	let format = format.unwrap_or_else(|| Format::detect(&bytes));
+	let segments = decode(&bytes, format)?;
+	relocate(segments, offset)
+}
+
+
+/// Copy the segments into memory, returning the address after the last word
+/// loaded. Segments loaded later write over earlier ones.
+pub fn load(memory: &mut [u16], segments: &[Segment]) -> usize {
+	let mut end = 0;
+	for segment in segments {
+		let start = segment.address as usize;
+		memory[start..start + segment.words.len()].copy_from_slice(&segment.words);
+		end = end.max(start + segment.words.len());
+	}
+	end
+}
+
+
+/// The segments stored in an image of the given format
+pub fn decode(bytes: &[u8], format: Format) -> Result<Vec<Segment>, String> {
+	match format {
+		Format::BigEndian | Format::LittleEndian => {
+			let words: Vec<u16> = bytes.chunks(2)
+				.map(|pair| {
+					let (first, second) = (pair[0] as u16, *pair.get(1).unwrap_or(&0) as u16);
+					if format == Format::BigEndian {first << 8 | second} else {second << 8 | first}
+				})
+				.collect();
+			Ok(vec![Segment {address: 0, words: words}])
+		},
+
+		Format::Hex | Format::IntelHex => {
+			let text = ::std::str::from_utf8(bytes).map_err(|_| "The image isn't text".to_string())?;
+			if format == Format::Hex {decode_hex(text)} else {decode_intel_hex(text)}
+		},
+	}
+}
+
+
+// Move the segments along memory, making sure they still fit in it
+fn relocate(segments: Vec<Segment>, offset: u16) -> Result<Vec<Segment>, String> {
+	segments.into_iter()
+		.map(|segment| {
+			let start = segment.address as u32 + offset as u32;
+			if start + segment.words.len() as u32 > MEMORY_WORDS {
+				return Err(format!("{} words loaded at 0x{:04x} don't fit in the 64K words of memory",
+					segment.words.len(), start));
+			}
+
+			Ok(Segment {address: start as u16, words: segment.words})
+		})
+		.collect()
+}
+
+
+// Gather words, each at an address, into runs of consecutive words
+fn gather<I>(words: I) -> Vec<Segment> where I: IntoIterator<Item = (u32, u16)> {
+	let mut segments: Vec<Segment> = vec![];
+	let mut next = None;
+	for (address, word) in words {
+		if next != Some(address) {
+			segments.push(Segment {address: address as u16, words: vec![]});
+		}
+		segments.last_mut().unwrap().words.push(word);
+		next = Some(address + 1);
+	}
+	segments
+}
+
+
+fn decode_hex(text: &str) -> Result<Vec<Segment>, String> {
+	let mut words = vec![];
+	let mut address = 0u32;
+
+	for (number, line) in text.lines().enumerate() {
+		let line = line.split(|c| c == ';' || c == '#').next().unwrap();
+		for token in line.split(|c: char| c.is_whitespace() || c == ',').filter(|token| !token.is_empty()) {
+			let is_address = token.ends_with(':');
+			let digits = if is_address {&token[..token.len() - 1]} else {token};
+			let digits = if digits.starts_with("0x") {&digits[2..]} else {digits};
+
+			let value = match u16::from_str_radix(digits, 16) {
+				Ok(value) if digits.len() <= 4 => value,
+				_ => return Err(format!("Line {}: {} isn't a hexadecimal word", number + 1, token)),
+			};
+
+			if is_address {
+				address = value as u32;
+			} else if address >= MEMORY_WORDS {
+				return Err(format!("Line {}: the image runs past the end of the 64K words of memory", number + 1));
+			} else {
+				words.push((address, value));
+				address += 1;
+			}
+		}
+	}
+
+	Ok(gather(words))
+}
+
+
+fn decode_intel_hex(text: &str) -> Result<Vec<Segment>, String> {
+	// Bytes by their address, and the address the records' addresses are from
+	let mut bytes = BTreeMap::new();
+	let mut base = 0u32;
+
+	for (number, line) in text.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+
+		let error = |problem: &str| format!("Line {}: {}", number + 1, problem);
+		if !line.starts_with(':') || !line.is_ascii() || line.len() % 2 == 0 {
+			return Err(error("expected a record like :0400100012345678d8"));
+		}
+
+		let record = (1..line.len()).step_by(2)
+			.map(|i| u8::from_str_radix(&line[i..i + 2], 16))
+			.collect::<Result<Vec<u8>, _>>()
+			.map_err(|_| error("the record isn't hexadecimal"))?;
+
+		if record.len() < 5 || record.len() != record[0] as usize + 5 {
+			return Err(error("the record's length is wrong"));
+		}
+
+		if record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+			return Err(error("the record's checksum is wrong"));
+		}
+
+		let address = (record[1] as u32) << 8 | record[2] as u32;
+		let data = &record[4..record.len() - 1];
+		match record[3] {
+			0 => for (i, &byte) in data.iter().enumerate() {
+				match base.checked_add(address + i as u32) {
+					Some(byte_address) if byte_address / 2 < MEMORY_WORDS => bytes.insert(byte_address, byte),
+					_ => return Err(error("the record runs past the end of the 64K words of memory")),
+				};
+			},
+			1 => break,
+			2 if data.len() == 2 => base = ((data[0] as u32) << 8 | data[1] as u32) << 4,
+			4 if data.len() == 2 => base = ((data[0] as u32) << 8 | data[1] as u32) << 16,
+			// Start addresses mean nothing to the DCPU
+			3 | 5 => (),
+			_ => return Err(error("the record's type isn't one the loader knows")),
+		}
+	}
+
+	// Pair the bytes up into words, high byte first
+	let mut words = BTreeMap::new();
+	for (&address, &byte) in &bytes {
+		let word = words.entry(address / 2).or_insert(0u16);
+		*word |= if address % 2 == 0 {(byte as u16) << 8} else {byte as u16};
+	}
+
+	Ok(gather(words))
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn raw_words() {
+		let bytes = [0x7c, 0x01, 0x00, 0x30, 0xab];
+		assert_eq!(decode(&bytes, Format::BigEndian).unwrap(), vec![Segment {address: 0, words: vec![0x7c01, 0x0030, 0xab00]}]);
+		assert_eq!(decode(&bytes, Format::LittleEndian).unwrap(), vec![Segment {address: 0, words: vec![0x017c, 0x3000, 0x00ab]}]);
+	}
+
+	#[test]
+	fn hex_dumps() {
+		let text = "; a program\n7c01 0x0030, 8b83\n\n1000: ffff # the data\n 0 1 ";
+		assert_eq!(decode(text.as_bytes(), Format::Hex).unwrap(), vec![
+			Segment {address: 0, words: vec![0x7c01, 0x0030, 0x8b83]},
+			Segment {address: 0x1000, words: vec![0xffff, 0, 1]},
+		]);
+
+		assert!(decode(b"7c01 12345", Format::Hex).unwrap_err().starts_with("Line 1: 12345"));
+		assert!(decode(b"ffff: 1 2", Format::Hex).is_err());
+	}
+
+	#[test]
+	fn intel_hex() {
+		// Two words at byte 0x10, a byte at 0x21 through an extended linear address of 0, then the end
+		let text = ":040010007c0100303f\n:020000040000fa\n:01002100ffdf\n:00000001FF\n:0400000011111111bb\n";
+		assert_eq!(decode(text.as_bytes(), Format::IntelHex).unwrap(), vec![
+			Segment {address: 8, words: vec![0x7c01, 0x0030]},
+			Segment {address: 0x10, words: vec![0x00ff]},
+		]);
+
+		assert_eq!(decode(b":040010007c01003040", Format::IntelHex).unwrap_err(), "Line 1: the record's checksum is wrong");
+		assert!(decode(b":020000040002f8\n:020000001234b8\n", Format::IntelHex).unwrap_err().contains("past the end"));
+		// Bytes past the last address a record can reach
+		assert_eq!(decode(b":02000004fffffc\n:04fffe001122334455\n", Format::IntelHex).unwrap_err(),
+			"Line 2: the record runs past the end of the 64K words of memory");
+	}
+
+	#[test]
+	fn detection() {
+		assert_eq!(Format::detect(b":00000001FF\n"), Format::IntelHex);
+		assert_eq!(Format::detect(b"7c01 0030\n"), Format::Hex);
+		assert_eq!(Format::detect(&[0x7c, 0x01, 0x00, 0x30]), Format::BigEndian);
+		assert_eq!(Format::detect(b""), Format::BigEndian);
+		assert_eq!(Format::parse("auto"), Ok(None));
+		assert!(Format::parse("elf").is_err());
+	}
+
+	#[test]
+	fn offsets_and_fitting_in_memory() {
+		assert_eq!(parse_location("ship.bin"), ("ship.bin", 0));
+		assert_eq!(parse_location("me@ship.bin@0x1000"), ("me@ship.bin", 0x1000));
+		assert_eq!(parse_location("me@ship.bin"), ("me@ship.bin", 0));
+
+		let segments = decode(&[0; 8], Format::BigEndian).unwrap();
+		assert_eq!(relocate(segments.clone(), 0xfffc).unwrap()[0].address, 0xfffc);
+		assert!(relocate(segments, 0xfffd).is_err());
+
+		// An image bigger than memory is an error rather than a panic
+		let segments = decode(&vec![0; 0x20002], Format::BigEndian).unwrap();
+		assert_eq!(relocate(segments, 0).unwrap_err(), "65537 words loaded at 0x0000 don't fit in the 64K words of memory");
+
+		let mut memory = vec![0; 0x10000];
+		let segments = vec![Segment {address: 2, words: vec![1, 2]}, Segment {address: 0, words: vec![3, 4, 5]}];
+		assert_eq!(load(&mut memory, &segments), 4);
+		assert_eq!(&memory[..5], &[3, 4, 5, 2, 0]);
+	}
+}
@@ -3,172 +3,646 @@ extern crate glium;
 #[macro_use]
 extern crate serde_derive;
 extern crate docopt;
+extern crate ctrlc;
+extern crate serde_json;
+extern crate toml;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 
 mod dcpu;
 mod modem;
+mod transport;
+mod line_noise;
+mod capture;
 mod lem1820;
+mod keyboard;
+mod screen;
+mod clock;
+mod events;
+mod session;
+mod system;
+mod symbols;
+mod disassembler;
+mod debugger;
+mod gdb;
+mod savestate;
+mod history;
+mod trace;
+mod profile;
+mod coverage;
+mod spec;
+mod decode;
+mod scheduler;
+mod batch;
+mod golden;
+mod loader;
+mod config;
+#[cfg(test)]
+mod conformance;
+#[cfg(test)]
+mod reference;
+#[cfg(test)]
+mod test_support;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use system::{System, HardwareType};
+use keyboard::Keyboard;
 
 const USAGE: &'static str = "
 dcpu
 
 Usage:
-	dcpu start <image> [-l | -lem1820] [-e | --eklectic] [-k | --keyboard]
+	dcpu start <image> [--load <image>]... [-l | -lem1820] [-e | --eklectic]... [-k | --keyboard] [-c | --clock] [options]
+	dcpu start --config <file> [options]
+	dcpu debug <image> [--load <image>]... [-l | -lem1820] [-e | --eklectic]... [-k | --keyboard] [-c | --clock] [--symbols <file>] [--gdb <port>] [options]
+	dcpu debug --config <file> [--symbols <file>] [--gdb <port>] [options]
 	dcpu assemble <file> [-o <outfile> | --output <outfile>]
+	dcpu bench <image> [--cycles <count>] [options]
+	dcpu fleet <images>... [--threads] [--quantum <cycles>] [options]
+	dcpu golden <image> <golden> [--load <image>]... [--cycles <count>] [--keys <file>] [-c | --clock] [--update] [options]
+	dcpu run <image> [--load <image>]... [--headless] [--max-cycles <count>] [-l | -lem1820] [-e | --eklectic]... [-k | --keyboard] [-c | --clock] [--result-address <addr>] [--exit-register <name>] [--keys <file>] [--screen <file>] [options]
 
 Options:
 	-l, --lem1820     Attach an LEM1820 Monitor
-	-e, --eklectic    Attach a Tesla Eklectic Modem
-	-k, --keyboard    Attach a generic keyboard
+	-e, --eklectic    Attach a Tesla Eklectic Modem, once for each modem wanted. The first answers calls on port 6483, the next on 6484 and so on, and dialing a port's number calls the modem on it.
+	-k, --keyboard    Attach a generic keyboard, which types what is pressed in the monitor's window
+	-c, --clock       Attach a generic clock
 	-o, --output      Set the file to output the assembled image to
+	--config <file>           Build the machine from a TOML or JSON description of its devices, in order, and its spec, speed and images
+	--format <format>         How images are stored: big-endian, little-endian, hex or intel-hex, or auto if not given, which works it out from each image
+	--load <image>            Load another image as well, once for each one. Any image can be given as file@address to load it at that address.
+	--symbols <file>          Load labels for the debugger and profiler from an assembler's symbol file
+	--profile <file>          Write where the CPU spent its cycles to a file once it halts or the emulator stops
+	--coverage <file>         Write which instructions and branches ran to an LCOV file once the CPU halts or the emulator stops
+	--source-map <file>       Report coverage by source line, from a file of addresses and file:line locations
+	--trace <file>            Write every instruction the CPU runs to a file
+	--trace-last <count>      Only write the last few instructions, once the CPU halts or the emulator stops [default: 0]
+	--trace-range <range>     Only trace instructions between two addresses, like 0x1000-0x1fff
+	--trace-jumps             Only trace instructions that jump
+	--trace-hardware          Only trace hwn, hwq and hwi
+	--history <count>         Instructions the debugger remembers so it can run backwards [default: 100000]
+	--gdb <port>              Let GDB debug over a TCP port, or over standard input and output if the port is -
+	--on-fault <policy>       When the DCPU catches fire or runs an illegal instruction: halt, trap into the debugger, or ignore it [default: halt]
+	--strict                  Treat a write to a literal operand as a fault, instead of losing it as the spec says
+	--spec <version>          Follow version 1.1, 1.7 or 2.0 of the DCPU-16 specification, or 1.7 if not given. The debugger, GDB, traces, profiles and coverage need 1.7 or later.
+	--load-state <file>       Start from a machine saved with F5, with the same devices attached. It keeps the spec it was saved with, so --spec can't be given too.
+	--save-state <file>       File to save the machine to when F5 is pressed [default: dcpu.state]
+	--line-noise <rate>       Chance that each word the modem receives has a bit flipped [default: 0]
+	--line-latency <cycles>   Cycles each word takes to travel down the modem line [default: 0]
+	--line-jitter <cycles>    Random extra cycles added to the line latency [default: 0]
+	--carrier-drop <rate>     Chance per cycle that the modem loses its connection [default: 0]
+	--seed <seed>             Seed for the simulated line faults [default: 0]
+	--capture <file>          Record everything the modem does to a file
+	--replay <file>           Plug the modem into a line that replays a captured session
+	--call-waiting            Let a second caller wait instead of hearing a busy line
+	--record-session <file>   Record every key pressed and everything the modems' lines do, so the session can be replayed exactly
+	--replay-session <file>   Replay a recorded session, started with the same image, devices and options it was recorded with
+	--verify                  Check a replayed session against the state of the machine when it was recorded, and stop at the end of it
+	--cycles <count>          Cycles to run the image for, with each way of running it when benchmarking [default: 10000000]
+	--threads                 Run each machine in a fleet on a thread of its own
+	--quantum <cycles>        Cycles each machine in a fleet runs before waiting for the others [default: 1000]
+	--headless                Attach devices without windows or sockets: the monitor only keeps what it shows, the keyboard only types --keys and the modems have no line
+	--max-cycles <count>      Cycles to run for before giving up, exiting with 255 [default: 10000000]
+	--result-address <addr>   Stop as soon as the program writes to an address, exiting with the value written
+	--exit-register <name>    Register holding the exit code when the program stops in a loop or with hcf [default: A]
+	--keys <file>             Type the text in a file on the keyboard, one key each time it is polled
+	--screen <file>           Write the text on the monitor's screen to a file when the run stops, or to standard output if the file is -
+	--update                  Write what is on the screen to the golden file instead of comparing it
 ";
 
 #[derive(Debug, Deserialize)]
 struct Arguments {
 	flag_lem1820: bool,
-	flag_eklectic: bool,
+	flag_eklectic: usize,
 	flag_keyboard: bool,
+	flag_clock: bool,
+	flag_line_noise: f64,
+	flag_line_latency: u32,
+	flag_line_jitter: u32,
+	flag_carrier_drop: f64,
+	flag_seed: u64,
+	flag_capture: Option<String>,
+	flag_replay: Option<String>,
+	flag_call_waiting: bool,
+	flag_record_session: Option<String>,
+	flag_replay_session: Option<String>,
+	flag_verify: bool,
+	flag_symbols: Option<String>,
+	flag_profile: Option<String>,
+	flag_coverage: Option<String>,
+	flag_source_map: Option<String>,
+	flag_history: usize,
+	flag_trace: Option<String>,
+	flag_trace_last: usize,
+	flag_trace_range: Option<String>,
+	flag_trace_jumps: bool,
+	flag_trace_hardware: bool,
+	flag_gdb: Option<String>,
+	flag_spec: Option<String>,
+	flag_on_fault: String,
+	flag_strict: bool,
+	flag_load_state: Option<String>,
+	flag_save_state: String,
+	flag_cycles: u64,
+	flag_threads: bool,
+	flag_quantum: u64,
+	flag_config: Option<String>,
+	flag_format: Option<String>,
+	flag_load: Vec<String>,
+	flag_headless: bool,
+	flag_max_cycles: u64,
+	flag_result_address: Option<String>,
+	flag_exit_register: String,
+	flag_keys: Option<String>,
+	flag_screen: Option<String>,
+	flag_update: bool,
 	cmd_start: bool,
+	cmd_debug: bool,
 	cmd_assemble: bool,
+	cmd_bench: bool,
+	cmd_fleet: bool,
+	cmd_run: bool,
+	cmd_golden: bool,
 	arg_image: Option<String>,
+	arg_images: Vec<String>,
+	arg_golden: Option<String>,
 	arg_file: Option<String>,
 	arg_outfile: Option<String>,
 }
 
 
 
-use std::collections::VecDeque;
+// Build the modem with the given index as described by the command line arguments.
+// Each modem after the first answers on the next port up and captures or
+// replays from a file with its index appended to the name.
+fn attach_modem(arguments: &Arguments, index: usize) -> Result<modem::Modem, String> {
+	let port = 6483 + index as u16;
+	let file_name = |path: &String| if index == 0 {path.clone()} else {format!("{}.{}", path, index)};
+
+	let mut modem = match arguments.flag_replay {
+		Some(ref path) =>
+			capture::ReplayTransport::open(&file_name(path))
+				.map(|replay| modem::Modem::with_transport(Box::new(replay)))
+				.map_err(|e| format!("Unable to replay {}: {}", file_name(path), e))?,
+
+		None =>
+			modem::Modem::new(port).map_err(|e|
+				if e.kind() == std::io::ErrorKind::AddrInUse {
+					format!("Unable to attach the modem: port {} is already in use", port)
+				} else {
+					format!("Unable to attach the modem: {}", e)
+				})?,
+	};
+
+	if let Some(ref path) = arguments.flag_capture {
+		let capture = capture::Capture::create(&file_name(path))
+			.map_err(|e| format!("Unable to capture to {}: {}", file_name(path), e))?;
+		modem.capture(capture);
+	}
+
+	modem.allow_call_waiting(arguments.flag_call_waiting);
+	modem.impair(line_noise::Impairment {
+		bit_error_rate: arguments.flag_line_noise,
+		latency: arguments.flag_line_latency,
+		jitter: arguments.flag_line_jitter,
+		carrier_drop_rate: arguments.flag_carrier_drop,
+		seed: arguments.flag_seed.wrapping_add(index as u64),
+	});
 
-struct Keyboard {
-	events_loop: glium::glutin::EventsLoop,
-	keyboard_buffer: VecDeque<u16>,
-	keyboard_interrupt: u16,
-	last_refresh: std::time::Instant,
+	Ok(modem)
 }
 
 
-impl Keyboard {
-	fn new(events_loop: glium::glutin::EventsLoop) -> Keyboard {
-		Keyboard {
-			events_loop: events_loop,
-			keyboard_buffer: VecDeque::new(),
-			keyboard_interrupt: 0,
-			last_refresh: std::time::Instant::now(),
-		}
+// Trace the CPU as described by the command line arguments, if asked to
+fn attach_trace(arguments: &Arguments, dcpu: &mut dcpu::Dcpu) -> Result<(), String> {
+	let path = match arguments.flag_trace {
+		Some(ref path) => path,
+		None => return Ok(()),
+	};
+
+	let range = match arguments.flag_trace_range {
+		Some(ref range) => {
+			let mut ends = range.splitn(2, '-').map(symbols::parse_number);
+			match (ends.next(), ends.next()) {
+				(Some(Some(start)), Some(Some(end))) => Some((start, end)),
+				_ => return Err(format!("Expected a trace range like 0x1000-0x1fff, not {}", range)),
+			}
+		},
+		None => None,
+	};
+
+	let filter = trace::Filter {
+		range: range,
+		only_jumps: arguments.flag_trace_jumps,
+		only_hardware: arguments.flag_trace_hardware,
+	};
+
+	let output = std::fs::File::create(path).map_err(|e| format!("Unable to trace to {}: {}", path, e))?;
+	let output = Box::new(std::io::BufWriter::new(output));
+	dcpu.trace_sinks.push(Box::new(
+		if arguments.flag_trace_last > 0 {
+			trace::Tracer::ring(output, filter, arguments.flag_trace_last)
+		} else {
+			trace::Tracer::new(output, filter)
+		}));
+
+	Ok(())
+}
+
+
+// Load the symbols given on the command line, if there are any
+fn load_symbols(arguments: &Arguments) -> Result<symbols::Symbols, String> {
+	match arguments.flag_symbols {
+		Some(ref path) => symbols::Symbols::load(path).map_err(|e| format!("Unable to load symbols from {}: {}", path, e)),
+		None => Ok(symbols::Symbols::new()),
 	}
+}
 
 
-	fn step(&mut self, dcpu: &mut dcpu::Dcpu) {
-		if let None = self.last_refresh.elapsed().checked_sub(std::time::Duration::new(0, 50_000_000)) {
-			return;
+// Profile the CPU as described by the command line arguments, if asked to
+fn attach_profiler(arguments: &Arguments, dcpu: &mut dcpu::Dcpu, symbols: &symbols::Symbols) -> Result<(), String> {
+	if let Some(ref path) = arguments.flag_profile {
+		let output = std::fs::File::create(path).map_err(|e| format!("Unable to write a profile to {}: {}", path, e))?;
+		dcpu.trace_sinks.push(Box::new(profile::Profiler::new(Some(symbols.clone()), Some(Box::new(output)))));
+	}
+
+	Ok(())
+}
+
+
+// Record the CPU's coverage as described by the command line arguments, if asked
+// to. The image named is the one that went into memory up to the given length.
+fn attach_coverage(arguments: &Arguments, dcpu: &mut dcpu::Dcpu, image: &str, image_length: usize, symbols: &symbols::Symbols) -> Result<(), String> {
+	let path = match arguments.flag_coverage {
+		Some(ref path) => path,
+		None => return Ok(()),
+	};
+
+	let source_map = match arguments.flag_source_map {
+		Some(ref path) => Some(coverage::SourceMap::load(path).map_err(|e| format!("Unable to load a source map from {}: {}", path, e))?),
+		None => None,
+	};
+
+	let output = std::fs::File::create(path).map_err(|e| format!("Unable to write coverage to {}: {}", path, e))?;
+	let program = dcpu.memory[..image_length].to_vec();
+	let sink = coverage::CoverageSink::new(loader::parse_location(image).0, program, Some(symbols.clone()), source_map, Some(Box::new(output)));
+	dcpu.trace_sinks.push(Box::new(sink));
+	Ok(())
+}
+
+
+// Read the image at a location given on the command line, like ship.bin@0x1000
+fn read_image(format: &str, location: &str) -> Result<Vec<loader::Segment>, String> {
+	let format = loader::Format::parse(format)?;
+	let (path, offset) = loader::parse_location(location);
+	loader::read(path, format, offset).map_err(|e| format!("Unable to load {}: {}", path, e))
+}
+
+
+// Load the image and any others given with --load into memory, in order,
+// returning the address after the last word loaded
+fn load_images(arguments: &Arguments, dcpu: &mut dcpu::Dcpu) -> Result<usize, String> {
+	let locations: Vec<String> = arguments.arg_image.iter().chain(arguments.flag_load.iter()).cloned().collect();
+	load_all(image_format(arguments), &locations, dcpu)
+}
+
+
+// How the images on the command line are stored
+fn image_format(arguments: &Arguments) -> &str {
+	arguments.flag_format.as_ref().map_or("auto", |format| format.as_str())
+}
+
+
+// Load images stored in the named format into memory, in order, returning the address after the last word loaded
+fn load_all(format: &str, locations: &[String], dcpu: &mut dcpu::Dcpu) -> Result<usize, String> {
+	let mut end = 0;
+	for location in locations {
+		let segments = read_image(format, location)?;
+		end = end.max(loader::load(&mut dcpu.memory, &segments));
+	}
+	Ok(end)
+}
+
+
+// How the command line asks the CPU of every machine it builds to behave
+#[derive(Copy, Clone)]
+struct CpuOptions {
+	spec: spec::Spec,
+	fault_policy: dcpu::FaultPolicy,
+	strict_operands: bool,
+}
+
+impl CpuOptions {
+	// The spec is 1.7 unless one is given
+	fn parse(arguments: &Arguments) -> Result<CpuOptions, String> {
+		let spec = match arguments.flag_spec {
+			Some(ref version) => spec::Spec::parse(version)
+				.ok_or_else(|| format!("Unknown spec version {}. Choose 1.1, 1.7 or 2.0.", version))?,
+			None => spec::Spec::default(),
+		};
+
+		let fault_policy = dcpu::FaultPolicy::parse(&arguments.flag_on_fault)
+			.ok_or_else(|| format!("Unknown fault policy {}. Choose halt, trap or ignore.", arguments.flag_on_fault))?;
+
+		Ok(CpuOptions {
+			spec: spec,
+			fault_policy: fault_policy,
+			strict_operands: arguments.flag_strict,
+		})
+	}
+
+	fn apply(&self, dcpu: &mut dcpu::Dcpu) {
+		dcpu.spec = self.spec;
+		dcpu.fault_policy = self.fault_policy;
+		dcpu.strict_operands = self.strict_operands;
+		dcpu.log_sink = Some(Box::new(print_log));
+	}
+}
+
+
+// The debugger, GDB, traces, profiles and coverage decode instructions as 1.7
+// does, so refuse to use them with a machine following 1.1
+fn check_tools(arguments: &Arguments, spec: spec::Spec) -> Result<(), String> {
+	if spec != spec::Spec::V1_1 {
+		return Ok(());
+	}
+
+	let tools = [
+		(arguments.cmd_debug, "The debugger"),
+		(arguments.flag_trace.is_some(), "--trace"),
+		(arguments.flag_profile.is_some(), "--profile"),
+		(arguments.flag_coverage.is_some(), "--coverage"),
+		(arguments.flag_on_fault == "trap", "--on-fault trap"),
+	];
+	match tools.iter().find(|&&(used, _)| used) {
+		Some(&(_, tool)) => Err(format!("{} only understands the instructions of spec 1.7 and later, not 1.1", tool)),
+		None => Ok(()),
+	}
+}
+
+
+// Print what the program logs with 2.0's log instruction
+fn print_log(value: u16) {
+	eprintln!("DCPU log: 0x{:04x} {}", value, value as i16);
+}
+
+
+// Wait for the wall clock to catch up with the given cycles, run at the given cycles a second
+fn pace(started: std::time::Instant, cycles: u64, speed: u64) {
+	let emulated = std::time::Duration::from_micros(cycles * 1_000_000 / speed);
+	let elapsed = started.elapsed();
+	if emulated > elapsed {
+		std::thread::sleep(emulated - elapsed);
+	}
+}
+
+
+// Run the image with no devices attached, first a cycle at a time with `System::step`
+// and then with `System::run_cycles`, and say how fast each was
+fn benchmark(arguments: &Arguments) -> Result<(), String> {
+	let options = CpuOptions::parse(arguments)?;
+
+	let load = || -> Result<System, String> {
+		let mut system = System::new(vec![]);
+		options.apply(&mut system.dcpu);
+		load_images(arguments, &mut system.dcpu)?;
+		Ok(system)
+	};
+
+	let report = |name: &str, cycles: u64, elapsed: std::time::Duration| {
+		let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+		let hertz = cycles as f64 / seconds;
+		println!("{:<12}{} cycles in {:.3}s, {:.2} MHz, {:.0} times a 100 kHz DCPU", name, cycles, seconds, hertz / 1e6, hertz / dcpu::CLOCK_RATE as f64);
+	};
+
+	let mut system = load()?;
+	let start = std::time::Instant::now();
+	for _ in 0..arguments.flag_cycles {
+		system.step();
+	}
+	report("step", arguments.flag_cycles, start.elapsed());
+
+	let mut system = load()?;
+	let start = std::time::Instant::now();
+	let cycles = system.run_cycles(arguments.flag_cycles);
+	report("run_cycles", cycles, start.elapsed());
+
+	Ok(())
+}
+
+
+// Run a machine for each image, each with a modem plugged into a switchboard
+// shared by all of them. The modems are numbered from 1 in the order the images
+// were given, so the first ship calls the second by dialing 2.
+fn fleet(arguments: &Arguments) -> Result<(), String> {
+	let options = CpuOptions::parse(arguments)?;
+	let panicked = |panicked: scheduler::Panicked| format!("The machine running {} panicked: {}", arguments.arg_images[panicked.index], panicked.message);
+
+	let mut scheduler = scheduler::Scheduler::new(arguments.flag_quantum);
+	for (index, path) in arguments.arg_images.iter().enumerate() {
+		let image = read_image(image_format(arguments), path)?;
+		let switchboard = scheduler.switchboard();
+		let number = index as u32 + 1;
+
+		let build = move || {
+			let modem = modem::Modem::with_transport(Box::new(switchboard.connect(number)));
+			let mut system = System::new(vec![HardwareType::Eklectic(modem)]);
+			options.apply(&mut system.dcpu);
+			loader::load(&mut system.dcpu.memory, &image);
+			system
+		};
+
+		if arguments.flag_threads {
+			scheduler.spawn(build).map_err(&panicked)?;
+		} else {
+			scheduler.add(build());
 		}
+	}
 
+	// Keep going until every ship has caught fire, reporting each one as it does
+	let mut faulted = vec![false; scheduler.len()];
+	while faulted.iter().any(|&faulted| !faulted) {
+		scheduler.run_cycles(100_000).map_err(&panicked)?;
 
-		let mut character = None;
-		self.events_loop.poll_events(|e| {
-			match e {
-				glium::glutin::Event::WindowEvent {event, ..} =>
-					match event {
-						glium::glutin::WindowEvent::ReceivedCharacter(c) => {
-							if c.is_ascii() {
-								let converted: u8 = c as u8;
-
-								if converted >= 0x20 && converted < 0x7f {
-									character = Some(converted as u16);
-								}
-							}
-						},
-
-						glium::glutin::WindowEvent::KeyboardInput {input: glium::glutin::KeyboardInput {virtual_keycode, state: glium::glutin::ElementState::Pressed, ..}, ..} => {
-							use glium::glutin::VirtualKeyCode as Vk;
-							match virtual_keycode {
-								Some(Vk::Back) => character = Some(0x10),
-								Some(Vk::Return) => character = Some(0x11),
-								Some(Vk::Insert) => character = Some(0x12),
-								Some(Vk::Delete) => character = Some(0x13),
-								Some(Vk::Up) => character = Some(0x80),
-								Some(Vk::Down) => character = Some(0x81),
-								Some(Vk::Left) => character = Some(0x82),
-								Some(Vk::Right) => character = Some(0x83),
-								Some(Vk::RShift) | Some(Vk::LShift) => character = Some(0x90),
-								Some(Vk::RControl) | Some(Vk::LControl) => character = Some(0x91),
-								_ => (),
-							}
-						}
-
-						_ => (),
-					},
-
-				_ => ()
+		for index in 0..scheduler.len() {
+			if faulted[index] {
+				continue;
 			}
-		});
 
-		if let Some(c) = character {
-			self.keyboard_buffer.push_back(c);
+			if let Some(fault) = scheduler.visit(index, |system| system.dcpu.fault).map_err(&panicked)? {
+				eprintln!("{}: {}", arguments.arg_images[index], fault);
+				faulted[index] = true;
+			}
 		}
 	}
 
+	Ok(())
+}
+
 
-	pub fn interrupt(&mut self, dcpu: &mut dcpu::Dcpu) {
-		match dcpu.registers[dcpu::A] {
-			0 => self.keyboard_buffer.clear(),
-			1 => dcpu.registers[dcpu::C] = self.keyboard_buffer.pop_front().unwrap_or(0),
-			2 => unimplemented!(),
-			3 => self.keyboard_interrupt = dcpu.registers[dcpu::B],
-			_ => (),
+// Attach the devices asked for on the command line: a monitor, keyboard, modems and clock, in that order
+fn attach_devices(arguments: &Arguments) -> Result<Vec<HardwareType>, String> {
+	let mut hardware = Vec::new();
+	let mut window_events = None;
+
+	if arguments.flag_lem1820 && arguments.flag_headless {
+		hardware.push(HardwareType::HeadlessLem1820(screen::Screen::new()));
+	} else if arguments.flag_lem1820 {
+		let mut lem = lem1820::Lem1820::new();
+		if arguments.flag_keyboard {
+			window_events = Some(lem.window_events());
+		}
+		hardware.push(HardwareType::Lem1820(lem));
+	}
+
+	// Without a monitor's window there is nothing to type in, so the keyboard only types --keys
+	if arguments.flag_keyboard {
+		hardware.push(HardwareType::Keyboard(match window_events {
+			Some(window_events) => Keyboard::new(window_events),
+			None => Keyboard::headless(),
+		}));
+	}
+
+	for index in 0..arguments.flag_eklectic {
+		if arguments.flag_headless {
+			hardware.push(HardwareType::Eklectic(modem::Modem::with_transport(Box::new(transport::Unplugged))));
+		} else {
+			hardware.push(HardwareType::Eklectic(attach_modem(arguments, index)?));
 		}
 	}
-}
 
+	if arguments.flag_clock {
+		hardware.push(HardwareType::Clock(clock::Clock::new()));
+	}
+
+	Ok(hardware)
+}
 
 
+// Type the text in the file on the machine's keyboard
+fn type_keys(system: &mut System, path: &str) -> Result<(), String> {
+	let keys = std::fs::read_to_string(path).map_err(|e| format!("Unable to read keys from {}: {}", path, e))?;
+	let keyboard = system.hardware.iter_mut()
+		.filter_map(|hardware| match *hardware {
+			HardwareType::Keyboard(ref mut keyboard) => Some(keyboard),
+			_ => None,
+		})
+		.next()
+		.ok_or_else(|| "There is no keyboard to type the keys on. Attach one with -k.".to_string())?;
 
-enum HardwareType {
-	Lem1820(lem1820::Lem1820),
-	Eklectic(modem::Modem),
-	Keyboard(Keyboard),
+	keyboard.type_text(&keys);
+	Ok(())
 }
 
-struct System {
-	dcpu: dcpu::Dcpu,
-	hardware: Vec<HardwareType>,
+
+// Run the image on a headless monitor and keyboard for the given cycles, then
+// compare the screen with a golden file. Returns whether it matched.
+fn golden_screen(arguments: &Arguments) -> Result<bool, String> {
+	let mut hardware = vec![
+		HardwareType::HeadlessLem1820(screen::Screen::new()),
+		HardwareType::Keyboard(Keyboard::headless()),
+	];
+	if arguments.flag_clock {
+		hardware.push(HardwareType::Clock(clock::Clock::new()));
+	}
+
+	let mut system = System::new(hardware);
+	CpuOptions::parse(arguments)?.apply(&mut system.dcpu);
+
+	load_images(arguments, &mut system.dcpu)?;
+
+	if let Some(ref path) = arguments.flag_keys {
+		type_keys(&mut system, path)?;
+	}
+
+	system.run_cycles(arguments.flag_cycles);
+
+	let snapshot = match system.hardware[0] {
+		HardwareType::HeadlessLem1820(ref screen) => golden::snapshot(screen, &system.dcpu.memory),
+		_ => unreachable!(),
+	};
+
+	let path = arguments.arg_golden.clone().unwrap();
+	match golden::check(&path, &snapshot, arguments.flag_update) {
+		Ok(()) if arguments.flag_update => println!("Wrote the screen to {}", path),
+		Ok(()) => println!("The screen matches {}", path),
+		Err(message) => {
+			eprintln!("{}", message);
+			return Ok(false);
+		},
+	}
+
+	Ok(true)
 }
 
-impl System {
-	fn step(&mut self) {
-		self.dcpu.step();
 
-		if let Some(h) = self.dcpu.hardware_interrupt {
-			match h {
-				dcpu::HardwareInstruction::GetCount(destination) =>
-					unimplemented!(),
+// Run the image flat out until it halts, as a test would, printing the registers
+// it halted with. Returns the exit code for the run.
+fn run_batch(arguments: &Arguments, hardware: Vec<HardwareType>) -> Result<i32, String> {
+	let options = CpuOptions::parse(arguments)?;
+	check_tools(arguments, options.spec)?;
+	let mut system = System::new(hardware);
+	options.apply(&mut system.dcpu);
 
-				dcpu::HardwareInstruction::GetInfo(hardware_id) =>
-					unimplemented!(),
+	let image_length = load_images(arguments, &mut system.dcpu)?;
 
-				dcpu::HardwareInstruction::Interrupt(hardware_id) => {
-					let hardware = self.hardware.get_mut(hardware_id as usize);
+	let register = batch::register_index(&arguments.flag_exit_register)
+		.ok_or_else(|| format!("Unknown register {}. Choose A, B, C, X, Y, Z, I or J.", arguments.flag_exit_register))?;
 
-					match hardware {
-						Some(&mut HardwareType::Lem1820(ref mut lem)) => lem.interrupt(&mut self.dcpu),
-						Some(&mut HardwareType::Keyboard(ref mut key)) => key.interrupt(&mut self.dcpu),
-						Some(&mut HardwareType::Eklectic(ref mut ek)) => ek.interrupt(&mut self.dcpu),
-						None => (),
-					}
-				}
+	let result_address = match arguments.flag_result_address {
+		Some(ref address) => Some(symbols::parse_number(address)
+			.ok_or_else(|| format!("Expected a result address like 0x1000, not {}", address))?),
+		None => None,
+	};
 
-			}
+	if let Some(ref path) = arguments.flag_keys {
+		type_keys(&mut system, path)?;
+	}
 
-			self.dcpu.hardware_interrupt = None;
-		}
+	let symbols = load_symbols(arguments)?;
+	attach_trace(arguments, &mut system.dcpu)?;
+	attach_profiler(arguments, &mut system.dcpu, &symbols)?;
+	attach_coverage(arguments, &mut system.dcpu, arguments.arg_image.as_ref().unwrap(), image_length, &symbols)?;
 
-		for hardware in &mut self.hardware {
-			match hardware {
-				&mut HardwareType::Lem1820(ref mut lem) => lem.step(&mut self.dcpu),
-				&mut HardwareType::Keyboard(ref mut key) => key.step(&mut self.dcpu),
-				&mut HardwareType::Eklectic(ref mut ek) => ek.step(&mut self.dcpu),
-			}
+	let halt = batch::run(&mut system, arguments.flag_max_cycles, result_address);
+
+	let dcpu = &system.dcpu;
+	match halt {
+		batch::Halt::Loop => println!("Halted in a loop at {:04x}", dcpu.program_counter),
+		batch::Halt::Fault(fault) => println!("{}", fault),
+		batch::Halt::Result(value) => println!("Wrote the result {:04x}", value),
+		batch::Halt::OutOfCycles => println!("Still running after {} cycles", arguments.flag_max_cycles),
+	}
+
+	let names = ["A", "B", "C", "X", "Y", "Z", "I", "J"];
+	let registers: Vec<String> = names.iter().zip(dcpu.registers.iter())
+		.map(|(name, value)| format!("{} {:04x}", name, value))
+		.collect();
+	println!("{}", registers.join("  "));
+	println!("SP {:04x}  PC {:04x}  EX {:04x}  IA {:04x}  cycles {}",
+		dcpu.stack_pointer, dcpu.program_counter, dcpu.excess, dcpu.interrupt_address, dcpu.cycle_count);
+
+	if let Some(ref path) = arguments.flag_screen {
+		let text = system.hardware.iter()
+			.filter_map(|hardware| match *hardware {
+				HardwareType::Lem1820(ref lem) => Some(lem.screen()),
+				HardwareType::HeadlessLem1820(ref screen) => Some(screen),
+				_ => None,
+			})
+			.next()
+			.map(|screen| screen.text(&dcpu.memory))
+			.ok_or_else(|| "There is no monitor to show the screen of. Attach one with -l.".to_string())?;
+
+		if path == "-" {
+			print!("{}", text);
+		} else {
+			std::fs::write(path, text).map_err(|e| format!("Unable to write the screen to {}: {}", path, e))?;
 		}
 	}
+
+	Ok(batch::exit_code(halt, dcpu.registers[register]))
 }
 
 
@@ -178,39 +652,227 @@ fn main() {
 		.unwrap_or_else(|e| e.exit());
 
 
-	let mut hardware = Vec::new();
+	let machine = arguments.flag_config.as_ref().map(|path| config::Machine::load(path).unwrap_or_else(|e| {
+		eprintln!("Unable to load the machine described in {}: {}", path, e);
+		std::process::exit(1);
+	}));
 
-	if arguments.flag_lem1820 {
-		let (lem, events_loop) = lem1820::Lem1820::new();
-		hardware.push(HardwareType::Lem1820(lem));
-		hardware.push(HardwareType::Keyboard(Keyboard::new(events_loop)));
+	if machine.is_some() && (arguments.flag_lem1820 || arguments.flag_eklectic > 0 || arguments.flag_keyboard || arguments.flag_clock) {
+		eprintln!("The machine's devices are listed in its description, so -l, -e, -k and -c can't be used with --config");
+		std::process::exit(1);
 	}
 
-	if arguments.flag_eklectic {
-		hardware.push(HardwareType::Eklectic(modem::Modem::new()));
+	if machine.is_some() && (arguments.flag_spec.is_some() || arguments.flag_format.is_some()) {
+		eprintln!("The machine's spec and image format are given in its description, so --spec and --format can't be used with --config");
+		std::process::exit(1);
 	}
 
-	if arguments.cmd_start {
-		let mut system = System {
-			dcpu: dcpu::Dcpu::new(),
-			hardware: hardware,
+	let hardware = match machine {
+		Some(ref machine) => machine.hardware(),
+		None => attach_devices(&arguments),
+	}.unwrap_or_else(|message| {
+		eprintln!("{}", message);
+		std::process::exit(1);
+	});
+
+	if arguments.cmd_start || arguments.cmd_debug {
+		let options = CpuOptions::parse(&arguments).and_then(|mut options| {
+			if let Some(ref machine) = machine {
+				options.spec = machine.spec()?;
+			}
+			check_tools(&arguments, options.spec)?;
+			Ok(options)
+		});
+		let options = options.unwrap_or_else(|message| {
+			eprintln!("{}", message);
+			std::process::exit(1);
+		});
+
+		let mut system = System::new(hardware);
+		options.apply(&mut system.dcpu);
+
+		let (format, images) = match machine {
+			Some(ref machine) => (machine.format.clone(), machine.images.clone()),
+			None => (image_format(&arguments).to_string(), arguments.arg_image.iter().chain(arguments.flag_load.iter()).cloned().collect()),
 		};
+		let image = images[0].clone();
+		let image_length = load_all(&format, &images, &mut system.dcpu).unwrap_or_else(|message| {
+			eprintln!("{}", message);
+			std::process::exit(1);
+		});
 
-		use std::io::Read;
-		let mut image: Vec<u8> = Vec::new();
-		std::fs::File::open(arguments.arg_image.clone().unwrap()).unwrap().read_to_end(&mut image).unwrap();
+		let symbols = load_symbols(&arguments).unwrap_or_else(|message| {
+			eprintln!("{}", message);
+			std::process::exit(1);
+		});
+
+		let attached = attach_trace(&arguments, &mut system.dcpu)
+			.and_then(|()| attach_profiler(&arguments, &mut system.dcpu, &symbols))
+			.and_then(|()| attach_coverage(&arguments, &mut system.dcpu, &image, image_length, &symbols));
+		if let Err(message) = attached {
+			eprintln!("{}", message);
+			std::process::exit(1);
+		}
 
-		for (index, byte) in image.iter().enumerate() {
-			if index % 2 == 0 {
-				system.dcpu.memory[(index / 2) as usize] = (*byte as u16) << 8;
+		if let Some(ref path) = arguments.flag_load_state {
+			if arguments.flag_spec.is_some() {
+				eprintln!("The machine saved in {} keeps the spec it was saved with, so --spec can't be used with --load-state", path);
+				std::process::exit(1);
+			}
+
+			let restored = savestate::SaveState::load(path)
+				.map_err(|e| e.to_string())
+				.and_then(|state| system.restore(&state));
+
+			if let Err(e) = restored {
+				eprintln!("Unable to load the machine saved in {}: {}", path, e);
+				std::process::exit(1);
+			}
+
+			if let Err(message) = check_tools(&arguments, system.dcpu.spec) {
+				eprintln!("The machine saved in {} follows spec {}: {}", path, system.dcpu.spec.name(), message);
+				std::process::exit(1);
+			}
+		}
+
+		if let Some(ref path) = arguments.flag_record_session {
+			match session::Recorder::create(path) {
+				Ok(recorder) => system.record(recorder),
+				Err(e) => {
+					eprintln!("Unable to record the session to {}: {}", path, e);
+					std::process::exit(1);
+				},
+			}
+		}
+
+		if let Some(ref path) = arguments.flag_replay_session {
+			match session::Player::open(path) {
+				Ok(player) => system.replay(player, arguments.flag_verify),
+				Err(e) => {
+					eprintln!("Unable to replay the session in {}: {}", path, e);
+					std::process::exit(1);
+				},
+			}
+		}
+
+		if arguments.cmd_debug && arguments.flag_gdb.is_some() {
+			let port = arguments.flag_gdb.clone().unwrap();
+			let stub = if port == "-" {
+				Ok(gdb::GdbStub::stdio(system))
 			} else {
-				system.dcpu.memory[(index / 2) as usize] |= *byte as u16;
+				match port.parse() {
+					Ok(port) => gdb::GdbStub::listen(system, port),
+					Err(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} is not a port", port))),
+				}
+			};
+
+			if let Err(e) = stub.and_then(|mut stub| stub.run()) {
+				eprintln!("Unable to serve GDB: {}", e);
+				std::process::exit(1);
 			}
+			return;
+		}
+
+		if arguments.cmd_debug {
+			let mut debugger = debugger::Debugger::new(system, symbols);
+			debugger.keep_history(arguments.flag_history);
+			debugger.run();
+			return;
+		}
+
+		// Stop on Ctrl-C instead of being killed, so that traces are written out. A
+		// second Ctrl-C kills the emulator if it is stuck somewhere else, like the debugger.
+		let interrupted = Arc::new(AtomicBool::new(false));
+		let handler_interrupted = interrupted.clone();
+		let handler = ctrlc::set_handler(move || if handler_interrupted.swap(true, Ordering::SeqCst) {
+			std::process::exit(130);
+		});
+		if let Err(e) = handler {
+			eprintln!("Unable to catch Ctrl-C: {}", e);
 		}
 
+		let speed = machine.as_ref().map_or(dcpu::CLOCK_RATE, |machine| machine.speed);
+		let started = std::time::Instant::now();
+		let start_cycle = system.cycle();
+		let mut fault_reported = false;
+		// A hundredth of a second at the machine's speed, so slow machines still keep to it
+		let slice = if speed == 0 {dcpu::CLOCK_RATE / 100} else {(speed / 100).max(1)};
 		loop {
-			system.step();
+			// Run at the machine's speed, a slice at a time. A replay being
+			// verified, or a machine with a speed of zero, runs flat out.
+			system.run_cycles(slice);
+			if !arguments.flag_verify && speed != 0 {
+				pace(started, system.cycle() - start_cycle, speed);
+			}
+
+			if interrupted.load(Ordering::SeqCst) {
+				drop(system);
+				std::process::exit(130);
+			}
+
+			// Dropping the machine first writes out its trace, profile and coverage
+			if let Some(divergence) = system.diverged() {
+				eprintln!("{}", divergence);
+				drop(system);
+				std::process::exit(1);
+			}
+
+			if arguments.flag_verify && system.replay_finished() {
+				println!("The replay matched the recording up to cycle {}", system.cycle());
+				return;
+			}
+
+			if let Some(fault) = system.dcpu.fault {
+				if system.dcpu.fault_policy == dcpu::FaultPolicy::Trap {
+					eprintln!("{}", fault);
+					let mut debugger = debugger::Debugger::new(system, symbols);
+					debugger.keep_history(arguments.flag_history);
+					debugger.run();
+					return;
+				}
+
+				// Leave the devices running so that whatever is on the screen can still be seen
+				if !fault_reported {
+					let dcpu = &system.dcpu;
+					eprintln!("{}. The DCPU has halted with registers {:04x?}, SP {:04x}, PC {:04x}, EX {:04x}",
+						fault, dcpu.registers, dcpu.stack_pointer, dcpu.program_counter, dcpu.excess);
+					fault_reported = true;
+				}
+			}
+
+			if system.save_requested() {
+				let path = &arguments.flag_save_state;
+				match system.save().save(path) {
+					Ok(()) => println!("Saved the machine to {}", path),
+					Err(e) => println!("Unable to save the machine to {}: {}", path, e),
+				}
+			}
+		}
+	} else if arguments.cmd_run {
+		// A run that couldn't get going exits like one that caught fire
+		match run_batch(&arguments, hardware) {
+			Ok(code) => std::process::exit(code),
+			Err(message) => {
+				eprintln!("{}", message);
+				std::process::exit(254);
+			},
+		}
+	} else if arguments.cmd_golden {
+		match golden_screen(&arguments) {
+			Ok(true) => return,
+			Ok(false) => std::process::exit(1),
+			Err(message) => {
+				eprintln!("{}", message);
+				std::process::exit(1);
+			},
+		}
+	} else if arguments.cmd_bench || arguments.cmd_fleet {
+		let result = if arguments.cmd_bench {benchmark(&arguments)} else {fleet(&arguments)};
+		if let Err(message) = result {
+			eprintln!("{}", message);
+			std::process::exit(1);
 		}
+		return;
 	} else if arguments.cmd_assemble {
 		unimplemented!();
 	}
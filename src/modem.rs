@@ -1,8 +1,16 @@
 use std;
+use std::io;
 use dcpu;
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream, Ipv4Addr};
 use dcpu::Dcpu;
+use transport::{Transport, TcpTransport, Connection, Unplugged};
+use line_noise::{Impairment, LineNoise};
+use capture::{Capture, Event, LineLog, RecordedLine, ReplayTransport};
+use session::{Recorder, Player};
+
+/// Tesla Eklectic Modem's hardware id, version and manufacturer
+pub const HARDWARE_ID: u32 = 0xe1ec_7c1c;
+pub const VERSION: u16 = 0x0001;
+pub const MANUFACTURER: u32 = 0x7e51_a000;
 
 const NOTHING: u16 = 0x0000;
 const NO_TELEPHONE_SERVICE: u16 = 0x0001;
@@ -12,56 +20,164 @@ const CONNECTION_MADE: u16 = 0x0004;
 const RINGING: u16 = 0x0005;
 const CONNECTION_LOST: u16 = 0x0006;
 const DATA_IN_BUFFER: u16 = 0x0007;
+const CALLER_ID: u16 = 0x0008;
+const CALL_WAITING: u16 = 0x0009;
 
 // Byte sent over the network when the user answers an incoming call
 const ANSWER: u8 = 0xaa;
 // Byte sent over the network to an incoming caller when a connection already exists
 const BUSY: u8 = 0xbb;
 
+// Cycles between looking at the line, a thousand times a second
+const POLL_INTERVAL: u64 = dcpu::CLOCK_RATE / 1000;
+
+// Bytes waiting to go down a full line before the other end is taken to have
+// stopped listening, enough for a whole memory's worth of words
+const OUTGOING_LIMIT: usize = 0x20000;
+
 enum ModemState {
 	Idle,
-	Ringing(TcpStream),
-	Dialing(TcpStream),
-	Connected(TcpStream),
-	Writing(TcpStream, u16, u16),
+	Ringing(Box<dyn Connection>),
+	Dialing(Box<dyn Connection>),
+	Connected(Box<dyn Connection>),
+	Writing(Box<dyn Connection>, u16, u16),
 }
 
 pub struct Modem {
-	incoming_server: TcpListener,
+	transport: Box<dyn Transport>,
 	state: ModemState,
 	buffer: Vec<u16>,
+	// Bytes sent that the line hasn't taken yet
+	outgoing: Vec<u8>,
 	interrupt_address: Option<u16>,
 	last_interrupt: u16,
+	line_noise: Option<LineNoise>,
+	capture: Option<Capture>,
+	no_service: bool,
+	caller: Option<u32>,
+	caller_id_sent: bool,
+	call_waiting: bool,
+	waiting: Option<(Box<dyn Connection>, u32)>,
+	// The cycle the modem was last stepped on
+	last_step: u64,
 }
 
 impl Modem {
-	/// Create a new Modem
-	pub fn new() -> Modem {
-		let incoming_server = TcpListener::bind("0.0.0.0:6483").unwrap();
-		incoming_server.set_nonblocking(true).unwrap();
+	/// Create a new Modem connected to the internet, answering calls on the given port
+	pub fn new(port: u16) -> io::Result<Modem> {
+		Modem::with_ports(port, 6482)
+	}
 
+	/// Create a new Modem connected to the internet, answering calls on one port and placing them to another
+	pub fn with_ports(port: u16, dial_port: u16) -> io::Result<Modem> {
+		Ok(Modem::with_transport(Box::new(TcpTransport::new(port, dial_port)?)))
+	}
+
+	/// Create a new Modem plugged into the given line
+	pub fn with_transport(transport: Box<dyn Transport>) -> Modem {
 		Modem {
-			incoming_server: incoming_server,
+			transport: transport,
 			state: ModemState::Idle,
 			buffer: vec![],
+			outgoing: vec![],
 			interrupt_address: None,
 			last_interrupt: NOTHING,
+			line_noise: None,
+			capture: None,
+			no_service: false,
+			caller: None,
+			caller_id_sent: false,
+			call_waiting: false,
+			waiting: None,
+			last_step: 0,
 		}
 	}
 
+	/// Let a second caller wait while a call is in progress instead of hearing a busy line
+	pub fn allow_call_waiting(&mut self, allow: bool) {
+		self.call_waiting = allow;
+	}
+
+	/// Record everything the modem and its line do from now on
+	pub fn capture(&mut self, capture: Capture) {
+		self.record_line(capture.clone());
+		self.capture = Some(capture);
+	}
+
+	/// Write down everything the line does from now on, for a session to be played back
+	pub fn record_session(&mut self, recorder: Recorder) {
+		self.record_line(recorder);
+	}
+
+	/// Unplug the modem and plug it into a line that does what it did in a recorded session
+	pub fn replay_session(&mut self, player: Player) {
+		self.transport = Box::new(ReplayTransport::new(player));
+
+		// Keep capturing what the new line does
+		if let Some(capture) = self.capture.clone() {
+			self.record_line(capture);
+		}
+	}
+
+	// Write down what the line does from now on
+	fn record_line<L: LineLog + Clone + 'static>(&mut self, log: L) {
+		let line = std::mem::replace(&mut self.transport, Box::new(Unplugged));
+		self.transport = Box::new(RecordedLine::new(line, log));
+	}
+
+	/// Simulate a bad telephone line, or a perfect one if `impairment` is perfect
+	pub fn impair(&mut self, impairment: Impairment) {
+		self.line_noise =
+			if impairment.is_perfect() {
+				None
+			} else {
+				Some(LineNoise::new(impairment))
+			};
+	}
+
 	/// Print the state of the modem
 	pub fn print_state(&self) {
+		println!("{}", self.state_name());
+	}
+
+	/// The modem's part of a save state. Calls can't be saved, so a restored
+	/// modem is always idle, but keeps its interrupt settings and receive buffer.
+	pub fn save(&self) -> Vec<u16> {
+		let mut words = vec![self.interrupt_address.is_some() as u16, self.interrupt_address.unwrap_or(0), self.last_interrupt];
+		words.extend_from_slice(&self.buffer);
+		words
+	}
+
+	pub fn restore(&mut self, words: &[u16]) -> Result<(), String> {
+		if words.len() < 3 {
+			return Err("The modem in the save state is incomplete".to_string());
+		}
+
+		self.interrupt_address = if words[0] != 0 {Some(words[1])} else {None};
+		self.last_interrupt = words[2];
+		self.buffer = words[3..].to_vec();
+		self.state = ModemState::Idle;
+		self.caller = None;
+		self.waiting = None;
+		self.clear_line();
+		Ok(())
+	}
+
+	/// Get the name of the modem's state
+	fn state_name(&self) -> &'static str {
 		match self.state {
-			ModemState::Idle => println!("Idle"),
-			ModemState::Ringing(_) => println!("Ringing"),
-			ModemState::Dialing(_) => println!("Dialing"),
-			ModemState::Connected(_) => println!("Connected"),
-			ModemState::Writing(_, _, _) => println!("Writing"),
+			ModemState::Idle => "Idle",
+			ModemState::Ringing(_) => "Ringing",
+			ModemState::Dialing(_) => "Dialing",
+			ModemState::Connected(_) => "Connected",
+			ModemState::Writing(_, _, _) => "Writing",
 		}
 	}
 
 	/// Interrupt the modem
 	pub fn interrupt(&mut self, dcpu: &mut Dcpu) {
+		let previous_state = self.state_name();
+
 		match dcpu.registers[dcpu::A] {
 			0 => self.set_interrupt(dcpu),
 			1 => self.get_status(dcpu),
@@ -70,8 +186,11 @@ impl Modem {
 			4 => self.hang_up(dcpu),
 			5 => self.send(dcpu),
 			// 6 => self.receive(dcpu),
+			7 => self.get_caller_id(dcpu),
 			_ => (),
 		}
+
+		self.record_state_change(dcpu, previous_state);
 	}
 
 	/// Get the status of the modem
@@ -89,6 +208,17 @@ impl Modem {
 		dcpu.registers[dcpu::C] = self.buffer.len() as u16;
 	}
 
+	/// Get the number of whoever is ringing or waiting, or of the caller on the line
+	fn get_caller_id(&mut self, dcpu: &mut Dcpu) {
+		let number = match self.waiting {
+			Some((_, number)) => number,
+			None => self.caller.unwrap_or(0),
+		};
+
+		dcpu.registers[dcpu::B] = (number >> 16) as u16;
+		dcpu.registers[dcpu::C] = number as u16;
+	}
+
 	/// Set the value to interrupt the Dcpu with when something happens
 	fn set_interrupt(&mut self, dcpu: &mut Dcpu) {
 		if dcpu.registers[dcpu::B] == 0 {
@@ -98,49 +228,78 @@ impl Modem {
 		}
 	}
 
-	/// Answer the ringing number
+	/// Answer the ringing number, or hang up and switch to the waiting call
 	fn answer(&mut self, dcpu: &mut Dcpu) {
 		match std::mem::replace(&mut self.state, ModemState::Idle) {
-			ModemState::Ringing(mut socket) => {
-				socket.write(&[0xaa]).unwrap();
-				self.state = ModemState::Connected(socket);
-			},
+			ModemState::Ringing(socket) =>
+				self.pick_up(dcpu, socket),
+
+			ModemState::Connected(socket) =>
+				match self.waiting.take() {
+					Some((waiting, number)) => {
+						self.caller = Some(number);
+						self.pick_up(dcpu, waiting);
+					},
+
+					None =>
+						self.state = ModemState::Connected(socket),
+				},
 
 			otherwise =>
 				self.state = otherwise,
 		}
 	}
 
-	/// Hang up the active connection
-	fn hang_up(&mut self, _dcpu: &mut Dcpu) {
+	// Tell the caller the call has been answered
+	fn pick_up(&mut self, dcpu: &mut Dcpu, mut socket: Box<dyn Connection>) {
+		match write_all(&mut socket, &[ANSWER]) {
+			Ok(()) => {
+				self.clear_line();
+				self.state = ModemState::Connected(socket);
+			},
+
+			// The caller hung up before we could answer
+			Err(_) =>
+				self.interrupt_dcpu(dcpu, CONNECTION_LOST),
+		}
+	}
+
+	/// Hang up the active connection. A waiting call starts ringing.
+	fn hang_up(&mut self, dcpu: &mut Dcpu) {
 		self.state = ModemState::Idle;
+		self.caller = None;
+
+		if let Some((waiting, number)) = self.waiting.take() {
+			self.state = self.ring(dcpu, waiting, number);
+		}
+	}
+
+	// Start ringing for an incoming call
+	fn ring(&mut self, dcpu: &mut Dcpu, socket: Box<dyn Connection>, number: u32) -> ModemState {
+		self.caller = Some(number);
+		self.caller_id_sent = false;
+		self.interrupt_dcpu(dcpu, RINGING);
+		ModemState::Ringing(socket)
 	}
 
 
 	// Dial to the given address
 	fn dial(&mut self, dcpu: &mut Dcpu) {
 		self.state = ModemState::Idle;
+		self.caller = None;
+		self.waiting = None;
 
-		let first_half = dcpu.registers[dcpu::B];
-		let second_half = dcpu.registers[dcpu::C];
+		let number = ((dcpu.registers[dcpu::B] as u32) << 16) | dcpu.registers[dcpu::C] as u32;
 
-		let a = (first_half >> 8) as u8;
-		let b = first_half as u8;
-		let c = (second_half >> 8) as u8;
-		let d = second_half as u8;
-
-		match TcpStream::connect((Ipv4Addr::new(a, b, c, d), 6482)) {
-			Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionRefused =>
+		match self.transport.dial(number) {
+			Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused =>
 				self.interrupt_dcpu(dcpu, NO_MODEM),
 
 			Err(_) =>
-				if let Some(address) = self.interrupt_address {
-					self.last_interrupt = NO_TELEPHONE_SERVICE;
-					dcpu.interrupt_queue.push(address);
-				},
+				self.interrupt_dcpu(dcpu, NO_TELEPHONE_SERVICE),
 
 			Ok(socket) => {
-				socket.set_nonblocking(true).unwrap();
+				self.no_service = false;
 				self.state = ModemState::Dialing(socket);
 			},
 		}
@@ -152,79 +311,227 @@ impl Modem {
 		if let Some(address) = self.interrupt_address {
 			self.last_interrupt = interrupt_type;
 			dcpu.interrupt_queue.push(address);
+			self.record(dcpu, Event::Interrupt(interrupt_type));
+		}
+	}
+
+
+	// Write an event to the capture, if there is one. What the line does is
+	// captured as it happens, by the line.
+	fn record(&self, dcpu: &Dcpu, event: Event) {
+		if let Some(ref capture) = self.capture {
+			capture.record(dcpu.cycle_count, &event);
+		}
+	}
+
+
+	// Record the modem's state if it has changed from the given state
+	fn record_state_change(&mut self, dcpu: &Dcpu, previous_state: &'static str) {
+		let state = self.state_name();
+		if state != previous_state {
+			self.record(dcpu, Event::State(state.to_string()));
+		}
+	}
+
+
+	// Put words that came over the connection into the buffer
+	fn receive(&mut self, dcpu: &mut Dcpu, mut words: Vec<u16>) {
+		if words.is_empty() {
+			return;
+		}
+
+		if self.buffer.len() == 0 {
+			self.interrupt_dcpu(dcpu, DATA_IN_BUFFER);
+		}
+
+		self.record(dcpu, Event::Receive(words.clone()));
+		self.buffer.append(&mut words);
+	}
+
+
+	// Forget anything left travelling down the line from the last call
+	fn clear_line(&mut self) {
+		self.outgoing.clear();
+		if let Some(ref mut noise) = self.line_noise {
+			noise.clear();
+		}
+	}
+
+
+	// Tell the DCPU the line has gone dead, once until it comes back
+	fn lose_service(&mut self, dcpu: &mut Dcpu, error: io::Error) {
+		if !self.no_service {
+			eprintln!("Modem lost telephone service: {}", error);
+			self.no_service = true;
+			self.interrupt_dcpu(dcpu, NO_TELEPHONE_SERVICE);
+		}
+	}
+
+
+	// Put an incoming call on hold if call waiting is allowed and nobody else is waiting,
+	// otherwise refuse it. Keep an eye on whoever is waiting in case they give up.
+	fn hold_incoming(&mut self, dcpu: &mut Dcpu) {
+		if !self.call_waiting || self.waiting.is_some() {
+			self.refuse_incoming();
+		} else if let Ok((socket, number)) = self.transport.accept() {
+			self.waiting = Some((socket, number));
+			self.interrupt_dcpu(dcpu, CALL_WAITING);
+		}
+
+		let hung_up = match self.waiting {
+			Some((ref mut socket, _)) => {
+				let mut buffer: [u8; 500] = [0; 500];
+				loop {
+					match socket.read(&mut buffer) {
+						Ok(0) => break true,
+						Ok(_) => (),
+						Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+						Err(_) => break true,
+					}
+				}
+			},
+
+			None => false,
+		};
+
+		if hung_up {
+			self.waiting = None;
 		}
 	}
 
 
-	// Refuse incoming connections on the tcp listener
-	fn refuse_incoming(tcp_listener: &mut TcpListener) {
-		if let Ok((mut socket, addr)) = tcp_listener.accept() {
-			socket.write(&[BUSY]).unwrap();
+	// Refuse incoming calls on the line
+	fn refuse_incoming(&mut self) {
+		if let Ok((mut socket, _)) = self.transport.accept() {
+			// If the caller has already gone there is nobody to tell that the line is busy
+			let _ = write_all(&mut socket, &[BUSY]);
 		}
 	}
 
 
-	/// Send data over the active connection
+	/// Send data over the active connection. Whatever the line can't take yet
+	/// waits to go down it on later steps, unless so much is waiting that the
+	/// other end must have stopped listening.
 	fn send(&mut self, dcpu: &mut Dcpu) {
-		if let ModemState::Connected(ref mut socket) = self.state {
-			let mut buffer: Vec<u8> = Vec::new();
-
-			let offset = dcpu.registers[dcpu::B];
-			let size = dcpu.registers[dcpu::C];
-			for i in 0..size {
-				buffer.push((dcpu.memory[(offset + i) as usize] >> 8) as u8);
-				buffer.push((dcpu.memory[(offset + i) as usize]) as u8);
+		let offset = dcpu.registers[dcpu::B];
+		let size = dcpu.registers[dcpu::C];
+		let words: Vec<u16> = (0..size).map(|i| dcpu.memory[offset.wrapping_add(i) as usize]).collect();
+
+		let result = if let ModemState::Connected(ref mut socket) = self.state {
+			for word in &words {
+				self.outgoing.push((word >> 8) as u8);
+				self.outgoing.push(*word as u8);
 			}
 
-			socket.write(buffer.as_slice()).unwrap();
+			match write_waiting(socket, &mut self.outgoing) {
+				Ok(()) if self.outgoing.len() > OUTGOING_LIMIT =>
+					Some(Err(io::Error::new(io::ErrorKind::WouldBlock, "The other end stopped listening"))),
+				result =>
+					Some(result),
+			}
+		} else {
+			None
+		};
+
+		match result {
+			Some(Ok(())) =>
+				self.record(dcpu, Event::Send(words)),
+
+			Some(Err(_)) => {
+				self.state = ModemState::Idle;
+				self.clear_line();
+				self.interrupt_dcpu(dcpu, CONNECTION_LOST);
+			},
+
+			None => (),
 		}
 	}
 
 
-	// Step th emodem forward one step
-	pub fn step(&mut self, dcpu: &mut Dcpu) {
+	/// Look at the line and move the modem along, returning the cycles until it
+	/// next needs to: the poll interval, or sooner if a word is about to come
+	/// down a noisy line
+	pub fn step(&mut self, dcpu: &mut Dcpu) -> u64 {
+		let previous_state = self.state_name();
+		let elapsed = dcpu.cycle_count.saturating_sub(self.last_step).max(1);
+		self.last_step = dcpu.cycle_count;
+		self.transport.advance(dcpu.cycle_count);
+
 		self.state = match std::mem::replace(&mut self.state, ModemState::Idle) {
 			ModemState::Idle =>
-				match self.incoming_server.accept() {
-					Ok((socket, _addr)) => {
-						self.interrupt_dcpu(dcpu, RINGING);
-						ModemState::Ringing(socket)
+				match self.transport.accept() {
+					Ok((socket, number)) => {
+						self.no_service = false;
+						self.ring(dcpu, socket, number)
 					},
 
-					Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock =>
+					Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+						ModemState::Idle,
+
+					// The caller gave up before the call could be picked up
+					Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted || e.kind() == io::ErrorKind::ConnectionReset =>
 						ModemState::Idle,
 
 					Err(e) => {
-						println!("No Client: {:?}", e);
+						self.lose_service(dcpu, e);
 						ModemState::Idle
 					},
 				},
 
 
 			ModemState::Ringing(mut socket) => {
-				Modem::refuse_incoming(&mut self.incoming_server);
+				self.refuse_incoming();
+
+				// Let the DCPU know who is calling once it has heard the phone ring
+				if !self.caller_id_sent {
+					self.caller_id_sent = true;
+					self.interrupt_dcpu(dcpu, CALLER_ID);
+				}
 
 				// Ignore any incoming bytes since the user hasn't answered yet
 				let mut buffer: [u8; 500] = [0; 500];
-				while let Ok(_bytes) = socket.read(&mut buffer) {
-					// Doing nothing in this loop is not a bug. It's a feature.
+				let mut hung_up = false;
+				loop {
+					match socket.read(&mut buffer) {
+						Ok(bytes) => {
+							// A read of zero bytes means the caller gave up
+							if bytes == 0 {
+								hung_up = true;
+								break;
+							}
+						},
+
+						Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+							break,
+
+						Err(_) => {
+							hung_up = true;
+							break;
+						},
+					}
 				}
 
-				ModemState::Ringing(socket)
+				if hung_up {
+					self.interrupt_dcpu(dcpu, CONNECTION_LOST);
+					ModemState::Idle
+				} else {
+					ModemState::Ringing(socket)
+				}
 			}
 
 
 			ModemState::Dialing(mut socket) => {
-				Modem::refuse_incoming(&mut self.incoming_server);
+				self.refuse_incoming();
 
 				let mut buffer: [u8; 1] = [0; 1];
 				match socket.read(&mut buffer) {
-					Ok(bytes_read) =>
+					Ok(bytes_read) => {
 						if bytes_read == 0 {
 							self.interrupt_dcpu(dcpu, CONNECTION_LOST);
 							ModemState::Idle
 						} else {
 							if buffer[0] == ANSWER {
+								self.clear_line();
 								self.interrupt_dcpu(dcpu, CONNECTION_MADE);
 								ModemState::Connected(socket)
 							} else if buffer[0] == BUSY {
@@ -233,13 +540,14 @@ impl Modem {
 							} else {
 								ModemState::Dialing(socket)
 							}
-						},
+						}
+					},
 
-					Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock =>
+					Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
 						ModemState::Dialing(socket),
 
-					Err(e) => {
-						println!("Error during read during dialing {:?}", e);
+					Err(_) => {
+						self.interrupt_dcpu(dcpu, CONNECTION_LOST);
 						ModemState::Idle
 					}
 				}
@@ -247,21 +555,26 @@ impl Modem {
 
 
 			ModemState::Connected(mut socket) => {
-				Modem::refuse_incoming(&mut self.incoming_server);
+				self.hold_incoming(dcpu);
 
-				let mut buffer: [u8; 1000] = [0; 1000];
-				match socket.read(&mut buffer) {
-					Ok(bytes_read) => {
-						if bytes_read == 0 {
-							self.interrupt_dcpu(dcpu, CONNECTION_LOST);
-							ModemState::Idle
-						} else {
-							if self.buffer.len() == 0 {
-								self.interrupt_dcpu(dcpu, DATA_IN_BUFFER);
-							}
-
-							self.buffer.append(
-								&mut buffer[..bytes_read]
+				// Whatever was still on its way down the line goes with the carrier
+				if self.line_noise.as_mut().map_or(false, |noise| noise.carrier_lost(elapsed)) {
+					self.clear_line();
+					self.interrupt_dcpu(dcpu, CONNECTION_LOST);
+					ModemState::Idle
+				} else if write_waiting(&mut socket, &mut self.outgoing).is_err() {
+					self.clear_line();
+					self.interrupt_dcpu(dcpu, CONNECTION_LOST);
+					ModemState::Idle
+				} else {
+					let mut buffer: [u8; 1000] = [0; 1000];
+					let state = match socket.read(&mut buffer) {
+						Ok(bytes_read) => {
+							if bytes_read == 0 {
+								self.interrupt_dcpu(dcpu, CONNECTION_LOST);
+								ModemState::Idle
+							} else {
+								let words = buffer[..bytes_read]
 									.chunks(2)
 									.map(|chunk|
 										if chunk.len() == 1 {
@@ -269,26 +582,40 @@ impl Modem {
 										} else {
 											((chunk[0] as u16) << 8) + (chunk[1] as u16)
 										})
-									.collect::<Vec<_>>()
-							);
+									.collect::<Vec<_>>();
 
-							ModemState::Connected(socket)
+								match self.line_noise {
+									Some(ref mut noise) => noise.transmit(words, dcpu.cycle_count),
+									None => self.receive(dcpu, words),
+								}
+
+								ModemState::Connected(socket)
+							}
+						},
+
+						Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+							ModemState::Connected(socket),
+
+						Err(_) => {
+							self.interrupt_dcpu(dcpu, CONNECTION_LOST);
+							ModemState::Idle
 						}
-					},
+					};
 
-					Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock =>
-						ModemState::Connected(socket),
+					// Deliver whatever has made it to the end of a noisy line
+					let arrived = match self.line_noise {
+						Some(ref mut noise) => noise.arrived(dcpu.cycle_count),
+						None => Vec::new(),
+					};
+					self.receive(dcpu, arrived);
 
-					Err(e) => {
-						println!("Unable to read: {:?}", e);
-						ModemState::Idle
-					}
+					state
 				}
 			},
 
 
 			ModemState::Writing(mut socket, current_location, end_location) => {
-				Modem::refuse_incoming(&mut self.incoming_server);
+				self.refuse_incoming();
 
 				let mut packet = Vec::new();
 				for i in current_location..current_location + 5 {
@@ -299,20 +626,498 @@ impl Modem {
 					}
 				}
 
-				match socket.write(&packet) {
-					Ok(_bytes_written) =>
+				match write_all(&mut socket, &packet) {
+					Ok(()) =>
 						if current_location + 5 > end_location {
 							ModemState::Connected(socket)
 						} else {
 							ModemState::Writing(socket, current_location + 5, end_location)
 						},
 
-					Err(e) => {
-						println!("Error writing: {:?}", e);
+					Err(_) => {
+						self.interrupt_dcpu(dcpu, CONNECTION_LOST);
 						ModemState::Idle
 					}
 				}
 			},
+		};
+
+		self.record_state_change(dcpu, previous_state);
+
+		match self.line_noise.as_ref().and_then(|noise| noise.next_arrival()) {
+			Some(arrival) => POLL_INTERVAL.min(arrival.saturating_sub(dcpu.cycle_count)),
+			None => POLL_INTERVAL,
+		}
+	}
+}
+
+
+// Write as many of the bytes to the connection as the line will take right
+// now, leaving the rest for later
+fn write_waiting(socket: &mut Box<dyn Connection>, bytes: &mut Vec<u8>) -> io::Result<()> {
+	while !bytes.is_empty() {
+		match socket.write(bytes) {
+			Ok(0) =>
+				return Err(io::Error::new(io::ErrorKind::WriteZero, "The line stopped accepting data")),
+
+			Ok(written) => {
+				bytes.drain(..written);
+			},
+
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted =>
+				(),
+
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+				break,
+
+			Err(e) =>
+				return Err(e),
+		}
+	}
+
+	Ok(())
+}
+
+
+// Write every byte to the connection, giving up if the line is full
+fn write_all(socket: &mut Box<dyn Connection>, bytes: &[u8]) -> io::Result<()> {
+	let mut bytes = bytes.to_vec();
+	write_waiting(socket, &mut bytes)?;
+
+	if bytes.is_empty() {
+		Ok(())
+	} else {
+		Err(io::Error::new(io::ErrorKind::WouldBlock, "The line is full"))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io;
+	use transport::{Script, ScriptedConnection, ScriptedTransport, Switchboard};
+	use line_noise::Impairment;
+	use test_support::SharedOutput;
+
+	const INTERRUPT_MESSAGE: u16 = 0x1234;
+
+	// Plug a modem into the given line and enable its interrupts
+	fn setup(transport: Box<dyn Transport>) -> (Modem, Box<Dcpu>) {
+		let mut modem = Modem::with_transport(transport);
+		let mut dcpu = Box::new(Dcpu::new());
+
+		dcpu.registers[dcpu::A] = 0;
+		dcpu.registers[dcpu::B] = INTERRUPT_MESSAGE;
+		modem.interrupt(&mut dcpu);
+
+		(modem, dcpu)
+	}
+
+	// Send the modem a hardware interrupt with the given registers
+	fn command(modem: &mut Modem, dcpu: &mut Dcpu, a: u16, b: u16, c: u16) {
+		dcpu.registers[dcpu::A] = a;
+		dcpu.registers[dcpu::B] = b;
+		dcpu.registers[dcpu::C] = c;
+		modem.interrupt(dcpu);
+	}
+
+	// Ask the modem for its (state, last interrupt, buffer length)
+	fn status(modem: &mut Modem, dcpu: &mut Dcpu) -> (u16, u16, u16) {
+		command(modem, dcpu, 1, 0, 0);
+		(dcpu.registers[dcpu::A], dcpu.registers[dcpu::B], dcpu.registers[dcpu::C])
+	}
+
+	#[test]
+	fn dial_connect_and_hang_up() {
+		let connection = ScriptedConnection::new(vec![
+			Script::Wait,
+			Script::Receive(vec![ANSWER]),
+			Script::Receive(vec![0x12, 0x34, 0x56, 0x78]),
+		]);
+		let written = connection.written();
+		let transport = ScriptedTransport::new().dial_result(Ok(connection));
+		let dialed = transport.dialed();
+		let (mut modem, mut dcpu) = setup(Box::new(transport));
+
+		command(&mut modem, &mut dcpu, 3, 0x0a00, 0x0001);
+		assert_eq!(*dialed.lock().unwrap(), vec![0x0a00_0001]);
+		assert_eq!(status(&mut modem, &mut dcpu), (2, NOTHING, 0));
+
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (2, NOTHING, 0));
+
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (3, CONNECTION_MADE, 0));
+
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (3, DATA_IN_BUFFER, 2));
+		assert_eq!(modem.buffer, vec![0x1234, 0x5678]);
+		assert_eq!(dcpu.interrupt_queue, vec![INTERRUPT_MESSAGE; 2]);
+
+		dcpu.memory[0x100] = 0xbeef;
+		command(&mut modem, &mut dcpu, 5, 0x100, 1);
+		assert_eq!(*written.lock().unwrap(), vec![0xbe, 0xef]);
+
+		command(&mut modem, &mut dcpu, 4, 0, 0);
+		assert_eq!(status(&mut modem, &mut dcpu).0, 0);
+	}
+
+	#[test]
+	fn dial_busy_line() {
+		let connection = ScriptedConnection::new(vec![Script::Receive(vec![BUSY])]);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().dial_result(Ok(connection))));
+
+		command(&mut modem, &mut dcpu, 3, 0, 1);
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, LINE_BUSY, 0));
+	}
+
+	#[test]
+	fn dial_without_answer() {
+		let connection = ScriptedConnection::new(vec![Script::HangUp]);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().dial_result(Ok(connection))));
+
+		command(&mut modem, &mut dcpu, 3, 0, 1);
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, CONNECTION_LOST, 0));
+	}
+
+	#[test]
+	fn dial_failures() {
+		let transport = ScriptedTransport::new()
+			.dial_result(Err(io::Error::new(io::ErrorKind::ConnectionRefused, "")))
+			.dial_result(Err(io::Error::new(io::ErrorKind::TimedOut, "")));
+		let (mut modem, mut dcpu) = setup(Box::new(transport));
+
+		command(&mut modem, &mut dcpu, 3, 0, 1);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, NO_MODEM, 0));
+
+		command(&mut modem, &mut dcpu, 3, 0, 1);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, NO_TELEPHONE_SERVICE, 0));
+	}
+
+	#[test]
+	fn connection_lost_while_connected() {
+		let connection = ScriptedConnection::new(vec![Script::Receive(vec![ANSWER]), Script::HangUp]);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().dial_result(Ok(connection))));
+
+		command(&mut modem, &mut dcpu, 3, 0, 1);
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (3, CONNECTION_MADE, 0));
+
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, CONNECTION_LOST, 0));
+	}
+
+	#[test]
+	fn carrier_drop_on_a_noisy_line() {
+		let connection = ScriptedConnection::new(vec![Script::Receive(vec![ANSWER])]);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().dial_result(Ok(connection))));
+		modem.impair(Impairment {carrier_drop_rate: 1.0, ..Impairment::default()});
+
+		command(&mut modem, &mut dcpu, 3, 0, 1);
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (3, CONNECTION_MADE, 0));
+
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, CONNECTION_LOST, 0));
+	}
+
+	#[test]
+	fn carrier_drop_loses_words_on_the_line() {
+		let connection = ScriptedConnection::new(vec![Script::Receive(vec![ANSWER])]);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().dial_result(Ok(connection))));
+
+		command(&mut modem, &mut dcpu, 3, 0, 1);
+		modem.step(&mut dcpu);
+
+		// A word that would arrive this step is still on the line when the carrier drops
+		let mut noise = LineNoise::new(Impairment {carrier_drop_rate: 1.0, ..Impairment::default()});
+		noise.transmit(vec![0x1234], 0);
+		modem.line_noise = Some(noise);
+
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, CONNECTION_LOST, 0));
+		assert!(modem.line_noise.as_ref().unwrap().next_arrival().is_none());
+	}
+
+	#[test]
+	fn latency_on_a_noisy_line() {
+		let connection = ScriptedConnection::new(vec![Script::Receive(vec![ANSWER]), Script::Receive(vec![0x12, 0x34])]);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().dial_result(Ok(connection))));
+		modem.impair(Impairment {latency: 100, ..Impairment::default()});
+
+		command(&mut modem, &mut dcpu, 3, 0, 1);
+		modem.step(&mut dcpu);
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (3, CONNECTION_MADE, 0));
+
+		dcpu.cycle_count = 99;
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (3, CONNECTION_MADE, 0));
+
+		dcpu.cycle_count = 100;
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (3, DATA_IN_BUFFER, 1));
+	}
+
+	#[test]
+	fn capture_and_replay() {
+		let connection = ScriptedConnection::new(vec![
+			Script::Wait,
+			Script::Receive(vec![ANSWER]),
+			Script::Receive(vec![0x12, 0x34]),
+			Script::Wait,
+			Script::Receive(vec![0x56, 0x78, 0x9a, 0xbc]),
+			Script::HangUp,
+		]);
+		let transport = ScriptedTransport::new().dial_result(Ok(connection));
+		let output = SharedOutput::new();
+
+		// Run the same program against the live line and against the capture of it
+		fn run(modem: &mut Modem, dcpu: &mut Dcpu) -> Vec<(u64, Vec<u16>, u16)> {
+			let mut history = Vec::new();
+			command(modem, dcpu, 3, 0, 1);
+			for cycle in 0..10 {
+				dcpu.cycle_count = cycle * 10;
+				modem.step(dcpu);
+				let (_, last_interrupt, _) = status(modem, dcpu);
+				history.push((cycle, modem.buffer.clone(), last_interrupt));
+			}
+			history
 		}
+
+		let (mut modem, mut dcpu) = setup(Box::new(transport));
+		modem.capture(Capture::new(Box::new(output.clone())).unwrap());
+		let live = run(&mut modem, &mut dcpu);
+		drop(modem);
+
+		let captured = output.text();
+		assert!(captured.contains("10 state Connected"));
+		assert!(captured.contains("20 receive 1234"));
+		assert!(captured.contains("20 read 1 12 34"));
+
+		let (mut modem, mut dcpu) = setup(Box::new(ReplayTransport::parse(&captured).unwrap()));
+		assert_eq!(run(&mut modem, &mut dcpu), live);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, CONNECTION_LOST, 3));
+	}
+
+	#[test]
+	fn caller_gone_before_answer() {
+		let caller = ScriptedConnection::new(vec![]).failing_writes(io::ErrorKind::BrokenPipe);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().incoming_call(1, caller)));
+
+		modem.step(&mut dcpu);
+		command(&mut modem, &mut dcpu, 2, 0, 0);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, CONNECTION_LOST, 0));
+	}
+
+	#[test]
+	fn send_on_a_broken_connection() {
+		let connection = ScriptedConnection::new(vec![Script::Receive(vec![ANSWER])]).failing_writes(io::ErrorKind::BrokenPipe);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().dial_result(Ok(connection))));
+
+		command(&mut modem, &mut dcpu, 3, 0, 1);
+		modem.step(&mut dcpu);
+		command(&mut modem, &mut dcpu, 5, 0xffff, 2);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, CONNECTION_LOST, 0));
+	}
+
+	#[test]
+	fn send_to_someone_who_stopped_listening() {
+		let connection = ScriptedConnection::new(vec![Script::Receive(vec![ANSWER])]).failing_writes(io::ErrorKind::WouldBlock);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().dial_result(Ok(connection))));
+
+		command(&mut modem, &mut dcpu, 3, 0, 1);
+		modem.step(&mut dcpu);
+
+		// What the line can't take waits for it, up to a limit
+		command(&mut modem, &mut dcpu, 5, 0, 0xffff);
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (3, CONNECTION_MADE, 0));
+		assert_eq!(modem.outgoing.len(), 0x1fffe);
+
+		command(&mut modem, &mut dcpu, 5, 0, 2);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, CONNECTION_LOST, 0));
+		assert!(modem.outgoing.is_empty());
+	}
+
+	#[test]
+	fn read_error_while_connected() {
+		let connection = ScriptedConnection::new(vec![Script::Receive(vec![ANSWER]), Script::Fail(io::ErrorKind::ConnectionReset)]);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().dial_result(Ok(connection))));
+
+		command(&mut modem, &mut dcpu, 3, 0, 1);
+		modem.step(&mut dcpu);
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, CONNECTION_LOST, 0));
+	}
+
+	#[test]
+	fn refused_caller_already_gone() {
+		let first = ScriptedConnection::new(vec![]);
+		let second = ScriptedConnection::new(vec![]).failing_writes(io::ErrorKind::BrokenPipe);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().incoming_call(1, first).incoming_call(2, second)));
+
+		modem.step(&mut dcpu);
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (1, CALLER_ID, 0));
+	}
+
+	#[test]
+	fn ring_and_answer() {
+		let caller = ScriptedConnection::new(vec![Script::Receive(vec![1, 2, 3]), Script::Wait, Script::Receive(vec![0xab, 0xcd])]);
+		let written = caller.written();
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().incoming_call(0x7f00_0001, caller)));
+
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (1, RINGING, 0));
+
+		// Anything sent before the call is answered is thrown away
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (1, CALLER_ID, 0));
+		command(&mut modem, &mut dcpu, 7, 0, 0);
+		assert_eq!((dcpu.registers[dcpu::B], dcpu.registers[dcpu::C]), (0x7f00, 0x0001));
+
+		command(&mut modem, &mut dcpu, 2, 0, 0);
+		assert_eq!(*written.lock().unwrap(), vec![ANSWER]);
+		assert_eq!(status(&mut modem, &mut dcpu).0, 3);
+
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (3, DATA_IN_BUFFER, 1));
+		assert_eq!(modem.buffer, vec![0xabcd]);
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn caller_hangs_up_while_ringing() {
+		let caller = ScriptedConnection::new(vec![Script::HangUp]);
+		let (mut modem, mut dcpu) = setup(Box::new(ScriptedTransport::new().incoming_call(1, caller)));
+
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (1, RINGING, 0));
+
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (0, CONNECTION_LOST, 0));
+	}
+
+	#[test]
+	fn second_caller_is_busy() {
+		let first = ScriptedConnection::new(vec![]);
+		let second = ScriptedConnection::new(vec![]);
+		let refused = second.written();
+		let transport = ScriptedTransport::new()
+			.incoming_call(1, first)
+			.incoming_call(2, second);
+		let (mut modem, mut dcpu) = setup(Box::new(transport));
+
+		modem.step(&mut dcpu);
+		command(&mut modem, &mut dcpu, 2, 0, 0);
+		modem.step(&mut dcpu);
+
+		assert_eq!(*refused.lock().unwrap(), vec![BUSY]);
+		assert_eq!(status(&mut modem, &mut dcpu).0, 3);
+	}
+
+	#[test]
+	fn call_waiting() {
+		let first = ScriptedConnection::new(vec![]);
+		let second = ScriptedConnection::new(vec![Script::Receive(vec![0x11, 0x22])]);
+		let first_written = first.written();
+		let second_written = second.written();
+		let third = ScriptedConnection::new(vec![]);
+		let refused = third.written();
+		let transport = ScriptedTransport::new()
+			.incoming_call(100, first)
+			.incoming_call(200, second)
+			.incoming_call(300, third);
+		let (mut modem, mut dcpu) = setup(Box::new(transport));
+		modem.allow_call_waiting(true);
+
+		modem.step(&mut dcpu);
+		command(&mut modem, &mut dcpu, 2, 0, 0);
+		assert_eq!(*first_written.lock().unwrap(), vec![ANSWER]);
+
+		// The second caller waits, the third hears a busy line
+		modem.step(&mut dcpu);
+		assert_eq!(status(&mut modem, &mut dcpu), (3, CALL_WAITING, 0));
+		command(&mut modem, &mut dcpu, 7, 0, 0);
+		assert_eq!(dcpu.registers[dcpu::C], 200);
+
+		modem.step(&mut dcpu);
+		assert_eq!(*refused.lock().unwrap(), vec![BUSY]);
+
+		// Switching to the waiting call drops the first one
+		command(&mut modem, &mut dcpu, 2, 0, 0);
+		assert_eq!(*second_written.lock().unwrap(), vec![ANSWER]);
+		assert_eq!(status(&mut modem, &mut dcpu).0, 3);
+		command(&mut modem, &mut dcpu, 7, 0, 0);
+		assert_eq!(dcpu.registers[dcpu::C], 200);
+	}
+
+	#[test]
+	fn waiting_call_rings_after_hang_up() {
+		let transport = ScriptedTransport::new()
+			.incoming_call(100, ScriptedConnection::new(vec![]))
+			.incoming_call(200, ScriptedConnection::new(vec![]));
+		let (mut modem, mut dcpu) = setup(Box::new(transport));
+		modem.allow_call_waiting(true);
+
+		modem.step(&mut dcpu);
+		command(&mut modem, &mut dcpu, 2, 0, 0);
+		modem.step(&mut dcpu);
+
+		command(&mut modem, &mut dcpu, 4, 0, 0);
+		assert_eq!(status(&mut modem, &mut dcpu), (1, RINGING, 0));
+		command(&mut modem, &mut dcpu, 7, 0, 0);
+		assert_eq!(dcpu.registers[dcpu::C], 200);
+	}
+
+	#[test]
+	fn two_modems_on_a_switchboard() {
+		let switchboard = Switchboard::new();
+		let (mut caller, mut caller_dcpu) = setup(Box::new(switchboard.connect(100)));
+		let (mut callee, mut callee_dcpu) = setup(Box::new(switchboard.connect(200)));
+
+		command(&mut caller, &mut caller_dcpu, 3, 0, 200);
+		assert_eq!(status(&mut caller, &mut caller_dcpu).0, 2);
+
+		callee.step(&mut callee_dcpu);
+		assert_eq!(status(&mut callee, &mut callee_dcpu), (1, RINGING, 0));
+
+		command(&mut callee, &mut callee_dcpu, 2, 0, 0);
+		caller.step(&mut caller_dcpu);
+		assert_eq!(status(&mut caller, &mut caller_dcpu), (3, CONNECTION_MADE, 0));
+
+		caller_dcpu.memory[0] = 0x4869;
+		command(&mut caller, &mut caller_dcpu, 5, 0, 1);
+		callee.step(&mut callee_dcpu);
+		assert_eq!(callee.buffer, vec![0x4869]);
+
+		command(&mut callee, &mut callee_dcpu, 4, 0, 0);
+		caller.step(&mut caller_dcpu);
+		assert_eq!(status(&mut caller, &mut caller_dcpu), (0, CONNECTION_LOST, 0));
+
+		command(&mut caller, &mut caller_dcpu, 3, 0, 300);
+		assert_eq!(status(&mut caller, &mut caller_dcpu), (0, NO_MODEM, 0));
+	}
+
+	#[test]
+	fn dial_a_modem_on_this_computer_by_its_port() {
+		let (mut caller, mut caller_dcpu) = setup(Box::new(TcpTransport::new(36483, 6482).unwrap()));
+		let (mut callee, mut callee_dcpu) = setup(Box::new(TcpTransport::new(36484, 6482).unwrap()));
+
+		command(&mut caller, &mut caller_dcpu, 3, 0, 36484);
+		assert_eq!(status(&mut caller, &mut caller_dcpu).0, 2);
+
+		// The call takes a moment to come through the loopback interface
+		for _ in 0..100 {
+			callee.step(&mut callee_dcpu);
+			if status(&mut callee, &mut callee_dcpu).0 == 1 {
+				break;
+			}
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		assert_eq!(status(&mut callee, &mut callee_dcpu), (1, RINGING, 0));
+	}
+}
@@ -0,0 +1,286 @@
+use std::io::prelude::*;
+use std::fmt::Write as FmtWrite;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use symbols::Symbols;
+use trace::{TraceSink, Entry};
+
+
+// Deepest the call stack is tracked, in case a program uses jsr without ever returning
+const MAX_DEPTH: usize = 4096;
+
+
+/// How many times something happened, and the cycles it took
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Cost {
+	pub count: u64,
+	pub cycles: u64,
+}
+
+
+/// The cost of a subroutine: cycles spent in its own instructions, and in
+/// those plus everything it called
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Function {
+	pub calls: u64,
+	pub self_cycles: u64,
+	pub inclusive_cycles: u64,
+}
+
+
+// A subroutine that has been called and hasn't returned yet
+struct Frame {
+	function: u16,
+	cycles: u64,
+}
+
+
+/// Where a program spent its cycles, by instruction and by subroutine. Subroutines
+/// start at the target of a jsr and end with `set pc, pop`.
+pub struct Profile {
+	pub total_cycles: u64,
+	pub instructions: HashMap<u16, Cost>,
+	pub functions: HashMap<u16, Function>,
+	/// Calls from one subroutine to another, with the cycles spent in the calls
+	pub calls: HashMap<(u16, u16), Cost>,
+	stack: Vec<Frame>,
+	on_stack: HashMap<u16, usize>,
+}
+
+impl Profile {
+	pub fn new() -> Profile {
+		Profile {
+			total_cycles: 0,
+			instructions: HashMap::new(),
+			functions: HashMap::new(),
+			calls: HashMap::new(),
+			stack: vec![],
+			on_stack: HashMap::new(),
+		}
+	}
+
+
+	fn enter(&mut self, function: u16) {
+		if self.stack.len() == MAX_DEPTH {
+			let oldest = self.stack.remove(0);
+			self.leave_stack(oldest.function);
+		}
+
+		self.functions.entry(function).or_insert_with(Function::default).calls += 1;
+		*self.on_stack.entry(function).or_insert(0) += 1;
+		self.stack.push(Frame {function: function, cycles: 0});
+	}
+
+
+	fn leave_stack(&mut self, function: u16) {
+		let remaining = {
+			let count = self.on_stack.get_mut(&function).unwrap();
+			*count -= 1;
+			*count
+		};
+
+		if remaining == 0 {
+			self.on_stack.remove(&function);
+		}
+	}
+
+
+	pub fn record(&mut self, entry: &Entry) {
+		// Whatever runs first is the root of the call graph
+		if self.stack.is_empty() {
+			self.enter(entry.address);
+		}
+
+		let cycles = entry.cycles as u64;
+		self.total_cycles += cycles;
+
+		let cost = self.instructions.entry(entry.address).or_insert_with(Cost::default);
+		cost.count += 1;
+		cost.cycles += cycles;
+
+		// Recursive calls are only counted once towards a subroutine's inclusive cost
+		let current = self.stack.last().unwrap().function;
+		self.functions.get_mut(&current).unwrap().self_cycles += cycles;
+		for function in self.on_stack.keys() {
+			self.functions.get_mut(function).unwrap().inclusive_cycles += cycles;
+		}
+		for frame in &mut self.stack {
+			frame.cycles += cycles;
+		}
+
+		let word = entry.words[0];
+		if word & 0x3ff == 0x01 << 5 {
+			// jsr
+			let callee = entry.after[9];
+			self.calls.entry((current, callee)).or_insert_with(Cost::default).count += 1;
+			self.enter(callee);
+		} else if word == 0x6381 && self.stack.len() > 1 {
+			// set pc, pop
+			let frame = self.stack.pop().unwrap();
+			self.leave_stack(frame.function);
+
+			let caller = self.stack.last().unwrap().function;
+			self.calls.entry((caller, frame.function)).or_insert_with(Cost::default).cycles += frame.cycles;
+		}
+	}
+
+
+	/// Write out where the cycles went, most expensive first
+	pub fn report(&self, symbols: Option<&Symbols>) -> String {
+		let name = |address: u16| match symbols.and_then(|symbols| symbols.nearest(address)) {
+			Some((label, 0)) => format!("0x{:04x} {}", address, label),
+			Some((label, offset)) => format!("0x{:04x} {}+{}", address, label, offset),
+			None => format!("0x{:04x}", address),
+		};
+		let percent = |cycles: u64| if self.total_cycles == 0 {0.0} else {cycles as f64 * 100.0 / self.total_cycles as f64};
+
+		let mut report = String::new();
+		let _ = writeln!(report, "# {} cycles", self.total_cycles);
+
+		let mut functions: Vec<(&u16, &Function)> = self.functions.iter().collect();
+		functions.sort_by(|a, b| b.1.inclusive_cycles.cmp(&a.1.inclusive_cycles).then(a.0.cmp(b.0)));
+		let _ = writeln!(report, "\n# subroutines by inclusive cost\n#  inclusive       %       self       %    calls  subroutine");
+		for (&address, function) in functions {
+			let _ = writeln!(report, "{:>11} {:>6.2}% {:>10} {:>6.2}% {:>8}  {}",
+				function.inclusive_cycles, percent(function.inclusive_cycles),
+				function.self_cycles, percent(function.self_cycles),
+				function.calls, name(address));
+		}
+
+		let mut instructions: Vec<(&u16, &Cost)> = self.instructions.iter().collect();
+		instructions.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+		let _ = writeln!(report, "\n# instructions by self cost\n#     cycles       %      count  address");
+		for (&address, cost) in instructions {
+			let _ = writeln!(report, "{:>12} {:>6.2}% {:>10}  {}", cost.cycles, percent(cost.cycles), cost.count, name(address));
+		}
+
+		let mut calls: Vec<(&(u16, u16), &Cost)> = self.calls.iter().collect();
+		calls.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+		let _ = writeln!(report, "\n# calls by cost\n#     cycles    calls  caller -> callee");
+		for (&(caller, callee), cost) in calls {
+			let _ = writeln!(report, "{:>12} {:>8}  {} -> {}", cost.cycles, cost.count, name(caller), name(callee));
+		}
+
+		report
+	}
+}
+
+
+
+
+/// Profiles the CPU it is attached to, writing a report out when the CPU halts,
+/// or when the profiler is dropped if it never does
+pub struct Profiler {
+	profile: Arc<Mutex<Profile>>,
+	symbols: Option<Symbols>,
+	output: Option<Box<dyn Write + Send>>,
+}
+
+impl Profiler {
+	pub fn new(symbols: Option<Symbols>, output: Option<Box<dyn Write + Send>>) -> Profiler {
+		Profiler {
+			profile: Arc::new(Mutex::new(Profile::new())),
+			symbols: symbols,
+			output: output,
+		}
+	}
+
+	/// The profile so far, to look at while the CPU keeps running
+	pub fn profile(&self) -> Arc<Mutex<Profile>> {
+		self.profile.clone()
+	}
+
+
+	// Write the report out, the first time only
+	fn write_report(&mut self) {
+		if let Some(mut output) = self.output.take() {
+			let profile = self.profile.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+			let _ = output.write_all(profile.report(self.symbols.as_ref()).as_bytes());
+			let _ = output.flush();
+		}
+	}
+}
+
+impl TraceSink for Profiler {
+	fn record(&mut self, entry: &Entry) {
+		self.profile.lock().unwrap().record(entry);
+
+		if entry.is_halt() {
+			self.write_report();
+		}
+	}
+}
+
+impl Drop for Profiler {
+	fn drop(&mut self) {
+		self.write_report();
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dcpu;
+
+	#[test]
+	fn profile_subroutines() {
+		let mut dcpu = dcpu::Dcpu::new();
+		let program = [
+			0x7c20, 0x0005, // jsr double
+			0x7c20, 0x0005, // jsr double
+			0x8b83,         // sub pc, 1
+			0x0002,         // double: add a, a
+			0x6381,         //         set pc, pop
+		];
+		dcpu.memory[..program.len()].copy_from_slice(&program);
+
+		let profiler = Profiler::new(None, None);
+		let profile = profiler.profile();
+		dcpu.trace_sinks.push(Box::new(profiler));
+
+		while dcpu.cycle_count < 30 {
+			dcpu.step();
+		}
+
+		let profile = profile.lock().unwrap();
+		let root = profile.functions[&0];
+		let double = profile.functions[&5];
+
+		assert_eq!(double.calls, 2);
+		assert_eq!(profile.calls[&(0, 5)].count, 2);
+		// add costs 2 cycles and set pc, pop 1
+		assert_eq!(double.self_cycles, 6);
+		assert_eq!(profile.calls[&(0, 5)].cycles, 6);
+		// each jsr costs 4 cycles with its next word
+		assert_eq!(root.inclusive_cycles, profile.total_cycles);
+		assert_eq!(root.self_cycles, profile.total_cycles - 6);
+		assert_eq!(profile.instructions[&0].cycles, 4);
+
+		let mut symbols = Symbols::new();
+		symbols.insert("double", 5);
+		let report = profile.report(Some(&symbols));
+		assert!(report.contains("0x0005 double"), "{}", report);
+	}
+
+	#[test]
+	fn report_written_when_dropped() {
+		let path = ::std::env::temp_dir().join(format!("dcpu-profile-{}.txt", ::std::process::id()));
+
+		// add a, 1; set pc, 0 never halts
+		let mut dcpu = dcpu::Dcpu::new();
+		dcpu.memory[..2].copy_from_slice(&[0x8802, 0x8781]);
+		let output = ::std::fs::File::create(&path).unwrap();
+		dcpu.trace_sinks.push(Box::new(Profiler::new(None, Some(Box::new(output)))));
+		for _ in 0..10 {
+			dcpu.step();
+		}
+		drop(dcpu);
+
+		let report = ::std::fs::read_to_string(&path).unwrap();
+		::std::fs::remove_file(&path).unwrap();
+		assert!(report.contains("0x0000"), "{}", report);
+	}
+}
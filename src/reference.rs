@@ -0,0 +1,489 @@
+//! A deliberately plain model of the 1.7 CPU, written from the specification
+//! rather than from `dcpu.rs`, and a proptest harness that runs random programs
+//! through both and reports the first place they disagree. Whole instructions
+//! run at a time, and hardware isn't modelled.
+
+use proptest::prelude::*;
+use dcpu::{Dcpu, FaultPolicy};
+
+
+// Where an operand's value lives
+#[derive(Copy, Clone)]
+enum Operand {
+	Register(usize),
+	Memory(u16),
+	StackPointer,
+	ProgramCounter,
+	Excess,
+	Literal(u16),
+}
+
+
+/// The state of the reference CPU
+#[derive(Clone)]
+pub struct Reference {
+	pub registers: [u16; 8],
+	pub stack_pointer: u16,
+	pub program_counter: u16,
+	pub excess: u16,
+	pub interrupt_address: u16,
+	pub memory: Vec<u16>,
+	pub queue: Vec<u16>,
+	pub queueing: bool,
+	pub cycles: u64,
+	/// Set by hcf, illegal instructions, writes to literals, and too many interrupts
+	pub on_fire: bool,
+}
+
+impl Reference {
+	pub fn new() -> Reference {
+		Reference {
+			registers: [0; 8],
+			stack_pointer: 0,
+			program_counter: 0,
+			excess: 0,
+			interrupt_address: 0,
+			memory: vec![0; 0x10000],
+			queue: vec![],
+			queueing: false,
+			cycles: 0,
+			on_fire: false,
+		}
+	}
+
+
+	fn next_word(&mut self) -> u16 {
+		let word = self.memory[self.program_counter as usize];
+		self.program_counter = self.program_counter.wrapping_add(1);
+		self.cycles += 1;
+		word
+	}
+
+
+	// Work out where an operand is, doing whatever pushing and popping it involves
+	fn operand(&mut self, code: u16, is_a: bool) -> Operand {
+		match code {
+			0x00...0x07 => Operand::Register(code as usize),
+			0x08...0x0f => Operand::Memory(self.registers[code as usize - 0x08]),
+			0x10...0x17 => {
+				let offset = self.next_word();
+				Operand::Memory(self.registers[code as usize - 0x10].wrapping_add(offset))
+			},
+			0x18 if is_a => {
+				let address = self.stack_pointer;
+				self.stack_pointer = self.stack_pointer.wrapping_add(1);
+				Operand::Memory(address)
+			},
+			0x18 => {
+				self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+				Operand::Memory(self.stack_pointer)
+			},
+			0x19 => Operand::Memory(self.stack_pointer),
+			0x1a => {
+				let offset = self.next_word();
+				Operand::Memory(self.stack_pointer.wrapping_add(offset))
+			},
+			0x1b => Operand::StackPointer,
+			0x1c => Operand::ProgramCounter,
+			0x1d => Operand::Excess,
+			0x1e => Operand::Memory(self.next_word()),
+			0x1f => Operand::Literal(self.next_word()),
+			_ => Operand::Literal(code.wrapping_sub(0x21)),
+		}
+	}
+
+
+	fn read(&self, operand: Operand) -> u16 {
+		match operand {
+			Operand::Register(index) => self.registers[index],
+			Operand::Memory(address) => self.memory[address as usize],
+			Operand::StackPointer => self.stack_pointer,
+			Operand::ProgramCounter => self.program_counter,
+			Operand::Excess => self.excess,
+			Operand::Literal(value) => value,
+		}
+	}
+
+
+	fn write(&mut self, operand: Operand, value: u16) {
+		match operand {
+			Operand::Register(index) => self.registers[index] = value,
+			Operand::Memory(address) => self.memory[address as usize] = value,
+			Operand::StackPointer => self.stack_pointer = value,
+			Operand::ProgramCounter => self.program_counter = value,
+			Operand::Excess => self.excess = value,
+			Operand::Literal(_) => self.on_fire = true,
+		}
+	}
+
+
+	fn push(&mut self, value: u16) {
+		self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+		self.memory[self.stack_pointer as usize] = value;
+	}
+
+
+	fn pop(&mut self) -> u16 {
+		let value = self.memory[self.stack_pointer as usize];
+		self.stack_pointer = self.stack_pointer.wrapping_add(1);
+		value
+	}
+
+
+	// Move past the instruction at the program counter without running it
+	fn skip(&mut self) {
+		let word = self.memory[self.program_counter as usize];
+		let has_next_word = |code: u16| (code >= 0x10 && code <= 0x17) || code == 0x1a || code == 0x1e || code == 0x1f;
+
+		let mut length = 1;
+		if has_next_word(word >> 10) {
+			length += 1;
+		}
+		if word & 0x1f != 0 && has_next_word((word >> 5) & 0x1f) {
+			length += 1;
+		}
+
+		self.program_counter = self.program_counter.wrapping_add(length);
+	}
+
+
+	/// Where the next instruction will run from, once any waiting interrupt is handled
+	pub fn next_address(&self) -> u16 {
+		if !self.queueing && !self.queue.is_empty() && self.interrupt_address != 0 {
+			self.interrupt_address
+		} else {
+			self.program_counter
+		}
+	}
+
+
+	/// Run one whole instruction, after handling an interrupt if one is waiting
+	pub fn step(&mut self) {
+		if self.on_fire {
+			return;
+		}
+
+		if self.queue.len() > 256 {
+			self.on_fire = true;
+			return;
+		}
+
+		if !self.queueing && !self.queue.is_empty() {
+			let message = self.queue.remove(0);
+			if self.interrupt_address != 0 {
+				self.queueing = true;
+				let (program_counter, a) = (self.program_counter, self.registers[0]);
+				self.push(program_counter);
+				self.push(a);
+				self.program_counter = self.interrupt_address;
+				self.registers[0] = message;
+			}
+		}
+
+		let word = self.next_word();
+		// The opcode's own word is part of the instruction's cost, not an extra
+		self.cycles -= 1;
+
+		let opcode = word & 0x1f;
+		let b_code = (word >> 5) & 0x1f;
+		let a = self.operand(word >> 10, true);
+		let a_value = self.read(a);
+
+		if opcode == 0 {
+			self.special(b_code, a, a_value);
+		} else {
+			let b = self.operand(b_code, false);
+			let b_value = self.read(b);
+			self.basic(opcode, b, b_value, a_value);
+		}
+	}
+
+
+	fn special(&mut self, opcode: u16, a: Operand, a_value: u16) {
+		match opcode {
+			0x01 => { // jsr
+				let program_counter = self.program_counter;
+				self.push(program_counter);
+				self.program_counter = a_value;
+				self.cycles += 3;
+			},
+			0x07 => self.on_fire = true, // hcf
+			0x08 => { // int
+				if self.interrupt_address != 0 {
+					self.queue.push(a_value);
+				}
+				self.cycles += 4;
+			},
+			0x09 => { // iag
+				let interrupt_address = self.interrupt_address;
+				self.write(a, interrupt_address);
+				self.cycles += 1;
+			},
+			0x0a => { // ias
+				self.interrupt_address = a_value;
+				self.cycles += 1;
+			},
+			0x0b => { // rfi
+				self.queueing = false;
+				self.registers[0] = self.pop();
+				self.program_counter = self.pop();
+				self.cycles += 3;
+			},
+			0x0c => { // iaq
+				self.queueing = a_value != 0;
+				self.cycles += 2;
+			},
+			_ => self.on_fire = true,
+		}
+	}
+
+
+	fn basic(&mut self, opcode: u16, b: Operand, b_value: u16, a_value: u16) {
+		let (b_wide, a_wide) = (b_value as i64, a_value as i64);
+		let (b_signed, a_signed) = (b_value as i16 as i64, a_value as i16 as i64);
+		let shift = (a_value as u32).min(63);
+
+		// The result and, for the instructions that set it, EX
+		let (result, excess, cycles): (i64, Option<i64>, u64) = match opcode {
+			0x01 => (a_wide, None, 1),
+			0x02 => (b_wide + a_wide, Some(((b_wide + a_wide) > 0xffff) as i64), 2),
+			0x03 => (b_wide - a_wide, Some(if b_wide < a_wide {0xffff} else {0}), 2),
+			0x04 => (b_wide * a_wide, Some((b_wide * a_wide) >> 16), 2),
+			0x05 => (b_signed * a_signed, Some((b_signed * a_signed) >> 16), 2),
+			0x06 if a_value == 0 => (0, Some(0), 3),
+			0x06 => (b_wide / a_wide, Some((b_wide << 16) / a_wide), 3),
+			0x07 if a_value == 0 => (0, Some(0), 3),
+			0x07 => (b_signed / a_signed, Some((b_signed << 16) / a_signed), 3),
+			0x08 => (if a_value == 0 {0} else {b_wide % a_wide}, None, 3),
+			0x09 => (if a_value == 0 {0} else {b_signed % a_signed}, None, 3),
+			0x0a => (b_wide & a_wide, None, 1),
+			0x0b => (b_wide | a_wide, None, 1),
+			0x0c => (b_wide ^ a_wide, None, 1),
+			0x0d => (b_wide >> shift, Some((b_wide << 16) >> shift), 1),
+			0x0e => (b_signed >> shift, Some((b_signed << 16) >> shift), 1),
+			0x0f => (b_wide << shift.min(48), Some((b_wide << shift.min(48)) >> 16), 1),
+			0x10...0x17 => {
+				let passes = match opcode {
+					0x10 => b_value & a_value != 0,
+					0x11 => b_value & a_value == 0,
+					0x12 => b_value == a_value,
+					0x13 => b_value != a_value,
+					0x14 => b_value > a_value,
+					0x15 => b_signed > a_signed,
+					0x16 => b_value < a_value,
+					_ => b_signed < a_signed,
+				};
+
+				self.cycles += 2;
+				if !passes {
+					self.cycles += 1;
+					while (0x10..0x18).contains(&(self.memory[self.program_counter as usize] & 0x1f)) {
+						self.skip();
+						self.cycles += 1;
+					}
+					self.skip();
+				}
+				return;
+			},
+			0x1a => {
+				let sum = b_wide + a_wide + self.excess as i64;
+				(sum, Some((sum > 0xffff) as i64), 3)
+			},
+			0x1b => {
+				let difference = b_wide - a_wide + self.excess as i16 as i64;
+				(difference, Some(if difference < 0 {0xffff} else if difference > 0xffff {1} else {0}), 3)
+			},
+			0x1e | 0x1f => (a_wide, None, 2),
+			_ => {
+				self.on_fire = true;
+				return;
+			},
+		};
+
+		self.cycles += cycles;
+		self.write(b, result as u16);
+		if let Some(excess) = excess {
+			self.excess = excess as u16;
+		}
+
+		let step = if opcode == 0x1e {1} else if opcode == 0x1f {0xffff} else {0};
+		self.registers[6] = self.registers[6].wrapping_add(step);
+		self.registers[7] = self.registers[7].wrapping_add(step);
+	}
+}
+
+
+
+
+// Everything a random test starts from
+#[derive(Clone, Debug)]
+struct Start {
+	registers: [u16; 8],
+	stack_pointer: u16,
+	excess: u16,
+	interrupt_address: u16,
+	program: Vec<u16>,
+	data: Vec<u16>,
+}
+
+// Where the random data goes, for pointers to find
+const DATA: usize = 0x100;
+
+
+// Whether the word is hwn, hwq or hwi, which need a System to finish
+fn is_hardware(word: u16) -> bool {
+	word & 0x1f == 0 && (word >> 5) & 0x1f >= 0x10 && (word >> 5) & 0x1f <= 0x12
+}
+
+
+// Step until the DCPU has run a whole instruction
+fn step_instruction(dcpu: &mut Dcpu) {
+	while dcpu.cycle_accumulator > 0 {
+		dcpu.step();
+	}
+	dcpu.step();
+}
+
+
+// Run the start through both CPUs for up to `count` instructions, running the
+// DCPU's with `run`, and describe the first difference
+fn compare(start: &Start, count: usize, run: fn(&mut Dcpu)) -> Result<(), String> {
+	let mut dcpu = Dcpu::new();
+	let mut reference = Reference::new();
+	dcpu.fault_policy = FaultPolicy::Halt;
+	dcpu.strict_operands = true;
+
+	dcpu.registers = start.registers;
+	dcpu.stack_pointer = start.stack_pointer;
+	dcpu.excess = start.excess;
+	dcpu.interrupt_address = start.interrupt_address;
+	dcpu.memory[..start.program.len()].copy_from_slice(&start.program);
+	dcpu.memory[DATA..DATA + start.data.len()].copy_from_slice(&start.data);
+
+	reference.registers = start.registers;
+	reference.stack_pointer = start.stack_pointer;
+	reference.excess = start.excess;
+	reference.interrupt_address = start.interrupt_address;
+	reference.memory.copy_from_slice(&dcpu.memory[..]);
+
+	for index in 0..count {
+		let address = reference.next_address();
+		let word = reference.memory[address as usize];
+		if reference.on_fire || is_hardware(word) {
+			return Ok(());
+		}
+
+		run(&mut dcpu);
+		reference.step();
+
+		let differences = differences(&dcpu, &reference);
+		if !differences.is_empty() {
+			return Err(format!("instruction {} (0x{:04x} at 0x{:04x}) diverged: {}", index, word, address, differences.join(", ")));
+		}
+	}
+
+	Ok(())
+}
+
+
+fn differences(dcpu: &Dcpu, reference: &Reference) -> Vec<String> {
+	let mut differences = vec![];
+	let mut compare = |name: &str, dcpu: u64, reference: u64| if dcpu != reference {
+		differences.push(format!("{} is 0x{:x} but should be 0x{:x}", name, dcpu, reference));
+	};
+
+	compare("on fire", dcpu.fault.is_some() as u64, reference.on_fire as u64);
+
+	let names = ["A", "B", "C", "X", "Y", "Z", "I", "J"];
+	for (index, name) in names.iter().enumerate() {
+		compare(name, dcpu.registers[index] as u64, reference.registers[index] as u64);
+	}
+	compare("SP", dcpu.stack_pointer as u64, reference.stack_pointer as u64);
+	compare("PC", dcpu.program_counter as u64, reference.program_counter as u64);
+	compare("EX", dcpu.excess as u64, reference.excess as u64);
+	compare("IA", dcpu.interrupt_address as u64, reference.interrupt_address as u64);
+	compare("queueing", dcpu.interrupt_queueing as u64, reference.queueing as u64);
+	compare("queue length", dcpu.interrupt_queue.len() as u64, reference.queue.len() as u64);
+
+	// A faulting instruction's cost isn't defined
+	if !reference.on_fire {
+		compare("cycles", dcpu.cycle_count + dcpu.cycle_accumulator as u64, reference.cycles);
+	}
+
+	if let Some(address) = (0..0x10000).find(|&address| dcpu.memory[address] != reference.memory[address]) {
+		compare(&format!("[0x{:04x}]", address), dcpu.memory[address] as u64, reference.memory[address] as u64);
+	}
+
+	differences
+}
+
+
+// Mostly real instructions with small literals and pointers into the data, and
+// sometimes any word at all
+fn instruction() -> BoxedStrategy<Vec<u16>> {
+	let basic = (prop_oneof![1u16..0x10, 0x10u16..0x18, Just(0x1a), Just(0x1b), Just(0x1e), Just(0x1f)], 0u16..0x20, 0u16..0x40)
+		.prop_map(|(opcode, b, a)| opcode | b << 5 | a << 10);
+	let special = (prop_oneof![Just(0x01u16), Just(0x08), Just(0x09), Just(0x0a), Just(0x0b), Just(0x0c)], 0u16..0x40)
+		.prop_map(|(opcode, a)| opcode << 5 | a << 10);
+	let next_word = prop_oneof![0u16..0x20, (DATA as u16)..(DATA as u16 + 0x20), any::<u16>()];
+
+	(prop_oneof![8 => basic, 2 => special, 1 => any::<u16>()], next_word.clone(), next_word)
+		.prop_map(|(word, first, second)| vec![word, first, second])
+		.boxed()
+}
+
+
+fn start() -> BoxedStrategy<Start> {
+	let register = prop_oneof![0u16..0x20, (DATA as u16)..(DATA as u16 + 0x20), any::<u16>()];
+
+	(
+		prop::array::uniform8(register),
+		prop_oneof![Just(0u16), 0xff00u16..0xffff],
+		any::<u16>(),
+		prop_oneof![Just(0u16), 0u16..0x60],
+		prop::collection::vec(instruction(), 1..24),
+		prop::collection::vec(any::<u16>(), 0x20),
+	).prop_map(|(registers, stack_pointer, excess, interrupt_address, instructions, data)| Start {
+		registers: registers,
+		stack_pointer: stack_pointer,
+		excess: excess,
+		interrupt_address: interrupt_address,
+		// Each instruction is given two words after it, which its operands may or may not use
+		program: instructions.concat(),
+		data: data,
+	}).boxed()
+}
+
+
+proptest! {
+	#![proptest_config(ProptestConfig::with_cases(512))]
+
+	#[test]
+	fn matches_reference(ref start in start()) {
+		if let Err(divergence) = compare(start, 200, step_instruction) {
+			return Err(TestCaseError::fail(divergence));
+		}
+	}
+
+	#[test]
+	fn decoded_matches_reference(ref start in start()) {
+		if let Err(divergence) = compare(start, 200, |dcpu| { dcpu.run_instruction(); }) {
+			return Err(TestCaseError::fail(divergence));
+		}
+	}
+}
+
+
+#[test]
+fn reference_agrees_with_itself() {
+	// A sanity check on the harness: add a, 1 forever
+	let start = Start {
+		registers: [0; 8],
+		stack_pointer: 0,
+		excess: 0,
+		interrupt_address: 0,
+		program: vec![0x8802, 0x8781],
+		data: vec![],
+	};
+	assert_eq!(compare(&start, 100, step_instruction), Ok(()));
+}
@@ -0,0 +1,165 @@
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+
+
+/// The version of the save state format written by this emulator
+pub const VERSION: u32 = 1;
+
+const HEADER: &'static str = "# dcpu save state";
+
+// Words per line when writing a section out
+const WORDS_PER_LINE: usize = 16;
+
+
+/// A snapshot of a whole machine, as named sections of words. The CPU and each
+/// device decide what goes in their own sections.
+pub struct SaveState {
+	sections: Vec<(String, Vec<u16>)>,
+}
+
+impl SaveState {
+	pub fn new() -> SaveState {
+		SaveState {sections: vec![]}
+	}
+
+	/// Load the save state at the given path
+	pub fn load(path: &str) -> io::Result<SaveState> {
+		let mut text = String::new();
+		File::open(path)?.read_to_string(&mut text)?;
+		SaveState::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+	}
+
+	/// Read a save state that has already been read into memory. The first line
+	/// after the header gives the version, and each line after that is a section
+	/// name followed by its words. Long sections are split over several lines.
+	pub fn parse(text: &str) -> Result<SaveState, String> {
+		let mut lines = text.lines().map(|line| line.trim()).enumerate()
+			.filter(|&(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+		match lines.next().map(|(_, line)| line.split_whitespace().collect::<Vec<_>>()) {
+			Some(ref words) if words.len() == 2 && words[0] == "version" => match words[1].parse() {
+				Ok(VERSION) => (),
+				Ok(version) => return Err(format!("Save states from version {} can't be loaded, only version {}", version, VERSION)),
+				Err(_) => return Err("Unreadable save state version".to_string()),
+			},
+			_ => return Err("This is not a save state".to_string()),
+		}
+
+		let mut state = SaveState::new();
+		for (number, line) in lines {
+			let mut parts = line.split_whitespace();
+			let name = parts.next().unwrap_or("");
+			let words = parts.map(|word| u16::from_str_radix(word, 16)).collect::<Result<Vec<_>, _>>()
+				.map_err(|_| format!("Unreadable save state on line {}: {}", number + 1, line))?;
+
+			match state.sections.iter().position(|&(ref section, _)| section == name) {
+				Some(index) => state.sections[index].1.extend(words),
+				None => state.sections.push((name.to_string(), words)),
+			}
+		}
+
+		Ok(state)
+	}
+
+	pub fn save(&self, path: &str) -> io::Result<()> {
+		let mut output = io::BufWriter::new(File::create(path)?);
+		self.write(&mut output)?;
+		output.flush()
+	}
+
+	pub fn write(&self, output: &mut dyn Write) -> io::Result<()> {
+		writeln!(output, "{}", HEADER)?;
+		writeln!(output, "version {}", VERSION)?;
+
+		for &(ref name, ref words) in &self.sections {
+			if words.is_empty() {
+				writeln!(output, "{}", name)?;
+			}
+
+			for line in words.chunks(WORDS_PER_LINE) {
+				writeln!(output, "{}{}", name, line.iter().map(|word| format!(" {:04x}", word)).collect::<String>())?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Set the words of a section, replacing any already there
+	pub fn put(&mut self, name: &str, words: Vec<u16>) {
+		match self.sections.iter().position(|&(ref section, _)| section == name) {
+			Some(index) => self.sections[index].1 = words,
+			None => self.sections.push((name.to_string(), words)),
+		}
+	}
+
+	pub fn get(&self, name: &str) -> Option<&[u16]> {
+		self.sections.iter().find(|&&(ref section, _)| section == name).map(|&(_, ref words)| &words[..])
+	}
+
+	/// The names of every section, in the order they were put in
+	pub fn names(&self) -> Vec<&str> {
+		self.sections.iter().map(|&(ref name, _)| name.as_str()).collect()
+	}
+}
+
+
+/// Take a section's words, or say which section is missing
+pub fn section<'a>(state: &'a SaveState, name: &str) -> Result<&'a [u16], String> {
+	state.get(name).ok_or_else(|| format!("The save state has no {} section", name))
+}
+
+
+/// Split a 32 bit number into words, high word first
+pub fn split(value: u32) -> [u16; 2] {
+	[(value >> 16) as u16, value as u16]
+}
+
+
+/// Join two words, high word first, back into a 32 bit number
+pub fn join(high: u16, low: u16) -> u32 {
+	(high as u32) << 16 | low as u32
+}
+
+
+/// Split a 64 bit number into words, high word first
+pub fn split_long(value: u64) -> [u16; 4] {
+	[(value >> 48) as u16, (value >> 32) as u16, (value >> 16) as u16, value as u16]
+}
+
+
+/// Join words, high word first, back into a 64 bit number
+pub fn join_long(words: &[u16]) -> u64 {
+	words.iter().fold(0, |value, &word| value << 16 | word as u64)
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		let mut state = SaveState::new();
+		state.put("cpu", vec![1, 2, 3]);
+		state.put("memory", (0..40).collect());
+		state.put("empty", vec![]);
+
+		let mut text = Vec::new();
+		state.write(&mut text).unwrap();
+		let loaded = SaveState::parse(&String::from_utf8(text).unwrap()).unwrap();
+
+		assert_eq!(loaded.get("cpu"), Some(&[1, 2, 3][..]));
+		assert_eq!(loaded.get("memory").map(|words| words.len()), Some(40));
+		assert_eq!(loaded.get("empty"), Some(&[][..]));
+		assert_eq!(loaded.names(), vec!["cpu", "memory", "empty"]);
+	}
+
+	#[test]
+	fn unknown_version() {
+		assert!(SaveState::parse("# dcpu save state\nversion 99\n").is_err());
+		assert!(SaveState::parse("cpu 0001\n").is_err());
+	}
+}
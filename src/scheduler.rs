@@ -0,0 +1,317 @@
+use std::any::Any;
+use std::fmt;
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use system::System;
+use transport::Switchboard;
+
+
+// What a machine's thread is asked to do
+enum Command {
+	Run(u64),
+	Visit(Box<dyn FnOnce(&mut System) + Send>),
+	Stop,
+}
+
+
+// A machine living on a thread of its own. Devices like the LEM1820 can't move
+// between threads, so the machine is built on the thread it runs on.
+struct Worker {
+	commands: Sender<Command>,
+	// The cycles run in answer to each Command::Run
+	finished: Receiver<u64>,
+	thread: Option<thread::JoinHandle<()>>,
+	// What the thread panicked with, once it has been waited for
+	panic: Option<String>,
+}
+
+impl Worker {
+	// Returns why the machine panicked if it did while it was being built
+	fn spawn<F>(build: F) -> Result<Worker, String> where F: FnOnce() -> System + Send + 'static {
+		let (commands, command_receiver) = channel();
+		let (finished_sender, finished) = channel();
+
+		let thread = thread::spawn(move || {
+			let mut system = build();
+			if finished_sender.send(0).is_err() {
+				return;
+			}
+
+			for command in command_receiver {
+				match command {
+					Command::Run(cycles) => {
+						if finished_sender.send(system.run_cycles(cycles)).is_err() {
+							break;
+						}
+					},
+					Command::Visit(visit) => visit(&mut system),
+					Command::Stop => break,
+				}
+			}
+		});
+
+		let mut worker = Worker {
+			commands: commands,
+			finished: finished,
+			thread: Some(thread),
+			panic: None,
+		};
+
+		// Wait for the machine to be built, so that its modems are plugged in before anyone dials them
+		match worker.finished.recv() {
+			Ok(_) => Ok(worker),
+			Err(_) => Err(worker.panicked()),
+		}
+	}
+
+
+	// Wait for the thread once it has stopped answering, and say what it panicked with
+	fn panicked(&mut self) -> String {
+		if let Some(thread) = self.thread.take() {
+			self.panic = Some(match thread.join() {
+				Err(payload) => panic_message(&*payload),
+				Ok(()) => "its thread stopped".to_string(),
+			});
+		}
+		self.panic.clone().unwrap_or_default()
+	}
+}
+
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+	if let Some(message) = payload.downcast_ref::<&str>() {
+		message.to_string()
+	} else if let Some(message) = payload.downcast_ref::<String>() {
+		message.clone()
+	} else {
+		"a panic".to_string()
+	}
+}
+
+impl Drop for Worker {
+	fn drop(&mut self) {
+		let _ = self.commands.send(Command::Stop);
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+
+enum Machine {
+	Local(System),
+	Threaded(Worker),
+}
+
+
+/// A machine on a thread of its own panicked. The scheduler can't run its
+/// machines any further once one has.
+#[derive(Debug)]
+pub struct Panicked {
+	/// The index of the machine
+	pub index: usize,
+	/// What it panicked with
+	pub message: String,
+}
+
+impl fmt::Display for Panicked {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Machine {} panicked: {}", self.index, self.message)
+	}
+}
+
+
+/// Runs many machines side by side, one DCPU per ship, keeping their emulated
+/// time in lockstep. Each machine runs a quantum of cycles and then waits for
+/// the others to catch up, so none is ever more than a quantum (and the
+/// instruction it was in the middle of) ahead of another.
+///
+/// Machines can run on the scheduler's thread, or each on a thread of its own.
+/// Modems can be wired to each other through the scheduler's switchboard.
+pub struct Scheduler {
+	// Each machine and the cycles it has run
+	machines: Vec<(Machine, u64)>,
+	quantum: u64,
+	cycle: u64,
+	switchboard: Switchboard,
+}
+
+impl Scheduler {
+	pub fn new(quantum: u64) -> Scheduler {
+		Scheduler {
+			machines: vec![],
+			quantum: quantum.max(1),
+			cycle: 0,
+			switchboard: Switchboard::new(),
+		}
+	}
+
+
+	/// Add a machine run on the scheduler's thread, returning its index
+	pub fn add(&mut self, system: System) -> usize {
+		self.machines.push((Machine::Local(system), 0));
+		self.machines.len() - 1
+	}
+
+
+	/// Add a machine run on a thread of its own, built there by `build`, returning its index
+	pub fn spawn<F>(&mut self, build: F) -> Result<usize, Panicked> where F: FnOnce() -> System + Send + 'static {
+		let index = self.machines.len();
+		let worker = Worker::spawn(build).map_err(|message| Panicked {index: index, message: message})?;
+		self.machines.push((Machine::Threaded(worker), 0));
+		Ok(index)
+	}
+
+
+	/// The exchange for wiring modems on different machines together. Plug a
+	/// modem in with `Modem::with_transport(Box::new(switchboard.connect(number)))`.
+	pub fn switchboard(&self) -> Switchboard {
+		self.switchboard.clone()
+	}
+
+
+	pub fn len(&self) -> usize {
+		self.machines.len()
+	}
+
+
+	/// The cycles every machine has run
+	pub fn cycle(&self) -> u64 {
+		self.cycle
+	}
+
+
+	/// Run every machine for the given number of cycles, a quantum at a time
+	pub fn run_cycles(&mut self, cycles: u64) -> Result<(), Panicked> {
+		let end = self.cycle + cycles;
+
+		while self.cycle < end {
+			let target = (self.cycle + self.quantum).min(end);
+
+			// Start the threaded machines first so that they run alongside the local ones.
+			// Machines that overshot the last quantum by an instruction have less to do.
+			for (index, &mut (ref mut machine, ran)) in self.machines.iter_mut().enumerate() {
+				if let Machine::Threaded(ref mut worker) = *machine {
+					if ran < target && worker.commands.send(Command::Run(target - ran)).is_err() {
+						return Err(Panicked {index: index, message: worker.panicked()});
+					}
+				}
+			}
+
+			for &mut (ref mut machine, ref mut ran) in &mut self.machines {
+				if let Machine::Local(ref mut system) = *machine {
+					if *ran < target {
+						*ran += system.run_cycles(target - *ran);
+					}
+				}
+			}
+
+			for (index, &mut (ref mut machine, ref mut ran)) in self.machines.iter_mut().enumerate() {
+				if let Machine::Threaded(ref mut worker) = *machine {
+					if *ran < target {
+						*ran += worker.finished.recv().map_err(|_| Panicked {index: index, message: worker.panicked()})?;
+					}
+				}
+			}
+
+			self.cycle = target;
+		}
+
+		Ok(())
+	}
+
+
+	/// Look at or change a machine, wherever it is running, between quanta
+	pub fn visit<F, R>(&mut self, index: usize, visit: F) -> Result<R, Panicked>
+		where F: FnOnce(&mut System) -> R + Send + 'static, R: Send + 'static
+	{
+		match self.machines[index].0 {
+			Machine::Local(ref mut system) => Ok(visit(system)),
+			Machine::Threaded(ref mut worker) => {
+				let (sender, receiver) = channel();
+				let command = Command::Visit(Box::new(move |system: &mut System| {
+					let _ = sender.send(visit(system));
+				}));
+
+				match worker.commands.send(command).ok().and_then(|()| receiver.recv().ok()) {
+					Some(result) => Ok(result),
+					None => Err(Panicked {index: index, message: worker.panicked()}),
+				}
+			},
+		}
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dcpu;
+	use modem::Modem;
+	use system::HardwareType;
+	use test_support::machine;
+
+	#[test]
+	fn lockstep() {
+		let mut scheduler = Scheduler::new(100);
+		// add a, 1 then sub pc, 2, four cycles a loop
+		let program = [0x8802, 0x8f83];
+		scheduler.add(machine(&program, vec![]));
+		scheduler.spawn(move || machine(&program, vec![])).unwrap();
+
+		scheduler.run_cycles(1000).unwrap();
+		assert_eq!(scheduler.cycle(), 1000);
+
+		for index in 0..scheduler.len() {
+			let (cycles, a) = scheduler.visit(index, |system| (system.dcpu.cycle_count, system.dcpu.registers[dcpu::A])).unwrap();
+			assert!(cycles >= 1000 && cycles < 1002);
+			assert_eq!(a as u64, (cycles + 3) / 4);
+		}
+	}
+
+	#[test]
+	fn modems_across_machines() {
+		let mut scheduler = Scheduler::new(10);
+		let switchboard = scheduler.switchboard();
+
+		// set a, 3; set b, 0; set c, 200; hwi 0; sub pc, 1 dials 200
+		let caller = machine(&[0x9001, 0x8421, 0x7c41, 200, 0x8640, 0x8b83],
+			vec![HardwareType::Eklectic(Modem::with_transport(Box::new(switchboard.connect(100))))]);
+		scheduler.add(caller);
+
+		let switchboard = scheduler.switchboard();
+		let callee = scheduler.spawn(move || machine(&[0x8b83],
+			vec![HardwareType::Eklectic(Modem::with_transport(Box::new(switchboard.connect(200))))])).unwrap();
+
+		// Long enough for the callee's modem to look at the line after the call is made
+		scheduler.run_cycles(200).unwrap();
+
+		// Ask the callee's modem for its status
+		let state = scheduler.visit(callee, |system| {
+			system.dcpu.registers[dcpu::A] = 1;
+			if let HardwareType::Eklectic(ref mut modem) = system.hardware[0] {
+				modem.interrupt(&mut system.dcpu);
+			}
+			system.dcpu.registers[dcpu::A]
+		}).unwrap();
+		assert_eq!(state, 1, "the callee should be ringing");
+	}
+
+	#[test]
+	fn panicking_machine() {
+		let mut scheduler = Scheduler::new(100);
+		scheduler.add(machine(&[0x8b83], vec![]));
+		let broken = scheduler.spawn(|| machine(&[0x8b83], vec![])).unwrap();
+
+		let panicked = scheduler.visit(broken, |_| panic!("the reactor blew")).unwrap_err();
+		assert_eq!((panicked.index, panicked.message.as_str()), (broken, "the reactor blew"));
+
+		let panicked = scheduler.run_cycles(1000).unwrap_err();
+		assert_eq!((panicked.index, panicked.message.as_str()), (broken, "the reactor blew"));
+
+		let panicked = scheduler.spawn(|| panic!("no ship to build")).unwrap_err();
+		assert_eq!((panicked.index, panicked.message.as_str()), (2, "no ship to build"));
+	}
+}
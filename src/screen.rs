@@ -0,0 +1,229 @@
+use dcpu;
+use dcpu::Dcpu;
+
+/// Characters across and down the LEM1802's screen, inside its border
+pub const COLUMNS: u16 = 32;
+pub const ROWS: u16 = 12;
+
+
+/// A character on the screen, decoded from a word of video RAM
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cell {
+	pub character: u8,
+	/// Indices into the pallet
+	pub foreground: u8,
+	pub background: u8,
+	pub blink: bool,
+}
+
+impl Cell {
+	pub fn decode(word: u16) -> Cell {
+		Cell {
+			character: (word & 0b0000_0000_0111_1111) as u8,
+			foreground: ((word & 0b1111_0000_0000_0000) >> 12) as u8,
+			background: ((word & 0b0000_1111_0000_0000) >> 8) as u8,
+			blink: word & 0b0000_0000_1000_0000 != 0,
+		}
+	}
+}
+
+
+/// The part of the LEM1802 the DCPU can see: where its video RAM is mapped, its
+/// font, pallet and border color. The monitor draws it in a window, but it can
+/// also be attached on its own when there is nobody to look at the screen.
+pub struct Screen {
+	pub font_ram: [u16; 256],
+	pub pallet_ram: [u16; 16],
+	/// Zero while the screen is disconnected
+	pub video_ram: u16,
+	pub border_color: u16,
+}
+
+impl Screen {
+	pub fn new() -> Screen {
+		Screen {
+			font_ram: DEFAULT_FONT,
+			pallet_ram: DEFAULT_PALLET,
+			video_ram: 0,
+			border_color: 7,
+		}
+	}
+
+
+	/// The character at the given column and row, or `None` while the screen is disconnected
+	pub fn cell(&self, memory: &[u16], column: u16, row: u16) -> Option<Cell> {
+		if self.video_ram == 0 {
+			return None;
+		}
+
+		let address = self.video_ram.wrapping_add(column + row * COLUMNS);
+		Some(Cell::decode(memory[address as usize]))
+	}
+
+
+	/// What the screen shows as text, a line for each row. Blank cells show as
+	/// spaces and characters outside printable ASCII as dots.
+	pub fn text(&self, memory: &[u16]) -> String {
+		let mut text = String::new();
+		for row in 0..ROWS {
+			for column in 0..COLUMNS {
+				let character = self.cell(memory, column, row).map_or(0, |cell| cell.character);
+				text.push(match character {
+					0 => ' ',
+					0x20...0x7e => character as char,
+					_ => '.',
+				});
+			}
+			text.push('\n');
+		}
+		text
+	}
+
+
+	pub fn interrupt(&mut self, dcpu: &mut Dcpu) {
+		let b = dcpu.registers[dcpu::B];
+
+		match dcpu.registers[dcpu::A] {
+			0 => self.video_ram = b,
+			// The font and pallet are copied so that they can go in a save state
+			1 => copy_from(&dcpu.memory, b, &mut self.font_ram),
+			2 => copy_from(&dcpu.memory, b, &mut self.pallet_ram),
+			3 => self.border_color = b & 0xf,
+			4 => copy_to(&self.font_ram, &mut dcpu.memory, b),
+			5 => copy_to(&self.pallet_ram, &mut dcpu.memory, b),
+			_ => (),
+		}
+	}
+
+
+	/// The screen's part of a save state: video RAM, border color, pallet and font
+	pub fn save(&self) -> Vec<u16> {
+		let mut words = vec![self.video_ram, self.border_color];
+		words.extend_from_slice(&self.pallet_ram);
+		words.extend_from_slice(&self.font_ram);
+		words
+	}
+
+	pub fn restore(&mut self, words: &[u16]) -> Result<(), String> {
+		if words.len() != 2 + 16 + 256 {
+			return Err("The LEM1802 in the save state is incomplete".to_string());
+		}
+
+		self.video_ram = words[0];
+		self.border_color = words[1];
+		self.pallet_ram.copy_from_slice(&words[2..18]);
+		self.font_ram.copy_from_slice(&words[18..]);
+		Ok(())
+	}
+}
+
+
+// Copy words out of memory starting at the address, wrapping round the end of memory
+fn copy_from(memory: &[u16], address: u16, words: &mut [u16]) {
+	for (offset, word) in words.iter_mut().enumerate() {
+		*word = memory[address.wrapping_add(offset as u16) as usize];
+	}
+}
+
+
+// Copy words into memory starting at the address, wrapping round the end of memory
+fn copy_to(words: &[u16], memory: &mut [u16], address: u16) {
+	for (offset, &word) in words.iter().enumerate() {
+		memory[address.wrapping_add(offset as u16) as usize] = word;
+	}
+}
+
+
+pub const DEFAULT_PALLET: [u16; 16] = [
+	0x000, 0x00a, 0x0a0, 0x0aa, 0xa00, 0xa0a, 0xa50, 0xaaa,
+	0x555, 0x55f, 0x5f5, 0x5ff, 0xf55, 0xf5f, 0xff5, 0xfff,
+];
+
+
+pub const DEFAULT_FONT: [u16; 256] = [
+	0x000f, 0x0808, 0x080f, 0x0808, 0x08f8, 0x0808, 0x00ff, 0x0808,
+	0x0808, 0x0808, 0x08ff, 0x0808, 0x00ff, 0x1414, 0xff00, 0xff08,
+	0x1f10, 0x1714, 0xfc04, 0xf414, 0x1710, 0x1714, 0xf404, 0xf414,
+	0xff00, 0xf714, 0x1414, 0x1414, 0xf700, 0xf714, 0x1417, 0x1414,
+	0x0f08, 0x0f08, 0x14f4, 0x1414, 0xf808, 0xf808, 0x0f08, 0x0f08,
+	0x001f, 0x1414, 0x00fc, 0x1414, 0xf808, 0xf808, 0xff08, 0xff08,
+	0x14ff, 0x1414, 0x080f, 0x0000, 0x00f8, 0x0808, 0xffff, 0xffff,
+	0xf0f0, 0xf0f0, 0xffff, 0x0000, 0x0000, 0xffff, 0x0f0f, 0x0f0f,
+	0x0000, 0x0000, 0x005f, 0x0000, 0x0300, 0x0300, 0x3e14, 0x3e00,
+	0x266b, 0x3200, 0x611c, 0x4300, 0x3629, 0x7650, 0x0002, 0x0100,
+	0x1c22, 0x4100, 0x4122, 0x1c00, 0x2a1c, 0x2a00, 0x083e, 0x0800,
+	0x4020, 0x0000, 0x0808, 0x0800, 0x0040, 0x0000, 0x601c, 0x0300,
+	0x3e41, 0x3e00, 0x427f, 0x4000, 0x6259, 0x4600, 0x2249, 0x3600,
+	0x0f08, 0x7f00, 0x2745, 0x3900, 0x3e49, 0x3200, 0x6119, 0x0700,
+	0x3649, 0x3600, 0x2649, 0x3e00, 0x0024, 0x0000, 0x4024, 0x0000,
+	0x0814, 0x2241, 0x1414, 0x1400, 0x4122, 0x1408, 0x0259, 0x0600,
+	0x3e59, 0x5e00, 0x7e09, 0x7e00, 0x7f49, 0x3600, 0x3e41, 0x2200,
+	0x7f41, 0x3e00, 0x7f49, 0x4100, 0x7f09, 0x0100, 0x3e49, 0x3a00,
+	0x7f08, 0x7f00, 0x417f, 0x4100, 0x2040, 0x3f00, 0x7f0c, 0x7300,
+	0x7f40, 0x4000, 0x7f06, 0x7f00, 0x7f01, 0x7e00, 0x3e41, 0x3e00,
+	0x7f09, 0x0600, 0x3e41, 0xbe00, 0x7f09, 0x7600, 0x2649, 0x3200,
+	0x017f, 0x0100, 0x7f40, 0x7f00, 0x1f60, 0x1f00, 0x7f30, 0x7f00,
+	0x7708, 0x7700, 0x0778, 0x0700, 0x7149, 0x4700, 0x007f, 0x4100,
+	0x031c, 0x6000, 0x0041, 0x7f00, 0x0201, 0x0200, 0x8080, 0x8000,
+	0x0001, 0x0200, 0x2454, 0x7800, 0x7f44, 0x3800, 0x3844, 0x2800,
+	0x3844, 0x7f00, 0x3854, 0x5800, 0x087e, 0x0900, 0x4854, 0x3c00,
+	0x7f04, 0x7800, 0x447d, 0x4000, 0x2040, 0x3d00, 0x7f10, 0x6c00,
+	0x417f, 0x4000, 0x7c18, 0x7c00, 0x7c04, 0x7800, 0x3844, 0x3800,
+	0x7c14, 0x0800, 0x0814, 0x7c00, 0x7c04, 0x0800, 0x4854, 0x2400,
+	0x043e, 0x4400, 0x3c40, 0x7c00, 0x1c60, 0x1c00, 0x7c30, 0x7c00,
+	0x6c10, 0x6c00, 0x4c50, 0x3c00, 0x6454, 0x4c00, 0x0836, 0x4100,
+	0x0077, 0x0000, 0x4136, 0x0800, 0x0201, 0x0201, 0x704c, 0x7000,
+];
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn decode_video_ram() {
+		let mut dcpu = Dcpu::new();
+		let mut screen = Screen::new();
+		assert_eq!(screen.cell(&dcpu.memory, 0, 0), None);
+
+		dcpu.registers[dcpu::A] = 0;
+		dcpu.registers[dcpu::B] = 0x8000;
+		screen.interrupt(&mut dcpu);
+
+		dcpu.memory[0x8000] = 0xf048;
+		dcpu.memory[0x8001] = 0x2169;
+		dcpu.memory[0x8000 + 32 + 31] = 0x0001;
+		assert_eq!(screen.cell(&dcpu.memory, 0, 0), Some(Cell {character: b'H', foreground: 0xf, background: 0, blink: false}));
+		assert_eq!(screen.cell(&dcpu.memory, 1, 0), Some(Cell {character: b'i', foreground: 2, background: 1, blink: false}));
+
+		let text = screen.text(&dcpu.memory);
+		let lines: Vec<&str> = text.lines().collect();
+		assert_eq!(lines.len(), 12);
+		assert_eq!(lines[0], format!("{:32}", "Hi"));
+		assert_eq!(lines[1], format!("{:31}.", ""));
+	}
+
+	#[test]
+	fn font_round_trips_through_memory() {
+		let mut dcpu = Dcpu::new();
+		let mut screen = Screen::new();
+
+		// Dump the font across the end of memory, then map it back in from there
+		dcpu.registers[dcpu::A] = 4;
+		dcpu.registers[dcpu::B] = 0xff80;
+		screen.interrupt(&mut dcpu);
+		assert_eq!(dcpu.memory[0xffff], DEFAULT_FONT[0x7f]);
+		assert_eq!(dcpu.memory[0], DEFAULT_FONT[0x80]);
+
+		dcpu.memory[0] = 0x1234;
+		dcpu.registers[dcpu::A] = 1;
+		screen.interrupt(&mut dcpu);
+		assert_eq!(screen.font_ram[0x80], 0x1234);
+
+		let mut restored = Screen::new();
+		restored.restore(&screen.save()).unwrap();
+		assert_eq!(restored.font_ram[0x80], 0x1234);
+	}
+}
@@ -0,0 +1,386 @@
+use std::io;
+use std::io::prelude::*;
+use std::fmt;
+use std::fs::File;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use dcpu::Dcpu;
+use capture::{Event, LineLog, LineEvents};
+
+
+/// Something from outside that went into a device during a session. These are
+/// everything the machine can't work out for itself, so a session played back
+/// from them runs exactly as it was recorded. Nothing in the machine reads the
+/// host's clock, as devices keep time in emulated cycles, so there is no time
+/// to record.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+	/// A key was typed
+	Key(u16),
+	/// A key went down, and is held until it comes up
+	KeyDown(u16),
+	KeyUp(u16),
+	/// A modem's line did something, written as it is in a modem capture
+	Line(Event),
+}
+
+impl fmt::Display for Input {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Input::Key(key) => write!(f, "key {:04x}", key),
+			Input::KeyDown(key) => write!(f, "key-down {:04x}", key),
+			Input::KeyUp(key) => write!(f, "key-up {:04x}", key),
+			Input::Line(ref event) => write!(f, "{}", event),
+		}
+	}
+}
+
+
+// A line of a session: an input to a device, or a checkpoint of the machine's state
+#[derive(Debug, PartialEq)]
+enum Line {
+	Input(usize, Input),
+	Checkpoint(u64),
+}
+
+
+// Parse a single `<cycle> <device> <input> [arguments]` or `<cycle> checkpoint <hash>` line of a session
+fn parse_line(line: &str) -> Option<(u64, Line)> {
+	let mut parts = line.split_whitespace();
+	let cycle = parts.next()?.parse().ok()?;
+	let device = parts.next()?;
+
+	if device == "checkpoint" {
+		return Some((cycle, Line::Checkpoint(u64::from_str_radix(parts.next()?, 16).ok()?)));
+	}
+
+	let device = device.parse().ok()?;
+	let kind = parts.next()?;
+	let arguments: Vec<&str> = parts.collect();
+
+	let input = match kind {
+		"key" => Input::Key(u16::from_str_radix(arguments.get(0)?, 16).ok()?),
+		"key-down" => Input::KeyDown(u16::from_str_radix(arguments.get(0)?, 16).ok()?),
+		"key-up" => Input::KeyUp(u16::from_str_radix(arguments.get(0)?, 16).ok()?),
+		_ => Input::Line(Event::parse(kind, &arguments).filter(Event::is_line)?),
+	};
+
+	Some((cycle, Line::Input(device, input)))
+}
+
+
+/// A hash of the CPU's registers and memory, to check a replay hasn't strayed from the recording
+pub fn hash(dcpu: &Dcpu) -> u64 {
+	// FNV-1a, a word at a time
+	let mut hash = 0xcbf2_9ce4_8422_2325u64;
+	let registers = [dcpu.stack_pointer, dcpu.program_counter, dcpu.excess, dcpu.interrupt_address];
+	for &word in dcpu.registers.iter().chain(registers.iter()).chain(dcpu.memory.iter()) {
+		hash = (hash ^ word as u64).wrapping_mul(0x0000_0100_0000_01b3);
+	}
+	hash
+}
+
+
+
+
+/// Writes a session down, one input or checkpoint per line with the cycle it
+/// happened on. Each device attached to the machine gets its own recorder for
+/// the same session, with `device`.
+#[derive(Clone)]
+pub struct Recorder {
+	output: Arc<Mutex<Box<dyn Write>>>,
+	device: usize,
+}
+
+impl Recorder {
+	/// Record to the given file, replacing anything already in it
+	pub fn create(path: &str) -> io::Result<Recorder> {
+		Recorder::new(Box::new(io::BufWriter::new(File::create(path)?)))
+	}
+
+	pub fn new(mut output: Box<dyn Write>) -> io::Result<Recorder> {
+		writeln!(output, "# dcpu session")?;
+		Ok(Recorder {output: Arc::new(Mutex::new(output)), device: 0})
+	}
+
+	/// A recorder for the device with the given index
+	pub fn device(&self, device: usize) -> Recorder {
+		Recorder {output: self.output.clone(), device: device}
+	}
+
+	pub fn record(&self, cycle: u64, input: &Input) {
+		self.write(format_args!("{} {} {}", cycle, self.device, input));
+	}
+
+	pub fn checkpoint(&self, cycle: u64, dcpu: &Dcpu) {
+		self.write(format_args!("{} checkpoint {:016x}", cycle, hash(dcpu)));
+	}
+
+	fn write(&self, line: fmt::Arguments) {
+		// The emulator usually exits by being killed, so don't leave anything sitting in a buffer
+		let mut output = self.output.lock().unwrap();
+		if writeln!(output, "{}", line).and_then(|_| output.flush()).is_err() {
+			eprintln!("Unable to write to the session recording");
+		}
+	}
+}
+
+impl LineLog for Recorder {
+	fn record_line(&self, cycle: u64, event: &Event) {
+		self.write(format_args!("{} {} {}", cycle, self.device, event));
+	}
+}
+
+
+
+
+// What is left of a session being played back
+struct Playback {
+	inputs: HashMap<usize, VecDeque<(u64, Input)>>,
+	checkpoints: VecDeque<(u64, u64)>,
+}
+
+
+/// Plays back a recorded session. Each device attached to the machine gets its
+/// own player for the same session, with `device`, and takes its inputs as the
+/// cycles they were recorded on come round.
+#[derive(Clone)]
+pub struct Player {
+	playback: Arc<Mutex<Playback>>,
+	device: usize,
+}
+
+impl Player {
+	/// Play back the session recorded in the given file
+	pub fn open(path: &str) -> io::Result<Player> {
+		let mut text = String::new();
+		File::open(path)?.read_to_string(&mut text)?;
+		Player::parse(&text)
+	}
+
+	/// Play back a session that has already been read into memory
+	pub fn parse(text: &str) -> io::Result<Player> {
+		let mut playback = Playback {inputs: HashMap::new(), checkpoints: VecDeque::new()};
+
+		for (number, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			match parse_line(line) {
+				Some((cycle, Line::Input(device, input))) =>
+					playback.inputs.entry(device).or_insert_with(VecDeque::new).push_back((cycle, input)),
+
+				Some((cycle, Line::Checkpoint(hash))) =>
+					playback.checkpoints.push_back((cycle, hash)),
+
+				None =>
+					return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unreadable session on line {}: {}", number + 1, line))),
+			}
+		}
+
+		Ok(Player {playback: Arc::new(Mutex::new(playback)), device: 0})
+	}
+
+	/// A player for the device with the given index
+	pub fn device(&self, device: usize) -> Player {
+		Player {playback: self.playback.clone(), device: device}
+	}
+
+	/// Take the device's next input if it was recorded by the given cycle and
+	/// `wanted` says it is the one being looked for
+	pub fn take<F>(&self, cycle: u64, wanted: F) -> Option<Input> where F: FnOnce(&Input) -> bool {
+		let mut playback = self.playback.lock().unwrap();
+		let inputs = playback.inputs.get_mut(&self.device)?;
+
+		let due = match inputs.front() {
+			Some(&(recorded, ref input)) => recorded <= cycle && wanted(input),
+			None => false,
+		};
+
+		if due {
+			inputs.pop_front().map(|(_, input)| input)
+		} else {
+			None
+		}
+	}
+
+	/// Put an input back to be taken again, like the rest of a read that didn't fit
+	pub fn put_back(&self, cycle: u64, input: Input) {
+		let mut playback = self.playback.lock().unwrap();
+		playback.inputs.entry(self.device).or_insert_with(VecDeque::new).push_front((cycle, input));
+	}
+
+	/// Check the machine against the session's next checkpoint, if the machine
+	/// has reached it. An error says how the replay has strayed.
+	pub fn verify(&self, dcpu: &Dcpu) -> Result<(), String> {
+		let mut playback = self.playback.lock().unwrap();
+
+		let (cycle, expected) = match playback.checkpoints.front() {
+			Some(&(cycle, expected)) if cycle <= dcpu.cycle_count => (cycle, expected),
+			_ => return Ok(()),
+		};
+		playback.checkpoints.pop_front();
+
+		if cycle != dcpu.cycle_count {
+			Err(format!("The replay passed the checkpoint at cycle {} without stopping there, at cycle {}", cycle, dcpu.cycle_count))
+		} else if hash(dcpu) != expected {
+			Err(format!("The replay has strayed from the recording by the checkpoint at cycle {}", cycle))
+		} else {
+			Ok(())
+		}
+	}
+
+	/// The cycle of the next checkpoint still to be checked
+	pub fn next_checkpoint(&self) -> Option<u64> {
+		self.playback.lock().unwrap().checkpoints.front().map(|&(cycle, _)| cycle)
+	}
+
+	/// Whether every input and checkpoint has been played back
+	pub fn finished(&self) -> bool {
+		let playback = self.playback.lock().unwrap();
+		playback.checkpoints.is_empty() && playback.inputs.values().all(|inputs| inputs.is_empty())
+	}
+}
+
+
+
+
+impl LineEvents for Player {
+	fn take(&self, cycle: u64, wanted: &dyn Fn(&Event) -> bool) -> Option<Event> {
+		let input = Player::take(self, cycle, |input| match *input {
+			Input::Line(ref event) => wanted(event),
+			_ => false,
+		});
+
+		match input {
+			Some(Input::Line(event)) => Some(event),
+			_ => None,
+		}
+	}
+
+	fn put_back(&self, cycle: u64, event: Event) {
+		Player::put_back(self, cycle, Input::Line(event));
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use transport::{Script, ScriptedConnection, ScriptedTransport, Transport, Unplugged};
+	use system::{System, HardwareType};
+	use modem::Modem;
+	use capture::DialResult;
+	use test_support::SharedOutput;
+
+	#[test]
+	fn lines_round_trip() {
+		let inputs = vec![
+			Input::Key(0x0041),
+			Input::KeyDown(0x0080),
+			Input::KeyUp(0x0080),
+			Input::Line(Event::Call(1, 0x0a00_0001)),
+			Input::Line(Event::NoService),
+			Input::Line(Event::Dial(0x0000_0002, DialResult::Connected, 2)),
+			Input::Line(Event::Dial(0x0000_0003, DialResult::Refused, 0)),
+			Input::Line(Event::Read(2, vec![0xaa, 0x01])),
+			Input::Line(Event::Read(2, vec![])),
+			Input::Line(Event::ReadFailed(1)),
+			Input::Line(Event::WriteFailed(2)),
+		];
+
+		for input in inputs {
+			let line = format!("12 3 {}", input);
+			assert_eq!(parse_line(&line), Some((12, Line::Input(3, input))), "{}", line);
+		}
+
+		assert_eq!(parse_line("40 checkpoint 00000000deadbeef"), Some((40, Line::Checkpoint(0xdead_beef))));
+		assert_eq!(parse_line("40 1 unplugged"), None);
+		// What the modem did, rather than its line, is left to a capture
+		assert_eq!(parse_line("40 1 state Idle"), None);
+	}
+
+	#[test]
+	fn inputs_wait_for_their_cycle() {
+		let player = Player::parse("# dcpu session\n10 1 key 0041\n20 1 key 0042\n5 2 key 0043\n").unwrap();
+		let keyboard = player.device(1);
+		let is_key = |input: &Input| match *input {Input::Key(_) => true, _ => false};
+
+		assert_eq!(keyboard.take(9, is_key), None);
+		assert_eq!(keyboard.take(15, is_key), Some(Input::Key(0x41)));
+		assert_eq!(keyboard.take(15, is_key), None);
+		assert_eq!(player.device(2).take(15, is_key), Some(Input::Key(0x43)));
+		assert!(!player.finished());
+		assert_eq!(keyboard.take(20, is_key), Some(Input::Key(0x42)));
+		assert!(player.finished());
+	}
+
+	// set a, 3; set b, 0; set c, 1; hwi 0 dials 1, then set a, 1; hwi 0; set [0x1000], c; set pc, 4
+	// keeps writing how many words the modem has received to memory
+	const PROGRAM: [u16; 9] = [0x9001, 0x8421, 0x8841, 0x8640, 0x8801, 0x8640, 0x0bc1, 0x1000, 0x9781];
+
+	fn machine(line: Box<dyn Transport>) -> System {
+		let mut system = System::new(vec![HardwareType::Eklectic(Modem::with_transport(line))]);
+		system.dcpu.memory[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+		system
+	}
+
+	#[test]
+	fn whole_session_replays_exactly() {
+		let connection = ScriptedConnection::new(vec![
+			Script::Wait,
+			Script::Receive(vec![0xaa]),
+			Script::Receive(vec![0x12, 0x34, 0x56, 0x78]),
+			Script::HangUp,
+		]);
+		let output = SharedOutput::new();
+		let mut recorded = machine(Box::new(ScriptedTransport::new().dial_result(Ok(connection))));
+		recorded.record(Recorder::new(Box::new(output.clone())).unwrap());
+		recorded.run_cycles(250_000);
+		assert_eq!(recorded.dcpu.memory[0x1000], 2);
+
+		let session = output.text();
+		assert_eq!(session.matches("checkpoint").count(), 3);
+
+		let mut replayed = machine(Box::new(Unplugged));
+		replayed.replay(Player::parse(&session).unwrap(), true);
+		replayed.run_cycles(250_000);
+		assert_eq!(replayed.diverged(), None);
+		assert!(replayed.replay_finished());
+		assert_eq!(hash(&replayed.dcpu), hash(&recorded.dcpu));
+
+		// Leave out the words that came down the line, and the replay strays
+		let tampered = session.lines().filter(|line| !line.contains("read 1 12")).collect::<Vec<_>>().join("\n");
+		let mut replayed = machine(Box::new(Unplugged));
+		replayed.replay(Player::parse(&tampered).unwrap(), true);
+		replayed.run_cycles(250_000);
+		assert!(replayed.diverged().is_some());
+	}
+
+	#[test]
+	fn checkpoints() {
+		let mut dcpu = Dcpu::new();
+		dcpu.cycle_count = 100;
+		let output = SharedOutput::new();
+		Recorder::new(Box::new(output.clone())).unwrap().checkpoint(100, &dcpu);
+
+		let matching = Player::parse(&output.text()).unwrap();
+		let strayed = Player::parse(&output.text()).unwrap();
+		let passed = Player::parse(&output.text()).unwrap();
+
+		assert_eq!(matching.verify(&dcpu), Ok(()));
+		assert!(matching.finished());
+
+		dcpu.memory[0x8000] = 1;
+		assert!(strayed.verify(&dcpu).is_err());
+
+		dcpu.cycle_count = 99;
+		assert_eq!(passed.verify(&dcpu), Ok(()));
+		dcpu.cycle_count = 101;
+		assert!(passed.verify(&dcpu).is_err());
+	}
+}
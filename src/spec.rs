@@ -0,0 +1,165 @@
+/// Which version of the DCPU-16 specification the CPU follows
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Spec {
+	/// The original: 4 bit opcodes with a and b swapped, the overflow register O,
+	/// and no interrupts or hardware. The disassembler, debugger, traces, profiler,
+	/// coverage and GDB only understand the later encoding, so the command line
+	/// refuses them with this version.
+	V1_1,
+	/// The last published specification
+	V1_7,
+	/// 1.7 with the community's extensions: log, brk and hlt
+	V2_0,
+}
+
+impl Default for Spec {
+	fn default() -> Spec {
+		Spec::V1_7
+	}
+}
+
+impl Spec {
+	/// Read a version as written on the command line, like `1.7`
+	pub fn parse(text: &str) -> Option<Spec> {
+		match text.trim().trim_start_matches('v') {
+			"1.1" => Some(Spec::V1_1),
+			"1.7" => Some(Spec::V1_7),
+			"2" | "2.0" => Some(Spec::V2_0),
+			_ => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Spec::V1_1 => "1.1",
+			Spec::V1_7 => "1.7",
+			Spec::V2_0 => "2.0",
+		}
+	}
+
+	/// The version as a word for a save state, major version in the high byte
+	pub fn code(&self) -> u16 {
+		match *self {
+			Spec::V1_1 => 0x0101,
+			Spec::V1_7 => 0x0107,
+			Spec::V2_0 => 0x0200,
+		}
+	}
+
+	pub fn from_code(code: u16) -> Option<Spec> {
+		[Spec::V1_1, Spec::V1_7, Spec::V2_0].iter().cloned().find(|spec| spec.code() == code)
+	}
+
+
+	/// Whether the CPU has interrupts and hardware at all
+	pub fn has_interrupts(&self) -> bool {
+		*self != Spec::V1_1
+	}
+
+
+	/// The cost of a basic instruction, numbered as in 1.7 for every version, or
+	/// None if this version doesn't have it. Costs for operands' next words and
+	/// failed ifs come on top.
+	pub fn basic_cost(&self, instruction: u16) -> Option<u32> {
+		match *self {
+			Spec::V1_1 => match instruction {
+				0x01 | 0x0a | 0x0b | 0x0c => Some(1),
+				0x02 | 0x03 | 0x04 | 0x0d | 0x0f => Some(2),
+				0x06 | 0x08 => Some(3),
+				0x10 | 0x12 | 0x13 | 0x14 => Some(2),
+				_ => None,
+			},
+
+			Spec::V1_7 | Spec::V2_0 => match instruction {
+				0x01 | 0x0a | 0x0b | 0x0c | 0x0d | 0x0e | 0x0f => Some(1),
+				0x02 | 0x03 | 0x04 | 0x05 | 0x1e | 0x1f => Some(2),
+				0x10...0x17 => Some(2),
+				0x06 | 0x07 | 0x08 | 0x09 | 0x1a | 0x1b => Some(3),
+				_ => None,
+			},
+		}
+	}
+
+
+	/// The cost of a special instruction, or None if this version doesn't have it
+	pub fn special_cost(&self, instruction: u16) -> Option<u32> {
+		match (*self, instruction) {
+			(Spec::V1_1, 0x01) => Some(2), // jsr
+			(Spec::V1_1, _) => None,
+
+			(_, 0x01) => Some(3), // jsr
+			(_, 0x07) => Some(9), // hcf
+			(_, 0x08) => Some(4), // int
+			(_, 0x09) => Some(1), // iag
+			(_, 0x0a) => Some(1), // ias
+			(_, 0x0b) => Some(3), // rfi
+			(_, 0x0c) => Some(2), // iaq
+			(_, 0x10) => Some(2), // hwn
+			(_, 0x11) => Some(4), // hwq
+			(_, 0x12) => Some(4), // hwi
+
+			(Spec::V2_0, 0x13) => Some(1), // log
+			(Spec::V2_0, 0x14) => Some(1), // brk
+			(Spec::V2_0, 0x15) => Some(1), // hlt
+			_ => None,
+		}
+	}
+
+
+	/// The number of words taken by the instruction starting with the given word
+	pub fn instruction_length(&self, word: u16) -> u16 {
+		// Special instructions have no b operand. In 1.1 that is where the opcode goes.
+		let (is_special, operand_b) = match *self {
+			Spec::V1_1 => (word & 0xf == 0, (word >> 4) & 0x3f),
+			Spec::V1_7 | Spec::V2_0 => (word & 0x1f == 0, (word >> 5) & 0x1f),
+		};
+
+		let mut length = 1;
+		if self.uses_next_word(word >> 10) {
+			length += 1;
+		}
+		if !is_special && self.uses_next_word(operand_b) {
+			length += 1;
+		}
+		length
+	}
+
+
+	/// Whether the operand is followed by a "next word"
+	pub fn uses_next_word(&self, operand: u16) -> bool {
+		match (*self, operand) {
+			(_, 0x10...0x17) | (_, 0x1e) | (_, 0x1f) => true,
+			// pick in 1.7, push in 1.1
+			(Spec::V1_7, 0x1a) | (Spec::V2_0, 0x1a) => true,
+			_ => false,
+		}
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn versions() {
+		for spec in &[Spec::V1_1, Spec::V1_7, Spec::V2_0] {
+			assert_eq!(Spec::parse(spec.name()), Some(*spec));
+			assert_eq!(Spec::from_code(spec.code()), Some(*spec));
+		}
+
+		assert_eq!(Spec::V1_1.basic_cost(0x05), None);
+		assert_eq!(Spec::V1_7.special_cost(0x07), Some(9));
+		assert_eq!(Spec::V1_7.special_cost(0x15), None);
+		assert_eq!(Spec::V2_0.special_cost(0x15), Some(1));
+
+		// set [0x1000 + a], 0x20 is three words in 1.1, set [0x1000 + a], pick 2 in 1.7
+		assert_eq!(Spec::V1_1.instruction_length(0x7d01), 3);
+		assert_eq!(Spec::V1_7.instruction_length(0x6a01), 3);
+		// jsr 0x1234 in each
+		assert_eq!(Spec::V1_1.instruction_length(0x7c10), 2);
+		assert_eq!(Spec::V1_7.instruction_length(0x7c20), 2);
+	}
+}
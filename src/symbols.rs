@@ -0,0 +1,115 @@
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
+
+
+/// Labels from an assembler's symbol file, looked up by name or by address
+#[derive(Clone)]
+pub struct Symbols {
+	by_name: HashMap<String, u16>,
+	by_address: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+	pub fn new() -> Symbols {
+		Symbols {
+			by_name: HashMap::new(),
+			by_address: BTreeMap::new(),
+		}
+	}
+
+	/// Load a symbol file with one label and address per line, in either order,
+	/// such as `:loop 0x0012` or `0x0012 loop`. Comments start with `;` or `#`.
+	pub fn load(path: &str) -> io::Result<Symbols> {
+		let mut text = String::new();
+		File::open(path)?.read_to_string(&mut text)?;
+		Symbols::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+	}
+
+	pub fn parse(text: &str) -> Result<Symbols, String> {
+		let mut symbols = Symbols::new();
+
+		for (number, line) in text.lines().enumerate() {
+			let line = line.split(|c| c == ';' || c == '#').next().unwrap_or("");
+			let words: Vec<&str> = line.split_whitespace().filter(|word| *word != "=").collect();
+
+			match words.len() {
+				0 => continue,
+
+				2 => match (parse_number(words[0]), parse_number(words[1])) {
+					(Some(address), None) => symbols.insert(words[1], address),
+					(None, Some(address)) => symbols.insert(words[0], address),
+					_ => return Err(format!("Expected a label and an address on line {}: {}", number + 1, line.trim())),
+				},
+
+				_ => return Err(format!("Expected a label and an address on line {}: {}", number + 1, line.trim())),
+			}
+		}
+
+		Ok(symbols)
+	}
+
+	pub fn insert(&mut self, label: &str, address: u16) {
+		let label = label.trim_start_matches(':').trim_end_matches(':');
+		self.by_name.insert(label.to_string(), address);
+		self.by_address.entry(address).or_insert_with(|| label.to_string());
+	}
+
+	/// The address of the given label
+	pub fn address(&self, label: &str) -> Option<u16> {
+		self.by_name.get(label.trim_start_matches(':')).cloned()
+	}
+
+	/// The label at exactly the given address
+	pub fn label(&self, address: u16) -> Option<&str> {
+		self.by_address.get(&address).map(|label| label.as_str())
+	}
+
+	/// The closest label at or before the given address, and how far past it the address is
+	pub fn nearest(&self, address: u16) -> Option<(&str, u16)> {
+		self.by_address.range(..=address).next_back()
+			.map(|(&start, label)| (label.as_str(), address - start))
+	}
+
+	/// Every label, with one per address, in address order
+	pub fn labels(&self) -> Vec<(&str, u16)> {
+		self.by_address.iter().map(|(&address, label)| (label.as_str(), address)).collect()
+	}
+
+	/// Turn a label or a number into an address
+	pub fn resolve(&self, text: &str) -> Option<u16> {
+		parse_number(text).or_else(|| self.address(text))
+	}
+}
+
+
+/// Parse a number written in hex with a `0x` prefix, or in decimal
+pub fn parse_number(text: &str) -> Option<u16> {
+	if text.starts_with("0x") || text.starts_with("0X") {
+		u16::from_str_radix(&text[2..], 16).ok()
+	} else {
+		text.parse().ok()
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_symbol_file() {
+		let symbols = Symbols::parse("; generated\n:start 0x0000\n0x0012 loop\nprint = 20\n\n").unwrap();
+
+		assert_eq!(symbols.address("loop"), Some(0x12));
+		assert_eq!(symbols.address(":print"), Some(20));
+		assert_eq!(symbols.label(0), Some("start"));
+		assert_eq!(symbols.nearest(0x13), Some(("loop", 1)));
+		assert_eq!(symbols.resolve("0x10"), Some(0x10));
+		assert_eq!(symbols.resolve("start"), Some(0));
+		assert!(Symbols::parse("loop\n").is_err());
+	}
+}
@@ -0,0 +1,365 @@
+use dcpu;
+use lem1820;
+use modem;
+use keyboard;
+use keyboard::Keyboard;
+use clock;
+use screen;
+use events::EventQueue;
+use session::{Recorder, Player};
+use savestate::SaveState;
+
+// Cycles between checkpoints of a recorded session, one every emulated second
+const CHECKPOINT_INTERVAL: u64 = dcpu::CLOCK_RATE;
+
+
+pub enum HardwareType {
+	Lem1820(lem1820::Lem1820),
+	/// A LEM1802 with no window to draw on, for running without a display
+	HeadlessLem1820(screen::Screen),
+	Eklectic(modem::Modem),
+	Keyboard(Keyboard),
+	Clock(clock::Clock),
+}
+
+impl HardwareType {
+	/// The (hardware id, version, manufacturer) reported to HWQ
+	fn info(&self) -> (u32, u16, u32) {
+		match *self {
+			HardwareType::Lem1820(_) | HardwareType::HeadlessLem1820(_) => (lem1820::HARDWARE_ID, lem1820::VERSION, lem1820::MANUFACTURER),
+			HardwareType::Eklectic(_) => (modem::HARDWARE_ID, modem::VERSION, modem::MANUFACTURER),
+			HardwareType::Keyboard(_) => (keyboard::HARDWARE_ID, keyboard::VERSION, keyboard::MANUFACTURER),
+			HardwareType::Clock(_) => (clock::HARDWARE_ID, clock::VERSION, clock::MANUFACTURER),
+		}
+	}
+
+	/// The name of the device's section in a save state
+	fn name(&self) -> &'static str {
+		match *self {
+			HardwareType::Lem1820(_) | HardwareType::HeadlessLem1820(_) => "lem1820",
+			HardwareType::Eklectic(_) => "eklectic",
+			HardwareType::Keyboard(_) => "keyboard",
+			HardwareType::Clock(_) => "clock",
+		}
+	}
+}
+
+
+// A session being recorded, or played back and maybe checked against its checkpoints
+enum Session {
+	Recording(Recorder),
+	Replaying(Player, bool),
+}
+
+
+/// A DCPU and the devices attached to it. Time is kept in emulated cycles by
+/// the DCPU's 64 bit cycle count. Devices aren't stepped along with every
+/// cycle, but when they are due, each saying how long until it next wants to be.
+pub struct System {
+	pub dcpu: dcpu::Dcpu,
+	pub hardware: Vec<HardwareType>,
+	events: EventQueue,
+	// The cycle devices were last stepped on
+	stepped: u64,
+	session: Option<Session>,
+	// The cycle the next checkpoint of the session is due on
+	checkpoint: u64,
+	diverged: Option<String>,
+}
+
+impl System {
+	pub fn new(hardware: Vec<HardwareType>) -> System {
+		System {
+			dcpu: dcpu::Dcpu::new(),
+			hardware: hardware,
+			events: EventQueue::new(),
+			stepped: 0,
+			session: None,
+			checkpoint: u64::max_value(),
+			diverged: None,
+		}
+	}
+
+
+	/// The cycles that have passed since the machine was started
+	pub fn cycle(&self) -> u64 {
+		self.dcpu.cycle_count
+	}
+
+
+	pub fn step(&mut self) {
+		self.dcpu.step();
+		self.handle_hardware();
+		self.step_devices();
+		self.check_session();
+	}
+
+
+	/// Run whole instructions until at least the given number of cycles have
+	/// passed, returning how many did, which can be a few more. Devices are
+	/// stepped after every instruction rather than every cycle.
+	pub fn run_cycles(&mut self, cycles: u64) -> u64 {
+		let mut elapsed = 0;
+		while elapsed < cycles {
+			elapsed += self.dcpu.run_instruction() as u64;
+			self.handle_hardware();
+			self.step_devices();
+			self.check_session();
+		}
+		elapsed
+	}
+
+
+	// Answer the hwn, hwq or hwi the DCPU is waiting on, if any
+	fn handle_hardware(&mut self) {
+		if let Some(h) = self.dcpu.hardware_interrupt {
+			match h {
+				dcpu::HardwareInstruction::GetCount(destination) => {
+					let count = self.hardware.len() as u16;
+					self.dcpu.set_value(destination, count);
+				},
+
+				dcpu::HardwareInstruction::GetInfo(hardware_id) => {
+					let (id, version, manufacturer) = self.hardware.get(hardware_id as usize)
+						.map_or((0, 0, 0), |hardware| hardware.info());
+
+					self.dcpu.registers[dcpu::A] = id as u16;
+					self.dcpu.registers[dcpu::B] = (id >> 16) as u16;
+					self.dcpu.registers[dcpu::C] = version;
+					self.dcpu.registers[dcpu::X] = manufacturer as u16;
+					self.dcpu.registers[dcpu::Y] = (manufacturer >> 16) as u16;
+				},
+
+				dcpu::HardwareInstruction::Interrupt(hardware_id) => {
+					let hardware = self.hardware.get_mut(hardware_id as usize);
+
+					// Devices whose timing depends on what they were just told are stepped again straight away
+					let reschedule = match hardware {
+						Some(&mut HardwareType::Lem1820(ref mut lem)) => {lem.interrupt(&mut self.dcpu); false},
+						Some(&mut HardwareType::HeadlessLem1820(ref mut screen)) => {screen.interrupt(&mut self.dcpu); false},
+						Some(&mut HardwareType::Keyboard(ref mut key)) => {key.interrupt(&mut self.dcpu); false},
+						Some(&mut HardwareType::Eklectic(ref mut ek)) => {ek.interrupt(&mut self.dcpu); true},
+						Some(&mut HardwareType::Clock(ref mut clock)) => {clock.interrupt(&mut self.dcpu); true},
+						None => false,
+					};
+
+					if reschedule {
+						self.events.wake(hardware_id as usize, self.dcpu.cycle_count);
+					}
+				}
+
+			}
+
+			self.dcpu.hardware_interrupt = None;
+		}
+	}
+
+
+	// Step every device that is due, and work out when each is next due
+	fn step_devices(&mut self) {
+		let now = self.dcpu.cycle_count;
+
+		// Running backwards in the debugger turns the clock back, leaving devices due far in the future
+		if now < self.stepped {
+			self.events.clear();
+		}
+		self.stepped = now;
+
+		// Devices attached since the last step are due straight away
+		for device in self.events.devices()..self.hardware.len() {
+			self.events.schedule(device, now);
+		}
+
+		while let Some(device) = self.events.pop(now) {
+			let delay = match self.hardware.get_mut(device) {
+				Some(&mut HardwareType::Lem1820(ref mut lem)) => lem.step(&mut self.dcpu),
+				// There is nothing to draw, so it never needs stepping
+				Some(&mut HardwareType::HeadlessLem1820(_)) => u64::max_value(),
+				Some(&mut HardwareType::Keyboard(ref mut key)) => key.step(&mut self.dcpu),
+				Some(&mut HardwareType::Eklectic(ref mut ek)) => ek.step(&mut self.dcpu),
+				Some(&mut HardwareType::Clock(ref mut clock)) => clock.step(&mut self.dcpu),
+				None => continue,
+			};
+
+			self.events.schedule(device, now.saturating_add(delay.max(1)));
+		}
+	}
+
+
+	/// Write down everything that goes into the machine from now on, so that the
+	/// session can be played back exactly with `replay`. The machine's state is
+	/// written down too every so often, for a replay to be checked against.
+	pub fn record(&mut self, recorder: Recorder) {
+		for (index, hardware) in self.hardware.iter_mut().enumerate() {
+			match *hardware {
+				HardwareType::Keyboard(ref mut key) => key.record_session(recorder.device(index)),
+				HardwareType::Eklectic(ref mut ek) => ek.record_session(recorder.device(index)),
+				HardwareType::Lem1820(_) | HardwareType::HeadlessLem1820(_) | HardwareType::Clock(_) => (),
+			}
+		}
+
+		self.checkpoint = self.dcpu.cycle_count;
+		self.session = Some(Session::Recording(recorder));
+		self.check_session();
+	}
+
+
+	/// Play back a recorded session. The machine has to be set up just as it was
+	/// when the recording started, and run the same way, with `run_cycles` or with
+	/// `step`. When `verify` is set, the machine is checked against each checkpoint
+	/// in the recording, and `diverged` says how it first failed to match.
+	pub fn replay(&mut self, player: Player, verify: bool) {
+		for (index, hardware) in self.hardware.iter_mut().enumerate() {
+			match *hardware {
+				HardwareType::Keyboard(ref mut key) => key.replay_session(player.device(index)),
+				HardwareType::Eklectic(ref mut ek) => ek.replay_session(player.device(index)),
+				HardwareType::Lem1820(_) | HardwareType::HeadlessLem1820(_) | HardwareType::Clock(_) => (),
+			}
+		}
+
+		self.checkpoint = if verify {player.next_checkpoint().unwrap_or(u64::max_value())} else {u64::max_value()};
+		self.session = Some(Session::Replaying(player, verify));
+		self.check_session();
+	}
+
+
+	/// How a replay being verified first strayed from its recording, if it has
+	pub fn diverged(&self) -> Option<&str> {
+		self.diverged.as_ref().map(|message| message.as_str())
+	}
+
+
+	/// Whether everything in a recorded session has been played back
+	pub fn replay_finished(&self) -> bool {
+		match self.session {
+			Some(Session::Replaying(ref player, _)) => player.finished(),
+			_ => false,
+		}
+	}
+
+
+	// Write down or check a checkpoint of the session once one is due. They are
+	// only taken between instructions, where running by cycle or by instruction agree.
+	fn check_session(&mut self) {
+		if self.dcpu.cycle_count < self.checkpoint || self.dcpu.cycle_accumulator != 0 {
+			return;
+		}
+
+		let now = self.dcpu.cycle_count;
+		self.checkpoint = match self.session {
+			Some(Session::Recording(ref recorder)) => {
+				recorder.checkpoint(now, &self.dcpu);
+				now + CHECKPOINT_INTERVAL
+			},
+
+			Some(Session::Replaying(ref player, true)) => {
+				if let Err(message) = player.verify(&self.dcpu) {
+					self.diverged = self.diverged.take().or(Some(message));
+				}
+				player.next_checkpoint().unwrap_or(u64::max_value())
+			},
+
+			_ => u64::max_value(),
+		};
+	}
+
+
+	/// Save the CPU and every device
+	pub fn save(&self) -> SaveState {
+		let mut state = SaveState::new();
+		self.dcpu.save(&mut state);
+
+		for (index, hardware) in self.hardware.iter().enumerate() {
+			let words = match *hardware {
+				HardwareType::Lem1820(ref lem) => lem.save(),
+				HardwareType::HeadlessLem1820(ref screen) => screen.save(),
+				HardwareType::Eklectic(ref ek) => ek.save(),
+				HardwareType::Keyboard(ref key) => key.save(),
+				HardwareType::Clock(ref clock) => clock.save(),
+			};
+			state.put(&format!("device.{}.{}", index, hardware.name()), words);
+		}
+
+		state
+	}
+
+
+	/// Put the CPU and every device back the way they were when the state was
+	/// saved. The same devices have to be attached, in the same order.
+	pub fn restore(&mut self, state: &SaveState) -> Result<(), String> {
+		let devices = state.names().iter().filter(|name| name.starts_with("device.")).count();
+		if devices != self.hardware.len() {
+			return Err(format!("The save state has {} devices attached, but there are {} attached now", devices, self.hardware.len()));
+		}
+
+		self.dcpu.restore(state)?;
+
+		for (index, hardware) in self.hardware.iter_mut().enumerate() {
+			let name = format!("device.{}.{}", index, hardware.name());
+			let words = state.get(&name)
+				.ok_or_else(|| format!("Device {} in the save state is not a {}", index, hardware.name()))?;
+
+			match *hardware {
+				HardwareType::Lem1820(ref mut lem) => lem.restore(words)?,
+				HardwareType::HeadlessLem1820(ref mut screen) => screen.restore(words)?,
+				HardwareType::Eklectic(ref mut ek) => ek.restore(words)?,
+				HardwareType::Keyboard(ref mut key) => key.restore(words)?,
+				HardwareType::Clock(ref mut clock) => clock.restore(words)?,
+			}
+		}
+
+		// The clock has jumped, so every device is stepped again to find out when it is next due
+		self.events.clear();
+		Ok(())
+	}
+
+
+	/// Whether a device asked for the machine to be saved since this was last asked
+	pub fn save_requested(&mut self) -> bool {
+		let mut requested = false;
+		for hardware in &mut self.hardware {
+			if let HardwareType::Keyboard(ref mut key) = *hardware {
+				requested |= key.take_save_request();
+			}
+		}
+		requested
+	}
+
+
+	/// Step until the DCPU has executed one whole instruction. Any cycles
+	/// still owed by the previous instruction are used up first.
+	pub fn step_instruction(&mut self) {
+		loop {
+			let executing = self.dcpu.cycle_accumulator == 0;
+			self.step();
+
+			if executing {
+				break;
+			}
+		}
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn devices_are_stepped_when_due() {
+		// set a, 0; set b, 1; hwi 0; sub pc, 1 starts the clock ticking 60 times a second
+		let mut system = System::new(vec![HardwareType::Clock(clock::Clock::new())]);
+		system.dcpu.memory[..4].copy_from_slice(&[0x8401, 0x8821, 0x8640, 0x8b83]);
+
+		system.run_cycles(dcpu::CLOCK_RATE + 10);
+		assert!(system.cycle() >= dcpu::CLOCK_RATE + 10);
+
+		system.dcpu.registers[dcpu::A] = 1;
+		if let HardwareType::Clock(ref mut clock) = system.hardware[0] {
+			clock.interrupt(&mut system.dcpu);
+		}
+		assert_eq!(system.dcpu.registers[dcpu::C], 60);
+	}
+}
@@ -0,0 +1,39 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use system::{HardwareType, System};
+
+
+/// Output that a test hands to something wanting a writer, keeping a clone to
+/// read back what was written
+#[derive(Clone)]
+pub struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl SharedOutput {
+	pub fn new() -> SharedOutput {
+		SharedOutput(Arc::new(Mutex::new(Vec::new())))
+	}
+
+	/// Everything written so far
+	pub fn text(&self) -> String {
+		String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+	}
+}
+
+impl io::Write for SharedOutput {
+	fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+		self.0.lock().unwrap().extend_from_slice(buffer);
+		Ok(buffer.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+
+/// A machine with the given devices and the program loaded at address 0
+pub fn machine(program: &[u16], hardware: Vec<HardwareType>) -> System {
+	let mut system = System::new(hardware);
+	system.dcpu.memory[..program.len()].copy_from_slice(program);
+	system
+}
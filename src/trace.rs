@@ -0,0 +1,287 @@
+use std::io::prelude::*;
+use std::fmt;
+use std::thread;
+use std::collections::VecDeque;
+use disassembler;
+use dcpu::Fault;
+
+
+const REGISTER_NAMES: [&'static str; 11] = ["A", "B", "C", "X", "Y", "Z", "I", "J", "SP", "PC", "EX"];
+const PC: usize = 9;
+const EX: usize = 10;
+
+
+/// Something that wants to hear about every instruction the CPU runs
+pub trait TraceSink {
+	fn record(&mut self, entry: &Entry);
+}
+
+
+/// One instruction the CPU ran
+pub struct Entry {
+	/// Where the instruction was
+	pub address: u16,
+	/// The words making up the instruction
+	pub words: Vec<u16>,
+	pub value_a: u16,
+	/// Special instructions only have an a operand
+	pub value_b: Option<u16>,
+	/// A, B, C, X, Y, Z, I, J, SP, PC and EX before and after the instruction ran
+	pub before: [u16; 11],
+	pub after: [u16; 11],
+	pub cycle_count: u64,
+	/// Cycles the instruction takes, including next words and skipped instructions
+	pub cycles: u32,
+	/// Set if the instruction put the CPU on fire
+	pub fault: Option<Fault>,
+}
+
+impl Entry {
+	fn parts(&self) -> (u16, u16) {
+		let word = self.words[0];
+		(word & 0x1f, (word >> 5) & 0x1f)
+	}
+
+	/// Whether the instruction can send the program somewhere other than the next instruction
+	pub fn is_jump(&self) -> bool {
+		match self.parts() {
+			(0x00, 0x01) | (0x00, 0x08) | (0x00, 0x0b) => true,
+			(0x00, _) => false,
+			(instruction, operand_b) => operand_b == 0x1c && !(0x10..0x18).contains(&instruction),
+		}
+	}
+
+	/// Whether the instruction talks to hardware: hwn, hwq or hwi
+	pub fn is_hardware(&self) -> bool {
+		match self.parts() {
+			(0x00, 0x10...0x12) => true,
+			_ => false,
+		}
+	}
+
+	/// Whether the instruction left the CPU stuck on itself, or set it on fire
+	pub fn is_halt(&self) -> bool {
+		self.after[PC] == self.address || self.parts() == (0x00, 0x07) || self.fault.is_some()
+	}
+}
+
+impl fmt::Display for Entry {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let (text, _) = disassembler::disassemble(&self.words, 0, None);
+		write!(f, "{:>10} {:04x}: {:<28} a={:04x}", self.cycle_count, self.address, text, self.value_a)?;
+
+		if let Some(value_b) = self.value_b {
+			write!(f, " b={:04x}", value_b)?;
+		}
+
+		write!(f, " |")?;
+		for (index, name) in REGISTER_NAMES.iter().enumerate() {
+			if index != PC && index != EX && self.before[index] != self.after[index] {
+				write!(f, " {} {:04x}>{:04x}", name, self.before[index], self.after[index])?;
+			}
+		}
+
+		write!(f, " | EX {:04x}", self.after[EX])
+	}
+}
+
+
+
+
+/// Which instructions make it into a trace
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+	/// Only instructions at addresses from the first to the second, inclusive
+	pub range: Option<(u16, u16)>,
+	pub only_jumps: bool,
+	pub only_hardware: bool,
+}
+
+impl Filter {
+	pub fn accepts(&self, entry: &Entry) -> bool {
+		let in_range = self.range.map_or(true, |(start, end)| entry.address >= start && entry.address <= end);
+		in_range && (!self.only_jumps || entry.is_jump()) && (!self.only_hardware || entry.is_hardware())
+	}
+}
+
+
+
+
+/// Writes instructions out as text, either as they run or, in ring buffer
+/// mode, only the last few when the CPU halts or the tracer is dropped, as it
+/// is when the emulator stops or panics
+pub struct Tracer {
+	filter: Filter,
+	output: Box<dyn Write + Send>,
+	ring: Option<(usize, VecDeque<String>)>,
+	dumped: bool,
+}
+
+impl Tracer {
+	/// Write every instruction the filter accepts
+	pub fn new(output: Box<dyn Write + Send>, filter: Filter) -> Tracer {
+		Tracer {
+			filter: filter,
+			output: output,
+			ring: None,
+			dumped: false,
+		}
+	}
+
+	/// Keep the last `length` instructions the filter accepts, and write them out
+	/// when the CPU halts or the tracer is dropped
+	pub fn ring(output: Box<dyn Write + Send>, filter: Filter, length: usize) -> Tracer {
+		Tracer {
+			filter: filter,
+			output: output,
+			ring: Some((length, VecDeque::with_capacity(length))),
+			dumped: false,
+		}
+	}
+
+
+	// Write out everything in the ring buffer, once until the CPU moves on. The
+	// emulator is usually stopped once the program halts, so flush the output too.
+	fn dump(&mut self, reason: &str) {
+		if self.dumped {
+			return;
+		}
+		self.dumped = true;
+
+		if let Some((_, ref mut entries)) = self.ring {
+			let _ = writeln!(self.output, "# last {} instructions before {}", entries.len(), reason);
+			for entry in entries.drain(..) {
+				let _ = writeln!(self.output, "{}", entry);
+			}
+		}
+
+		let _ = self.output.flush();
+	}
+}
+
+impl TraceSink for Tracer {
+	fn record(&mut self, entry: &Entry) {
+		if self.filter.accepts(entry) {
+			let line = entry.to_string();
+			match self.ring {
+				Some((length, ref mut entries)) => {
+					if entries.len() == length {
+						entries.pop_front();
+					}
+					entries.push_back(line);
+				},
+
+				None => {
+					let _ = writeln!(self.output, "{}", line);
+				},
+			}
+		}
+
+		if entry.is_halt() {
+			self.dump("the CPU halted");
+		} else {
+			self.dumped = false;
+		}
+	}
+}
+
+impl Drop for Tracer {
+	fn drop(&mut self) {
+		// Anything in the ring buffer was run since the last dump
+		if self.ring.as_ref().map_or(false, |&(_, ref entries)| !entries.is_empty()) {
+			self.dumped = false;
+			self.dump(if thread::panicking() {"a panic"} else {"the emulator stopped"});
+		}
+
+		let _ = self.output.flush();
+	}
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io;
+	use dcpu;
+	use test_support::SharedOutput;
+
+	// Run a program that counts A up to 3, asks for the hardware count, then halts
+	fn run(tracer: Tracer) {
+		let mut dcpu = dcpu::Dcpu::new();
+		let program = [
+			0x8802, // add a, 1
+			0x9013, // ifn a, 3
+			0x8781, // set pc, 0
+			0x0200, // hwn a
+			0x8b83, // sub pc, 1
+		];
+		dcpu.memory[..program.len()].copy_from_slice(&program);
+		dcpu.trace_sinks.push(Box::new(tracer));
+
+		for _ in 0..40 {
+			dcpu.step();
+			dcpu.hardware_interrupt = None;
+		}
+	}
+
+	#[test]
+	fn trace_everything() {
+		let output = SharedOutput::new();
+		run(Tracer::new(Box::new(output.clone()), Filter::default()));
+
+		let text = output.text();
+		let first = text.lines().next().unwrap();
+		assert!(first.contains("0000: add a, 1"), "{}", first);
+		assert!(first.contains("A 0000>0001"), "{}", first);
+		assert!(text.contains("hwn a"));
+	}
+
+	#[test]
+	fn ring_buffer_dumps_on_halt() {
+		let output = SharedOutput::new();
+		let filter = Filter {range: Some((0, 3)), only_jumps: false, only_hardware: false};
+		run(Tracer::ring(Box::new(output.clone()), filter, 2));
+
+		let text = output.text();
+		let lines: Vec<&str> = text.lines().collect();
+		assert_eq!(lines.len(), 3, "{}", text);
+		assert!(lines[0].contains("before the CPU halted"));
+		assert!(lines[1].contains("ifn a, 3"), "{}", lines[1]);
+		assert!(lines[2].contains("hwn a"), "{}", lines[2]);
+	}
+
+	#[test]
+	fn written_out_when_dropped() {
+		// add a, 1; set pc, 0 never halts
+		let run_forever = |tracer: Tracer| {
+			let mut dcpu = dcpu::Dcpu::new();
+			dcpu.memory[..2].copy_from_slice(&[0x8802, 0x8781]);
+			dcpu.trace_sinks.push(Box::new(tracer));
+			for _ in 0..10 {
+				dcpu.step();
+			}
+		};
+
+		let output = SharedOutput::new();
+		run_forever(Tracer::ring(Box::new(output.clone()), Filter::default(), 2));
+		let text = output.text();
+		assert!(text.starts_with("# last 2 instructions before the emulator stopped"), "{}", text);
+
+		// Nothing gets past the buffer until it is flushed
+		let output = SharedOutput::new();
+		run_forever(Tracer::new(Box::new(io::BufWriter::new(output.clone())), Filter::default()));
+		assert!(output.text().contains("add a, 1"));
+	}
+
+	#[test]
+	fn only_hardware() {
+		let output = SharedOutput::new();
+		let filter = Filter {range: None, only_jumps: false, only_hardware: true};
+		run(Tracer::new(Box::new(output.clone()), filter));
+
+		let text = output.text();
+		assert_eq!(text.lines().count(), 1, "{}", text);
+	}
+}
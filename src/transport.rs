@@ -0,0 +1,377 @@
+use std;
+use std::io;
+use std::io::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::net::{TcpListener, TcpStream, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+
+
+/// A call in progress between two modems
+pub trait Connection {
+	/// Read whatever has arrived without blocking. Returns `Ok(0)` once the
+	/// other end has hung up and `WouldBlock` when nothing is waiting.
+	fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+
+	/// Write bytes to the other end of the call
+	fn write(&mut self, buffer: &[u8]) -> io::Result<usize>;
+}
+
+
+/// The telephone line a modem is plugged into
+pub trait Transport {
+	/// Accept the next incoming call along with the caller's number, or
+	/// return `WouldBlock` if nobody is calling.
+	fn accept(&mut self) -> io::Result<(Box<dyn Connection>, u32)>;
+
+	/// Place a call to the given number. `ConnectionRefused` means there
+	/// is no modem at the other end.
+	fn dial(&mut self, number: u32) -> io::Result<Box<dyn Connection>>;
+
+	/// Called whenever the modem is stepped with the emulated cycle count, for lines that care about time
+	fn advance(&mut self, _cycle: u64) {}
+}
+
+
+
+
+/// A line carried over TCP, where a telephone number is an IPv4 address. Numbers
+/// below 0x10000, which aren't addresses anyone can be reached at, call the modem
+/// on this machine answering on the port with that number instead.
+pub struct TcpTransport {
+	listener: TcpListener,
+	dial_port: u16,
+}
+
+impl TcpTransport {
+	/// Listen for calls on `listen_port` and place calls to `dial_port`
+	pub fn new(listen_port: u16, dial_port: u16) -> io::Result<TcpTransport> {
+		let listener = TcpListener::bind(("0.0.0.0", listen_port))?;
+		listener.set_nonblocking(true)?;
+
+		Ok(TcpTransport {
+			listener: listener,
+			dial_port: dial_port,
+		})
+	}
+}
+
+impl Transport for TcpTransport {
+	fn accept(&mut self) -> io::Result<(Box<dyn Connection>, u32)> {
+		let (socket, address) = self.listener.accept()?;
+		socket.set_nonblocking(true)?;
+
+		let number = match address.ip() {
+			std::net::IpAddr::V4(ip) => u32::from(ip),
+			std::net::IpAddr::V6(_) => 0,
+		};
+
+		Ok((Box::new(socket), number))
+	}
+
+	fn dial(&mut self, number: u32) -> io::Result<Box<dyn Connection>> {
+		let socket = if number <= 0xffff {
+			TcpStream::connect((Ipv4Addr::new(127, 0, 0, 1), number as u16))?
+		} else {
+			TcpStream::connect((Ipv4Addr::from(number), self.dial_port))?
+		};
+		socket.set_nonblocking(true)?;
+		Ok(Box::new(socket))
+	}
+}
+
+impl Connection for TcpStream {
+	fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+		Read::read(self, buffer)
+	}
+
+	fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+		Write::write(self, buffer)
+	}
+}
+
+
+
+
+/// A line with nothing on the other end. Nobody ever calls, and dialing out finds no telephone service.
+pub struct Unplugged;
+
+impl Transport for Unplugged {
+	fn accept(&mut self) -> io::Result<(Box<dyn Connection>, u32)> {
+		Err(io::Error::new(io::ErrorKind::WouldBlock, "Nobody is calling"))
+	}
+
+	fn dial(&mut self, _number: u32) -> io::Result<Box<dyn Connection>> {
+		Err(io::Error::new(io::ErrorKind::NotConnected, "The modem is unplugged"))
+	}
+}
+
+
+
+
+/// An in-process telephone exchange connecting modems in the same emulator
+#[derive(Clone)]
+pub struct Switchboard {
+	lines: Arc<Mutex<HashMap<u32, VecDeque<(ChannelConnection, u32)>>>>,
+}
+
+impl Switchboard {
+	pub fn new() -> Switchboard {
+		Switchboard {
+			lines: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	/// Plug a new line into the switchboard with the given number
+	pub fn connect(&self, number: u32) -> ChannelTransport {
+		self.lines.lock().unwrap().insert(number, VecDeque::new());
+
+		ChannelTransport {
+			switchboard: self.clone(),
+			number: number,
+		}
+	}
+}
+
+
+/// A line plugged into a `Switchboard`
+pub struct ChannelTransport {
+	switchboard: Switchboard,
+	number: u32,
+}
+
+impl Transport for ChannelTransport {
+	fn accept(&mut self) -> io::Result<(Box<dyn Connection>, u32)> {
+		let mut lines = self.switchboard.lines.lock().unwrap();
+
+		match lines.get_mut(&self.number).and_then(|waiting| waiting.pop_front()) {
+			Some((connection, caller)) => Ok((Box::new(connection), caller)),
+			None => Err(io::Error::new(io::ErrorKind::WouldBlock, "Nobody is calling")),
+		}
+	}
+
+	fn dial(&mut self, number: u32) -> io::Result<Box<dyn Connection>> {
+		let mut lines = self.switchboard.lines.lock().unwrap();
+
+		match lines.get_mut(&number) {
+			Some(waiting) => {
+				let (local, remote) = ChannelConnection::pair();
+				waiting.push_back((remote, self.number));
+				Ok(Box::new(local))
+			},
+
+			None =>
+				Err(io::Error::new(io::ErrorKind::ConnectionRefused, "No modem with that number")),
+		}
+	}
+}
+
+impl Drop for ChannelTransport {
+	fn drop(&mut self) {
+		if let Ok(mut lines) = self.switchboard.lines.lock() {
+			lines.remove(&self.number);
+		}
+	}
+}
+
+
+/// One end of a call placed through a `Switchboard`
+pub struct ChannelConnection {
+	sender: Sender<Vec<u8>>,
+	receiver: Receiver<Vec<u8>>,
+	pending: VecDeque<u8>,
+}
+
+impl ChannelConnection {
+	/// Create both ends of a new call
+	pub fn pair() -> (ChannelConnection, ChannelConnection) {
+		let (a_sender, b_receiver) = channel();
+		let (b_sender, a_receiver) = channel();
+
+		(
+			ChannelConnection {sender: a_sender, receiver: a_receiver, pending: VecDeque::new()},
+			ChannelConnection {sender: b_sender, receiver: b_receiver, pending: VecDeque::new()},
+		)
+	}
+}
+
+impl Connection for ChannelConnection {
+	fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+		let mut hung_up = false;
+
+		loop {
+			match self.receiver.try_recv() {
+				Ok(bytes) => self.pending.extend(bytes),
+				Err(TryRecvError::Empty) => break,
+				Err(TryRecvError::Disconnected) => {
+					hung_up = true;
+					break;
+				},
+			}
+		}
+
+		if self.pending.is_empty() {
+			if hung_up {
+				Ok(0)
+			} else {
+				Err(io::Error::new(io::ErrorKind::WouldBlock, "No data waiting"))
+			}
+		} else {
+			let length = std::cmp::min(buffer.len(), self.pending.len());
+			for (destination, byte) in buffer.iter_mut().zip(self.pending.drain(..length)) {
+				*destination = byte;
+			}
+			Ok(length)
+		}
+	}
+
+	fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+		match self.sender.send(buffer.to_vec()) {
+			Ok(()) => Ok(buffer.len()),
+			Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "The other end hung up")),
+		}
+	}
+}
+
+
+
+
+/// A step in the life of a scripted call
+#[cfg(test)]
+pub enum Script {
+	/// The next read returns these bytes
+	Receive(Vec<u8>),
+	/// The next read finds nothing waiting
+	Wait,
+	/// Every read from now on reports that the other end hung up
+	HangUp,
+	/// The next read fails with the given error
+	Fail(io::ErrorKind),
+}
+
+
+/// A connection that plays back a fixed script and records what was written to it
+#[cfg(test)]
+pub struct ScriptedConnection {
+	script: VecDeque<Script>,
+	written: Arc<Mutex<Vec<u8>>>,
+	write_error: Option<io::ErrorKind>,
+}
+
+#[cfg(test)]
+impl ScriptedConnection {
+	pub fn new(script: Vec<Script>) -> ScriptedConnection {
+		ScriptedConnection {
+			script: script.into_iter().collect(),
+			written: Arc::new(Mutex::new(Vec::new())),
+			write_error: None,
+		}
+	}
+
+	/// Make every write to this connection fail with the given error
+	pub fn failing_writes(mut self, kind: io::ErrorKind) -> ScriptedConnection {
+		self.write_error = Some(kind);
+		self
+	}
+
+	/// A handle to every byte written to this connection
+	pub fn written(&self) -> Arc<Mutex<Vec<u8>>> {
+		self.written.clone()
+	}
+}
+
+#[cfg(test)]
+impl Connection for ScriptedConnection {
+	fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+		match self.script.pop_front() {
+			Some(Script::Receive(mut bytes)) => {
+				let length = std::cmp::min(buffer.len(), bytes.len());
+				buffer[..length].copy_from_slice(&bytes[..length]);
+
+				let remainder = bytes.split_off(length);
+				if !remainder.is_empty() {
+					self.script.push_front(Script::Receive(remainder));
+				}
+
+				Ok(length)
+			},
+
+			Some(Script::HangUp) => {
+				self.script.push_front(Script::HangUp);
+				Ok(0)
+			},
+
+			Some(Script::Fail(kind)) =>
+				Err(io::Error::new(kind, "Scripted failure")),
+
+			Some(Script::Wait) | None =>
+				Err(io::Error::new(io::ErrorKind::WouldBlock, "No data waiting")),
+		}
+	}
+
+	fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+		if let Some(kind) = self.write_error {
+			return Err(io::Error::new(kind, "Scripted failure"));
+		}
+
+		self.written.lock().unwrap().extend_from_slice(buffer);
+		Ok(buffer.len())
+	}
+}
+
+
+/// A transport that hands out scripted connections
+#[cfg(test)]
+pub struct ScriptedTransport {
+	incoming: VecDeque<(ScriptedConnection, u32)>,
+	dial_results: VecDeque<io::Result<ScriptedConnection>>,
+	dialed: Arc<Mutex<Vec<u32>>>,
+}
+
+#[cfg(test)]
+impl ScriptedTransport {
+	pub fn new() -> ScriptedTransport {
+		ScriptedTransport {
+			incoming: VecDeque::new(),
+			dial_results: VecDeque::new(),
+			dialed: Arc::new(Mutex::new(Vec::new())),
+		}
+	}
+
+	/// Queue an incoming call from the given number
+	pub fn incoming_call(mut self, number: u32, connection: ScriptedConnection) -> ScriptedTransport {
+		self.incoming.push_back((connection, number));
+		self
+	}
+
+	/// Queue the result of the next call to `dial`
+	pub fn dial_result(mut self, result: io::Result<ScriptedConnection>) -> ScriptedTransport {
+		self.dial_results.push_back(result);
+		self
+	}
+
+	/// A handle to every number that has been dialed
+	pub fn dialed(&self) -> Arc<Mutex<Vec<u32>>> {
+		self.dialed.clone()
+	}
+}
+
+#[cfg(test)]
+impl Transport for ScriptedTransport {
+	fn accept(&mut self) -> io::Result<(Box<dyn Connection>, u32)> {
+		match self.incoming.pop_front() {
+			Some((connection, number)) => Ok((Box::new(connection), number)),
+			None => Err(io::Error::new(io::ErrorKind::WouldBlock, "Nobody is calling")),
+		}
+	}
+
+	fn dial(&mut self, number: u32) -> io::Result<Box<dyn Connection>> {
+		self.dialed.lock().unwrap().push(number);
+
+		match self.dial_results.pop_front() {
+			Some(Ok(connection)) => Ok(Box::new(connection)),
+			Some(Err(e)) => Err(e),
+			None => Err(io::Error::new(io::ErrorKind::ConnectionRefused, "No modem with that number")),
+		}
+	}
+}